@@ -24,6 +24,8 @@ use logging::SessionLogger;
 const USERS_FILE: &str = "/etc/tau/users.toml";
 const SESSION_LOG: &str = "/var/log/tau/session.log";
 const SESSION_DIR: &str = "/var/lib/tau/sessions";
+/// Users whose service manager tau-service keeps running after logout
+const LINGER_DIR: &str = "/var/lib/tau-service/linger";
 
 #[derive(Debug, Serialize, Deserialize)]
 struct User {
@@ -133,6 +135,9 @@ impl TauSession {
         // Load user session configuration
         let session_config = self.load_session_config(username)?;
         
+        // The user's service manager supervises the desktop components
+        self.start_user_manager(username, user.uid);
+        
         // Set up environment
         let env = self.setup_environment(username, &user, &session_config)?;
         
@@ -233,15 +238,47 @@ impl TauSession {
             ))?;
             
             // Remove from active sessions
-            {
+            let last_session = {
                 let mut sessions = self.active_sessions.lock().unwrap();
                 sessions.remove(session_id);
+                !sessions.values().any(|session| session.username == info.username)
+            };
+            
+            if last_session && !Path::new(LINGER_DIR).join(&info.username).exists() {
+                let uid = self.auth_manager.get_user(&info.username)?.uid;
+                self.stop_user_manager(&info.username, uid);
             }
         }
         
         Ok(())
     }
     
+    /// Starts `user@<uid>` in the system service manager. A session still
+    /// starts without it.
+    fn start_user_manager(&self, username: &str, uid: u32) {
+        self.run_service_command("start", username, uid);
+    }
+    
+    /// Stops the user's service manager after their last session ended,
+    /// unless they linger.
+    fn stop_user_manager(&self, username: &str, uid: u32) {
+        self.run_service_command("stop", username, uid);
+    }
+    
+    fn run_service_command(&self, action: &str, username: &str, uid: u32) {
+        let unit = format!("user@{}", uid);
+        let result = Command::new("tau-service")
+            .args([action, unit.as_str()])
+            .status();
+        
+        let event = match result {
+            Ok(status) if status.success() => format!("USER_MANAGER: {} user={} unit={}", action, username, unit),
+            Ok(status) => format!("USER_MANAGER: {} failed user={} unit={} status={}", action, username, unit, status),
+            Err(e) => format!("USER_MANAGER: {} failed user={} unit={} error={}", action, username, unit, e),
+        };
+        let _ = self.logger.log_event(&event);
+    }
+    
     fn lock_session(&mut self, session_id: &str) -> Result<(), Box<dyn std::error::Error>> {
         // Update session status
         {
@@ -21,6 +21,7 @@ SYSTEM_DIR="/etc/tau/system"
 STATE_DIR="/var/lib/tau-service"
 LOG_DIR="/var/log/tau/journal"
 USER_SERVICES_DIR="$HOME/.config/tau/services"
+DBUS_POLICY_DIR="/usr/share/dbus-1/system.d"
 
 # Function to print colored output
 print_status() {
@@ -132,6 +133,16 @@ install_binary() {
     fi
 }
 
+# Function to install the D-Bus policy
+install_dbus_policy() {
+    print_status "Installing D-Bus policy..."
+    
+    mkdir -p "$DBUS_POLICY_DIR"
+    cp system.d/org.tauos.ServiceManager1.conf "$DBUS_POLICY_DIR/"
+    
+    print_success "D-Bus policy installed to $DBUS_POLICY_DIR"
+}
+
 # Function to create systemd service
 create_systemd_service() {
     print_status "Creating systemd service..."
@@ -344,6 +355,9 @@ main() {
     # Install binary
     install_binary
     
+    # Install D-Bus policy
+    install_dbus_policy
+    
     # Create systemd service
     create_systemd_service
     
@@ -383,6 +397,9 @@ case "${1:-install}" in
         rm -f /etc/systemd/system/tauserviced.service
         systemctl daemon-reload
         
+        # Remove D-Bus policy
+        rm -f "$DBUS_POLICY_DIR/org.tauos.ServiceManager1.conf"
+        
         # Remove man page
         rm -f /usr/share/man/man8/tau-service.8
         mandb -q
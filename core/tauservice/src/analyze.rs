@@ -0,0 +1,244 @@
+use crate::service_manager::ServiceManager;
+use crate::unit::UnitDependencies;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime};
+
+/// Pixels per second in `plot`, bounded so short boots stay readable
+/// and long ones do not get too wide.
+const PLOT_MIN_SCALE: f64 = 20.0;
+const PLOT_MAX_SCALE: f64 = 200.0;
+const PLOT_TARGET_WIDTH: f64 = 1000.0;
+const PLOT_ROW_HEIGHT: f64 = 20.0;
+
+/// When units started, relative to the manager starting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootTimes {
+    pub manager_start: SystemTime,
+    pub units: Vec<UnitTiming>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitTiming {
+    pub name: String,
+    /// When the unit started activating
+    pub activating: Option<SystemTime>,
+    /// When the unit became active
+    pub active: Option<SystemTime>,
+    pub dependencies: UnitDependencies,
+}
+
+impl UnitTiming {
+    /// How long the unit took to become active.
+    pub fn activation_time(&self) -> Option<Duration> {
+        self.active?.duration_since(self.activating?).ok()
+    }
+}
+
+/// One unit of a critical chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainLink {
+    pub name: String,
+    /// When the unit became active, relative to the manager starting
+    pub active_at: Option<Duration>,
+    pub took: Option<Duration>,
+}
+
+impl BootTimes {
+    pub fn collect(manager: &ServiceManager) -> Self {
+        let mut units: Vec<UnitTiming> = manager.unit_graph()
+            .into_iter()
+            .map(|(name, dependencies)| {
+                let status = manager.get_service_status(&name);
+                UnitTiming {
+                    activating: status.as_ref().and_then(|s| s.activation_start),
+                    active: status.and_then(|s| s.start_time),
+                    name,
+                    dependencies,
+                }
+            })
+            .collect();
+        units.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Self {
+            manager_start: manager.started_at(),
+            units,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&UnitTiming> {
+        self.units.iter().find(|unit| unit.name == name)
+    }
+
+    fn offset(&self, time: SystemTime) -> Duration {
+        time.duration_since(self.manager_start).unwrap_or_default()
+    }
+
+    /// Units by the time they took to activate, slowest first.
+    pub fn blame(&self) -> Vec<(&UnitTiming, Duration)> {
+        let mut blame: Vec<(&UnitTiming, Duration)> = self.units.iter()
+            .filter_map(|unit| unit.activation_time().map(|time| (unit, time)))
+            .collect();
+        blame.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.name.cmp(&b.0.name)));
+        blame
+    }
+
+    /// Units ordered before `name` by After= or their Before=.
+    fn ordered_before(&self, name: &str) -> Vec<&UnitTiming> {
+        let Some(unit) = self.get(name) else {
+            return Vec::new();
+        };
+
+        self.units.iter()
+            .filter(|other| {
+                unit.dependencies.after.contains(&other.name)
+                    || other.dependencies.before.iter().any(|before| before == name)
+            })
+            .collect()
+    }
+
+    /// The chain of units `name` waited for: starting from it, each next
+    /// unit is the one ordered before that became active last before the
+    /// previous one started.
+    pub fn critical_chain(&self, name: &str) -> Result<Vec<ChainLink>> {
+        let mut current = self.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Unit {} not found", name))?;
+        let mut visited = HashSet::new();
+        let mut chain = Vec::new();
+
+        loop {
+            visited.insert(current.name.as_str());
+            chain.push(ChainLink {
+                name: current.name.clone(),
+                active_at: current.active.map(|time| self.offset(time)),
+                took: current.activation_time(),
+            });
+
+            let Some(started) = current.activating.or(current.active) else {
+                break;
+            };
+
+            let waited_for = self.ordered_before(&current.name)
+                .into_iter()
+                .filter(|unit| !visited.contains(unit.name.as_str()))
+                .filter_map(|unit| unit.active.filter(|active| *active <= started).map(|active| (unit, active)))
+                .max_by_key(|(_, active)| *active);
+
+            match waited_for {
+                Some((unit, _)) => current = unit,
+                None => break,
+            }
+        }
+
+        Ok(chain)
+    }
+
+    /// An SVG timeline with a bar per unit, red while activating.
+    pub fn plot(&self) -> String {
+        let mut units: Vec<&UnitTiming> = self.units.iter()
+            .filter(|unit| unit.activating.is_some() || unit.active.is_some())
+            .collect();
+        units.sort_by_key(|unit| (unit.activating.or(unit.active), unit.name.clone()));
+
+        let seconds = |time: SystemTime| self.offset(time).as_secs_f64();
+        let end = units.iter()
+            .filter_map(|unit| unit.active.or(unit.activating))
+            .map(seconds)
+            .fold(1.0, f64::max);
+
+        let scale = (PLOT_TARGET_WIDTH / end).clamp(PLOT_MIN_SCALE, PLOT_MAX_SCALE);
+        let label_space = 300.0;
+        let width = end * scale + label_space;
+        let top = 40.0;
+        let height = top + units.len() as f64 * PLOT_ROW_HEIGHT + 20.0;
+
+        let mut svg = String::new();
+        let _ = writeln!(svg, r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{:.0}" height="{:.0}" font-family="sans-serif" font-size="12">"#, width, height);
+        let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+
+        for second in 0..=end.ceil() as u64 {
+            let x = second as f64 * scale;
+            let _ = writeln!(svg, r##"<line x1="{x:.1}" y1="{:.1}" x2="{x:.1}" y2="{:.1}" stroke="#ddd"/>"##, top - 10.0, height - 20.0);
+            let _ = writeln!(svg, r#"<text x="{x:.1}" y="{:.1}">{}s</text>"#, top - 15.0, second);
+        }
+
+        for (row, unit) in units.iter().enumerate() {
+            let y = top + row as f64 * PLOT_ROW_HEIGHT;
+            let start = unit.activating.or(unit.active).map(seconds).unwrap_or_default();
+            let active = unit.active.map(seconds);
+
+            if let Some(active) = active {
+                let _ = writeln!(svg, r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#f08080"/>"##,
+                    start * scale, y, (active - start) * scale, PLOT_ROW_HEIGHT - 4.0);
+                let _ = writeln!(svg, r##"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}" fill="#ccc"/>"##,
+                    active * scale, y, (end - active) * scale, PLOT_ROW_HEIGHT - 4.0);
+            }
+
+            let took = unit.activation_time().map(|took| format!(" ({})", format_span(took))).unwrap_or_default();
+            let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}">{}{}</text>"#,
+                start * scale + 4.0, y + PLOT_ROW_HEIGHT - 8.0, escape_xml(&unit.name), took);
+        }
+
+        svg.push_str("</svg>\n");
+        svg
+    }
+
+    /// The dependency graph in Graphviz format. With `filter`, only edges
+    /// touching those units are included.
+    pub fn dot(&self, filter: &[String]) -> String {
+        let mut dot = String::from("digraph tau {\n");
+        let included = |a: &str, b: &str| {
+            filter.is_empty() || filter.iter().any(|unit| unit == a || unit == b)
+        };
+
+        for unit in &self.units {
+            let deps = &unit.dependencies;
+            let edges = [
+                (&deps.requires, "black"),
+                (&deps.binds_to, "black"),
+                (&deps.wants, "grey66"),
+                (&deps.conflicts, "red"),
+                (&deps.after, "green"),
+            ];
+
+            for (targets, color) in edges {
+                for target in targets {
+                    if included(&unit.name, target) {
+                        let _ = writeln!(dot, "\t\"{}\"->\"{}\" [color=\"{}\"];", unit.name, target, color);
+                    }
+                }
+            }
+
+            // Before= is After= seen from the other unit
+            for target in &deps.before {
+                if included(&unit.name, target) {
+                    let _ = writeln!(dot, "\t\"{}\"->\"{}\" [color=\"green\"];", target, unit.name);
+                }
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Formats a duration the way `analyze` prints them: `850ms`, `1.204s`
+/// or `2min 3.500s`.
+pub fn format_span(duration: Duration) -> String {
+    let millis = duration.as_millis();
+
+    if millis < 1000 {
+        format!("{}ms", millis)
+    } else if millis < 60_000 {
+        format!("{:.3}s", duration.as_secs_f64())
+    } else {
+        format!("{}min {:.3}s", millis / 60_000, (millis % 60_000) as f64 / 1000.0)
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
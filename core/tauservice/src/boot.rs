@@ -1,7 +1,6 @@
 use crate::service_manager::ServiceManager;
-use crate::unit::ServiceUnit;
-use anyhow::{Result, Context};
-use std::collections::HashMap;
+use crate::scope::{lingering_users, user_manager_unit, ManagerScope, LINGER_DIR, USER_MANAGER_UNIT};
+use anyhow::Result;
 use std::fs;
 use std::path::{Path, PathBuf};
 use log::{info, warn, error};
@@ -10,14 +9,26 @@ pub struct BootManager {
     system_dir: PathBuf,
     state_dir: PathBuf,
     targets_dir: PathBuf,
+    scope: ManagerScope,
+}
+
+impl Default for BootManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl BootManager {
     pub fn new() -> Self {
+        Self::for_scope(ManagerScope::current().clone())
+    }
+    
+    pub fn for_scope(scope: ManagerScope) -> Self {
         Self {
-            system_dir: PathBuf::from("/etc/tau/system"),
-            state_dir: PathBuf::from("/var/lib/tau-service"),
-            targets_dir: PathBuf::from("/etc/tau/system"),
+            system_dir: scope.targets_dir(),
+            state_dir: scope.state_dir(),
+            targets_dir: scope.targets_dir(),
+            scope,
         }
     }
     
@@ -29,6 +40,13 @@ impl BootManager {
         fs::create_dir_all(&self.state_dir)?;
         fs::create_dir_all(&self.targets_dir)?;
         
+        // A user manager only has its default target
+        if self.scope.is_user() {
+            fs::create_dir_all(self.targets_dir.join(self.scope.default_target()))?;
+            info!("Boot integration setup complete");
+            return Ok(());
+        }
+        
         // Create boot.target
         self.create_boot_target()?;
         
@@ -38,6 +56,8 @@ impl BootManager {
         // Create network.target
         self.create_network_target()?;
         
+        self.create_user_manager_unit()?;
+        
         info!("Boot integration setup complete");
         Ok(())
     }
@@ -96,14 +116,24 @@ Wants = network-online.target
         Ok(())
     }
     
+    /// Installs the template the user managers run from.
+    fn create_user_manager_unit(&self) -> Result<()> {
+        let units_dir = self.scope.unit_loader().system_units_dir;
+        fs::create_dir_all(&units_dir)?;
+        
+        fs::write(units_dir.join("user@.tau"), USER_MANAGER_UNIT)?;
+        info!("Created user@.tau");
+        Ok(())
+    }
+    
     pub fn enable_service_for_boot(&self, service_name: &str) -> Result<()> {
         let service_unit = format!("{}.tau", service_name);
-        let source_path = PathBuf::from(format!("/etc/tau/services/{}", service_unit));
-        let target_path = self.targets_dir.join("multi-user.target").join(service_unit);
+        let source_path = self.scope.unit_loader().find_fragment(service_name)
+            .ok_or_else(|| anyhow::anyhow!("Service unit file not found: {}", service_unit))?;
+        let target_dir = self.targets_dir.join(self.scope.default_target());
+        let target_path = target_dir.join(service_unit);
         
-        if !source_path.exists() {
-            return Err(anyhow::anyhow!("Service unit file not found: {}", source_path.display()));
-        }
+        fs::create_dir_all(&target_dir)?;
         
         // Create symlink
         if target_path.exists() {
@@ -118,7 +148,7 @@ Wants = network-online.target
     
     pub fn disable_service_from_boot(&self, service_name: &str) -> Result<()> {
         let service_unit = format!("{}.tau", service_name);
-        let target_path = self.targets_dir.join("multi-user.target").join(service_unit);
+        let target_path = self.targets_dir.join(self.scope.default_target()).join(service_unit);
         
         if target_path.exists() {
             fs::remove_file(&target_path)?;
@@ -130,14 +160,14 @@ Wants = network-online.target
     
     pub fn get_boot_services(&self) -> Result<Vec<String>> {
         let mut services = Vec::new();
-        let multi_user_dir = self.targets_dir.join("multi-user.target");
+        let multi_user_dir = self.targets_dir.join(self.scope.default_target());
         
         if multi_user_dir.exists() {
             for entry in fs::read_dir(&multi_user_dir)? {
                 let entry = entry?;
                 let path = entry.path();
                 
-                if path.is_file() && path.extension().map_or(false, |ext| ext == "tau") {
+                if path.is_file() && path.extension().is_some_and(|ext| ext == "tau") {
                     if let Some(service_name) = path.file_stem() {
                         services.push(service_name.to_string_lossy().to_string());
                     }
@@ -151,16 +181,35 @@ Wants = network-online.target
     pub fn start_boot_services(&self, manager: &ServiceManager) -> Result<()> {
         info!("Starting boot services");
         
-        let boot_services = self.get_boot_services()?;
+        let mut boot_services = self.get_boot_services()?;
+        if !self.scope.is_user() {
+            boot_services.extend(self.lingering_managers());
+        }
         
-        for service in boot_services {
-            info!("Starting boot service: {}", service);
-            if let Err(e) = manager.start_service(&service) {
-                error!("Failed to start boot service {}: {}", service, e);
-            }
+        // One transaction for all of them, so independent services start
+        // in parallel and a failure only affects the units requiring it
+        let report = manager.start_units(&boot_services)?;
+        
+        for job in report.failed() {
+            error!("Failed to start boot service {}: {}", job.unit, job.outcome);
         }
         
-        info!("Boot services started");
+        let elapsed = report.jobs.iter().map(|job| job.started_at + job.duration).max().unwrap_or_default();
+        info!("Boot services started in {:?}", elapsed);
         Ok(())
     }
+    
+    /// Managers of the users that linger, started at boot without a
+    /// session.
+    fn lingering_managers(&self) -> Vec<String> {
+        lingering_users(Path::new(LINGER_DIR)).into_iter()
+            .filter_map(|name| match nix::unistd::User::from_name(&name) {
+                Ok(Some(user)) => Some(user_manager_unit(user.uid.as_raw())),
+                _ => {
+                    warn!("Not starting the manager of unknown user {}", name);
+                    None
+                }
+            })
+            .collect()
+    }
 } 
\ No newline at end of file
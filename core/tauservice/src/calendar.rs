@@ -0,0 +1,256 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use std::fmt;
+
+/// Upper bound on the search for the next matching time, so expressions
+/// that can never match (e.g. `*-02-30`) terminate.
+const MAX_SEARCH_YEARS: i32 = 100;
+
+/// One `start[..end][/step]` element of a calendar field.
+#[derive(Debug, Clone, PartialEq)]
+struct Range {
+    start: u32,
+    end: u32,
+    step: u32,
+}
+
+impl Range {
+    fn matches(&self, value: u32) -> bool {
+        value >= self.start && value <= self.end && (value - self.start).is_multiple_of(self.step)
+    }
+}
+
+/// A comma-separated list of ranges. `None` matches any value.
+#[derive(Debug, Clone, PartialEq)]
+struct Field(Option<Vec<Range>>);
+
+impl Field {
+    fn any() -> Self {
+        Field(None)
+    }
+
+    fn exactly(value: u32) -> Self {
+        Field(Some(vec![Range { start: value, end: value, step: 1 }]))
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        match &self.0 {
+            None => true,
+            Some(ranges) => ranges.iter().any(|r| r.matches(value)),
+        }
+    }
+
+    fn parse(value: &str, min: u32, max: u32, parse_value: fn(&str) -> Option<u32>) -> Result<Self> {
+        if value == "*" {
+            return Ok(Field::any());
+        }
+
+        let mut ranges = Vec::new();
+
+        for part in value.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => {
+                    let step: u32 = step.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid repetition '{}'", step))?;
+                    if step == 0 {
+                        return Err(anyhow::anyhow!("Repetition must be positive"));
+                    }
+                    (range, Some(step))
+                }
+                None => (part, None),
+            };
+
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some((start, end)) = range.split_once("..") {
+                let start = parse_value(start).ok_or_else(|| anyhow::anyhow!("Invalid value '{}'", start))?;
+                let end = parse_value(end).ok_or_else(|| anyhow::anyhow!("Invalid value '{}'", end))?;
+                (start, end)
+            } else {
+                let start = parse_value(range).ok_or_else(|| anyhow::anyhow!("Invalid value '{}'", range))?;
+                // `a/step` repeats from a up to the maximum
+                (start, if step.is_some() { max } else { start })
+            };
+
+            if start < min || end > max || start > end {
+                return Err(anyhow::anyhow!("'{}' is outside {}..{}", part, min, max));
+            }
+
+            ranges.push(Range { start, end, step: step.unwrap_or(1) });
+        }
+
+        Ok(Field(Some(ranges)))
+    }
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    value.parse().ok()
+}
+
+/// Monday is 0, matching `chrono::Weekday::num_days_from_monday`.
+fn parse_weekday(value: &str) -> Option<u32> {
+    let days = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+    let value = value.to_ascii_lowercase();
+    days.iter().position(|day| value.starts_with(day)).map(|i| i as u32)
+}
+
+/// A parsed OnCalendar= expression, e.g. `Mon..Fri *-*-* 04:00:00`,
+/// `*-*-01 00:00`, `*:0/15` or one of the shorthands like `daily`.
+/// Times are interpreted in the local timezone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalendarSpec {
+    source: String,
+    weekdays: Field,
+    years: Field,
+    months: Field,
+    days: Field,
+    hours: Field,
+    minutes: Field,
+    seconds: Field,
+}
+
+impl CalendarSpec {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let source = expression.trim().to_string();
+
+        let normalized = match source.to_ascii_lowercase().as_str() {
+            "minutely" => "*-*-* *:*:00",
+            "hourly" => "*-*-* *:00:00",
+            "daily" => "*-*-* 00:00:00",
+            "weekly" => "Mon *-*-* 00:00:00",
+            "monthly" => "*-*-01 00:00:00",
+            "yearly" | "annually" => "*-01-01 00:00:00",
+            "quarterly" => "*-01,04,07,10-01 00:00:00",
+            "semiannually" => "*-01,07-01 00:00:00",
+            _ => source.as_str(),
+        }.to_string();
+
+        let mut spec = CalendarSpec {
+            source: source.clone(),
+            weekdays: Field::any(),
+            years: Field::any(),
+            months: Field::any(),
+            days: Field::any(),
+            hours: Field::exactly(0),
+            minutes: Field::exactly(0),
+            seconds: Field::exactly(0),
+        };
+
+        let mut tokens: Vec<&str> = normalized.split_whitespace().collect();
+        if tokens.is_empty() {
+            return Err(anyhow::anyhow!("Empty calendar expression"));
+        }
+
+        if tokens[0].chars().next().is_some_and(|c| c.is_ascii_alphabetic()) {
+            spec.weekdays = Field::parse(tokens.remove(0), 0, 6, parse_weekday)
+                .map_err(|e| anyhow::anyhow!("Invalid weekday in '{}': {}", source, e))?;
+        }
+
+        let mut has_date = false;
+        let mut has_time = false;
+
+        for token in tokens {
+            if token.contains(':') && !has_time {
+                spec.parse_time(token)
+                    .map_err(|e| anyhow::anyhow!("Invalid time in '{}': {}", source, e))?;
+                has_time = true;
+            } else if token.contains('-') && !has_date && !has_time {
+                spec.parse_date(token)
+                    .map_err(|e| anyhow::anyhow!("Invalid date in '{}': {}", source, e))?;
+                has_date = true;
+            } else {
+                return Err(anyhow::anyhow!("Unexpected '{}' in calendar expression '{}'", token, source));
+            }
+        }
+
+        if spec.weekdays == Field::any() && !has_date && !has_time {
+            return Err(anyhow::anyhow!("Invalid calendar expression '{}'", source));
+        }
+
+        Ok(spec)
+    }
+
+    fn parse_date(&mut self, token: &str) -> Result<()> {
+        let parts: Vec<&str> = token.split('-').collect();
+        let (year, month, day) = match parts.as_slice() {
+            [year, month, day] => (*year, *month, *day),
+            [month, day] => ("*", *month, *day),
+            _ => return Err(anyhow::anyhow!("expected YYYY-MM-DD")),
+        };
+
+        self.years = Field::parse(year, 1970, 2199, parse_number)?;
+        self.months = Field::parse(month, 1, 12, parse_number)?;
+        self.days = Field::parse(day, 1, 31, parse_number)?;
+        Ok(())
+    }
+
+    fn parse_time(&mut self, token: &str) -> Result<()> {
+        let parts: Vec<&str> = token.split(':').collect();
+        let (hour, minute, second) = match parts.as_slice() {
+            [hour, minute, second] => (*hour, *minute, *second),
+            [hour, minute] => (*hour, *minute, "00"),
+            _ => return Err(anyhow::anyhow!("expected HH:MM[:SS]")),
+        };
+
+        self.hours = Field::parse(hour, 0, 23, parse_number)?;
+        self.minutes = Field::parse(minute, 0, 59, parse_number)?;
+        self.seconds = Field::parse(second, 0, 59, parse_number)?;
+        Ok(())
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        self.years.matches(date.year() as u32)
+            && self.months.matches(date.month())
+            && self.days.matches(date.day())
+            && self.weekdays.matches(date.weekday().num_days_from_monday())
+    }
+
+    /// The first time strictly after `after` that matches the expression.
+    pub fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_nanosecond(0)? + Duration::seconds(1);
+        let limit = start.year() + MAX_SEARCH_YEARS;
+
+        let mut date = start.date();
+        let mut first_day = true;
+
+        while date.year() <= limit {
+            if self.matches_date(date) {
+                let from = if first_day { start.time() } else { chrono::NaiveTime::MIN };
+
+                for time in self.times_from(from) {
+                    // Times skipped by a DST transition do not exist locally
+                    if let Some(local) = Local.from_local_datetime(&NaiveDateTime::new(date, time)).earliest() {
+                        return Some(local);
+                    }
+                }
+            }
+
+            date = date.succ_opt()?;
+            first_day = false;
+        }
+
+        None
+    }
+
+    /// Matching times of day at or after `from`, in order.
+    fn times_from(&self, from: chrono::NaiveTime) -> impl Iterator<Item = chrono::NaiveTime> + '_ {
+        (from.hour()..24u32)
+            .filter(move |h| self.hours.matches(*h))
+            .flat_map(move |h| {
+                (0..60u32)
+                    .filter(move |m| self.minutes.matches(*m))
+                    .flat_map(move |m| {
+                        (0..60u32)
+                            .filter(move |s| self.seconds.matches(*s))
+                            .map(move |s| chrono::NaiveTime::from_hms_opt(h, m, s).unwrap())
+                    })
+            })
+            .filter(move |time| *time >= from)
+    }
+}
+
+impl fmt::Display for CalendarSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
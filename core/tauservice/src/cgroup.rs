@@ -0,0 +1,264 @@
+use crate::unit::ServiceSection;
+use crate::scope::ManagerScope;
+use anyhow::{Result, Context};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use log::{warn, debug};
+
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Parent of all service cgroups, so the manager's own cgroup stays out
+/// of the way of the no-internal-processes rule.
+pub const SERVICES_SLICE: &str = "tau.slice";
+
+const CONTROLLERS: &[&str] = &["cpu", "memory", "io", "pids"];
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+/// Resource usage of a unit, read from its cgroup.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub memory_current: Option<u64>,
+    pub memory_peak: Option<u64>,
+    pub cpu_usage_usec: Option<u64>,
+    pub tasks_current: Option<u64>,
+}
+
+/// The cgroup v2 subtree of a single service.
+#[derive(Debug)]
+pub struct ServiceCgroup {
+    path: PathBuf,
+}
+
+impl ServiceCgroup {
+    /// Whether the unified cgroup v2 hierarchy is mounted.
+    pub fn is_supported() -> bool {
+        Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+    }
+
+    pub fn create(unit_name: &str) -> Result<Self> {
+        Self::create_in(&ManagerScope::current().cgroup_root(), unit_name)
+    }
+
+    pub fn create_in(root: &Path, unit_name: &str) -> Result<Self> {
+        let slice = root.join(SERVICES_SLICE);
+        fs::create_dir_all(&slice)
+            .context(format!("Failed to create cgroup {}", slice.display()))?;
+
+        enable_controllers(root);
+        enable_controllers(&slice);
+
+        let path = slice.join(format!("{}.service", unit_name));
+        fs::create_dir_all(&path)
+            .context(format!("Failed to create cgroup {}", path.display()))?;
+
+        Ok(Self { path })
+    }
+
+    /// An existing cgroup, e.g. of a service adopted from a previous
+    /// daemon.
+    pub fn open(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the resource limits of a service. Limits that are not set
+    /// are reset to their defaults, so removing one from the unit file
+    /// takes effect on the next start.
+    pub fn apply_limits(&self, service: &ServiceSection) -> Result<()> {
+        let memory_max = match &service.memory_max {
+            Some(value) => format_bytes_limit(value)?,
+            None => "max".to_string(),
+        };
+        self.write_optional("memory.max", &memory_max);
+
+        let memory_high = match &service.memory_high {
+            Some(value) => format_bytes_limit(value)?,
+            None => "max".to_string(),
+        };
+        self.write_optional("memory.high", &memory_high);
+
+        let cpu_max = match &service.cpu_quota {
+            Some(value) => format!("{} {}", parse_cpu_quota(value)?, CPU_PERIOD_USEC),
+            None => format!("max {}", CPU_PERIOD_USEC),
+        };
+        self.write_optional("cpu.max", &cpu_max);
+
+        self.write_optional("cpu.weight", &service.cpu_weight.unwrap_or(100).to_string());
+        self.write_optional("io.weight", &format!("default {}", service.io_weight.unwrap_or(100)));
+
+        let tasks_max = service.tasks_max.map_or("max".to_string(), |max| max.to_string());
+        self.write_optional("pids.max", &tasks_max);
+
+        Ok(())
+    }
+
+    /// Limits of controllers the kernel does not provide are skipped
+    /// rather than failing the start.
+    fn write_optional(&self, file: &str, value: &str) {
+        if let Err(e) = fs::write(self.path.join(file), value) {
+            debug!("Not setting {} = {} for {}: {}", file, value, self.path.display(), e);
+        }
+    }
+
+    /// Path to `cgroup.procs` for joining the cgroup from a `pre_exec`
+    /// hook, where nothing may allocate.
+    pub fn procs_path(&self) -> Result<CString> {
+        Ok(CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes())?)
+    }
+
+    /// Processes in the cgroup and the cgroups nested in it, which a
+    /// service may create below its own.
+    pub fn pids(&self) -> Vec<i32> {
+        let mut pids = Vec::new();
+        for cgroup in subtree(&self.path) {
+            if let Ok(content) = fs::read_to_string(cgroup.join("cgroup.procs")) {
+                pids.extend(content.lines().filter_map(|line| line.trim().parse::<i32>().ok()));
+            }
+        }
+        pids
+    }
+
+    pub fn is_populated(&self) -> bool {
+        fs::read_to_string(self.path.join("cgroup.events"))
+            .map(|content| content.lines().any(|line| line == "populated 1"))
+            .unwrap_or(false)
+    }
+
+    /// Sends a signal to every process in the cgroup and its nested
+    /// cgroups, optionally sparing one (the main process for KillMode=mixed).
+    pub fn kill(&self, signal: Signal, except: Option<i32>) -> Result<()> {
+        // cgroup.kill (Linux 5.14+) also catches processes forked while
+        // the list is being walked
+        if signal == Signal::SIGKILL && except.is_none() {
+            let kill_file = self.path.join("cgroup.kill");
+            if kill_file.exists() && fs::write(&kill_file, "1").is_ok() {
+                return Ok(());
+            }
+        }
+
+        for pid in self.pids() {
+            if Some(pid) == except {
+                continue;
+            }
+
+            if let Err(e) = kill(Pid::from_raw(pid), signal) {
+                // The process may have exited since the list was read
+                debug!("Failed to send {} to {}: {}", signal, pid, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn usage(&self) -> ResourceUsage {
+        ResourceUsage {
+            memory_current: self.read_u64("memory.current"),
+            memory_peak: self.read_u64("memory.peak"),
+            cpu_usage_usec: fs::read_to_string(self.path.join("cpu.stat"))
+                .ok()
+                .and_then(|content| {
+                    content.lines()
+                        .find_map(|line| line.strip_prefix("usage_usec "))
+                        .and_then(|value| value.trim().parse().ok())
+                }),
+            tasks_current: self.read_u64("pids.current"),
+        }
+    }
+
+    fn read_u64(&self, file: &str) -> Option<u64> {
+        fs::read_to_string(self.path.join(file)).ok()?.trim().parse().ok()
+    }
+
+    /// Removes the cgroup once it is empty. A cgroup still holding
+    /// processes (e.g. left behind by KillMode=process) is kept.
+    pub fn remove(&self) {
+        if self.is_populated() {
+            debug!("Keeping populated cgroup {}", self.path.display());
+            return;
+        }
+
+        // Nested cgroups go first, the deepest before their parents
+        for cgroup in subtree(&self.path).iter().rev() {
+            if let Err(e) = fs::remove_dir(cgroup) {
+                warn!("Failed to remove cgroup {}: {}", cgroup.display(), e);
+            }
+        }
+    }
+}
+
+/// A cgroup followed by all cgroups below it, parents before children.
+fn subtree(cgroup: &Path) -> Vec<PathBuf> {
+    let mut cgroups = vec![cgroup.to_path_buf()];
+    let mut next = 0;
+
+    while let Some(dir) = cgroups.get(next).cloned() {
+        if let Ok(entries) = fs::read_dir(&dir) {
+            cgroups.extend(entries.flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+                .map(|entry| entry.path()));
+        }
+        next += 1;
+    }
+
+    cgroups
+}
+
+fn enable_controllers(cgroup: &Path) {
+    let available = fs::read_to_string(cgroup.join("cgroup.controllers")).unwrap_or_default();
+
+    for controller in CONTROLLERS {
+        if !available.split_whitespace().any(|c| c == *controller) {
+            continue;
+        }
+
+        if let Err(e) = fs::write(cgroup.join("cgroup.subtree_control"), format!("+{}", controller)) {
+            debug!("Failed to enable {} controller in {}: {}", controller, cgroup.display(), e);
+        }
+    }
+}
+
+/// Parses a byte size like `512M`, `2G` or `1048576`. Suffixes are
+/// powers of 1024, as in systemd.
+pub fn parse_bytes(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let (number, multiplier) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1u64 << 10),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1u64 << 20),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1u64 << 30),
+        Some('T') | Some('t') => (&value[..value.len() - 1], 1u64 << 40),
+        _ => (value, 1),
+    };
+
+    number.trim().parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| anyhow::anyhow!("Invalid byte size '{}'", value))
+}
+
+/// Value for memory.max / memory.high: a byte size or `infinity`.
+pub fn format_bytes_limit(value: &str) -> Result<String> {
+    if value.trim() == "infinity" {
+        Ok("max".to_string())
+    } else {
+        Ok(parse_bytes(value)?.to_string())
+    }
+}
+
+/// Converts a CPUQuota= percentage into microseconds per 100ms period,
+/// e.g. `50%` is 50000 and `200%` (two CPUs) is 200000.
+pub fn parse_cpu_quota(value: &str) -> Result<u64> {
+    let percent: u64 = value.trim()
+        .strip_suffix('%')
+        .and_then(|p| p.trim().parse().ok())
+        .filter(|p| *p > 0)
+        .ok_or_else(|| anyhow::anyhow!("Invalid CPU quota '{}', expected a percentage like 50%", value))?;
+
+    Ok(percent * CPU_PERIOD_USEC / 100)
+}
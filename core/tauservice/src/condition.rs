@@ -0,0 +1,400 @@
+use crate::unit::UnitSection;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionKind {
+    PathExists,
+    PathIsDirectory,
+    FileNotEmpty,
+    KernelCommandLine,
+    Virtualization,
+    FirstBoot,
+    ACPower,
+    Environment,
+    Host,
+}
+
+impl fmt::Display for ConditionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ConditionKind::PathExists => "PathExists",
+            ConditionKind::PathIsDirectory => "PathIsDirectory",
+            ConditionKind::FileNotEmpty => "FileNotEmpty",
+            ConditionKind::KernelCommandLine => "KernelCommandLine",
+            ConditionKind::Virtualization => "Virtualization",
+            ConditionKind::FirstBoot => "FirstBoot",
+            ConditionKind::ACPower => "ACPower",
+            ConditionKind::Environment => "Environment",
+            ConditionKind::Host => "Host",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One Condition*= or Assert*= check. A leading `|` makes it a
+/// triggering condition, of which at least one has to pass; a `!`
+/// after that negates it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    pub kind: ConditionKind,
+    pub parameter: String,
+    pub assert: bool,
+    pub trigger: bool,
+    pub negate: bool,
+}
+
+impl Condition {
+    pub fn parse(kind: ConditionKind, assert: bool, value: &str) -> Self {
+        let value = value.trim();
+        let (trigger, value) = match value.strip_prefix('|') {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, value),
+        };
+        let (negate, value) = match value.strip_prefix('!') {
+            Some(rest) => (true, rest.trim_start()),
+            None => (false, value),
+        };
+
+        Self {
+            kind,
+            parameter: value.to_string(),
+            assert,
+            trigger,
+            negate,
+        }
+    }
+
+    pub fn test(&self, host: &HostFacts) -> bool {
+        let result = match self.kind {
+            ConditionKind::PathExists => Path::new(&self.parameter).exists(),
+            ConditionKind::PathIsDirectory => Path::new(&self.parameter).is_dir(),
+            ConditionKind::FileNotEmpty => fs::metadata(&self.parameter)
+                .is_ok_and(|meta| meta.is_file() && meta.len() > 0),
+            ConditionKind::KernelCommandLine => kernel_command_line_has(&host.kernel_command_line, &self.parameter),
+            ConditionKind::Virtualization => host.virtualization.matches(&self.parameter),
+            ConditionKind::FirstBoot => parse_boolean(&self.parameter) == Some(host.first_boot),
+            ConditionKind::ACPower => parse_boolean(&self.parameter) == Some(host.on_ac_power),
+            ConditionKind::Environment => match self.parameter.split_once('=') {
+                Some((name, value)) => host.environment.get(name).is_some_and(|set| set == value),
+                None => host.environment.contains_key(&self.parameter),
+            },
+            ConditionKind::Host => {
+                self.parameter.eq_ignore_ascii_case(&host.machine_id)
+                    || glob::Pattern::new(&self.parameter)
+                        .is_ok_and(|pattern| pattern.matches(&host.hostname))
+            }
+        };
+
+        result != self.negate
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}={}{}{}",
+            if self.assert { "Assert" } else { "Condition" },
+            self.kind,
+            if self.trigger { "|" } else { "" },
+            if self.negate { "!" } else { "" },
+            self.parameter)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConditionResult {
+    Met,
+    /// A condition failed, the start is skipped without an error
+    Skipped(Condition),
+    /// An assertion failed, the start fails
+    AssertionFailed(Condition),
+}
+
+/// The conditions and assertions of a unit's [unit] section.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UnitConditions {
+    pub conditions: Vec<Condition>,
+    pub assertions: Vec<Condition>,
+}
+
+impl UnitConditions {
+    pub fn from_section(section: Option<&UnitSection>) -> Self {
+        let Some(section) = section else {
+            return Self::default();
+        };
+
+        let boolean = |value: Option<bool>| value.map(|value| vec![value.to_string()]);
+        let checks = |assert: bool| -> Vec<Condition> {
+            let values = if assert {
+                [
+                    (ConditionKind::PathExists, section.assert_path_exists.clone()),
+                    (ConditionKind::PathIsDirectory, section.assert_path_is_directory.clone()),
+                    (ConditionKind::FileNotEmpty, section.assert_file_not_empty.clone()),
+                    (ConditionKind::KernelCommandLine, section.assert_kernel_command_line.clone()),
+                    (ConditionKind::Virtualization, section.assert_virtualization.clone()),
+                    (ConditionKind::FirstBoot, boolean(section.assert_first_boot)),
+                    (ConditionKind::ACPower, boolean(section.assert_ac_power)),
+                    (ConditionKind::Environment, section.assert_environment.clone()),
+                    (ConditionKind::Host, section.assert_host.clone()),
+                ]
+            } else {
+                [
+                    (ConditionKind::PathExists, section.condition_path_exists.clone()),
+                    (ConditionKind::PathIsDirectory, section.condition_path_is_directory.clone()),
+                    (ConditionKind::FileNotEmpty, section.condition_file_not_empty.clone()),
+                    (ConditionKind::KernelCommandLine, section.condition_kernel_command_line.clone()),
+                    (ConditionKind::Virtualization, section.condition_virtualization.clone()),
+                    (ConditionKind::FirstBoot, boolean(section.condition_first_boot)),
+                    (ConditionKind::ACPower, boolean(section.condition_ac_power)),
+                    (ConditionKind::Environment, section.condition_environment.clone()),
+                    (ConditionKind::Host, section.condition_host.clone()),
+                ]
+            };
+
+            values.into_iter()
+                .flat_map(|(kind, values)| values.unwrap_or_default().into_iter()
+                    .map(move |value| Condition::parse(kind, assert, &value)))
+                .collect()
+        };
+
+        Self {
+            conditions: checks(false),
+            assertions: checks(true),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty() && self.assertions.is_empty()
+    }
+
+    /// Checks against the running system. Nothing is probed for a unit
+    /// without conditions.
+    pub fn check(&self) -> ConditionResult {
+        if self.is_empty() {
+            return ConditionResult::Met;
+        }
+        self.evaluate(&HostFacts::detect())
+    }
+
+    /// Conditions are checked first; if they are not met the assertions
+    /// are not looked at.
+    pub fn evaluate(&self, host: &HostFacts) -> ConditionResult {
+        if let Some(condition) = first_unmet(&self.conditions, host) {
+            return ConditionResult::Skipped(condition.clone());
+        }
+        if let Some(assertion) = first_unmet(&self.assertions, host) {
+            return ConditionResult::AssertionFailed(assertion.clone());
+        }
+        ConditionResult::Met
+    }
+}
+
+/// All regular checks have to pass, and at least one triggering check
+/// if there are any.
+fn first_unmet<'a>(checks: &'a [Condition], host: &HostFacts) -> Option<&'a Condition> {
+    if let Some(failed) = checks.iter().find(|check| !check.trigger && !check.test(host)) {
+        return Some(failed);
+    }
+
+    let mut triggers = checks.iter().filter(|check| check.trigger).peekable();
+    let first_trigger = triggers.peek().copied();
+    match first_trigger {
+        Some(first) if !triggers.any(|check| check.test(host)) => Some(first),
+        _ => None,
+    }
+}
+
+fn parse_boolean(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "yes" | "true" | "on" => Some(true),
+        "0" | "no" | "false" | "off" => Some(false),
+        _ => None,
+    }
+}
+
+/// `quiet` matches the word itself or `quiet=...`; `console=ttyS0` only
+/// the exact word.
+fn kernel_command_line_has(command_line: &str, parameter: &str) -> bool {
+    command_line.split_whitespace().any(|word| {
+        word == parameter
+            || (!parameter.contains('=') && word.split_once('=').is_some_and(|(key, _)| key == parameter))
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Virtualization {
+    None,
+    Vm(String),
+    Container(String),
+}
+
+impl Virtualization {
+    /// Known technologies as named in ConditionVirtualization=.
+    pub const VMS: &'static [&'static str] = &[
+        "qemu", "kvm", "amazon", "zvm", "vmware", "microsoft", "oracle", "powervm", "xen",
+        "bochs", "uml", "parallels", "bhyve", "qnx", "acrn", "apple", "sre", "google", "vm-other",
+    ];
+    pub const CONTAINERS: &'static [&'static str] = &[
+        "openvz", "lxc", "lxc-libvirt", "systemd-nspawn", "docker", "podman", "rkt", "wsl",
+        "proot", "pouch", "container-other",
+    ];
+
+    pub fn is_known(parameter: &str) -> bool {
+        parse_boolean(parameter).is_some()
+            || ["vm", "container"].contains(&parameter)
+            || Self::VMS.contains(&parameter)
+            || Self::CONTAINERS.contains(&parameter)
+    }
+
+    /// `yes`/`no`, `vm`, `container` or a technology name.
+    pub fn matches(&self, parameter: &str) -> bool {
+        if let Some(virtualized) = parse_boolean(parameter) {
+            return virtualized != (*self == Virtualization::None);
+        }
+
+        match (self, parameter) {
+            (Virtualization::Vm(_), "vm") | (Virtualization::Container(_), "container") => true,
+            (Virtualization::Vm(id), _) | (Virtualization::Container(id), _) => id == parameter,
+            (Virtualization::None, _) => false,
+        }
+    }
+
+    pub fn detect() -> Self {
+        if let Some(container) = detect_container() {
+            return Virtualization::Container(container);
+        }
+        detect_vm().map_or(Virtualization::None, Virtualization::Vm)
+    }
+}
+
+fn detect_container() -> Option<String> {
+    let normalize = |id: &str| match id.trim() {
+        "" => None,
+        "oci" => Some("container-other".to_string()),
+        id => Some(id.to_string()),
+    };
+
+    if let Some(id) = fs::read_to_string("/run/systemd/container").ok().and_then(|id| normalize(&id)) {
+        return Some(id);
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".to_string());
+    }
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".to_string());
+    }
+
+    // container= in the environment of PID 1, as set by most managers
+    let environ = fs::read("/proc/1/environ").unwrap_or_default();
+    if let Some(id) = environ.split(|byte| *byte == 0)
+        .filter_map(|var| std::str::from_utf8(var).ok())
+        .find_map(|var| var.strip_prefix("container="))
+        .and_then(normalize) {
+        return Some(id);
+    }
+
+    let osrelease = fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default().to_lowercase();
+    if osrelease.contains("microsoft") || osrelease.contains("wsl") {
+        return Some("wsl".to_string());
+    }
+    None
+}
+
+fn detect_vm() -> Option<String> {
+    let dmi = |field: &str| fs::read_to_string(Path::new("/sys/class/dmi/id").join(field)).unwrap_or_default();
+    let vendor = format!("{} {} {}", dmi("sys_vendor"), dmi("board_vendor"), dmi("product_name"));
+
+    let known = [
+        ("KVM", "kvm"), ("Amazon EC2", "amazon"), ("QEMU", "qemu"), ("VMware", "vmware"),
+        ("VMW", "vmware"), ("innotek GmbH", "oracle"), ("VirtualBox", "oracle"), ("Xen", "xen"),
+        ("Bochs", "bochs"), ("Parallels", "parallels"), ("BHYVE", "bhyve"), ("Google", "google"),
+        ("Apple Virtualization", "apple"), ("Microsoft Corporation Virtual Machine", "microsoft"),
+    ];
+    if let Some((_, id)) = known.iter().find(|(name, _)| vendor.contains(name)) {
+        return Some(id.to_string());
+    }
+
+    if Path::new("/proc/xen").exists() {
+        return Some("xen".to_string());
+    }
+
+    let cpuinfo = fs::read_to_string("/proc/cpuinfo").unwrap_or_default();
+    let hypervisor = cpuinfo.lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| line.split_whitespace().any(|flag| flag == "hypervisor"));
+    hypervisor.then(|| "vm-other".to_string())
+}
+
+/// What conditions are tested against, read from the running system.
+#[derive(Debug, Clone)]
+pub struct HostFacts {
+    pub kernel_command_line: String,
+    pub virtualization: Virtualization,
+    pub first_boot: bool,
+    pub on_ac_power: bool,
+    pub hostname: String,
+    pub machine_id: String,
+    /// The manager's own environment
+    pub environment: HashMap<String, String>,
+}
+
+impl HostFacts {
+    pub fn detect() -> Self {
+        static VIRTUALIZATION: OnceLock<Virtualization> = OnceLock::new();
+
+        Self {
+            kernel_command_line: fs::read_to_string("/proc/cmdline").unwrap_or_default().trim().to_string(),
+            virtualization: VIRTUALIZATION.get_or_init(Virtualization::detect).clone(),
+            first_boot: is_first_boot(),
+            on_ac_power: on_ac_power(),
+            hostname: fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default().trim().to_string(),
+            machine_id: fs::read_to_string("/etc/machine-id").unwrap_or_default().trim().to_string(),
+            environment: std::env::vars().collect(),
+        }
+    }
+}
+
+static FIRST_BOOT: OnceLock<bool> = OnceLock::new();
+
+/// Decides whether the system booted without a machine ID. The daemon
+/// does so before it starts any unit, as units run on first boot usually
+/// create it, and keeps the decision of the daemon it re-executed from.
+pub fn init_first_boot(inherited: Option<bool>) -> bool {
+    *FIRST_BOOT.get_or_init(|| {
+        inherited.unwrap_or_else(|| {
+            let machine_id = fs::read_to_string("/etc/machine-id").unwrap_or_default();
+            matches!(machine_id.trim(), "" | "uninitialized")
+        })
+    })
+}
+
+/// Whether this is the first boot, as decided by `init_first_boot`.
+pub fn is_first_boot() -> bool {
+    init_first_boot(None)
+}
+
+/// True if any non-battery power supply is online, or if there are none
+/// at all (desktops without power supply information).
+fn on_ac_power() -> bool {
+    let Ok(entries) = fs::read_dir("/sys/class/power_supply") else {
+        return true;
+    };
+
+    let mut found = false;
+    for entry in entries.flatten() {
+        let read = |name: &str| fs::read_to_string(entry.path().join(name)).unwrap_or_default();
+        if read("type").trim() == "Battery" {
+            continue;
+        }
+
+        match read("online").trim() {
+            "1" => return true,
+            "0" => found = true,
+            _ => {}
+        }
+    }
+    !found
+}
@@ -0,0 +1,497 @@
+use crate::service_manager::{ManagerEvent, ServiceManager, ServiceState, ServiceStatus};
+use crate::journal::{JournalEntry, JournalQuery};
+use crate::boot::BootManager;
+use crate::socket_activation::SocketSummary;
+use crate::timer::TimerSummary;
+use crate::analyze::BootTimes;
+use crate::scope::ManagerScope;
+use crate::coredump::CoredumpStore;
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::OwnedWriteHalf;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+use log::{info, warn, error, debug};
+
+pub const CONTROL_SOCKET_PATH: &str = "/run/tau/service.sock";
+
+/// Requests understood by the daemon's control socket.
+///
+/// The wire format is newline-delimited JSON: one request per line, each
+/// answered by exactly one `ControlResponse` line, except `Subscribe` which
+/// turns the connection into a stream of `ControlResponse::Event` lines and
+/// `FollowLogs` which answers with the matching backlog followed by a
+/// `ControlResponse::LogEntry` line per new entry. `DaemonReexec` is
+/// answered before the daemon replaces itself, closing the connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    Start { service: String, wait: bool },
+    Stop { service: String, force: bool },
+    Restart { service: String },
+    Reload { service: String },
+    Enable { service: String },
+    Disable { service: String },
+    Status { service: Option<String> },
+    List { running: bool, enabled: bool, failed: bool },
+    Logs { query: JournalQuery },
+    FollowLogs { query: JournalQuery },
+    ClearLogs { service: Option<String> },
+    VacuumLogs { max_size: u64 },
+    ResetFailed { service: Option<String> },
+    ListSockets,
+    ListTimers,
+    BootTimes,
+    DaemonReload,
+    DaemonReexec,
+    BootStart,
+    /// Sent by the core dump handler once a dump is stored
+    Coredump { id: String },
+    Subscribe,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", content = "data", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Ok,
+    Services(Vec<ServiceSummary>),
+    Logs(Vec<JournalEntry>),
+    LogEntry(JournalEntry),
+    Sockets(Vec<SocketSummary>),
+    Timers(Vec<TimerSummary>),
+    BootTimes(BootTimes),
+    Event(ManagerEvent),
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceSummary {
+    #[serde(flatten)]
+    pub status: ServiceStatus,
+    pub enabled: bool,
+}
+
+pub fn control_socket_path() -> PathBuf {
+    std::env::var_os("TAU_SERVICE_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| ManagerScope::current().control_socket())
+}
+
+pub struct ControlServer {
+    manager: ServiceManager,
+    socket_path: PathBuf,
+}
+
+impl ControlServer {
+    pub fn new(manager: ServiceManager, socket_path: &Path) -> Self {
+        Self {
+            manager,
+            socket_path: socket_path.to_path_buf(),
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        if let Some(parent) = self.socket_path.parent() {
+            fs::create_dir_all(parent)
+                .context("Failed to create control socket directory")?;
+        }
+
+        // A previous daemon that died uncleanly leaves its socket behind
+        if self.socket_path.exists() {
+            fs::remove_file(&self.socket_path)
+                .context("Failed to remove stale control socket")?;
+        }
+
+        let listener = UnixListener::bind(&self.socket_path)
+            .context("Failed to bind control socket")?;
+        fs::set_permissions(&self.socket_path, fs::Permissions::from_mode(0o600))?;
+
+        info!("Control socket listening on {}", self.socket_path.display());
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let manager = self.manager.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_connection(manager, stream).await {
+                            debug!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.socket_path);
+    }
+}
+
+async fn handle_connection(manager: ServiceManager, stream: UnixStream) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let request: ControlRequest = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                write_response(&mut writer, &ControlResponse::Error(format!("Invalid request: {}", e))).await?;
+                continue;
+            }
+        };
+
+        debug!("Control request: {:?}", request);
+
+        if let ControlRequest::Subscribe = request {
+            return stream_events(&manager, &mut writer).await;
+        }
+
+        if let ControlRequest::FollowLogs { query } = request {
+            return stream_logs(&manager, query, &mut writer).await;
+        }
+
+        if let ControlRequest::DaemonReexec = request {
+            write_response(&mut writer, &ControlResponse::Ok).await?;
+            let error = tokio::task::spawn_blocking(move || manager.reexec()).await?;
+            error!("Failed to re-execute the daemon: {:#}", error);
+            return Ok(());
+        }
+
+        let manager = manager.clone();
+        let response = tokio::task::spawn_blocking(move || dispatch(&manager, request))
+            .await
+            .unwrap_or_else(|e| ControlResponse::Error(format!("Request handler panicked: {}", e)));
+
+        write_response(&mut writer, &response).await?;
+    }
+
+    Ok(())
+}
+
+async fn stream_events(manager: &ServiceManager, writer: &mut OwnedWriteHalf) -> Result<()> {
+    let mut events = manager.subscribe();
+    write_response(writer, &ControlResponse::Ok).await?;
+
+    loop {
+        match events.recv().await {
+            Ok(event) => write_response(writer, &ControlResponse::Event(event)).await?,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Event subscriber lagged, {} events dropped", skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn stream_logs(manager: &ServiceManager, query: JournalQuery, writer: &mut OwnedWriteHalf) -> Result<()> {
+    let journal = manager.journal().clone();
+
+    // Subscribe before reading the backlog so no entry falls in between
+    let mut entries = journal.subscribe();
+    let (filter, backlog) = match journal.compile(&query).and_then(|filter| Ok((filter, journal.query(&query)?))) {
+        Ok(result) => result,
+        Err(e) => return write_response(writer, &ControlResponse::Error(format!("{:#}", e))).await,
+    };
+
+    let mut last_seqnum = backlog.last().map_or(0, |entry| entry.seqnum);
+    write_response(writer, &ControlResponse::Logs(backlog)).await?;
+
+    loop {
+        match entries.recv().await {
+            Ok(entry) => {
+                if entry.seqnum > last_seqnum && filter.matches(&entry) {
+                    last_seqnum = entry.seqnum;
+                    write_response(writer, &ControlResponse::LogEntry(entry)).await?;
+                }
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Log follower lagged, {} entries dropped", skipped);
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &ControlResponse) -> Result<()> {
+    let mut payload = serde_json::to_vec(response)?;
+    payload.push(b'\n');
+    writer.write_all(&payload).await?;
+    Ok(())
+}
+
+/// Executes a single request against the daemon's manager. Runs on a
+/// blocking thread since the manager API is synchronous.
+fn dispatch(manager: &ServiceManager, request: ControlRequest) -> ControlResponse {
+    match handle_request(manager, request) {
+        Ok(response) => response,
+        Err(e) => ControlResponse::Error(format!("{:#}", e)),
+    }
+}
+
+fn handle_request(manager: &ServiceManager, request: ControlRequest) -> Result<ControlResponse> {
+    match request {
+        ControlRequest::Start { service, wait } => {
+            manager.start_service(&service)?;
+
+            if wait {
+                for _ in 0..30 {
+                    if manager.is_service_active(&service) {
+                        break;
+                    }
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+            }
+
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Stop { service, force } => {
+            if force {
+                manager.kill_service(&service, nix::sys::signal::Signal::SIGKILL)?;
+            }
+            manager.stop_service(&service)?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Restart { service } => {
+            tokio::runtime::Handle::current().block_on(manager.restart_service(&service))?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Reload { service } => {
+            manager.reload_service(&service)?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Enable { service } => {
+            manager.enable_service(&service)?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Disable { service } => {
+            manager.disable_service(&service)?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Status { service } => {
+            let services = match service {
+                Some(name) => {
+                    let status = manager.get_service_status(&name)
+                        .ok_or_else(|| anyhow::anyhow!("Service '{}' not found", name))?;
+                    vec![status]
+                }
+                None => manager.list_services(None),
+            };
+
+            Ok(ControlResponse::Services(summarize(manager, services)))
+        }
+        ControlRequest::List { running, enabled, failed } => {
+            let filter = if running {
+                Some(ServiceState::Active)
+            } else if failed {
+                Some(ServiceState::Failed)
+            } else {
+                None
+            };
+
+            let mut services = summarize(manager, manager.list_services(filter));
+            if enabled {
+                services.retain(|s| s.enabled);
+            }
+
+            Ok(ControlResponse::Services(services))
+        }
+        ControlRequest::Logs { query } => {
+            Ok(ControlResponse::Logs(manager.journal().query(&query)?))
+        }
+        ControlRequest::ClearLogs { service } => {
+            manager.journal().clear_logs(service.as_deref())?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::VacuumLogs { max_size } => {
+            let freed = manager.journal().vacuum(max_size);
+            info!("Vacuumed {} bytes of journal files", freed);
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::ResetFailed { service } => {
+            manager.reset_failed(service.as_deref());
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::ListSockets => {
+            Ok(ControlResponse::Sockets(manager.sockets().summaries()))
+        }
+        ControlRequest::ListTimers => {
+            Ok(ControlResponse::Timers(manager.timers().summaries()))
+        }
+        ControlRequest::BootTimes => {
+            Ok(ControlResponse::BootTimes(BootTimes::collect(manager)))
+        }
+        ControlRequest::DaemonReload => {
+            manager.load_units()?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::BootStart => {
+            BootManager::new().start_boot_services(manager)?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Coredump { id } => {
+            let info = CoredumpStore::new().get(&id)?;
+            manager.journal().write(info.journal_entry())?;
+            Ok(ControlResponse::Ok)
+        }
+        ControlRequest::Subscribe | ControlRequest::FollowLogs { .. } | ControlRequest::DaemonReexec => {
+            Err(anyhow::anyhow!("Streaming requests are handled by the connection"))
+        }
+    }
+}
+
+fn summarize(manager: &ServiceManager, services: Vec<ServiceStatus>) -> Vec<ServiceSummary> {
+    let mut summaries: Vec<ServiceSummary> = services
+        .into_iter()
+        .map(|status| {
+            let enabled = manager.get_unit(&status.name)
+                .map(|unit| unit.is_enabled())
+                .unwrap_or(false);
+            ServiceSummary { status, enabled }
+        })
+        .collect();
+
+    summaries.sort_by(|a, b| a.status.name.cmp(&b.status.name));
+    summaries
+}
+
+pub struct ControlClient {
+    reader: BufReader<tokio::net::unix::OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl ControlClient {
+    pub async fn connect(socket_path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket_path)
+            .await
+            .with_context(|| format!(
+                "Failed to connect to tau-service daemon at {} (is `tau-service daemon` running?)",
+                socket_path.display()
+            ))?;
+
+        let (reader, writer) = stream.into_split();
+
+        Ok(Self {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+
+    pub async fn connect_default() -> Result<Self> {
+        Self::connect(&control_socket_path()).await
+    }
+
+    /// Sends a request and waits for its response. Daemon-side failures are
+    /// turned into errors so callers can use `?` directly.
+    pub async fn call(&mut self, request: ControlRequest) -> Result<ControlResponse> {
+        let mut payload = serde_json::to_vec(&request)?;
+        payload.push(b'\n');
+        self.writer.write_all(&payload).await
+            .context("Failed to send request to daemon")?;
+
+        match self.read_response().await? {
+            ControlResponse::Error(message) => Err(anyhow::anyhow!(message)),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn services(&mut self, request: ControlRequest) -> Result<Vec<ServiceSummary>> {
+        match self.call(request).await? {
+            ControlResponse::Services(services) => Ok(services),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    pub async fn logs(&mut self, query: JournalQuery) -> Result<Vec<JournalEntry>> {
+        match self.call(ControlRequest::Logs { query }).await? {
+            ControlResponse::Logs(entries) => Ok(entries),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    pub async fn sockets(&mut self) -> Result<Vec<SocketSummary>> {
+        match self.call(ControlRequest::ListSockets).await? {
+            ControlResponse::Sockets(sockets) => Ok(sockets),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    pub async fn timers(&mut self) -> Result<Vec<TimerSummary>> {
+        match self.call(ControlRequest::ListTimers).await? {
+            ControlResponse::Timers(timers) => Ok(timers),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    pub async fn boot_times(&mut self) -> Result<BootTimes> {
+        match self.call(ControlRequest::BootTimes).await? {
+            ControlResponse::BootTimes(times) => Ok(times),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    /// Switches the connection into event streaming mode. Use
+    /// `next_event` afterwards to receive manager events.
+    pub async fn subscribe(&mut self) -> Result<()> {
+        self.call(ControlRequest::Subscribe).await?;
+        Ok(())
+    }
+
+    pub async fn next_event(&mut self) -> Result<Option<ManagerEvent>> {
+        loop {
+            match self.read_response_opt().await? {
+                Some(ControlResponse::Event(event)) => return Ok(Some(event)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Switches the connection into log following mode and returns the
+    /// backlog. Use `next_log_entry` afterwards to receive new entries.
+    pub async fn follow_logs(&mut self, query: JournalQuery) -> Result<Vec<JournalEntry>> {
+        match self.call(ControlRequest::FollowLogs { query }).await? {
+            ControlResponse::Logs(entries) => Ok(entries),
+            other => Err(anyhow::anyhow!("Unexpected response from daemon: {:?}", other)),
+        }
+    }
+
+    pub async fn next_log_entry(&mut self) -> Result<Option<JournalEntry>> {
+        loop {
+            match self.read_response_opt().await? {
+                Some(ControlResponse::LogEntry(entry)) => return Ok(Some(entry)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    async fn read_response(&mut self) -> Result<ControlResponse> {
+        self.read_response_opt().await?
+            .ok_or_else(|| anyhow::anyhow!("Daemon closed the control connection"))
+    }
+
+    async fn read_response_opt(&mut self) -> Result<Option<ControlResponse>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Ok(None);
+        }
+
+        let response = serde_json::from_str(&line)
+            .context("Failed to parse daemon response")?;
+        Ok(Some(response))
+    }
+}
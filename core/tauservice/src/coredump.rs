@@ -0,0 +1,489 @@
+use crate::cgroup::SERVICES_SLICE;
+use crate::journal::{current_boot_id, JournalEntry, LogLevel};
+use crate::scope::ManagerScope;
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Read};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{fchown, DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use log::{info, warn, debug};
+
+pub const CORE_PATTERN_PATH: &str = "/proc/sys/kernel/core_pattern";
+
+/// Arguments the kernel passes to the handler: PID in the initial PID
+/// namespace, UID, GID, signal, time, RLIMIT_CORE, hostname and the
+/// command name, last because it may contain spaces.
+pub const CORE_PATTERN_ARGS: &str = "%P %u %g %s %t %c %h %e";
+
+/// Cores are cut off at this size
+pub const DEFAULT_MAX_CORE_SIZE: u64 = 2 << 30;
+/// Space all stored dumps may take together
+pub const DEFAULT_MAX_USE: u64 = 1 << 30;
+/// Dumps older than this are removed
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+const BACKTRACE_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the kernel tells the handler about a crashed process.
+#[derive(Debug, Clone)]
+pub struct Crash {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub signal: i32,
+    pub timestamp: i64,
+    pub core_limit: u64,
+    pub hostname: String,
+    pub comm: String,
+}
+
+/// Metadata stored next to a core.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CoredumpInfo {
+    /// Name of the dump in the store
+    pub id: String,
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub signal: i32,
+    pub timestamp: DateTime<Utc>,
+    pub comm: String,
+    pub exe: Option<String>,
+    pub cmdline: String,
+    pub unit: Option<String>,
+    pub boot_id: String,
+    pub hostname: String,
+    /// Compressed core, if one was stored
+    #[serde(default)]
+    pub core_file: Option<PathBuf>,
+    /// Uncompressed size of the stored core
+    #[serde(default)]
+    pub size: u64,
+    /// The core was larger than the store keeps
+    #[serde(default)]
+    pub truncated: bool,
+    #[serde(default)]
+    pub backtrace: Option<String>,
+}
+
+impl CoredumpInfo {
+    pub fn new(crash: &Crash, boot_id: &str) -> Self {
+        let comm: String = crash.comm.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+
+        Self {
+            id: format!("core.{}.{}.{}.{}.{}", comm, crash.uid, boot_id, crash.pid, crash.timestamp),
+            pid: crash.pid,
+            uid: crash.uid,
+            gid: crash.gid,
+            signal: crash.signal,
+            timestamp: Utc.timestamp_opt(crash.timestamp, 0).single().unwrap_or_else(Utc::now),
+            comm: crash.comm.clone(),
+            exe: None,
+            cmdline: String::new(),
+            unit: None,
+            boot_id: boot_id.to_string(),
+            hostname: crash.hostname.clone(),
+            core_file: None,
+            size: 0,
+            truncated: false,
+            backtrace: None,
+        }
+    }
+
+    /// Adds what /proc still has of the crashed process. It is gone once
+    /// the kernel finishes writing the core, so this runs first.
+    pub fn collect(crash: &Crash) -> Self {
+        let mut info = Self::new(crash, &current_boot_id());
+        let proc_dir = PathBuf::from(format!("/proc/{}", crash.pid));
+
+        info.exe = fs::read_link(proc_dir.join("exe")).ok()
+            .map(|exe| exe.to_string_lossy().trim_end_matches(" (deleted)").to_string());
+        info.cmdline = fs::read(proc_dir.join("cmdline"))
+            .map(|cmdline| String::from_utf8_lossy(&cmdline)
+                .split('\0')
+                .filter(|arg| !arg.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "))
+            .unwrap_or_default();
+        info.unit = fs::read_to_string(proc_dir.join("cgroup")).ok()
+            .and_then(|cgroup| unit_from_cgroup(&cgroup));
+
+        info
+    }
+
+    pub fn signal_name(&self) -> String {
+        Signal::try_from(self.signal)
+            .map(|signal| signal.as_str().to_string())
+            .unwrap_or_else(|_| self.signal.to_string())
+    }
+
+    /// Whether the dump is named by its ID, PID, unit, command name or
+    /// executable.
+    pub fn matches(&self, query: &str) -> bool {
+        self.id == query
+            || self.pid.to_string() == query
+            || self.unit.as_deref() == Some(query)
+            || self.comm == query
+            || self.exe.as_deref().is_some_and(|exe| {
+                exe == query || Path::new(exe).file_name().is_some_and(|name| name == query)
+            })
+    }
+
+    /// The entry reporting the crash in the journal of its unit, or of
+    /// `coredump` for processes outside of units.
+    pub fn journal_entry(&self) -> JournalEntry {
+        let mut message = format!("Process {} ({}) of user {} dumped core ({})",
+            self.pid, self.comm, self.uid, self.signal_name());
+        if let Some(backtrace) = &self.backtrace {
+            message.push_str("\n\n");
+            message.push_str(backtrace);
+        }
+
+        let mut entry = JournalEntry::new(self.unit.as_deref().unwrap_or("coredump"), "system", &message)
+            .with_pid(Some(self.pid))
+            .with_level(LogLevel::Critical)
+            .with_field("COREDUMP_ID", &self.id)
+            .with_field("COREDUMP_SIGNAL", &self.signal.to_string());
+        if let Some(exe) = &self.exe {
+            entry = entry.with_field("COREDUMP_EXE", exe);
+        }
+        if let Some(core_file) = &self.core_file {
+            entry = entry.with_field("COREDUMP_FILENAME", &core_file.to_string_lossy());
+        }
+
+        entry
+    }
+}
+
+/// The unit a process runs in, from the contents of /proc/PID/cgroup.
+pub fn unit_from_cgroup(cgroup: &str) -> Option<String> {
+    let path = cgroup.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let mut components = path.split('/').skip_while(|component| *component != SERVICES_SLICE);
+    components.next()?;
+    components.next()?.strip_suffix(".service").map(str::to_string)
+}
+
+/// The frames of gdb's `thread apply all bt` output, without the
+/// messages about loading symbols.
+pub fn parse_backtrace(output: &str) -> Option<String> {
+    let lines: Vec<&str> = output.lines()
+        .map(str::trim_end)
+        .filter(|line| line.starts_with('#') || line.starts_with("Thread "))
+        .collect();
+
+    if lines.iter().any(|line| line.starts_with('#')) {
+        Some(lines.join("\n"))
+    } else {
+        None
+    }
+}
+
+/// The pattern sending cores to the `coredump handle` command of `exe`.
+pub fn core_pattern(exe: &Path) -> String {
+    format!("|{} coredump handle {}", exe.to_string_lossy().trim_end_matches(" (deleted)"), CORE_PATTERN_ARGS)
+}
+
+/// Makes the kernel pipe cores to this binary.
+pub fn register_handler() -> Result<()> {
+    let exe = std::env::current_exe().context("Failed to find the daemon binary")?;
+    let pattern = core_pattern(&exe);
+
+    fs::write(CORE_PATTERN_PATH, &pattern)
+        .with_context(|| format!("Failed to write {}", CORE_PATTERN_PATH))?;
+    info!("Capturing core dumps with {}", pattern);
+    Ok(())
+}
+
+/// Stores the core the kernel pipes to stdin. A core limit of zero
+/// means the process did not want a core, only the crash is recorded.
+pub fn handle(crash: &Crash) -> Result<CoredumpInfo> {
+    let info = CoredumpInfo::collect(crash);
+    let store = CoredumpStore::new();
+
+    if crash.core_limit == 0 {
+        store.store(info, io::empty())
+    } else {
+        store.store(info, io::stdin().lock())
+    }
+}
+
+/// Compressed cores and their metadata, `<id>.gz` and `<id>.json`.
+pub struct CoredumpStore {
+    dir: PathBuf,
+    max_core_size: u64,
+    max_use: u64,
+    max_age: Duration,
+}
+
+impl CoredumpStore {
+    pub fn new() -> Self {
+        Self::open(ManagerScope::current().state_dir().join("coredump"))
+    }
+
+    pub fn open(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_core_size: DEFAULT_MAX_CORE_SIZE,
+            max_use: DEFAULT_MAX_USE,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    pub fn with_limits(mut self, max_core_size: u64, max_use: u64, max_age: Duration) -> Self {
+        self.max_core_size = max_core_size;
+        self.max_use = max_use;
+        self.max_age = max_age;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Stores a core with a backtrace, if gdb can make one, then removes
+    /// dumps over the retention limits.
+    pub fn store(&self, mut info: CoredumpInfo, core: impl Read) -> Result<CoredumpInfo> {
+        // Cores hold whatever the process had in memory
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(&self.dir)
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        let raw = self.dir.join(format!("{}.core", info.id));
+        let mut file = create_private(&raw)?;
+        let size = io::copy(&mut core.take(self.max_core_size), &mut file)
+            .context("Failed to read the core")?;
+        drop(file);
+
+        if size > 0 {
+            info.size = size;
+            info.truncated = size >= self.max_core_size;
+            info.backtrace = info.exe.as_deref()
+                .filter(|exe| Path::new(exe).exists())
+                .and_then(|exe| backtrace(Path::new(exe), &raw, info.uid, info.gid));
+
+            let compressed = self.dir.join(format!("{}.gz", info.id));
+            let mut encoder = GzEncoder::new(create_private(&compressed)?, Compression::default());
+            io::copy(&mut fs::File::open(&raw)?, &mut encoder)?;
+            encoder.finish().context("Failed to compress the core")?;
+            info.core_file = Some(compressed);
+        }
+        let _ = fs::remove_file(&raw);
+
+        let metadata = self.dir.join(format!("{}.json", info.id));
+        serde_json::to_writer_pretty(create_private(&metadata)?, &info)?;
+        info!("Stored core dump {} of PID {} ({})", info.id, info.pid, info.comm);
+
+        self.vacuum();
+        Ok(info)
+    }
+
+    pub fn get(&self, id: &str) -> Result<CoredumpInfo> {
+        if id.contains('/') {
+            return Err(anyhow::anyhow!("Invalid core dump ID '{}'", id));
+        }
+
+        let path = self.dir.join(format!("{}.json", id));
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("Core dump '{}' not found", id))?;
+        serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// All stored dumps, oldest first.
+    pub fn list(&self) -> Result<Vec<CoredumpInfo>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", self.dir.display())),
+        };
+
+        let mut dumps = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            match fs::read_to_string(&path).map_err(anyhow::Error::from)
+                .and_then(|content| Ok(serde_json::from_str::<CoredumpInfo>(&content)?))
+            {
+                Ok(info) => dumps.push(info),
+                Err(e) => debug!("Skipping {}: {}", path.display(), e),
+            }
+        }
+
+        dumps.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        Ok(dumps)
+    }
+
+    /// The most recent dump matching the query, or the most recent one.
+    pub fn find(&self, query: Option<&str>) -> Result<CoredumpInfo> {
+        let dumps = self.list()?;
+
+        match query {
+            Some(query) => dumps.into_iter().rev().find(|info| info.matches(query))
+                .ok_or_else(|| anyhow::anyhow!("No core dump matches '{}'", query)),
+            None => dumps.into_iter().last()
+                .ok_or_else(|| anyhow::anyhow!("No core dumps stored")),
+        }
+    }
+
+    /// Decompresses the core of a dump to `dest`.
+    pub fn extract(&self, info: &CoredumpInfo, dest: &Path) -> Result<()> {
+        let core_file = info.core_file.as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No core was stored for {}", info.id))?;
+        let source = fs::File::open(core_file)
+            .with_context(|| format!("Failed to open {}", core_file.display()))?;
+
+        io::copy(&mut GzDecoder::new(source), &mut create_private(dest)?)
+            .with_context(|| format!("Failed to decompress {}", core_file.display()))?;
+        Ok(())
+    }
+
+    pub fn remove(&self, info: &CoredumpInfo) {
+        if let Some(core_file) = &info.core_file {
+            let _ = fs::remove_file(core_file);
+        }
+        let _ = fs::remove_file(self.dir.join(format!("{}.json", info.id)));
+    }
+
+    /// Removes dumps older than the maximum age, then the oldest ones
+    /// until the store fits its maximum size. Returns how many were
+    /// removed.
+    pub fn vacuum(&self) -> usize {
+        let dumps = match self.list() {
+            Ok(dumps) => dumps,
+            Err(e) => {
+                warn!("Failed to list core dumps: {:#}", e);
+                return 0;
+            }
+        };
+
+        let now = Utc::now();
+        let max_age = chrono::Duration::from_std(self.max_age).unwrap_or(chrono::Duration::MAX);
+        let disk_usage = |info: &CoredumpInfo| -> u64 {
+            let metadata = fs::metadata(self.dir.join(format!("{}.json", info.id))).map_or(0, |m| m.len());
+            let core = info.core_file.as_ref().and_then(|path| fs::metadata(path).ok()).map_or(0, |m| m.len());
+            metadata + core
+        };
+
+        let mut usage: u64 = dumps.iter().map(disk_usage).sum();
+        let mut removed = 0;
+
+        for info in &dumps {
+            if now - info.timestamp <= max_age && usage <= self.max_use {
+                continue;
+            }
+
+            usage = usage.saturating_sub(disk_usage(info));
+            self.remove(info);
+            removed += 1;
+            debug!("Removed core dump {}", info.id);
+        }
+
+        removed
+    }
+}
+
+impl Default for CoredumpStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn create_private(path: &Path) -> Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to create {}", path.display()))
+}
+
+/// Runs gdb on the core, giving up on it after a while. Symbols are
+/// resolved where the executable and its libraries have debug info.
+/// gdb parses files the crashed process controlled, so it runs as the
+/// user of that process and reads the core through an inherited
+/// descriptor, the store itself is only open to root.
+fn backtrace(exe: &Path, core: &Path, uid: u32, gid: u32) -> Option<String> {
+    let core = fs::File::open(core)
+        .map_err(|e| debug!("Not making a backtrace: {}", e))
+        .ok()?;
+    let core_fd = core.as_raw_fd();
+
+    let drop_to = (nix::unistd::geteuid().is_root() && uid != 0).then_some((uid, gid));
+    if drop_to.is_some() {
+        if let Err(e) = fchown(&core, None, Some(gid)).and_then(|()| core.set_permissions(fs::Permissions::from_mode(0o640))) {
+            debug!("Not making a backtrace: {}", e);
+            return None;
+        }
+    }
+
+    let mut command = Command::new("gdb");
+    command
+        .args(["--batch", "--nx", "-ex", "set pagination off", "-ex", "thread apply all bt"])
+        .arg(exe)
+        .arg(format!("/proc/self/fd/{}", core_fd))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(move || {
+            // Files are opened close-on-exec
+            if libc::fcntl(core_fd, libc::F_SETFD, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if let Some((uid, gid)) = drop_to {
+                if libc::setgroups(0, std::ptr::null()) != 0
+                    || libc::setresgid(gid, gid, gid) != 0
+                    || libc::setresuid(uid, uid, uid) != 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()
+        .map_err(|e| debug!("Not making a backtrace: {}", e))
+        .ok()?;
+    drop(core);
+
+    // Read while waiting, a large backtrace fills the pipe
+    let mut stdout = child.stdout.take()?;
+    let reader = std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        output
+    });
+
+    let deadline = Instant::now() + BACKTRACE_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => std::thread::sleep(POLL_INTERVAL),
+            _ => {
+                warn!("gdb did not finish the backtrace of {}", exe.display());
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+
+    parse_backtrace(&reader.join().ok()?)
+}
@@ -0,0 +1,393 @@
+use crate::unit::ServiceUnit;
+use crate::scope::ManagerScope;
+use anyhow::{Result, Context};
+use nix::unistd::{Gid, Group, Uid, User};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::CString;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use log::{info, debug};
+
+pub const DYNAMIC_UID_DIR: &str = "/run/tau/dynamic-uid";
+/// Same range systemd reserves for DynamicUser=.
+pub const DYNAMIC_UID_MIN: u32 = 61184;
+pub const DYNAMIC_UID_MAX: u32 = 65519;
+
+const CAPABILITY_NAMES: &[&str] = &[
+    "CAP_CHOWN", "CAP_DAC_OVERRIDE", "CAP_DAC_READ_SEARCH", "CAP_FOWNER",
+    "CAP_FSETID", "CAP_KILL", "CAP_SETGID", "CAP_SETUID", "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE", "CAP_NET_BIND_SERVICE", "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN", "CAP_NET_RAW", "CAP_IPC_LOCK", "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE", "CAP_SYS_RAWIO", "CAP_SYS_CHROOT", "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT", "CAP_SYS_ADMIN", "CAP_SYS_BOOT", "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE", "CAP_SYS_TIME", "CAP_SYS_TTY_CONFIG", "CAP_MKNOD",
+    "CAP_LEASE", "CAP_AUDIT_WRITE", "CAP_AUDIT_CONTROL", "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE", "CAP_MAC_ADMIN", "CAP_SYSLOG", "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND", "CAP_AUDIT_READ", "CAP_PERFMON", "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+/// Parses a capability name such as `CAP_NET_BIND_SERVICE`. The prefix
+/// and case are optional, so `net_bind_service` works too.
+pub fn parse_capability(name: &str) -> Result<u32> {
+    let upper = name.trim().to_ascii_uppercase();
+    let full = if upper.starts_with("CAP_") { upper } else { format!("CAP_{}", upper) };
+
+    CAPABILITY_NAMES.iter()
+        .position(|cap| *cap == full)
+        .map(|i| i as u32)
+        .ok_or_else(|| anyhow::anyhow!("Unknown capability '{}'", name))
+}
+
+pub fn parse_capabilities(names: &[String]) -> Result<u64> {
+    names.iter().try_fold(0u64, |mask, name| Ok(mask | 1u64 << parse_capability(name)?))
+}
+
+/// Highest capability the running kernel knows about.
+fn last_capability() -> u32 {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(CAPABILITY_NAMES.len() as u32 - 1)
+}
+
+/// The identity and capabilities a service process runs with, resolved
+/// before fork so the `pre_exec` hook only has to make syscalls.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Credentials {
+    pub user_name: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub groups: Vec<u32>,
+    pub home: Option<PathBuf>,
+    pub shell: Option<PathBuf>,
+    /// Capabilities kept in the bounding set; `None` leaves it untouched
+    pub bounding_set: Option<u64>,
+    pub ambient: u64,
+    /// PR_SET_NO_NEW_PRIVS, so setuid binaries and file capabilities
+    /// grant nothing
+    #[serde(default)]
+    pub no_new_privileges: bool,
+    pub dynamic: bool,
+}
+
+impl Credentials {
+    pub fn resolve(unit: &ServiceUnit) -> Result<Self> {
+        if !ManagerScope::current().is_user() {
+            return Self::resolve_in(unit, Path::new(DYNAMIC_UID_DIR));
+        }
+
+        // A user manager cannot change identity, User= may only name the
+        // user it runs as
+        if unit.service.dynamic_user.unwrap_or(false) || unit.service.group.is_some() {
+            return Err(anyhow::anyhow!("DynamicUser= and Group= need the system manager, not supported in {}", unit.name));
+        }
+
+        let mut credentials = Self::resolve_in(unit, Path::new(DYNAMIC_UID_DIR))?;
+        if credentials.uid.is_some_and(|uid| uid != nix::unistd::getuid().as_raw()) {
+            return Err(anyhow::anyhow!("A user manager cannot run {} as {}", unit.name,
+                credentials.user_name.as_deref().unwrap_or_default()));
+        }
+
+        credentials.uid = None;
+        credentials.gid = None;
+        credentials.groups.clear();
+        Ok(credentials)
+    }
+
+    pub fn resolve_in(unit: &ServiceUnit, dynamic_uid_dir: &Path) -> Result<Self> {
+        let service = &unit.service;
+        let mut credentials = Credentials::default();
+
+        if service.dynamic_user.unwrap_or(false) {
+            let name = service.user.clone().unwrap_or_else(|| unit.name.clone());
+            let uid = allocate_dynamic_uid(dynamic_uid_dir, &name)?;
+            credentials.user_name = Some(name);
+            credentials.uid = Some(uid);
+            credentials.gid = Some(uid);
+            credentials.home = Some(PathBuf::from("/"));
+            credentials.shell = Some(PathBuf::from("/usr/sbin/nologin"));
+            credentials.dynamic = true;
+        } else if let Some(name) = &service.user {
+            let user = lookup_user(name)?;
+            credentials.user_name = Some(user.name.clone());
+            credentials.uid = Some(user.uid.as_raw());
+            credentials.gid = Some(user.gid.as_raw());
+            credentials.home = Some(user.dir.clone());
+            credentials.shell = Some(user.shell.clone());
+
+            // initgroups(): every group the user is a member of
+            let c_name = CString::new(user.name.as_str())?;
+            credentials.groups = nix::unistd::getgrouplist(&c_name, user.gid)
+                .context(format!("Failed to look up groups of {}", user.name))?
+                .into_iter()
+                .map(|gid| gid.as_raw())
+                .collect();
+        }
+
+        if let Some(name) = &service.group {
+            let gid = lookup_group(name)?;
+            credentials.gid = Some(gid);
+            if !credentials.groups.contains(&gid) && credentials.uid.is_some() {
+                credentials.groups.push(gid);
+            }
+        }
+
+        for name in service.supplementary_groups.iter().flatten() {
+            let gid = lookup_group(name)?;
+            if !credentials.groups.contains(&gid) {
+                credentials.groups.push(gid);
+            }
+        }
+
+        if let Some(sandbox) = &unit.sandbox {
+            // `capabilities` predates CapabilityBoundingSet= and means the same
+            let bounding = sandbox.capability_bounding_set.as_ref().or(sandbox.capabilities.as_ref());
+            if let Some(names) = bounding {
+                credentials.bounding_set = Some(parse_capabilities(names)?);
+            }
+
+            if let Some(names) = &sandbox.ambient_capabilities {
+                credentials.ambient = parse_capabilities(names)?;
+            }
+
+            credentials.no_new_privileges = sandbox.no_new_privileges.unwrap_or(false);
+        }
+
+        if let Some(bounding) = credentials.bounding_set {
+            if credentials.ambient & !bounding != 0 {
+                return Err(anyhow::anyhow!(
+                    "AmbientCapabilities of {} are not a subset of its CapabilityBoundingSet",
+                    unit.name
+                ));
+            }
+        }
+
+        Ok(credentials)
+    }
+
+    pub fn changes_identity(&self) -> bool {
+        self.uid.is_some() || self.gid.is_some() || !self.groups.is_empty()
+    }
+
+    /// Sets the environment a login for this user would have and
+    /// registers a `pre_exec` hook that drops to the user and applies the
+    /// capability sets and no_new_privileges. Must be the last hook registered, since the ones
+    /// before it may need root.
+    pub fn apply(&self, cmd: &mut Command) -> Result<()> {
+        // Environment= in the unit takes precedence
+        let mut set_default = |key: &str, value: &std::ffi::OsStr| {
+            if !cmd.get_envs().any(|(k, _)| k == key) {
+                cmd.env(key, value);
+            }
+        };
+        if let Some(name) = &self.user_name {
+            set_default("USER", name.as_ref());
+            set_default("LOGNAME", name.as_ref());
+        }
+        if let Some(home) = &self.home {
+            set_default("HOME", home.as_os_str());
+        }
+        if let Some(shell) = &self.shell {
+            set_default("SHELL", shell.as_os_str());
+        }
+
+        if !self.changes_identity() && self.bounding_set.is_none() && self.ambient == 0 && !self.no_new_privileges {
+            return Ok(());
+        }
+
+        if let (Some(uid), Some(name)) = (self.uid, &self.user_name) {
+            info!("Running as {} (uid {}, gid {})", name, uid, self.gid.unwrap_or(uid));
+        }
+
+        let uid = self.uid;
+        let gid = self.gid;
+        let groups: Vec<libc::gid_t> = self.groups.clone();
+        let bounding_set = self.bounding_set;
+        let ambient = self.ambient;
+        let no_new_privileges = self.no_new_privileges;
+        let last_cap = last_capability();
+        let drops_root = uid.is_some_and(|uid| uid != 0);
+
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(keep) = bounding_set {
+                    for cap in 0..=last_cap {
+                        if keep & (1u64 << cap) == 0
+                            && libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) != 0
+                        {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+
+                // Keep the permitted set across setuid so ambient
+                // capabilities can be raised afterwards
+                if drops_root && ambient != 0
+                    && libc::prctl(libc::PR_SET_KEEPCAPS, 1 as libc::c_ulong, 0, 0, 0) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                if (uid.is_some() || !groups.is_empty())
+                    && libc::setgroups(groups.len(), groups.as_ptr()) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                if let Some(gid) = gid {
+                    if libc::setresgid(gid, gid, gid) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                if let Some(uid) = uid {
+                    if libc::setresuid(uid, uid, uid) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                if ambient != 0 {
+                    set_capabilities(ambient)?;
+
+                    for cap in 0..=last_cap {
+                        if ambient & (1u64 << cap) != 0
+                            && libc::prctl(
+                                libc::PR_CAP_AMBIENT,
+                                libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+                                cap as libc::c_ulong,
+                                0,
+                                0,
+                            ) != 0
+                        {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                }
+
+                if no_new_privileges && libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                Ok(())
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Gives the dynamic UID back once the service has exited.
+    pub fn release(&self) {
+        if self.dynamic {
+            if let (Some(uid), Some(name)) = (self.uid, &self.user_name) {
+                release_dynamic_uid(Path::new(DYNAMIC_UID_DIR), uid, name);
+            }
+        }
+    }
+}
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+/// Sets the permitted, effective and inheritable sets to `caps`, which
+/// raising ambient capabilities requires.
+fn set_capabilities(caps: u64) -> std::io::Result<()> {
+    let mut header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [
+        CapData { effective: caps as u32, permitted: caps as u32, inheritable: caps as u32 },
+        CapData {
+            effective: (caps >> 32) as u32,
+            permitted: (caps >> 32) as u32,
+            inheritable: (caps >> 32) as u32,
+        },
+    ];
+
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<User> {
+    let user = match name.parse::<u32>() {
+        Ok(uid) => User::from_uid(Uid::from_raw(uid))?,
+        Err(_) => User::from_name(name)?,
+    };
+    user.ok_or_else(|| anyhow::anyhow!("User '{}' does not exist", name))
+}
+
+fn lookup_group(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse::<u32>() {
+        return Ok(gid);
+    }
+
+    Group::from_name(name)?
+        .map(|group| group.gid.as_raw())
+        .ok_or_else(|| anyhow::anyhow!("Group '{}' does not exist", name))
+}
+
+/// Picks a UID for a DynamicUser= service. The starting point is a hash of
+/// the unit name, so a unit tends to get the same UID on every start and
+/// files it leaves behind keep a stable owner. Allocations are recorded
+/// as files named after the UID, containing the unit name.
+pub fn allocate_dynamic_uid(dir: &Path, unit_name: &str) -> Result<u32> {
+    fs::create_dir_all(dir).context("Failed to create dynamic UID directory")?;
+
+    let range = DYNAMIC_UID_MAX - DYNAMIC_UID_MIN + 1;
+    let mut hasher = DefaultHasher::new();
+    unit_name.hash(&mut hasher);
+    let start = (hasher.finish() % range as u64) as u32;
+
+    for offset in 0..range {
+        let uid = DYNAMIC_UID_MIN + (start + offset) % range;
+
+        // Never hand out a UID that belongs to a real user
+        if User::from_uid(Uid::from_raw(uid)).ok().flatten().is_some()
+            || Group::from_gid(Gid::from_raw(uid)).ok().flatten().is_some()
+        {
+            continue;
+        }
+
+        let path = dir.join(uid.to_string());
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(unit_name.as_bytes())?;
+                debug!("Allocated dynamic UID {} to {}", uid, unit_name);
+                return Ok(uid);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if fs::read_to_string(&path).is_ok_and(|owner| owner == unit_name) {
+                    return Ok(uid);
+                }
+            }
+            Err(e) => return Err(e).context("Failed to record dynamic UID"),
+        }
+    }
+
+    Err(anyhow::anyhow!("No free dynamic UID left for {}", unit_name))
+}
+
+pub fn release_dynamic_uid(dir: &Path, uid: u32, unit_name: &str) {
+    let path = dir.join(uid.to_string());
+    if fs::read_to_string(&path).is_ok_and(|owner| owner == unit_name) {
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,612 @@
+use crate::service_manager::{ManagerEvent, ServiceManager, ServiceState, ServiceStatus};
+use crate::transaction::JobOutcome;
+use crate::scope::ManagerScope;
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use zbus::names::InterfaceName;
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, Value};
+use zbus::message::Header;
+use zbus::{interface, Connection, SignalContext};
+use log::{info, warn, debug};
+
+pub const BUS_NAME: &str = "org.tauos.ServiceManager1";
+pub const MANAGER_PATH: &str = "/org/tauos/ServiceManager1";
+pub const UNIT_INTERFACE: &str = "org.tauos.ServiceManager1.Unit";
+
+/// Properties of a unit as last published, in `unit_properties` order.
+type Snapshot = Vec<(&'static str, Value<'static>)>;
+
+/// Object path of a unit, e.g. `/org/tauos/ServiceManager1/unit/nginx`.
+pub fn unit_path(name: &str) -> OwnedObjectPath {
+    let path = format!("{}/unit/{}", MANAGER_PATH, escape_path_label(name));
+    OwnedObjectPath::try_from(path).expect("escaped unit paths are valid")
+}
+
+pub fn job_path(id: u32) -> OwnedObjectPath {
+    OwnedObjectPath::try_from(format!("{}/job/{}", MANAGER_PATH, id)).expect("job paths are valid")
+}
+
+/// Escapes a unit name into an object path element the way systemd does:
+/// bytes other than ASCII letters and digits become `_xx`, as does a
+/// leading digit.
+pub fn escape_path_label(name: &str) -> String {
+    if name.is_empty() {
+        return "_".to_string();
+    }
+
+    let mut label = String::new();
+    for (i, byte) in name.bytes().enumerate() {
+        if byte.is_ascii_alphabetic() || (i > 0 && byte.is_ascii_digit()) {
+            label.push(byte as char);
+        } else {
+            label.push_str(&format!("_{:02x}", byte));
+        }
+    }
+    label
+}
+
+pub fn active_state(state: &ServiceState) -> &'static str {
+    match state {
+        ServiceState::Inactive => "inactive",
+        ServiceState::Activating => "activating",
+        ServiceState::Active => "active",
+        ServiceState::Deactivating => "deactivating",
+        ServiceState::Failed => "failed",
+        ServiceState::Reloading => "reloading",
+    }
+}
+
+/// Microseconds since the epoch, 0 for never.
+fn timestamp(time: Option<SystemTime>) -> u64 {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+/// The properties of a unit object that follow its status, and are
+/// announced with PropertiesChanged. Resource usage is only read on
+/// request.
+pub fn unit_properties(status: &ServiceStatus) -> Snapshot {
+    vec![
+        ("ActiveState", Value::from(active_state(&status.state))),
+        ("MainPID", Value::from(status.pid.unwrap_or(0))),
+        ("ExitCode", Value::from(status.exit_code.unwrap_or(0))),
+        ("ExitSignal", Value::from(status.exit_signal.unwrap_or(0))),
+        ("StartTimestamp", Value::from(timestamp(status.start_time))),
+        ("ActivationTimestamp", Value::from(timestamp(status.activation_start))),
+        ("RestartTimestamp", Value::from(timestamp(status.last_restart))),
+        ("RestartCount", Value::from(status.restart_count)),
+        ("StatusText", Value::from(status.status_text.clone().unwrap_or_default())),
+        ("LoadError", Value::from(status.load_error.clone().unwrap_or_default())),
+        ("NeedsRestart", Value::from(status.needs_restart)),
+        ("FailedPhase", Value::from(status.failed_phase.map(|phase| phase.to_string()).unwrap_or_default())),
+        ("UnmetCondition", Value::from(status.unmet_condition.clone().unwrap_or_default())),
+    ]
+}
+
+/// Properties whose value differs between two snapshots of a unit.
+pub fn changed_properties<'a>(old: &[(&'static str, Value<'static>)], new: &'a Snapshot) -> HashMap<&'static str, &'a Value<'static>> {
+    new.iter()
+        .filter(|(name, value)| old.iter().find(|(old_name, _)| old_name == name).is_none_or(|(_, old)| old != value))
+        .map(|(name, value)| (*name, value))
+        .collect()
+}
+
+/// The result JobRemoved reports for a unit's job in a transaction.
+pub fn job_result(outcome: &JobOutcome) -> &'static str {
+    match outcome {
+        JobOutcome::Done => "done",
+        JobOutcome::Skipped => "skipped",
+        JobOutcome::Failed(_) => "failed",
+        JobOutcome::DependencyFailed(_) => "dependency",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JobKind {
+    Start,
+    Stop,
+    Restart,
+    Reload,
+}
+
+impl JobKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Start => "start",
+            JobKind::Stop => "stop",
+            JobKind::Restart => "restart",
+            JobKind::Reload => "reload",
+        }
+    }
+
+    /// Runs the job to completion. Blocks, like the manager API.
+    fn run(&self, manager: &ServiceManager, unit: &str) -> &'static str {
+        let units = [unit.to_string()];
+        let result = match self {
+            JobKind::Start => manager.start_units(&units)
+                .map(|report| report.outcome(unit).map_or("done", job_result)),
+            JobKind::Stop => manager.stop_units(&units)
+                .map(|report| report.outcome(unit).map_or("done", job_result)),
+            JobKind::Restart => tokio::runtime::Handle::current().block_on(manager.restart_service(unit))
+                .map(|_| "done"),
+            JobKind::Reload => manager.reload_service(unit).map(|_| "done"),
+        };
+
+        result.unwrap_or_else(|e| {
+            warn!("{} job for {} failed: {:#}", self.as_str(), unit, e);
+            "failed"
+        })
+    }
+}
+
+/// What the objects of the API share.
+#[derive(Clone)]
+struct Shared {
+    manager: ServiceManager,
+    last_job: Arc<AtomicU32>,
+    /// Methods are called on zbus' own executor, jobs run on the daemon's
+    runtime: tokio::runtime::Handle,
+}
+
+impl Shared {
+    /// Rejects callers that may not change units: only root, or the user
+    /// a user manager runs as, may.
+    async fn authorize(&self, connection: &Connection, header: &Header<'_>) -> zbus::fdo::Result<()> {
+        let sender = header.sender()
+            .ok_or_else(|| zbus::fdo::Error::AccessDenied("Caller has no bus name".into()))?;
+        let uid = zbus::fdo::DBusProxy::new(connection).await?
+            .get_connection_unix_user(sender.clone().into())
+            .await?;
+
+        if !may_manage_units(uid) {
+            return Err(zbus::fdo::Error::AccessDenied(format!("UID {} may not manage units", uid)));
+        }
+        Ok(())
+    }
+
+    /// Queues a job, exported as an object until it finishes. JobNew and
+    /// JobRemoved are emitted on the manager object.
+    async fn enqueue(&self, connection: &Connection, unit: &str, kind: JobKind) -> zbus::fdo::Result<OwnedObjectPath> {
+        let id = self.last_job.fetch_add(1, Ordering::Relaxed) + 1;
+        let path = job_path(id);
+
+        let job = JobInterface { id, unit: unit.to_string(), kind };
+        connection.object_server().at(&path, job).await?;
+
+        let ctxt = SignalContext::new(connection, MANAGER_PATH)?;
+        ManagerInterface::job_new(&ctxt, id, path.as_ref(), unit).await?;
+        debug!("Queued {} job {} for {}", kind.as_str(), id, unit);
+
+        let manager = self.manager.clone();
+        let connection = connection.clone();
+        let unit = unit.to_string();
+        let job_path = path.clone();
+        self.runtime.spawn(async move {
+            let run_unit = unit.clone();
+            let result = tokio::task::spawn_blocking(move || kind.run(&manager, &run_unit))
+                .await
+                .unwrap_or("failed");
+
+            if let Err(e) = connection.object_server().remove::<JobInterface, _>(&job_path).await {
+                warn!("Failed to remove job {}: {}", id, e);
+            }
+
+            let signal = match SignalContext::new(&connection, MANAGER_PATH) {
+                Ok(ctxt) => ManagerInterface::job_removed(&ctxt, id, job_path.as_ref(), &unit, result).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = signal {
+                warn!("Failed to announce the end of job {}: {}", id, e);
+            }
+        });
+
+        Ok(path)
+    }
+}
+
+/// Whether a caller of the given UID may start, stop, enable or reload
+/// units. Reading state is open to everyone.
+pub fn may_manage_units(uid: u32) -> bool {
+    uid == 0 || uid == nix::unistd::getuid().as_raw()
+}
+
+fn failed(e: anyhow::Error) -> zbus::fdo::Error {
+    zbus::fdo::Error::Failed(format!("{:#}", e))
+}
+
+/// The `org.tauos.ServiceManager1` bus API: a manager object, an object
+/// per loaded unit and one per queued job, kept in sync with the
+/// manager's state so clients can subscribe instead of polling.
+pub struct ServiceDbusApi {
+    shared: Shared,
+}
+
+impl ServiceDbusApi {
+    pub fn new(manager: ServiceManager) -> Self {
+        Self {
+            shared: Shared {
+                manager,
+                last_job: Arc::new(AtomicU32::new(0)),
+                runtime: tokio::runtime::Handle::current(),
+            },
+        }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let connection = match ManagerScope::current() {
+            ManagerScope::System => Connection::system().await?,
+            ManagerScope::User { .. } => Connection::session().await?,
+        };
+
+        // Subscribed before the units are exported, so no change falls in
+        // between
+        let mut events = self.shared.manager.subscribe();
+
+        connection.object_server().at(MANAGER_PATH, ManagerInterface { shared: self.shared.clone() }).await?;
+        connection.request_name(BUS_NAME).await?;
+
+        info!("Service Manager D-Bus API running on {}", BUS_NAME);
+
+        let mut units = HashMap::new();
+        self.sync_units(&connection, &mut units).await;
+
+        loop {
+            match events.recv().await {
+                Ok(ManagerEvent::StateChanged { name, .. }) => {
+                    if !units.contains_key(&name) {
+                        self.sync_units(&connection, &mut units).await;
+                    }
+                    self.publish_changes(&connection, &name, &mut units).await;
+                }
+                Ok(ManagerEvent::UnitsReloaded { .. }) | Ok(ManagerEvent::UnitRemoved { .. }) => {
+                    self.sync_units(&connection, &mut units).await;
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("D-Bus API lagged, {} events dropped", skipped);
+                    self.sync_units(&connection, &mut units).await;
+                    let names: Vec<String> = units.keys().cloned().collect();
+                    for name in names {
+                        self.publish_changes(&connection, &name, &mut units).await;
+                    }
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    fn snapshot(&self, name: &str) -> Snapshot {
+        let status = self.shared.manager.get_service_status(name)
+            .unwrap_or_else(|| ServiceStatus::new(name));
+        unit_properties(&status)
+    }
+
+    /// Exports objects for new units and removes those of units that are
+    /// gone, with UnitNew and UnitRemoved.
+    async fn sync_units(&self, connection: &Connection, units: &mut HashMap<String, Snapshot>) {
+        let names: HashSet<String> = self.shared.manager.unit_names().into_iter().collect();
+        let server = connection.object_server();
+        let Ok(ctxt) = SignalContext::new(connection, MANAGER_PATH) else {
+            return;
+        };
+
+        let removed: Vec<String> = units.keys().filter(|name| !names.contains(*name)).cloned().collect();
+        for name in removed {
+            let path = unit_path(&name);
+            units.remove(&name);
+            if let Err(e) = server.remove::<UnitInterface, _>(&path).await {
+                warn!("Failed to remove object of {}: {}", name, e);
+            }
+            if let Err(e) = ManagerInterface::unit_removed(&ctxt, &name, path.as_ref()).await {
+                warn!("Failed to announce removal of {}: {}", name, e);
+            }
+        }
+
+        for name in names {
+            if units.contains_key(&name) {
+                continue;
+            }
+
+            let path = unit_path(&name);
+            let unit = UnitInterface { shared: self.shared.clone(), name: name.clone() };
+            if let Err(e) = server.at(&path, unit).await {
+                warn!("Failed to export {}: {}", name, e);
+                continue;
+            }
+            units.insert(name.clone(), self.snapshot(&name));
+            if let Err(e) = ManagerInterface::unit_new(&ctxt, &name, path.as_ref()).await {
+                warn!("Failed to announce {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Emits one PropertiesChanged with every property of the unit that
+    /// changed since it was last published.
+    async fn publish_changes(&self, connection: &Connection, name: &str, units: &mut HashMap<String, Snapshot>) {
+        let Some(published) = units.get_mut(name) else {
+            return;
+        };
+
+        let current = self.snapshot(name);
+        let changed = changed_properties(published, &current);
+        if changed.is_empty() {
+            return;
+        }
+
+        let result = match SignalContext::new(connection, unit_path(name)) {
+            Ok(ctxt) => zbus::fdo::Properties::properties_changed(
+                &ctxt,
+                InterfaceName::from_static_str_unchecked(UNIT_INTERFACE),
+                &changed,
+                &[],
+            ).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            warn!("Failed to announce changes of {}: {}", name, e);
+        }
+
+        *published = current;
+    }
+}
+
+struct ManagerInterface {
+    shared: Shared,
+}
+
+#[interface(name = "org.tauos.ServiceManager1")]
+impl ManagerInterface {
+    /// Loaded units as (name, active state, object path).
+    async fn list_units(&self) -> Vec<(String, String, OwnedObjectPath)> {
+        let mut names = self.shared.manager.unit_names();
+        names.sort();
+
+        names.into_iter()
+            .map(|name| {
+                let state = self.shared.manager.get_service_status(&name)
+                    .map_or("inactive", |status| active_state(&status.state));
+                let path = unit_path(&name);
+                (name, state.to_string(), path)
+            })
+            .collect()
+    }
+
+    async fn get_unit(&self, name: String) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        if !self.shared.manager.unit_names().contains(&name) {
+            return Err(zbus::fdo::Error::UnknownObject(format!("Unit {} is not loaded", name)));
+        }
+        Ok(unit_path(&name))
+    }
+
+    async fn start_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &name, JobKind::Start).await
+    }
+
+    async fn stop_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &name, JobKind::Stop).await
+    }
+
+    async fn restart_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &name, JobKind::Restart).await
+    }
+
+    async fn reload_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &name, JobKind::Reload).await
+    }
+
+    async fn enable_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<(), zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.manager.enable_service(&name).map_err(failed)
+    }
+
+    async fn disable_unit(&self, name: String, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<(), zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.manager.disable_service(&name).map_err(failed)
+    }
+
+    /// Reloads all unit files, like `tau-service daemon-reload`.
+    async fn reload(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<(), zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        let manager = self.shared.manager.clone();
+        self.shared.runtime.spawn_blocking(move || manager.load_units())
+            .await
+            .map_err(|e| zbus::fdo::Error::Failed(e.to_string()))?
+            .map_err(failed)
+    }
+
+    #[zbus(property)]
+    async fn version(&self) -> String {
+        env!("CARGO_PKG_VERSION").to_string()
+    }
+
+    /// "system" or "user".
+    #[zbus(property)]
+    async fn scope(&self) -> String {
+        if ManagerScope::current().is_user() { "user" } else { "system" }.to_string()
+    }
+
+    #[zbus(signal)]
+    async fn unit_new(ctxt: &SignalContext<'_>, name: &str, unit: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn unit_removed(ctxt: &SignalContext<'_>, name: &str, unit: ObjectPath<'_>) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn job_new(ctxt: &SignalContext<'_>, id: u32, job: ObjectPath<'_>, unit: &str) -> zbus::Result<()>;
+
+    /// `result` is one of "done", "skipped", "failed" or "dependency".
+    #[zbus(signal)]
+    async fn job_removed(ctxt: &SignalContext<'_>, id: u32, job: ObjectPath<'_>, unit: &str, result: &str) -> zbus::Result<()>;
+}
+
+/// A loaded unit, exposing its `ServiceStatus`.
+struct UnitInterface {
+    shared: Shared,
+    name: String,
+}
+
+impl UnitInterface {
+    fn status(&self) -> ServiceStatus {
+        self.shared.manager.get_service_status(&self.name)
+            .unwrap_or_else(|| ServiceStatus::new(&self.name))
+    }
+}
+
+#[interface(name = "org.tauos.ServiceManager1.Unit")]
+impl UnitInterface {
+    async fn start(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &self.name, JobKind::Start).await
+    }
+
+    async fn stop(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &self.name, JobKind::Stop).await
+    }
+
+    async fn restart(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &self.name, JobKind::Restart).await
+    }
+
+    async fn reload(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<OwnedObjectPath, zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.enqueue(connection, &self.name, JobKind::Reload).await
+    }
+
+    async fn enable(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<(), zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.manager.enable_service(&self.name).map_err(failed)
+    }
+
+    async fn disable(&self, #[zbus(connection)] connection: &Connection, #[zbus(header)] header: Header<'_>) -> Result<(), zbus::fdo::Error> {
+        self.shared.authorize(connection, &header).await?;
+        self.shared.manager.disable_service(&self.name).map_err(failed)
+    }
+
+    #[zbus(property)]
+    async fn id(&self) -> String {
+        self.name.clone()
+    }
+
+    #[zbus(property)]
+    async fn active_state(&self) -> String {
+        active_state(&self.status().state).to_string()
+    }
+
+    #[zbus(property, name = "MainPID")]
+    async fn main_pid(&self) -> u32 {
+        self.status().pid.unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    async fn exit_code(&self) -> i32 {
+        self.status().exit_code.unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    async fn exit_signal(&self) -> i32 {
+        self.status().exit_signal.unwrap_or(0)
+    }
+
+    #[zbus(property)]
+    async fn start_timestamp(&self) -> u64 {
+        timestamp(self.status().start_time)
+    }
+
+    #[zbus(property)]
+    async fn activation_timestamp(&self) -> u64 {
+        timestamp(self.status().activation_start)
+    }
+
+    #[zbus(property)]
+    async fn restart_timestamp(&self) -> u64 {
+        timestamp(self.status().last_restart)
+    }
+
+    #[zbus(property)]
+    async fn restart_count(&self) -> u32 {
+        self.status().restart_count
+    }
+
+    #[zbus(property)]
+    async fn status_text(&self) -> String {
+        self.status().status_text.unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    async fn load_error(&self) -> String {
+        self.status().load_error.unwrap_or_default()
+    }
+
+    #[zbus(property)]
+    async fn needs_restart(&self) -> bool {
+        self.status().needs_restart
+    }
+
+    #[zbus(property)]
+    async fn failed_phase(&self) -> String {
+        self.status().failed_phase.map(|phase| phase.to_string()).unwrap_or_default()
+    }
+
+    /// The condition that skipped, or assertion that failed, the last
+    /// start.
+    #[zbus(property)]
+    async fn unmet_condition(&self) -> String {
+        self.status().unmet_condition.unwrap_or_default()
+    }
+
+    /// Bytes, u64::MAX if unknown.
+    #[zbus(property)]
+    async fn memory_current(&self) -> u64 {
+        self.status().resources.and_then(|usage| usage.memory_current).unwrap_or(u64::MAX)
+    }
+
+    #[zbus(property, name = "CPUUsageNSec")]
+    async fn cpu_usage_nsec(&self) -> u64 {
+        self.status().resources.and_then(|usage| usage.cpu_usage_usec).map_or(u64::MAX, |usec| usec * 1000)
+    }
+
+    #[zbus(property)]
+    async fn tasks_current(&self) -> u64 {
+        self.status().resources.and_then(|usage| usage.tasks_current).unwrap_or(u64::MAX)
+    }
+}
+
+/// A queued job, exported until it finishes.
+struct JobInterface {
+    id: u32,
+    unit: String,
+    kind: JobKind,
+}
+
+#[interface(name = "org.tauos.ServiceManager1.Job")]
+impl JobInterface {
+    #[zbus(property)]
+    async fn id(&self) -> u32 {
+        self.id
+    }
+
+    #[zbus(property)]
+    async fn unit(&self) -> String {
+        self.unit.clone()
+    }
+
+    #[zbus(property)]
+    async fn job_type(&self) -> String {
+        self.kind.as_str().to_string()
+    }
+
+    /// Jobs run as soon as they are queued.
+    #[zbus(property)]
+    async fn state(&self) -> String {
+        "running".to_string()
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// A parsed ExecStart=, ExecStop=, ... line.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecCommand {
+    /// The executable, searched in PATH if it has no slash
+    pub path: String,
+    /// What the command sees as argv[0]
+    pub argv0: String,
+    pub args: Vec<String>,
+    /// `-`: a failure of the command is ignored
+    pub ignore_failure: bool,
+    /// `+`: runs with full privileges, without User=, sandboxing or the
+    /// syscall filter
+    pub full_privileges: bool,
+    /// `!`: runs without switching to User=/Group=, sandboxed otherwise
+    pub keep_identity: bool,
+    /// `:`: environment variables are not expanded
+    pub no_expand: bool,
+}
+
+impl ExecCommand {
+    /// Parses an Exec line: prefixes, then words with shell-like quoting.
+    /// `${VAR}` is replaced by the variable's value, a `$VAR` word by the
+    /// value split at whitespace. Unset variables expand to nothing.
+    pub fn parse(line: &str, env: &HashMap<String, String>) -> Result<Self> {
+        let mut command = ExecCommand::default();
+        let mut custom_argv0 = false;
+
+        let line = line.trim_start();
+        let prefix_len = line.find(|c| !matches!(c, '-' | '@' | '+' | '!' | ':')).unwrap_or(line.len());
+        for prefix in line[..prefix_len].chars() {
+            let flag = match prefix {
+                '-' => &mut command.ignore_failure,
+                '@' => &mut custom_argv0,
+                '+' => &mut command.full_privileges,
+                '!' => &mut command.keep_identity,
+                _ => &mut command.no_expand,
+            };
+            if *flag {
+                return Err(anyhow::anyhow!("Duplicate prefix '{}' in command line: {}", prefix, line));
+            }
+            *flag = true;
+        }
+
+        if command.full_privileges && command.keep_identity {
+            return Err(anyhow::anyhow!("Prefixes '+' and '!' cannot be combined: {}", line));
+        }
+
+        let env = if command.no_expand { None } else { Some(env) };
+        let mut words = split_words(&line[prefix_len..], env)?.into_iter();
+
+        command.path = words.next().ok_or_else(|| anyhow::anyhow!("Empty command"))?;
+        command.argv0 = if custom_argv0 {
+            words.next().ok_or_else(|| anyhow::anyhow!("'@' needs an argv[0] after the executable: {}", line))?
+        } else {
+            command.path.clone()
+        };
+        command.args = words.collect();
+
+        Ok(command)
+    }
+}
+
+/// Splits a command line into words. Single quotes are literal, double
+/// quotes allow escapes and `${VAR}`, and a backslash outside quotes
+/// escapes the next character. Without `env`, `$` is an ordinary
+/// character.
+fn split_words(line: &str, env: Option<&HashMap<String, String>>) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.peek().is_none() {
+            return Ok(words);
+        }
+
+        let mut word = String::new();
+        let mut quoted = false;
+
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            match c {
+                '\'' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => word.push(c),
+                            None => return Err(anyhow::anyhow!("Unterminated quote in command line: {}", line)),
+                        }
+                    }
+                }
+                '"' => {
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => word.push(unescape(&mut chars, line)?),
+                            Some('$') if env.is_some() => word.push_str(&expand(&mut chars, env, line)?),
+                            Some(c) => word.push(c),
+                            None => return Err(anyhow::anyhow!("Unterminated quote in command line: {}", line)),
+                        }
+                    }
+                }
+                '\\' => word.push(unescape(&mut chars, line)?),
+                '$' if env.is_some() => {
+                    let standalone = word.is_empty() && !quoted && chars.peek().is_some_and(|c| *c != '{');
+                    let value = expand(&mut chars, env, line)?;
+
+                    // A `$VAR` word becomes one word per field of the value
+                    if standalone && chars.peek().is_none_or(|c| c.is_whitespace()) {
+                        words.extend(value.split_whitespace().map(String::from));
+                        break;
+                    }
+                    word.push_str(&value);
+                }
+                c => word.push(c),
+            }
+
+            if chars.peek().is_none_or(|c| c.is_whitespace()) {
+                words.push(std::mem::take(&mut word));
+                break;
+            }
+        }
+    }
+}
+
+/// The character after a backslash.
+fn unescape(chars: &mut Peekable<Chars>, line: &str) -> Result<char> {
+    let escaped = match chars.next() {
+        Some('n') => '\n',
+        Some('t') => '\t',
+        Some('r') => '\r',
+        Some(c) => c,
+        None => return Err(anyhow::anyhow!("Trailing backslash in command line: {}", line)),
+    };
+    Ok(escaped)
+}
+
+/// Expands the variable reference after a `$`. `$$` is a literal `$`,
+/// as is a `$` that starts no variable name.
+fn expand(chars: &mut Peekable<Chars>, env: Option<&HashMap<String, String>>, line: &str) -> Result<String> {
+    let name = match chars.peek() {
+        Some('$') => {
+            chars.next();
+            return Ok("$".to_string());
+        }
+        Some('{') => {
+            chars.next();
+            let mut name = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) => name.push(c),
+                    None => return Err(anyhow::anyhow!("Unterminated ${{ in command line: {}", line)),
+                }
+            }
+            if !is_variable_name(&name) {
+                return Err(anyhow::anyhow!("Invalid variable name '{}' in command line: {}", name, line));
+            }
+            name
+        }
+        Some(c) if c.is_ascii_alphabetic() || *c == '_' => {
+            let mut name = String::new();
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+            name
+        }
+        _ => return Ok("$".to_string()),
+    };
+
+    Ok(env.and_then(|env| env.get(&name)).cloned().unwrap_or_default())
+}
+
+fn is_variable_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses an EnvironmentFile=: `KEY=VALUE` lines, with `#` and `;`
+/// comments, single or double quoted values that may span lines, and
+/// backslash line continuations. Lines without a valid assignment are
+/// skipped.
+pub fn parse_environment_file(content: &str) -> Result<Vec<(String, String)>> {
+    let mut variables = Vec::new();
+    let mut chars = content.chars().peekable();
+    let mut line_number = 1;
+
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line_number += 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            continue;
+        }
+
+        // Comments run to the end of the line
+        if c == '#' || c == ';' {
+            while chars.next_if(|c| *c != '\n').is_some() {}
+            continue;
+        }
+
+        let mut key = c.to_string();
+        while let Some(c) = chars.next_if(|c| *c != '=' && *c != '\n') {
+            key.push(c);
+        }
+        let key = key.trim().trim_start_matches("export ").trim().to_string();
+
+        if chars.next_if(|c| *c == '=').is_none() || !is_variable_name(&key) {
+            log::warn!("Ignoring invalid line {} in environment file", line_number);
+            while chars.next_if(|c| *c != '\n').is_some() {}
+            continue;
+        }
+
+        let start_line = line_number;
+        let mut value = String::new();
+        // Length of the value up to its last quoted or escaped character,
+        // trailing whitespace after it is trimmed
+        let mut kept = 0;
+
+        while chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+
+        while let Some(c) = chars.next_if(|c| *c != '\n') {
+            match c {
+                '\'' | '"' => {
+                    loop {
+                        match chars.next() {
+                            Some(q) if q == c => break,
+                            Some('\\') if c == '"' => match chars.next() {
+                                Some('\n') => line_number += 1,
+                                Some(e @ ('"' | '\\' | '$' | '`')) => value.push(e),
+                                Some(e) => {
+                                    value.push('\\');
+                                    value.push(e);
+                                }
+                                None => break,
+                            },
+                            Some(e) => {
+                                if e == '\n' {
+                                    line_number += 1;
+                                }
+                                value.push(e);
+                            }
+                            None => return Err(anyhow::anyhow!("Unterminated quote in environment file, line {}", start_line)),
+                        }
+                    }
+                    kept = value.len();
+                }
+                '\\' => match chars.next() {
+                    Some('\n') => line_number += 1,
+                    Some(e) => {
+                        value.push(e);
+                        kept = value.len();
+                    }
+                    None => {}
+                },
+                '#' if value.ends_with([' ', '\t']) || value.is_empty() => {
+                    while chars.next_if(|c| *c != '\n').is_some() {}
+                }
+                c => {
+                    value.push(c);
+                    if !c.is_whitespace() {
+                        kept = value.len();
+                    }
+                }
+            }
+        }
+
+        value.truncate(kept);
+        variables.push((key, value));
+    }
+
+    Ok(variables)
+}
@@ -1,21 +1,92 @@
+use crate::scope::ManagerScope;
 use anyhow::{Result, Context};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
-use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
-use log::{info, warn, error};
+use tokio::sync::broadcast;
+use log::{info, warn, debug};
+
+/// The journal file currently written to. Full files are renamed to
+/// `system@<first>-<last>.journal` (sequence numbers in hex) and get an
+/// index written next to them.
+const ACTIVE_FILE: &str = "system.journal";
+const JOURNAL_EXTENSION: &str = "journal";
+const INDEX_EXTENSION: &str = "idx";
+
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 8 << 20;
+pub const DEFAULT_MAX_USE: u64 = 128 << 20;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct JournalEntry {
+    /// Position in the journal, increasing across rotations
+    #[serde(default)]
+    pub seqnum: u64,
     pub timestamp: DateTime<Utc>,
+    /// Unit the entry belongs to
     pub service: String,
     pub stream: String, // "stdout", "stderr", "system"
     pub message: String,
     pub pid: Option<u32>,
     pub level: LogLevel,
+    #[serde(default)]
+    pub boot_id: String,
+    /// Additional KEY=VALUE fields
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, String>,
+}
+
+impl JournalEntry {
+    pub fn new(service: &str, stream: &str, message: &str) -> Self {
+        Self {
+            seqnum: 0,
+            timestamp: Utc::now(),
+            service: service.to_string(),
+            stream: stream.to_string(),
+            message: message.to_string(),
+            pid: None,
+            level: LogLevel::Info,
+            boot_id: String::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+    
+    pub fn with_pid(mut self, pid: Option<u32>) -> Self {
+        self.pid = pid;
+        self
+    }
+    
+    pub fn with_level(mut self, level: LogLevel) -> Self {
+        self.level = level;
+        self
+    }
+    
+    pub fn with_field(mut self, key: &str, value: &str) -> Self {
+        self.fields.insert(key.to_string(), value.to_string());
+        self
+    }
+    
+    pub fn priority(&self) -> u8 {
+        self.level.priority()
+    }
+    
+    /// Looks up a field by its journal name. The built-in fields are
+    /// available as UNIT, _PID, PRIORITY, _BOOT_ID, STREAM and MESSAGE.
+    pub fn field(&self, key: &str) -> Option<String> {
+        match key {
+            "UNIT" => Some(self.service.clone()),
+            "_PID" => self.pid.map(|pid| pid.to_string()),
+            "PRIORITY" => Some(self.priority().to_string()),
+            "_BOOT_ID" => Some(self.boot_id.clone()),
+            "STREAM" => Some(self.stream.clone()),
+            "MESSAGE" => Some(self.message.clone()),
+            _ => self.fields.get(key).cloned(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -25,229 +96,545 @@ pub enum LogLevel {
     Warning,
     Error,
     Critical,
+    Notice,
+    Alert,
+    Emergency,
 }
 
+impl LogLevel {
+    /// The syslog priority, 0 (emerg) to 7 (debug).
+    pub fn priority(&self) -> u8 {
+        match self {
+            LogLevel::Emergency => 0,
+            LogLevel::Alert => 1,
+            LogLevel::Critical => 2,
+            LogLevel::Error => 3,
+            LogLevel::Warning => 4,
+            LogLevel::Notice => 5,
+            LogLevel::Info => 6,
+            LogLevel::Debug => 7,
+        }
+    }
+    
+    pub fn from_priority(priority: u8) -> Self {
+        match priority {
+            0 => LogLevel::Emergency,
+            1 => LogLevel::Alert,
+            2 => LogLevel::Critical,
+            3 => LogLevel::Error,
+            4 => LogLevel::Warning,
+            5 => LogLevel::Notice,
+            6 => LogLevel::Info,
+            _ => LogLevel::Debug,
+        }
+    }
+    
+    /// Parses a priority name as journalctl accepts them (`err`,
+    /// `warning`, ...) or a number from 0 to 7.
+    pub fn parse(value: &str) -> Result<Self> {
+        let level = match value.trim().to_lowercase().as_str() {
+            "emerg" | "emergency" => LogLevel::Emergency,
+            "alert" => LogLevel::Alert,
+            "crit" | "critical" => LogLevel::Critical,
+            "err" | "error" => LogLevel::Error,
+            "warning" | "warn" => LogLevel::Warning,
+            "notice" => LogLevel::Notice,
+            "info" => LogLevel::Info,
+            "debug" => LogLevel::Debug,
+            other => match other.parse::<u8>() {
+                Ok(priority) if priority <= 7 => LogLevel::from_priority(priority),
+                _ => return Err(anyhow::anyhow!("Invalid priority '{}'", value)),
+            },
+        };
+        
+        Ok(level)
+    }
+}
+
+/// Selects journal entries. All set conditions must match.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JournalQuery {
+    pub unit: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Entries of this priority or more important
+    pub priority: Option<u8>,
+    /// A boot ID, or an offset like `0` (current) or `-1` (previous)
+    pub boot: Option<String>,
+    /// Regular expression matched against the message
+    pub grep: Option<String>,
+    /// KEY=VALUE matches, see `JournalEntry::field`
+    #[serde(default)]
+    pub fields: BTreeMap<String, String>,
+    /// Only the last this many matching entries
+    pub lines: Option<usize>,
+}
+
+/// A query with its boot resolved and pattern compiled.
+pub struct EntryFilter {
+    unit: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    priority: Option<u8>,
+    boot: Option<String>,
+    grep: Option<Regex>,
+    fields: BTreeMap<String, String>,
+}
+
+impl EntryFilter {
+    /// Checks the conditions the index can answer.
+    fn matches_record(&self, record: &IndexRecord) -> bool {
+        self.unit.as_ref().is_none_or(|unit| *unit == record.unit)
+            && self.since.is_none_or(|since| record.realtime >= since)
+            && self.until.is_none_or(|until| record.realtime <= until)
+            && self.priority.is_none_or(|priority| record.priority <= priority)
+            && self.boot.as_ref().is_none_or(|boot| *boot == record.boot_id)
+    }
+    
+    fn may_match_file(&self, summary: &FileSummary) -> bool {
+        summary.entries > 0
+            && self.unit.as_ref().is_none_or(|unit| summary.units.contains(unit))
+            && self.boot.as_ref().is_none_or(|boot| summary.boots.contains_key(boot))
+            && self.since.is_none_or(|since| summary.last_time >= since)
+            && self.until.is_none_or(|until| summary.first_time <= until)
+    }
+    
+    pub fn matches(&self, entry: &JournalEntry) -> bool {
+        self.matches_record(&IndexRecord::of(entry, 0))
+            && self.grep.as_ref().is_none_or(|grep| grep.is_match(&entry.message))
+            && self.fields.iter().all(|(key, value)| entry.field(key).as_deref() == Some(value.as_str()))
+    }
+}
+
+/// Where an entry is and what queries filter on, so matching entries
+/// can be found without parsing the whole journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexRecord {
+    offset: u64,
+    seqnum: u64,
+    /// Microseconds since the epoch
+    realtime: i64,
+    unit: String,
+    priority: u8,
+    boot_id: String,
+}
+
+impl IndexRecord {
+    fn of(entry: &JournalEntry, offset: u64) -> Self {
+        Self {
+            offset,
+            seqnum: entry.seqnum,
+            realtime: entry.timestamp.timestamp_micros(),
+            unit: entry.service.clone(),
+            priority: entry.priority(),
+            boot_id: entry.boot_id.clone(),
+        }
+    }
+}
+
+/// Per-file overview kept in memory, used to skip whole files.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileSummary {
+    entries: usize,
+    first_seqnum: u64,
+    last_seqnum: u64,
+    first_time: i64,
+    last_time: i64,
+    units: BTreeSet<String>,
+    /// Boot ID to the time of its first and last entry in the file
+    boots: BTreeMap<String, (i64, i64)>,
+}
+
+impl FileSummary {
+    fn add(&mut self, record: &IndexRecord) {
+        if self.entries == 0 {
+            self.first_seqnum = record.seqnum;
+            self.first_time = record.realtime;
+        }
+        self.entries += 1;
+        self.last_seqnum = record.seqnum;
+        self.last_time = record.realtime;
+        self.units.insert(record.unit.clone());
+        
+        let boot = self.boots.entry(record.boot_id.clone()).or_insert((record.realtime, record.realtime));
+        boot.1 = record.realtime;
+    }
+}
+
+struct JournalFile {
+    path: PathBuf,
+    summary: FileSummary,
+    size: u64,
+}
+
+impl JournalFile {
+    fn index_path(&self) -> PathBuf {
+        self.path.with_extension(INDEX_EXTENSION)
+    }
+    
+    /// Reads the index of an archived file. The first line holds the
+    /// summary, every further line one record.
+    fn load_records(&self) -> Result<Vec<IndexRecord>> {
+        let file = File::open(self.index_path())?;
+        BufReader::new(file)
+            .lines()
+            .skip(1)
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+    
+    fn write_index(&self, records: &[IndexRecord]) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(self.index_path())?);
+        serde_json::to_writer(&mut writer, &self.summary)?;
+        writer.write_all(b"\n")?;
+        for record in records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+struct JournalState {
+    active: File,
+    active_file: JournalFile,
+    /// Index of the active file, archived files load theirs on demand
+    active_records: Vec<IndexRecord>,
+    /// Oldest first
+    archived: Vec<JournalFile>,
+    next_seqnum: u64,
+}
+
+/// A boot as recorded in the journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootInfo {
+    pub boot_id: String,
+    pub first_entry: DateTime<Utc>,
+    pub last_entry: DateTime<Utc>,
+}
+
+/// Append-only structured journal. Entries are stored as JSON lines and
+/// indexed by unit, time, priority and boot.
 pub struct JournalLogger {
     journal_dir: PathBuf,
-    max_entries: usize,
-    entries: Arc<Mutex<VecDeque<JournalEntry>>>,
+    boot_id: String,
+    max_file_size: u64,
+    max_use: u64,
+    state: Arc<Mutex<JournalState>>,
+    live: broadcast::Sender<JournalEntry>,
 }
 
 impl JournalLogger {
     pub fn new() -> Result<Self> {
-        let journal_dir = PathBuf::from("/var/log/tau/journal");
-        fs::create_dir_all(&journal_dir)
+        Self::open(&ManagerScope::current().journal_dir())
+    }
+    
+    pub fn open(journal_dir: &Path) -> Result<Self> {
+        fs::create_dir_all(journal_dir)
             .context("Failed to create journal directory")?;
         
+        let state = load_state(journal_dir)?;
+        let (live, _) = broadcast::channel(1024);
+        
         Ok(Self {
-            journal_dir,
-            max_entries: 10000, // Keep last 10k entries in memory
-            entries: Arc::new(Mutex::new(VecDeque::new())),
+            journal_dir: journal_dir.to_path_buf(),
+            boot_id: current_boot_id(),
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+            max_use: DEFAULT_MAX_USE,
+            state: Arc::new(Mutex::new(state)),
+            live,
         })
     }
     
+    /// Sets the size at which the active file is rotated and the total
+    /// size archived files are vacuumed down to.
+    pub fn with_limits(mut self, max_file_size: u64, max_use: u64) -> Self {
+        self.max_file_size = max_file_size;
+        self.max_use = max_use;
+        self
+    }
+    
+    pub fn with_boot_id(mut self, boot_id: &str) -> Self {
+        self.boot_id = boot_id.to_string();
+        self
+    }
+    
+    pub fn boot_id(&self) -> &str {
+        &self.boot_id
+    }
+    
     pub fn log(&self, service: &str, stream: &str, message: &str) -> Result<()> {
-        let entry = JournalEntry {
-            timestamp: Utc::now(),
-            service: service.to_string(),
-            stream: stream.to_string(),
-            message: message.to_string(),
-            pid: None, // Will be set by caller if needed
-            level: self.determine_level(message),
-        };
-        
-        // Add to memory buffer
+        self.write(JournalEntry::new(service, stream, message))?;
+        Ok(())
+    }
+    
+    /// Appends an entry, assigning its sequence number and boot ID.
+    pub fn write(&self, mut entry: JournalEntry) -> Result<JournalEntry> {
         {
-            let mut entries = self.entries.lock().unwrap();
-            entries.push_back(entry.clone());
+            let mut state = self.state.lock().unwrap();
+            
+            entry.seqnum = state.next_seqnum;
+            entry.boot_id = self.boot_id.clone();
+            
+            let mut line = serde_json::to_vec(&entry)?;
+            line.push(b'\n');
+            
+            let record = IndexRecord::of(&entry, state.active_file.size);
+            state.active.write_all(&line)
+                .context("Failed to write journal entry")?;
+            
+            state.next_seqnum += 1;
+            state.active_file.size += line.len() as u64;
+            state.active_file.summary.add(&record);
+            state.active_records.push(record);
             
-            // Trim if too many entries
-            while entries.len() > self.max_entries {
-                entries.pop_front();
+            if state.active_file.size >= self.max_file_size {
+                self.rotate(&mut state)?;
             }
         }
         
-        // Write to file
-        self.write_to_file(&entry)?;
-        
-        Ok(())
+        // Nobody following is the common case
+        let _ = self.live.send(entry.clone());
+        Ok(entry)
     }
     
-    pub fn get_logs(&self, service: &str, limit: Option<usize>) -> Vec<JournalEntry> {
-        let entries = self.entries.lock().unwrap();
+    /// Archives the active file with its index and starts a new one.
+    fn rotate(&self, state: &mut JournalState) -> Result<()> {
+        let summary = &state.active_file.summary;
+        let archived_path = self.journal_dir.join(format!(
+            "system@{:016x}-{:016x}.{}", summary.first_seqnum, summary.last_seqnum, JOURNAL_EXTENSION
+        ));
         
-        let filtered: Vec<JournalEntry> = entries
-            .iter()
-            .filter(|entry| entry.service == service)
-            .cloned()
-            .collect();
+        fs::rename(&state.active_file.path, &archived_path)
+            .context("Failed to rotate journal file")?;
         
-        if let Some(limit) = limit {
-            filtered.into_iter().rev().take(limit).rev().collect()
-        } else {
-            filtered
+        let archived = JournalFile {
+            path: archived_path,
+            summary: summary.clone(),
+            size: state.active_file.size,
+        };
+        if let Err(e) = archived.write_index(&state.active_records) {
+            // The index is rebuilt from the file when it is next opened
+            warn!("Failed to write journal index {}: {}", archived.index_path().display(), e);
         }
+        
+        debug!("Rotated journal to {}", archived.path.display());
+        
+        let (active, active_file) = open_active(&self.journal_dir)?;
+        state.active = active;
+        state.active_file = active_file;
+        state.active_records.clear();
+        state.archived.push(archived);
+        
+        vacuum_locked(state, self.max_use);
+        Ok(())
     }
     
-    pub fn get_logs_follow(&self, service: &str) -> impl Iterator<Item = JournalEntry> {
-        // This would be implemented with file watching in a real system
-        // For now, return an empty iterator
-        std::iter::empty()
+    /// Deletes the oldest archived files until the journal takes at most
+    /// `max_use` bytes. Returns the number of bytes freed.
+    pub fn vacuum(&self, max_use: u64) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        vacuum_locked(&mut state, max_use)
     }
     
-    pub fn clear_logs(&self, service: Option<&str>) -> Result<()> {
-        if let Some(service) = service {
-            // Clear logs for specific service
-            let service_file = self.journal_dir.join(format!("{}.log", service));
-            if service_file.exists() {
-                fs::remove_file(&service_file)?;
-            }
+    pub fn disk_usage(&self) -> u64 {
+        let state = self.state.lock().unwrap();
+        state.active_file.size + state.archived.iter().map(|file| file.size).sum::<u64>()
+    }
+    
+    /// Resolves the boot, compiles the pattern and validates the fields
+    /// of a query.
+    pub fn compile(&self, query: &JournalQuery) -> Result<EntryFilter> {
+        let boot = query.boot.as_deref().map(|boot| self.resolve_boot(boot)).transpose()?;
+        let grep = query.grep.as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("Invalid grep pattern")?;
+        
+        Ok(EntryFilter {
+            unit: query.unit.clone(),
+            since: query.since.map(|t| t.timestamp_micros()),
+            until: query.until.map(|t| t.timestamp_micros()),
+            priority: query.priority,
+            boot,
+            grep,
+            fields: query.fields.clone(),
+        })
+    }
+    
+    pub fn query(&self, query: &JournalQuery) -> Result<Vec<JournalEntry>> {
+        let filter = self.compile(query)?;
+        
+        // Work out which files to read under the lock, read them outside
+        // of it so services are not kept from logging
+        let (archived, active) = {
+            let state = self.state.lock().unwrap();
             
-            // Remove from memory buffer
-            {
-                let mut entries = self.entries.lock().unwrap();
-                entries.retain(|entry| entry.service != service);
-            }
-        } else {
-            // Clear all logs
-            for entry in fs::read_dir(&self.journal_dir)? {
-                let entry = entry?;
-                if entry.path().extension().map_or(false, |ext| ext == "log") {
-                    fs::remove_file(entry.path())?;
-                }
-            }
+            let archived: Vec<PathBuf> = state.archived.iter()
+                .filter(|file| filter.may_match_file(&file.summary))
+                .map(|file| file.path.clone())
+                .collect();
+            
+            let offsets: Vec<u64> = state.active_records.iter()
+                .filter(|record| filter.matches_record(record))
+                .map(|record| record.offset)
+                .collect();
+            
+            // A handle stays valid if the file is rotated meanwhile
+            let active = File::open(&state.active_file.path).ok().map(|file| (file, offsets));
+            (archived, active)
+        };
+        
+        let mut entries = Vec::new();
+        
+        for path in archived {
+            let file = JournalFile { path, summary: FileSummary::default(), size: 0 };
+            let records = match file.load_records() {
+                Ok(records) => records,
+                Err(_) => match scan_file(&file.path) {
+                    Ok((records, _)) => records,
+                    Err(e) => {
+                        // Vacuumed since the file list was taken
+                        debug!("Skipping journal file {}: {}", file.path.display(), e);
+                        continue;
+                    }
+                },
+            };
+            let offsets: Vec<u64> = records.iter()
+                .filter(|record| filter.matches_record(record))
+                .map(|record| record.offset)
+                .collect();
             
-            // Clear memory buffer
-            {
-                let mut entries = self.entries.lock().unwrap();
-                entries.clear();
+            if let Ok(handle) = File::open(&file.path) {
+                read_entries(handle, &offsets, &filter, &mut entries)?;
             }
         }
         
-        Ok(())
-    }
-    
-    pub fn list_services(&self) -> Vec<String> {
-        let entries = self.entries.lock().unwrap();
-        let mut services = std::collections::HashSet::new();
+        if let Some((handle, offsets)) = active {
+            read_entries(handle, &offsets, &filter, &mut entries)?;
+        }
         
-        for entry in entries.iter() {
-            services.insert(entry.service.clone());
+        if let Some(lines) = query.lines {
+            let skip = entries.len().saturating_sub(lines);
+            entries.drain(..skip);
         }
         
-        services.into_iter().collect()
+        Ok(entries)
     }
     
-    fn write_to_file(&self, entry: &JournalEntry) -> Result<()> {
-        let service_file = self.journal_dir.join(format!("{}.log", entry.service));
-        
-        let log_line = format!(
-            "{} [{}] {}: {}\n",
-            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
-            entry.stream.to_uppercase(),
-            entry.level.to_string(),
-            entry.message
-        );
-        
-        // Append to file
-        use std::io::Write;
-        let mut file = std::fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&service_file)?;
-        
-        file.write_all(log_line.as_bytes())?;
-        
-        Ok(())
+    /// New entries as they are written, for following the journal.
+    pub fn subscribe(&self) -> broadcast::Receiver<JournalEntry> {
+        self.live.subscribe()
     }
     
-    fn determine_level(&self, message: &str) -> LogLevel {
-        let message_lower = message.to_lowercase();
+    pub fn get_logs(&self, service: &str, limit: Option<usize>) -> Vec<JournalEntry> {
+        let query = JournalQuery {
+            unit: Some(service.to_string()),
+            lines: limit,
+            ..Default::default()
+        };
         
-        if message_lower.contains("error") || message_lower.contains("fatal") {
-            LogLevel::Error
-        } else if message_lower.contains("warning") || message_lower.contains("warn") {
-            LogLevel::Warning
-        } else if message_lower.contains("debug") {
-            LogLevel::Debug
-        } else if message_lower.contains("critical") {
-            LogLevel::Critical
-        } else {
-            LogLevel::Info
-        }
+        self.query(&query).unwrap_or_else(|e| {
+            warn!("Failed to read journal: {}", e);
+            Vec::new()
+        })
     }
     
-    pub fn load_existing_logs(&self) -> Result<()> {
-        for entry in fs::read_dir(&self.journal_dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            
-            if path.extension().map_or(false, |ext| ext == "log") {
-                if let Some(service_name) = path.file_stem() {
-                    self.load_service_logs(service_name.to_string_lossy().as_ref())?;
-                }
+    /// Boots in the journal, oldest first.
+    pub fn list_boots(&self) -> Vec<BootInfo> {
+        let state = self.state.lock().unwrap();
+        let mut boots: BTreeMap<String, (i64, i64)> = BTreeMap::new();
+        
+        for file in state.archived.iter().chain(std::iter::once(&state.active_file)) {
+            for (boot_id, (first, last)) in &file.summary.boots {
+                let boot = boots.entry(boot_id.clone()).or_insert((*first, *last));
+                boot.0 = boot.0.min(*first);
+                boot.1 = boot.1.max(*last);
             }
         }
         
-        Ok(())
+        let mut boots: Vec<BootInfo> = boots.into_iter()
+            .map(|(boot_id, (first, last))| BootInfo {
+                boot_id,
+                first_entry: micros_to_time(first),
+                last_entry: micros_to_time(last),
+            })
+            .collect();
+        boots.sort_by_key(|boot| boot.first_entry);
+        boots
     }
     
-    fn load_service_logs(&self, service: &str) -> Result<()> {
-        let service_file = self.journal_dir.join(format!("{}.log", service));
+    /// Turns `0`/`-N` into the ID of the current/Nth previous boot.
+    /// Anything else is taken as a boot ID.
+    pub fn resolve_boot(&self, boot: &str) -> Result<String> {
+        let Ok(offset) = boot.parse::<i64>() else {
+            return Ok(boot.to_string());
+        };
         
-        if !service_file.exists() {
-            return Ok(());
+        if offset > 0 {
+            return Err(anyhow::anyhow!("Boot offset must be 0 or negative"));
         }
         
-        let content = fs::read_to_string(&service_file)?;
-        
-        for line in content.lines() {
-            if let Ok(entry) = self.parse_log_line(line, service) {
-                let mut entries = self.entries.lock().unwrap();
-                entries.push_back(entry);
-            }
+        // The current boot has no entries yet right after starting
+        let mut boots: Vec<String> = self.list_boots().into_iter().map(|b| b.boot_id).collect();
+        if boots.last() != Some(&self.boot_id) {
+            boots.retain(|b| *b != self.boot_id);
+            boots.push(self.boot_id.clone());
         }
         
-        Ok(())
+        boots.len().checked_sub(1 + offset.unsigned_abs() as usize)
+            .map(|i| boots[i].clone())
+            .ok_or_else(|| anyhow::anyhow!("No boot with offset {} in the journal", offset))
     }
     
-    fn parse_log_line(&self, line: &str, service: &str) -> Result<JournalEntry> {
-        // Parse log line format: "2024-01-01 12:00:00 [STDOUT] INFO: message"
-        let parts: Vec<&str> = line.splitn(4, ' ').collect();
+    pub fn clear_logs(&self, service: Option<&str>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        
+        let Some(service) = service else {
+            for file in state.archived.drain(..) {
+                remove_journal_file(&file);
+            }
+            fs::remove_file(&state.active_file.path)?;
+            
+            let (active, active_file) = open_active(&self.journal_dir)?;
+            state.active = active;
+            state.active_file = active_file;
+            state.active_records.clear();
+            return Ok(());
+        };
         
-        if parts.len() < 4 {
-            return Err(anyhow::anyhow!("Invalid log line format"));
+        // Entries are never modified in place, so dropping a unit means
+        // rewriting the files that have entries of it
+        for file in state.archived.iter_mut() {
+            if file.summary.units.contains(service) {
+                let (summary, size) = rewrite_without(&file.path, service)?;
+                file.summary = summary;
+                file.size = size;
+                let records = scan_file(&file.path)?.0;
+                file.write_index(&records)?;
+            }
         }
         
-        let timestamp_str = format!("{} {}", parts[0], parts[1]);
-        let timestamp = DateTime::parse_from_str(&timestamp_str, "%Y-%m-%d %H:%M:%S")?
-            .with_timezone(&Utc);
-        
-        let stream_part = parts[2].trim_matches('[').trim_matches(']');
-        let stream = stream_part.to_lowercase();
-        
-        let level_message = parts[3];
-        let colon_pos = level_message.find(':').unwrap_or(0);
-        let level_str = &level_message[..colon_pos];
-        let message = &level_message[colon_pos + 1..].trim();
-        
-        let level = match level_str.to_uppercase().as_str() {
-            "DEBUG" => LogLevel::Debug,
-            "INFO" => LogLevel::Info,
-            "WARNING" => LogLevel::Warning,
-            "ERROR" => LogLevel::Error,
-            "CRITICAL" => LogLevel::Critical,
-            _ => LogLevel::Info,
-        };
+        if state.active_file.summary.units.contains(service) {
+            rewrite_without(&state.active_file.path, service)?;
+            let (active, active_file) = open_active(&self.journal_dir)?;
+            state.active_records = scan_file(&active_file.path)?.0;
+            state.active = active;
+            state.active_file = active_file;
+        }
         
-        Ok(JournalEntry {
-            timestamp,
-            service: service.to_string(),
-            stream,
-            message: message.to_string(),
-            pid: None,
-            level,
-        })
+        Ok(())
+    }
+    
+    pub fn list_services(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let services: BTreeSet<String> = state.archived.iter()
+            .chain(std::iter::once(&state.active_file))
+            .flat_map(|file| file.summary.units.iter().cloned())
+            .collect();
+        
+        services.into_iter().collect()
     }
 }
 
@@ -255,8 +642,11 @@ impl Clone for JournalLogger {
     fn clone(&self) -> Self {
         Self {
             journal_dir: self.journal_dir.clone(),
-            max_entries: self.max_entries,
-            entries: Arc::clone(&self.entries),
+            boot_id: self.boot_id.clone(),
+            max_file_size: self.max_file_size,
+            max_use: self.max_use,
+            state: Arc::clone(&self.state),
+            live: self.live.clone(),
         }
     }
 }
@@ -269,6 +659,291 @@ impl std::fmt::Display for LogLevel {
             LogLevel::Warning => write!(f, "WARNING"),
             LogLevel::Error => write!(f, "ERROR"),
             LogLevel::Critical => write!(f, "CRITICAL"),
+            LogLevel::Notice => write!(f, "NOTICE"),
+            LogLevel::Alert => write!(f, "ALERT"),
+            LogLevel::Emergency => write!(f, "EMERGENCY"),
+        }
+    }
+}
+
+/// Splits the `<N>` priority prefix of sd-daemon(3) off a line of
+/// service output.
+pub fn split_priority_prefix(line: &str) -> (Option<LogLevel>, &str) {
+    match line.as_bytes() {
+        [b'<', priority @ b'0'..=b'7', b'>', ..] => (Some(LogLevel::from_priority(priority - b'0')), &line[3..]),
+        _ => (None, line),
+    }
+}
+
+/// ID of the running boot, without dashes.
+pub fn current_boot_id() -> String {
+    fs::read_to_string("/proc/sys/kernel/random/boot_id")
+        .map(|id| id.trim().replace('-', ""))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+fn micros_to_time(micros: i64) -> DateTime<Utc> {
+    Utc.timestamp_micros(micros).single().unwrap_or_default()
+}
+
+/// Opens the journal directory, indexing the active file and reading
+/// the summaries of archived ones.
+fn load_state(journal_dir: &Path) -> Result<JournalState> {
+    let mut archived = Vec::new();
+    
+    for entry in fs::read_dir(journal_dir)? {
+        let path = entry?.path();
+        let is_archive = path.extension().is_some_and(|ext| ext == JOURNAL_EXTENSION)
+            && path.file_name().is_some_and(|name| name != ACTIVE_FILE);
+        if !is_archive {
+            continue;
         }
+        
+        match load_archived(&path) {
+            Ok(file) => archived.push(file),
+            Err(e) => warn!("Skipping unreadable journal file {}: {}", path.display(), e),
+        }
+    }
+    
+    archived.sort_by_key(|file| file.summary.first_seqnum);
+    
+    let active_path = journal_dir.join(ACTIVE_FILE);
+    let (active_records, valid_size) = if active_path.exists() {
+        scan_file(&active_path)?
+    } else {
+        (Vec::new(), 0)
+    };
+    
+    // Drop a partially written last entry, e.g. after a crash
+    if active_path.exists() && fs::metadata(&active_path)?.len() > valid_size {
+        warn!("Truncating torn entry at the end of {}", active_path.display());
+        OpenOptions::new().write(true).open(&active_path)?.set_len(valid_size)?;
+    }
+    
+    let (active, mut active_file) = open_active(journal_dir)?;
+    for record in &active_records {
+        active_file.summary.add(record);
     }
-} 
\ No newline at end of file
+    
+    let next_seqnum = archived.iter()
+        .map(|file| &file.summary)
+        .chain(std::iter::once(&active_file.summary))
+        .filter(|summary| summary.entries > 0)
+        .map(|summary| summary.last_seqnum + 1)
+        .max()
+        .unwrap_or(1);
+    
+    info!("Opened journal with {} archived files", archived.len());
+    
+    Ok(JournalState {
+        active,
+        active_file,
+        active_records,
+        archived,
+        next_seqnum,
+    })
+}
+
+fn open_active(journal_dir: &Path) -> Result<(File, JournalFile)> {
+    let path = journal_dir.join(ACTIVE_FILE);
+    let active = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .context("Failed to open journal file")?;
+    let size = active.metadata()?.len();
+    
+    Ok((active, JournalFile { path, summary: FileSummary::default(), size }))
+}
+
+fn load_archived(path: &Path) -> Result<JournalFile> {
+    let size = fs::metadata(path)?.len();
+    let mut file = JournalFile { path: path.to_path_buf(), summary: FileSummary::default(), size };
+    
+    let summary = File::open(file.index_path())
+        .ok()
+        .and_then(|index| BufReader::new(index).lines().next())
+        .and_then(|line| line.ok())
+        .and_then(|line| serde_json::from_str::<FileSummary>(&line).ok());
+    
+    match summary {
+        Some(summary) => file.summary = summary,
+        None => {
+            // Missing or damaged index, rebuild it
+            let (records, _) = scan_file(path)?;
+            for record in &records {
+                file.summary.add(record);
+            }
+            file.write_index(&records)?;
+        }
+    }
+    
+    Ok(file)
+}
+
+/// Indexes a journal file by parsing every entry. Also returns the size
+/// up to the last complete entry.
+fn scan_file(path: &Path) -> Result<(Vec<IndexRecord>, u64)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+    let mut offset = 0u64;
+    let mut line = String::new();
+    
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            break;
+        }
+        
+        match serde_json::from_str::<JournalEntry>(&line) {
+            Ok(entry) => records.push(IndexRecord::of(&entry, offset)),
+            Err(e) => debug!("Skipping corrupt entry at {}:{}: {}", path.display(), offset, e),
+        }
+        offset += read as u64;
+    }
+    
+    Ok((records, offset))
+}
+
+fn read_entries(file: File, offsets: &[u64], filter: &EntryFilter, entries: &mut Vec<JournalEntry>) -> Result<()> {
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    
+    for offset in offsets {
+        reader.seek(SeekFrom::Start(*offset))?;
+        line.clear();
+        reader.read_line(&mut line)?;
+        
+        if let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) {
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+    }
+    
+    Ok(())
+}
+
+fn rewrite_without(path: &Path, service: &str) -> Result<(FileSummary, u64)> {
+    let reader = BufReader::new(File::open(path)?);
+    let temp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+    let mut summary = FileSummary::default();
+    let mut size = 0u64;
+    
+    for line in reader.lines() {
+        let line = line?;
+        let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) else {
+            continue;
+        };
+        
+        if entry.service != service {
+            summary.add(&IndexRecord::of(&entry, size));
+            writer.write_all(line.as_bytes())?;
+            writer.write_all(b"\n")?;
+            size += line.len() as u64 + 1;
+        }
+    }
+    
+    writer.flush()?;
+    fs::rename(&temp_path, path)?;
+    Ok((summary, size))
+}
+
+fn vacuum_locked(state: &mut JournalState, max_use: u64) -> u64 {
+    let mut usage = state.active_file.size + state.archived.iter().map(|file| file.size).sum::<u64>();
+    let mut freed = 0;
+    
+    while usage > max_use && !state.archived.is_empty() {
+        let file = state.archived.remove(0);
+        info!("Vacuuming journal file {}", file.path.display());
+        remove_journal_file(&file);
+        usage -= file.size;
+        freed += file.size;
+    }
+    
+    freed
+}
+
+fn remove_journal_file(file: &JournalFile) {
+    for path in [file.path.clone(), file.index_path()] {
+        if let Err(e) = fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Parses `--since`/`--until` values: `now`, `today`, `yesterday`,
+/// relative times like `-1h` or `30min ago`, and local dates and times
+/// like `2024-01-31`, `2024-01-31 08:00[:00]` or `08:00`.
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    let value = value.trim();
+    let now = Local::now();
+    let midnight = |date: NaiveDate| local_to_utc(date.and_time(NaiveTime::MIN));
+    
+    match value {
+        "now" => return Ok(now.with_timezone(&Utc)),
+        "today" => return midnight(now.date_naive()),
+        "yesterday" => return midnight(now.date_naive() - Duration::days(1)),
+        "tomorrow" => return midnight(now.date_naive() + Duration::days(1)),
+        _ => {}
+    }
+    
+    let relative = value.strip_prefix('-')
+        .or_else(|| value.strip_suffix("ago").map(str::trim));
+    if let Some(relative) = relative {
+        return Ok(now.with_timezone(&Utc) - parse_span(relative)?);
+    }
+    
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(value, format) {
+            return local_to_utc(time);
+        }
+    }
+    
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return midnight(date);
+    }
+    
+    for format in ["%H:%M:%S", "%H:%M"] {
+        if let Ok(time) = NaiveTime::parse_from_str(value, format) {
+            return local_to_utc(now.date_naive().and_time(time));
+        }
+    }
+    
+    Err(anyhow::anyhow!("Invalid time '{}'", value))
+}
+
+fn local_to_utc(time: NaiveDateTime) -> Result<DateTime<Utc>> {
+    Local.from_local_datetime(&time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| anyhow::anyhow!("Time {} does not exist locally", time))
+}
+
+/// Parses a span like `90s`, `5min`, `2 h` or `1d`.
+fn parse_span(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: i64 = number.parse()
+        .map_err(|_| anyhow::anyhow!("Invalid time span '{}'", value))?;
+    
+    let span = match unit.trim() {
+        "" | "s" | "sec" | "second" | "seconds" => Duration::seconds(number),
+        "m" | "min" | "minute" | "minutes" => Duration::minutes(number),
+        "h" | "hour" | "hours" => Duration::hours(number),
+        "d" | "day" | "days" => Duration::days(number),
+        "w" | "week" | "weeks" => Duration::weeks(number),
+        other => return Err(anyhow::anyhow!("Invalid time unit '{}'", other)),
+    };
+    
+    Ok(span)
+}
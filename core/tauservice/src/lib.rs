@@ -0,0 +1,28 @@
+pub mod unit;
+pub mod service_manager;
+pub mod process;
+pub mod journal;
+pub mod boot;
+pub mod state;
+pub mod sandbox;
+pub mod taupkg_hooks;
+pub mod tui;
+pub mod control;
+pub mod supervisor;
+pub mod notify;
+pub mod socket_activation;
+pub mod calendar;
+pub mod timer;
+pub mod cgroup;
+pub mod credentials;
+pub mod seccomp;
+pub mod transaction;
+pub mod path_activation;
+pub mod analyze;
+pub mod watcher;
+pub mod verify;
+pub mod exec;
+pub mod scope;
+pub mod dbus_api;
+pub mod condition;
+pub mod coredump;
@@ -1,25 +1,25 @@
-mod unit;
-mod service_manager;
-mod process;
-mod journal;
-mod boot;
-mod state;
-mod sandbox;
-mod taupkg_hooks;
-mod tui;
-
 use clap::{Parser, Subcommand};
+use tau_service::{analyze, boot, cgroup, condition, control, coredump, dbus_api, journal, path_activation, sandbox, scope, service_manager, socket_activation, state, supervisor, taupkg_hooks, timer, tui, unit, verify, watcher};
 use service_manager::{ServiceManager, ServiceState};
-use journal::JournalLogger;
 use boot::BootManager;
 use state::StateManager;
-use sandbox::{SandboxManager, SecurityAuditor};
+use sandbox::SandboxManager;
 use taupkg_hooks::TauPkgHooks;
 use tui::TauServiceTUI;
+use control::{ControlClient, ControlRequest, ControlServer, control_socket_path};
+use supervisor::{ExitOutcome, Supervisor};
+use timer::TimerScheduler;
+use watcher::UnitWatcher;
+use path_activation::PathWatcher;
+use journal::{JournalEntry, JournalQuery, LogLevel};
+use unit::UnitLoader;
+use verify::{Severity, Verifier};
+use scope::{ManagerScope, LINGER_DIR};
+use dbus_api::ServiceDbusApi;
+use coredump::{CoredumpInfo, CoredumpStore, Crash};
 use anyhow::Result;
+use tokio::signal::unix::{signal, SignalKind};
 use log::{info, warn, error};
-use std::sync::Arc;
-use tokio::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "tau-service")]
@@ -33,6 +33,9 @@ struct Cli {
     /// Quiet mode
     #[arg(short, long, default_value_t = false)]
     quiet: bool,
+    /// Talk to, or run as, the service manager of the calling user
+    #[arg(long, global = true, default_value_t = false)]
+    user: bool,
 }
 
 #[derive(Subcommand)]
@@ -71,6 +74,24 @@ enum Commands {
     Status { 
         service: Option<String>,
     },
+    /// Show a unit merged with its drop-ins and where each setting comes from
+    Cat {
+        unit: String,
+    },
+    /// Check unit files for mistakes without loading them
+    Verify {
+        #[arg(required = true)]
+        files: Vec<std::path::PathBuf>,
+        /// Also look for executables and units below this directory
+        #[arg(long)]
+        root: Option<std::path::PathBuf>,
+        /// Fail on warnings too
+        #[arg(long)]
+        strict: bool,
+        /// Output format
+        #[arg(short, long, default_value = "short", value_parser = ["short", "json"])]
+        output: String,
+    },
     /// List services
     List {
         /// Show only running services
@@ -85,22 +106,70 @@ enum Commands {
     },
     /// Show service logs
     Logs { 
-        service: String,
+        /// Only entries of this unit
+        service: Option<String>,
         /// Follow log output
         #[arg(short, long)]
         follow: bool,
         /// Number of lines to show
         #[arg(short, long, default_value = "50")]
         lines: usize,
+        /// Show entries not older than this, e.g. "today" or "-1h"
+        #[arg(long)]
+        since: Option<String>,
+        /// Show entries not newer than this
+        #[arg(long)]
+        until: Option<String>,
+        /// Show entries of this priority or more important, e.g. "err" or "3"
+        #[arg(short, long)]
+        priority: Option<String>,
+        /// Show entries of a boot: 0 for the current, -1 for the previous, or a boot ID
+        #[arg(short, long, num_args = 0..=1, default_missing_value = "0", allow_hyphen_values = true)]
+        boot: Option<String>,
+        /// Show entries whose message matches this regular expression
+        #[arg(short, long)]
+        grep: Option<String>,
+        /// Show entries with this field value, as KEY=VALUE
+        #[arg(long = "field")]
+        fields: Vec<String>,
+        /// Output format
+        #[arg(short, long, default_value = "short", value_parser = ["short", "json", "cat"])]
+        output: String,
+    },
+    /// Remove the oldest archived journal files
+    VacuumLogs {
+        /// Keep the journal below this size, e.g. "64M"
+        #[arg(long)]
+        size: String,
     },
     /// Clear service logs
     ClearLogs { 
         service: Option<String>,
     },
+    /// Reset the failed state and start rate limit of services
+    ResetFailed {
+        service: Option<String>,
+    },
+    /// List socket units
+    ListSockets,
+    /// List timer units with their next and last elapse
+    ListTimers,
     /// Reload all unit files
     DaemonReload,
+    /// Replace the daemon with a fresh exec of its binary, keeping services running
+    DaemonReexec,
     /// Start the service manager daemon
     Daemon,
+    /// Keep a user's service manager running without a session, starting it at boot
+    EnableLinger {
+        /// Defaults to the calling user
+        user: Option<String>,
+    },
+    /// Stop keeping a user's service manager running without a session
+    DisableLinger {
+        /// Defaults to the calling user
+        user: Option<String>,
+    },
     /// Open TUI interface
     Tui,
     /// Boot integration commands
@@ -108,6 +177,16 @@ enum Commands {
         #[command(subcommand)]
         action: BootCommands,
     },
+    /// Analyze boot performance and unit dependencies
+    Analyze {
+        #[command(subcommand)]
+        action: AnalyzeCommands,
+    },
+    /// Inspect core dumps of crashed processes
+    Coredump {
+        #[command(subcommand)]
+        action: CoredumpCommands,
+    },
     /// State management commands
     State {
         #[command(subcommand)]
@@ -125,6 +204,47 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum AnalyzeCommands {
+    /// List units by the time they took to start, slowest first
+    Blame,
+    /// Show the chain of units a unit waited for when starting
+    CriticalChain { unit: String },
+    /// Write an SVG timeline of unit startup to stdout
+    Plot,
+    /// Write the dependency graph in Graphviz format to stdout
+    Dot {
+        /// Only show dependencies of these units
+        units: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CoredumpCommands {
+    /// List stored core dumps, oldest first
+    List {
+        /// Only dumps of this PID, unit, command or executable
+        query: Option<String>,
+    },
+    /// Show the details and backtrace of the latest matching dump
+    Info { query: Option<String> },
+    /// Open the latest matching dump in gdb
+    Gdb { query: Option<String> },
+    /// Store a core piped in by the kernel
+    #[command(hide = true)]
+    Handle {
+        pid: u32,
+        uid: u32,
+        gid: u32,
+        signal: i32,
+        timestamp: i64,
+        core_limit: u64,
+        hostname: String,
+        #[arg(required = true, num_args = 1.., allow_hyphen_values = true)]
+        comm: Vec<String>,
+    },
+}
+
 #[derive(Subcommand)]
 enum BootCommands {
     /// Setup boot integration
@@ -185,6 +305,11 @@ async fn main() -> Result<()> {
     
     let cli = Cli::parse();
     
+    // Everything below picks its paths from the scope
+    if cli.user {
+        ManagerScope::current_user()?.init();
+    }
+    
     // Set log level based on verbosity
     if cli.verbose {
         std::env::set_var("RUST_LOG", "debug");
@@ -194,176 +319,322 @@ async fn main() -> Result<()> {
     
     match &cli.command {
         Commands::Start { service, wait } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Starting service: {}", service);
-            manager.start_service(service)?;
+            client.call(ControlRequest::Start { service: service.clone(), wait: *wait }).await?;
             
             if *wait {
-                // Wait for service to be active
-                for _ in 0..30 {
-                    if let Some(status) = manager.get_service_status(service) {
-                        if status.state == ServiceState::Active {
-                            info!("Service {} is now active", service);
-                            break;
-                        }
-                    }
-                    tokio::time::sleep(Duration::from_millis(100)).await;
-                }
+                info!("Service {} is now active", service);
             }
         },
         
         Commands::Stop { service, force } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Stopping service: {}", service);
             if *force {
                 warn!("Force stopping service: {}", service);
-                // In a real implementation, you'd send SIGKILL
             }
             
-            manager.stop_service(service)?;
+            client.call(ControlRequest::Stop { service: service.clone(), force: *force }).await?;
         },
         
         Commands::Restart { service } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Restarting service: {}", service);
-            manager.restart_service(service).await?;
+            client.call(ControlRequest::Restart { service: service.clone() }).await?;
         },
         
         Commands::Reload { service } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Reloading service: {}", service);
-            manager.reload_service(service)?;
+            client.call(ControlRequest::Reload { service: service.clone() }).await?;
         },
         
         Commands::Enable { service } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Enabling service: {}", service);
-            manager.enable_service(service)?;
+            client.call(ControlRequest::Enable { service: service.clone() }).await?;
         },
         
         Commands::Disable { service } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+            let mut client = ControlClient::connect_default().await?;
             
             info!("Disabling service: {}", service);
-            manager.disable_service(service)?;
+            client.call(ControlRequest::Disable { service: service.clone() }).await?;
         },
         
-        Commands::Status { service } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+        Commands::Cat { unit } => {
+            let source = UnitLoader::new().source(unit)?;
+            print!("{}", source.render());
+        },
+        
+        Commands::Verify { files, root, strict, output } => {
+            let mut verifier = Verifier::new(UnitLoader::new());
+            if let Some(root) = root {
+                verifier = verifier.with_root(root.clone());
+            }
             
-            if let Some(service_name) = service {
-                if let Some(status) = manager.get_service_status(service_name) {
-                    print_service_status(&status);
-                } else {
-                    println!("Service '{}' not found", service_name);
-                }
+            let diagnostics = verifier.verify_files(files);
+            if output == "json" {
+                println!("{}", serde_json::to_string_pretty(&diagnostics)?);
             } else {
-                // Show status of all services
-                let services = manager.list_services(None);
-                for status in services {
-                    print_service_status(&status);
+                for diagnostic in &diagnostics {
+                    println!("{}", diagnostic);
                 }
             }
+            
+            let errors = diagnostics.iter().filter(|d| d.severity == Severity::Error).count();
+            let warnings = diagnostics.len() - errors;
+            if errors > 0 || (*strict && warnings > 0) {
+                return Err(anyhow::anyhow!("{} errors and {} warnings in {} files", errors, warnings, files.len()));
+            }
         },
         
-        Commands::List { running, enabled, failed } => {
-            let manager = ServiceManager::new()?;
-            manager.load_units()?;
+        Commands::Status { service } => {
+            let mut client = ControlClient::connect_default().await?;
             
-            let filter = if *running {
-                Some(ServiceState::Active)
-            } else if *failed {
-                Some(ServiceState::Failed)
-            } else {
-                None
-            };
+            let services = client.services(ControlRequest::Status { service: service.clone() }).await?;
+            for summary in services {
+                print_service_status(&summary.status);
+            }
+        },
+        
+        Commands::List { running, enabled, failed } => {
+            let mut client = ControlClient::connect_default().await?;
             
-            let services = manager.list_services(filter);
+            let services = client.services(ControlRequest::List {
+                running: *running,
+                enabled: *enabled,
+                failed: *failed,
+            }).await?;
             
-            if *enabled {
-                // Filter enabled services
-                let enabled_services: Vec<_> = services
-                    .into_iter()
-                    .filter(|s| {
-                        if let Some(unit) = manager.get_unit(&s.name).ok() {
-                            unit.is_enabled()
-                        } else {
-                            false
-                        }
-                    })
-                    .collect();
-                
-                print_service_list(&enabled_services);
-            } else {
-                print_service_list(&services);
-            }
+            let statuses: Vec<_> = services.into_iter().map(|s| s.status).collect();
+            print_service_list(&statuses);
         },
         
-        Commands::Logs { service, follow, lines } => {
-            let journal_logger = JournalLogger::new()?;
-            journal_logger.load_existing_logs()?;
+        Commands::Logs { service, follow, lines, since, until, priority, boot, grep, fields, output } => {
+            let mut client = ControlClient::connect_default().await?;
             
-            let logs = journal_logger.get_logs(service, Some(*lines));
+            let mut query = JournalQuery {
+                unit: service.clone(),
+                since: since.as_deref().map(journal::parse_time).transpose()?,
+                until: until.as_deref().map(journal::parse_time).transpose()?,
+                priority: priority.as_deref().map(LogLevel::parse).transpose()?.map(|level| level.priority()),
+                boot: boot.clone(),
+                grep: grep.clone(),
+                lines: Some(*lines),
+                ..Default::default()
+            };
             
-            for entry in logs {
-                println!("{} [{}] {}: {}", 
-                    entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
-                    entry.stream.to_uppercase(),
-                    entry.level,
-                    entry.message);
+            for field in fields {
+                let (key, value) = field.split_once('=')
+                    .ok_or_else(|| anyhow::anyhow!("Invalid field match '{}', expected KEY=VALUE", field))?;
+                query.fields.insert(key.to_string(), value.to_string());
             }
             
             if *follow {
-                println!("Following logs for {} (press Ctrl+C to stop)...", service);
-                // In a real implementation, you'd implement file watching here
-                loop {
-                    tokio::time::sleep(Duration::from_secs(1)).await;
+                for entry in client.follow_logs(query).await? {
+                    print_journal_entry(&entry, output)?;
+                }
+                while let Some(entry) = client.next_log_entry().await? {
+                    print_journal_entry(&entry, output)?;
+                }
+            } else {
+                for entry in client.logs(query).await? {
+                    print_journal_entry(&entry, output)?;
                 }
             }
         },
         
+        Commands::VacuumLogs { size } => {
+            let mut client = ControlClient::connect_default().await?;
+            let max_size = cgroup::parse_bytes(size)?;
+            client.call(ControlRequest::VacuumLogs { max_size }).await?;
+        },
+        
         Commands::ClearLogs { service } => {
-            let journal_logger = JournalLogger::new()?;
+            let mut client = ControlClient::connect_default().await?;
             
             if let Some(service_name) = service {
                 info!("Clearing logs for service: {}", service_name);
-                journal_logger.clear_logs(Some(service_name))?;
             } else {
                 info!("Clearing all service logs");
-                journal_logger.clear_logs(None)?;
             }
+            
+            client.call(ControlRequest::ClearLogs { service: service.clone() }).await?;
+        },
+        
+        Commands::ResetFailed { service } => {
+            let mut client = ControlClient::connect_default().await?;
+            client.call(ControlRequest::ResetFailed { service: service.clone() }).await?;
+        },
+        
+        Commands::ListSockets => {
+            let mut client = ControlClient::connect_default().await?;
+            print_socket_list(&client.sockets().await?);
+        },
+        
+        Commands::ListTimers => {
+            let mut client = ControlClient::connect_default().await?;
+            print_timer_list(&client.timers().await?);
         },
         
         Commands::DaemonReload => {
-            let manager = ServiceManager::new()?;
+            let mut client = ControlClient::connect_default().await?;
             info!("Reloading service units");
-            manager.load_units()?;
+            client.call(ControlRequest::DaemonReload).await?;
             info!("Service units reloaded successfully");
         },
         
+        Commands::DaemonReexec => {
+            let mut client = ControlClient::connect_default().await?;
+            info!("Re-executing the daemon");
+            client.call(ControlRequest::DaemonReexec).await?;
+        },
+        
         Commands::Daemon => {
             info!("Starting TauService daemon");
             run_daemon().await?;
         },
         
+        Commands::EnableLinger { user } => {
+            let user = linger_user(user.as_deref())?;
+            scope::set_linger(std::path::Path::new(LINGER_DIR), &user.name, true)?;
+            
+            // Start the manager right away, as boot would
+            let unit = scope::user_manager_unit(user.uid.as_raw());
+            match ControlClient::connect(&ManagerScope::System.control_socket()).await {
+                Ok(mut client) => {
+                    client.call(ControlRequest::Start { service: unit, wait: false }).await?;
+                }
+                Err(e) => info!("Not starting {}: {}", unit, e),
+            }
+            println!("✅ Lingering enabled for {}", user.name);
+        },
+        
+        Commands::DisableLinger { user } => {
+            let user = linger_user(user.as_deref())?;
+            scope::set_linger(std::path::Path::new(LINGER_DIR), &user.name, false)?;
+            println!("✅ Lingering disabled for {}, its manager stops at the end of its last session", user.name);
+        },
+        
         Commands::Tui => {
             info!("Starting TauService TUI");
-            let mut tui = TauServiceTUI::new()?;
+            let mut tui = TauServiceTUI::new().await?;
             tui.run().await?;
         },
         
+        Commands::Analyze { action } => {
+            let mut client = ControlClient::connect_default().await?;
+            let times = client.boot_times().await?;
+            
+            match action {
+                AnalyzeCommands::Blame => {
+                    for (unit, took) in times.blame() {
+                        println!("{:>12} {}", analyze::format_span(took), unit.name);
+                    }
+                },
+                AnalyzeCommands::CriticalChain { unit } => {
+                    println!("The time when unit became active is printed after the \"@\" character.");
+                    println!("The time the unit took to start is printed after the \"+\" character.");
+                    println!();
+                    
+                    for (depth, link) in times.critical_chain(unit)?.iter().enumerate() {
+                        let prefix = if depth == 0 { String::new() } else { format!("{}└─", "  ".repeat(depth - 1)) };
+                        let active_at = link.active_at.map(|at| format!(" @{}", analyze::format_span(at))).unwrap_or_default();
+                        let took = link.took.map(|took| format!(" +{}", analyze::format_span(took))).unwrap_or_default();
+                        println!("{}{}{}{}", prefix, link.name, active_at, took);
+                    }
+                },
+                AnalyzeCommands::Plot => {
+                    print!("{}", times.plot());
+                },
+                AnalyzeCommands::Dot { units } => {
+                    print!("{}", times.dot(units));
+                    eprintln!("Color legend: black = Requires/BindsTo, grey = Wants, red = Conflicts, green = After/Before");
+                },
+            }
+        },
+        
+        Commands::Coredump { action } => {
+            let store = CoredumpStore::new();
+            
+            match action {
+                CoredumpCommands::List { query } => {
+                    let dumps: Vec<CoredumpInfo> = store.list()?
+                        .into_iter()
+                        .filter(|info| query.as_deref().is_none_or(|query| info.matches(query)))
+                        .collect();
+                    if dumps.is_empty() {
+                        println!("No core dumps found");
+                        return Ok(());
+                    }
+                    
+                    println!("{:<19}  {:>7}  {:>5}  {:<7}  {:<9}  EXE", "TIME", "PID", "UID", "SIG", "COREFILE");
+                    for info in dumps {
+                        let core_file = match &info.core_file {
+                            Some(path) if !path.exists() => "missing",
+                            Some(_) if info.truncated => "truncated",
+                            Some(_) => "present",
+                            None => "none",
+                        };
+                        println!("{:<19}  {:>7}  {:>5}  {:<7}  {:<9}  {}",
+                            info.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                            info.pid,
+                            info.uid,
+                            info.signal_name(),
+                            core_file,
+                            info.exe.as_deref().unwrap_or(&info.comm));
+                    }
+                },
+                CoredumpCommands::Info { query } => {
+                    print_coredump(&store.find(query.as_deref())?);
+                },
+                CoredumpCommands::Gdb { query } => {
+                    let info = store.find(query.as_deref())?;
+                    let exe = info.exe.as_deref()
+                        .ok_or_else(|| anyhow::anyhow!("The executable of {} is unknown", info.id))?;
+                    
+                    let core = tempfile::NamedTempFile::new()?;
+                    store.extract(&info, core.path())?;
+                    std::process::Command::new("gdb")
+                        .arg(exe)
+                        .arg(core.path())
+                        .status()
+                        .map_err(|e| anyhow::anyhow!("Failed to run gdb: {}", e))?;
+                },
+                CoredumpCommands::Handle { pid, uid, gid, signal, timestamp, core_limit, hostname, comm } => {
+                    let crash = Crash {
+                        pid: *pid,
+                        uid: *uid,
+                        gid: *gid,
+                        signal: *signal,
+                        timestamp: *timestamp,
+                        core_limit: *core_limit,
+                        hostname: hostname.clone(),
+                        comm: comm.join(" "),
+                    };
+                    let info = coredump::handle(&crash)?;
+                    
+                    // The daemon owns the journal, it links the dump
+                    // into the entries of the unit
+                    match ControlClient::connect_default().await {
+                        Ok(mut client) => {
+                            if let Err(e) = client.call(ControlRequest::Coredump { id: info.id.clone() }).await {
+                                warn!("Daemon failed to log core dump {}: {}", info.id, e);
+                            }
+                        }
+                        Err(e) => info!("Not logging core dump {}: {}", info.id, e),
+                    }
+                },
+            }
+        },
+        
         Commands::Boot { action } => {
             let boot_manager = BootManager::new();
             
@@ -373,9 +644,8 @@ async fn main() -> Result<()> {
                     println!("✅ Boot integration setup complete");
                 },
                 BootCommands::Start => {
-                    let manager = ServiceManager::new()?;
-                    manager.load_units()?;
-                    boot_manager.start_boot_services(&manager)?;
+                    let mut client = ControlClient::connect_default().await?;
+                    client.call(ControlRequest::BootStart).await?;
                     println!("✅ Boot services started");
                 },
                 BootCommands::List => {
@@ -431,7 +701,6 @@ async fn main() -> Result<()> {
         },
         
         Commands::Security { action } => {
-            let security_auditor = SecurityAuditor::new();
             let sandbox_manager = SandboxManager::new();
             
             match action {
@@ -461,10 +730,12 @@ async fn main() -> Result<()> {
             match action {
                 TauPkgCommands::InstallHooks { package, path } => {
                     hooks.install_package_hooks(package, std::path::Path::new(path))?;
+                    notify_daemon_reload().await;
                     println!("✅ TauPkg hooks installed for package: {}", package);
                 },
                 TauPkgCommands::RemoveHooks { package } => {
                     hooks.remove_package_hooks(package)?;
+                    notify_daemon_reload().await;
                     println!("✅ TauPkg hooks removed for package: {}", package);
                 },
                 TauPkgCommands::ListServices { package } => {
@@ -489,10 +760,35 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn print_coredump(info: &CoredumpInfo) {
+    println!("           ID: {}", info.id);
+    println!("          PID: {}", info.pid);
+    println!("      UID/GID: {}/{}", info.uid, info.gid);
+    println!("       Signal: {} ({})", info.signal, info.signal_name());
+    println!("    Timestamp: {}", info.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S %Z"));
+    println!(" Command Line: {}", info.cmdline);
+    println!("   Executable: {}", info.exe.as_deref().unwrap_or("n/a"));
+    println!("         Unit: {}", info.unit.as_deref().unwrap_or("n/a"));
+    println!("      Boot ID: {}", info.boot_id);
+    println!("     Hostname: {}", info.hostname);
+    match &info.core_file {
+        Some(path) => println!("      Storage: {} ({} bytes{})", path.display(), info.size,
+            if info.truncated { ", truncated" } else { "" }),
+        None => println!("      Storage: none"),
+    }
+    
+    if let Some(backtrace) = &info.backtrace {
+        println!();
+        println!("{}", backtrace);
+    }
+}
+
 fn print_service_status(status: &service_manager::ServiceStatus) {
     let state_str = match status.state {
         ServiceState::Inactive => "inactive",
         ServiceState::Activating => "activating",
+        // Type=oneshot with RemainAfterExit=
+        ServiceState::Active if status.pid.is_none() => "active (exited)",
         ServiceState::Active => "active",
         ServiceState::Deactivating => "deactivating",
         ServiceState::Failed => "failed",
@@ -503,8 +799,16 @@ fn print_service_status(status: &service_manager::ServiceStatus) {
     
     println!("● {} - {} {}", status.name, state_str, pid_str);
     
+    if status.needs_restart {
+        println!("   Warning: The unit file changed on disk. Run 'tau-service restart {}' to apply it.", status.name);
+    }
+    
+    if let Some(text) = &status.status_text {
+        println!("   Status: \"{}\"", text);
+    }
+    
     if let Some(start_time) = status.start_time {
-        let duration = start_time.elapsed();
+        let duration = start_time.elapsed().unwrap_or_default();
         println!("   Started: {} ago", format_duration(duration));
     }
     
@@ -512,6 +816,39 @@ fn print_service_status(status: &service_manager::ServiceStatus) {
         println!("   Restarts: {}", status.restart_count);
     }
     
+    if let Some(resources) = &status.resources {
+        if let Some(tasks) = resources.tasks_current {
+            println!("   Tasks: {}", tasks);
+        }
+        if let Some(memory) = resources.memory_current {
+            match resources.memory_peak {
+                Some(peak) => println!("   Memory: {} (peak: {})", format_bytes(memory), format_bytes(peak)),
+                None => println!("   Memory: {}", format_bytes(memory)),
+            }
+        }
+        if let Some(usage) = resources.cpu_usage_usec {
+            println!("   CPU: {}", format_cpu_time(usage));
+        }
+    }
+    
+    let last_exit = match (status.exit_code, status.exit_signal) {
+        (_, Some(signal)) => Some(ExitOutcome::Signaled(signal)),
+        (Some(code), None) => Some(ExitOutcome::Exited(code)),
+        (None, None) => None,
+    };
+    if let Some(outcome) = last_exit {
+        println!("   Last exit: {}", outcome);
+    }
+    
+    if let (ServiceState::Failed, Some(phase)) = (&status.state, status.failed_phase) {
+        println!("   Failed in: {}", phase);
+    }
+    
+    // A failed assertion shows up as the error below
+    if let (ServiceState::Inactive, Some(condition)) = (&status.state, &status.unmet_condition) {
+        println!("   Condition: start skipped, {} was not met", condition);
+    }
+    
     if let Some(error) = &status.load_error {
         println!("   Error: {}", error);
     }
@@ -542,6 +879,83 @@ fn print_service_list(services: &[service_manager::ServiceStatus]) {
     }
 }
 
+fn print_socket_list(sockets: &[socket_activation::SocketSummary]) {
+    if sockets.is_empty() {
+        println!("No sockets found");
+        return;
+    }
+    
+    println!("{:<30} {:<20} {:<10} {:<30}", "LISTEN", "SOCKET", "STATE", "ACTIVATES");
+    println!("{:-<90}", "");
+    
+    for socket in sockets {
+        let state_str = if socket.listening { "listening" } else { "inactive" };
+        let activates = if socket.accept {
+            format!("{}@*", socket.service)
+        } else {
+            socket.service.clone()
+        };
+        
+        for address in &socket.listen {
+            println!("{:<30} {:<20} {:<10} {:<30}", address, socket.name, state_str, activates);
+        }
+    }
+}
+
+fn print_timer_list(timers: &[timer::TimerSummary]) {
+    if timers.is_empty() {
+        println!("No timers found");
+        return;
+    }
+    
+    println!("{:<20} {:<10} {:<20} {:<10} {:<20} {:<20}", "NEXT", "LEFT", "LAST", "PASSED", "TIMER", "ACTIVATES");
+    println!("{:-<104}", "");
+    
+    let now = std::time::SystemTime::now();
+    let format_time = |time: Option<std::time::SystemTime>| {
+        time.map_or("n/a".to_string(), |t| {
+            chrono::DateTime::<chrono::Local>::from(t).format("%Y-%m-%d %H:%M:%S").to_string()
+        })
+    };
+    
+    for timer in timers {
+        let left = timer.next_elapse
+            .map_or("n/a".to_string(), |t| format_duration(t.duration_since(now).unwrap_or_default()));
+        let passed = timer.last_trigger
+            .map_or("n/a".to_string(), |t| format!("{} ago", format_duration(now.duration_since(t).unwrap_or_default())));
+        
+        println!("{:<20} {:<10} {:<20} {:<10} {:<20} {:<20}",
+            format_time(timer.next_elapse), left,
+            format_time(timer.last_trigger), passed,
+            timer.name, timer.service);
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[0])
+    } else {
+        format!("{:.1}{}", value, UNITS[unit])
+    }
+}
+
+fn format_cpu_time(usec: u64) -> String {
+    if usec < 1_000_000 {
+        format!("{}ms", usec / 1000)
+    } else {
+        format!("{:.3}s", usec as f64 / 1_000_000.0)
+    }
+}
+
 fn format_duration(duration: std::time::Duration) -> String {
     let secs = duration.as_secs();
     if secs < 60 {
@@ -555,24 +969,166 @@ fn format_duration(duration: std::time::Duration) -> String {
     }
 }
 
+fn print_journal_entry(entry: &JournalEntry, output: &str) -> Result<()> {
+    match output {
+        "json" => println!("{}", serde_json::to_string(entry)?),
+        "cat" => println!("{}", entry.message),
+        _ => {
+            let pid = entry.pid.map(|pid| format!("[{}]", pid)).unwrap_or_default();
+            println!("{} {}{} {}: {}",
+                entry.timestamp.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S"),
+                entry.service,
+                pid,
+                entry.level,
+                entry.message);
+        }
+    }
+    
+    Ok(())
+}
+
 async fn run_daemon() -> Result<()> {
+    // Forking daemons are re-parented to us once their parent exits, so
+    // their exit status is ours to reap
+    if unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) } != 0 {
+        warn!("Failed to become a subreaper: {}", std::io::Error::last_os_error());
+    }
+    
+    // A stop of the daemon sends SIGTERM, which must not kill it before
+    // it stopped its services
+    let mut terminate = signal(SignalKind::terminate())?;
+    
     let manager = ServiceManager::new()?;
     manager.load_units()?;
     
-    info!("TauService daemon started");
+    // Take over the services of the daemon this one replaces, before
+    // binding sockets it may have handed over
+    let state_manager = StateManager::new()?;
+    let state = state_manager.take_runtime_state().unwrap_or_else(|e| {
+        warn!("Not adopting running services: {:#}", e);
+        None
+    });
+    
+    // Units run on first boot create the machine ID, so decide before any
+    // of them starts and keep the decision across daemon-reexec
+    if condition::init_first_boot(state.as_ref().and_then(|state| state.first_boot)) {
+        info!("First boot of the system");
+    }
+    
+    if let Some(state) = state {
+        info!("Adopted {} running services", manager.restore(state));
+    }
+    manager.start_all_sockets();
+    
+    let scope = ManagerScope::current();
+    info!("TauService daemon started ({})", if scope.is_user() { "user" } else { "system" });
     info!("Loaded {} service units", manager.list_services(None).len());
     
-    // Keep daemon running
-    loop {
-        tokio::time::sleep(Duration::from_secs(60)).await;
-        
-        // Check for failed services and restart if needed
-        let services = manager.list_services(Some(ServiceState::Failed));
-        for service in services {
-            warn!("Service {} failed, attempting restart", service.name);
-            if let Err(e) = manager.restart_service(&service.name).await {
-                error!("Failed to restart service {}: {}", service.name, e);
+    let control_server = ControlServer::new(manager.clone(), &control_socket_path());
+    let control_task = tokio::spawn(async move {
+        if let Err(e) = control_server.run().await {
+            error!("Control socket failed: {}", e);
+        }
+    });
+    
+    let supervisor = Supervisor::new(manager.clone());
+    let supervisor_task = tokio::spawn(async move {
+        if let Err(e) = supervisor.run().await {
+            error!("Service supervisor failed: {}", e);
+        }
+    });
+    
+    let scheduler = TimerScheduler::new(manager.clone());
+    let scheduler_task = tokio::spawn(async move {
+        if let Err(e) = scheduler.run().await {
+            error!("Timer scheduler failed: {}", e);
+        }
+    });
+    
+    let watcher = UnitWatcher::new(manager.clone());
+    let watcher_task = tokio::spawn(async move {
+        if let Err(e) = watcher.run().await {
+            error!("Unit directory watcher failed: {}", e);
+        }
+    });
+    
+    let path_watcher = PathWatcher::new(manager.clone());
+    let path_task = tokio::spawn(async move {
+        if let Err(e) = path_watcher.run().await {
+            error!("Path watcher failed: {}", e);
+        }
+    });
+    
+    // The bus may not be up yet, or at all; the control socket still works
+    let dbus_api = ServiceDbusApi::new(manager.clone());
+    let dbus_task = tokio::spawn(async move {
+        if let Err(e) = dbus_api.run().await {
+            error!("D-Bus API failed: {}", e);
+        }
+    });
+    
+    // Core dumps go to the system manager
+    if !scope.is_user() {
+        if let Err(e) = coredump::register_handler() {
+            warn!("Not capturing core dumps: {:#}", e);
+        }
+    }
+    
+    // Nothing boots a user manager, it starts its default target itself
+    if scope.is_user() {
+        let manager = manager.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = BootManager::new().start_boot_services(&manager) {
+                error!("Failed to start {}: {:#}", ManagerScope::current().default_target(), e);
+            }
+        });
+    }
+    
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {}
+    }
+    info!("TauService daemon shutting down");
+    
+    // Only daemon-reexec hands services over, a stop of the daemon stops
+    // them too
+    let stopping = manager.clone();
+    match tokio::task::spawn_blocking(move || stopping.stop_all()).await? {
+        Ok(report) => info!("Finished {} stop jobs", report.jobs.len()),
+        Err(e) => error!("Failed to stop units: {:#}", e),
+    }
+    if let Err(e) = state_manager.remove_runtime_state() {
+        error!("{:#}", e);
+    }
+    
+    control_task.abort();
+    supervisor_task.abort();
+    scheduler_task.abort();
+    watcher_task.abort();
+    path_task.abort();
+    dbus_task.abort();
+    Ok(())
+}
+
+/// The user named on the command line, or the calling user.
+fn linger_user(name: Option<&str>) -> Result<nix::unistd::User> {
+    let user = match name {
+        Some(name) => nix::unistd::User::from_name(name)?,
+        None => nix::unistd::User::from_uid(nix::unistd::getuid())?,
+    };
+    user.ok_or_else(|| anyhow::anyhow!("User '{}' does not exist", name.unwrap_or("(current)")))
+}
+
+/// Asks a running daemon to pick up unit files written by this process.
+/// Package hooks also run at image build time without a daemon, so a
+/// missing socket is not an error.
+async fn notify_daemon_reload() {
+    match ControlClient::connect_default().await {
+        Ok(mut client) => {
+            if let Err(e) = client.call(ControlRequest::DaemonReload).await {
+                warn!("Daemon failed to reload units: {}", e);
             }
         }
+        Err(e) => info!("Not reloading units: {}", e),
     }
-} 
\ No newline at end of file
+}
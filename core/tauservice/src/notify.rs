@@ -0,0 +1,159 @@
+use crate::service_manager::ServiceEvent;
+use anyhow::{Result, Context};
+use nix::sys::socket::{recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, UnixCredentials};
+use std::fs;
+use std::io::IoSliceMut;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::io::Interest;
+use tokio::net::UnixDatagram;
+use tokio::sync::mpsc;
+use log::{warn, debug};
+
+pub const NOTIFY_SOCKET_DIR: &str = "/run/tau/notify";
+
+/// One sd_notify datagram, e.g. `READY=1\nSTATUS=Listening on :80`.
+/// Unknown assignments are ignored, as with systemd.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NotifyMessage {
+    pub ready: bool,
+    pub reloading: bool,
+    pub stopping: bool,
+    pub watchdog: bool,
+    pub watchdog_trigger: bool,
+    pub watchdog_usec: Option<u64>,
+    pub status: Option<String>,
+    pub main_pid: Option<u32>,
+    pub errno: Option<i32>,
+}
+
+impl NotifyMessage {
+    pub fn parse(payload: &str) -> Self {
+        let mut message = NotifyMessage::default();
+
+        for line in payload.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+
+            match key {
+                "READY" => message.ready = value == "1",
+                "RELOADING" => message.reloading = value == "1",
+                "STOPPING" => message.stopping = value == "1",
+                "WATCHDOG" => match value {
+                    "1" => message.watchdog = true,
+                    "trigger" => message.watchdog_trigger = true,
+                    _ => {}
+                },
+                "WATCHDOG_USEC" => message.watchdog_usec = value.parse().ok(),
+                "STATUS" => message.status = Some(value.to_string()),
+                "MAINPID" => message.main_pid = value.parse().ok(),
+                "ERRNO" => message.errno = value.parse().ok(),
+                _ => debug!("Ignoring notify assignment: {}", key),
+            }
+        }
+
+        message
+    }
+}
+
+/// Per-unit `NOTIFY_SOCKET` datagram listener. Each unit gets its own
+/// socket, only writable by the user the unit runs as. Messages are
+/// forwarded to the manager's event loop with the PID of their sender,
+/// which checks it against NotifyAccess=.
+pub struct NotifySocket {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl NotifySocket {
+    pub fn bind_in(dir: &Path, unit_name: &str, events: mpsc::UnboundedSender<ServiceEvent>) -> Result<Self> {
+        fs::create_dir_all(dir)
+            .context("Failed to create notify socket directory")?;
+
+        let path = dir.join(unit_name);
+        if path.exists() {
+            fs::remove_file(&path)?;
+        }
+
+        let socket = std::os::unix::net::UnixDatagram::bind(&path)
+            .context(format!("Failed to bind notify socket {}", path.display()))?;
+        socket.set_nonblocking(true)?;
+
+        // Owned by the unit's user once its credentials are resolved
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+
+        // The kernel attaches the sender's credentials to every datagram
+        setsockopt(&socket, sockopt::PassCred, &true)
+            .context("Failed to enable credentials on the notify socket")?;
+
+        let socket = UnixDatagram::from_std(socket)?;
+        let name = unit_name.to_string();
+
+        let task = tokio::spawn(async move {
+            let mut buffer = vec![0u8; 4096];
+
+            loop {
+                let received = match socket.readable().await {
+                    Ok(()) => socket.try_io(Interest::READABLE, || recv_with_sender(&socket, &mut buffer)),
+                    Err(e) => Err(e),
+                };
+
+                let (n, sender) = match received {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                    Err(e) => {
+                        warn!("Notify socket for {} failed: {}", name, e);
+                        break;
+                    }
+                };
+
+                let Some(pid) = sender else {
+                    debug!("Ignoring notification without credentials for {}", name);
+                    continue;
+                };
+
+                let payload = String::from_utf8_lossy(&buffer[..n]);
+                let message = NotifyMessage::parse(&payload);
+
+                if events.send(ServiceEvent::Notify { name: name.clone(), message, pid }).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { path, task })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lets the user a service runs as send notifications.
+    pub fn set_owner(&self, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        chown(&self.path, uid, gid)
+            .with_context(|| format!("Failed to change the owner of {}", self.path.display()))
+    }
+}
+
+/// Receives one datagram, returning its length and the PID of its sender.
+fn recv_with_sender(socket: &UnixDatagram, buffer: &mut [u8]) -> std::io::Result<(usize, Option<u32>)> {
+    let mut iov = [IoSliceMut::new(buffer)];
+    let mut control = nix::cmsg_space!(UnixCredentials);
+
+    let message = recvmsg::<()>(socket.as_raw_fd(), &mut iov, Some(&mut control), MsgFlags::MSG_DONTWAIT)?;
+    let sender = message.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::ScmCredentials(credentials) => Some(credentials.pid() as u32),
+        _ => None,
+    });
+
+    Ok((message.bytes, sender))
+}
+
+impl Drop for NotifySocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,294 @@
+use crate::service_manager::{ManagerEvent, ServiceEvent, ServiceManager, ServiceState};
+use crate::unit::PathUnit;
+use anyhow::{Result, Context};
+use notify::event::{AccessKind, AccessMode, ModifyKind};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, Notify};
+use log::{info, warn, debug};
+
+/// One trigger of a path unit.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathCondition {
+    /// The path exists
+    Exists(PathBuf),
+    /// Any path matching the glob pattern exists
+    ExistsGlob(String),
+    /// The file was written and closed, created, removed or renamed
+    Changed(PathBuf),
+    /// Like `Changed`, and also on every write
+    Modified(PathBuf),
+    /// The directory has at least one entry
+    DirectoryNotEmpty(PathBuf),
+}
+
+impl PathCondition {
+    pub fn absolute(value: String, kind: fn(PathBuf) -> PathCondition) -> Result<Self> {
+        let path = PathBuf::from(value);
+        if !path.is_absolute() {
+            return Err(anyhow::anyhow!("{} is not an absolute path", path.display()));
+        }
+
+        Ok(kind(path))
+    }
+
+    pub fn glob(pattern: String) -> Result<Self> {
+        if !pattern.starts_with('/') {
+            return Err(anyhow::anyhow!("{} is not an absolute path", pattern));
+        }
+        glob::Pattern::new(&pattern)?;
+
+        Ok(PathCondition::ExistsGlob(pattern))
+    }
+
+    /// The path watched for this condition. For globs it is the directory
+    /// before the first wildcard, so wildcards in deeper components are
+    /// only noticed when that directory changes.
+    pub fn path(&self) -> PathBuf {
+        match self {
+            PathCondition::Exists(path)
+            | PathCondition::Changed(path)
+            | PathCondition::Modified(path)
+            | PathCondition::DirectoryNotEmpty(path) => path.clone(),
+            PathCondition::ExistsGlob(pattern) => {
+                let wildcard = pattern.find(['*', '?', '[']).unwrap_or(pattern.len());
+                let prefix = &pattern[..wildcard];
+                let dir = &prefix[..prefix.rfind('/').unwrap_or(0)];
+                PathBuf::from(if dir.is_empty() { "/" } else { dir })
+            }
+        }
+    }
+
+    /// Conditions that hold as long as the file system is in some state,
+    /// as opposed to ones triggered by an event.
+    pub fn is_state(&self) -> bool {
+        matches!(self, PathCondition::Exists(_) | PathCondition::ExistsGlob(_) | PathCondition::DirectoryNotEmpty(_))
+    }
+
+    /// Whether a state condition currently holds.
+    pub fn is_met(&self) -> bool {
+        match self {
+            PathCondition::Exists(path) => path.exists(),
+            PathCondition::ExistsGlob(pattern) => glob::glob(pattern)
+                .map(|mut paths| paths.any(|path| path.is_ok()))
+                .unwrap_or(false),
+            PathCondition::DirectoryNotEmpty(path) => fs::read_dir(path)
+                .map(|mut entries| entries.next().is_some())
+                .unwrap_or(false),
+            PathCondition::Changed(_) | PathCondition::Modified(_) => false,
+        }
+    }
+
+    /// Whether a file system event triggers this condition. State
+    /// conditions are triggered by events that make them hold.
+    pub fn is_triggered_by(&self, event: &Event) -> bool {
+        let path = self.path();
+
+        if self.is_state() {
+            let related = event.paths.iter().any(|changed| {
+                changed.starts_with(&path) || path.starts_with(changed)
+            });
+            return related && self.is_met();
+        }
+
+        let kind = match event.kind {
+            EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Name(_))
+            | EventKind::Modify(ModifyKind::Metadata(_)) => true,
+            EventKind::Modify(_) => matches!(self, PathCondition::Modified(_)),
+            _ => false,
+        };
+
+        // Changes to the path itself, or to entries of a watched directory
+        kind && event.paths.iter().any(|changed| {
+            *changed == path || changed.parent() == Some(path.as_path())
+        })
+    }
+}
+
+impl std::fmt::Display for PathCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathCondition::Exists(path) => write!(f, "PathExists={}", path.display()),
+            PathCondition::ExistsGlob(pattern) => write!(f, "PathExistsGlob={}", pattern),
+            PathCondition::Changed(path) => write!(f, "PathChanged={}", path.display()),
+            PathCondition::Modified(path) => write!(f, "PathModified={}", path.display()),
+            PathCondition::DirectoryNotEmpty(path) => write!(f, "DirectoryNotEmpty={}", path.display()),
+        }
+    }
+}
+
+/// Path units known to the manager.
+#[derive(Clone)]
+pub struct PathRegistry {
+    units: Arc<Mutex<HashMap<String, PathUnit>>>,
+    changed: Arc<Notify>,
+}
+
+impl PathRegistry {
+    pub fn new() -> Self {
+        Self {
+            units: Arc::new(Mutex::new(HashMap::new())),
+            changed: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn set_units(&self, units: HashMap<String, PathUnit>) {
+        *self.units.lock().unwrap() = units;
+        self.changed.notify_one();
+    }
+
+    /// Each unit's conditions, skipping ones that no longer parse.
+    fn conditions(&self) -> Vec<(PathUnit, Vec<PathCondition>)> {
+        self.units.lock().unwrap()
+            .values()
+            .filter_map(|unit| unit.conditions().ok().map(|conditions| (unit.clone(), conditions)))
+            .collect()
+    }
+}
+
+impl Default for PathRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Watches the paths of path units and starts their services.
+pub struct PathWatcher {
+    manager: ServiceManager,
+}
+
+impl PathWatcher {
+    pub fn new(manager: ServiceManager) -> Self {
+        Self { manager }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            match result {
+                Ok(event) => {
+                    let _ = sender.send(event);
+                }
+                Err(e) => warn!("Path watch failed: {}", e),
+            }
+        }).context("Failed to create path watcher")?;
+
+        let registry = self.manager.paths().clone();
+        let mut states = self.manager.subscribe();
+        let mut watched = HashSet::new();
+
+        sync_watches(&mut watcher, &mut watched, &registry);
+        self.check_states(&registry, None);
+
+        info!("Path watcher started");
+
+        loop {
+            tokio::select! {
+                Some(event) = events.recv() => {
+                    // Watches on removed directories are gone, re-add them
+                    // when the directory is created again
+                    if matches!(event.kind, EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(_))) {
+                        for path in &event.paths {
+                            watched.remove(path);
+                        }
+                    }
+
+                    for (unit, conditions) in registry.conditions() {
+                        if let Some(condition) = conditions.iter().find(|c| c.is_triggered_by(&event)) {
+                            self.trigger(&unit, condition);
+                        }
+                    }
+
+                    sync_watches(&mut watcher, &mut watched, &registry);
+                }
+                _ = registry.changed.notified() => {
+                    sync_watches(&mut watcher, &mut watched, &registry);
+                    self.check_states(&registry, None);
+                }
+                event = states.recv() => match event {
+                    // A service that finished is started again while the
+                    // state that triggered it persists, like a spool
+                    // directory that is not empty yet
+                    Ok(ManagerEvent::StateChanged { name, state: ServiceState::Inactive, .. }) => {
+                        self.check_states(&registry, Some(&name));
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(_)) => self.check_states(&registry, None),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Triggers the path units, or those of one service, whose state
+    /// conditions hold.
+    fn check_states(&self, registry: &PathRegistry, service: Option<&str>) {
+        for (unit, conditions) in registry.conditions() {
+            if service.is_some_and(|service| unit.service_name() != service) {
+                continue;
+            }
+
+            if let Some(condition) = conditions.iter().find(|c| c.is_state() && c.is_met()) {
+                self.trigger(&unit, condition);
+            }
+        }
+    }
+
+    fn trigger(&self, unit: &PathUnit, condition: &PathCondition) {
+        let service = unit.service_name();
+
+        // Also while it starts, events keep coming until it handles them
+        let state = self.manager.get_service_status(&service).map(|status| status.state);
+        if matches!(state, Some(ServiceState::Active | ServiceState::Activating)) {
+            debug!("Path {} triggered by {}, {} is already running", unit.name, condition, service);
+            return;
+        }
+
+        info!("Path {} triggered by {}, starting {}", unit.name, condition, service);
+        self.manager.send_event(ServiceEvent::Start { name: service });
+    }
+}
+
+/// Watches every path of the path units, or its closest existing
+/// ancestor so the path is noticed when it is created.
+fn sync_watches(watcher: &mut RecommendedWatcher, watched: &mut HashSet<PathBuf>, registry: &PathRegistry) {
+    let mut wanted = HashSet::new();
+
+    for (_, conditions) in registry.conditions() {
+        for condition in conditions {
+            let path = condition.path();
+            if path.is_dir() {
+                wanted.insert(path.clone());
+            }
+            if let Some(ancestor) = path.ancestors().skip(1).find(|dir| dir.is_dir()) {
+                wanted.insert(ancestor.to_path_buf());
+            }
+        }
+    }
+
+    for path in watched.difference(&wanted).cloned().collect::<Vec<_>>() {
+        let _ = watcher.unwatch(&path);
+        watched.remove(&path);
+    }
+
+    for path in wanted {
+        if watched.contains(&path) {
+            continue;
+        }
+
+        match watcher.watch(&path, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                debug!("Watching {}", path.display());
+                watched.insert(path);
+            }
+            Err(e) => warn!("Failed to watch {}: {}", path.display(), e),
+        }
+    }
+}
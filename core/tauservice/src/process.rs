@@ -1,22 +1,173 @@
-use crate::unit::ServiceUnit;
-use crate::journal::JournalLogger;
+use crate::unit::{KillMode, ServiceUnit, StandardOutput};
+use crate::cgroup::{ResourceUsage, ServiceCgroup};
+use crate::credentials::Credentials;
+use crate::sandbox::SandboxManager;
+use crate::journal::{split_priority_prefix, JournalEntry, JournalLogger, LogLevel};
+use crate::notify::NotifySocket;
+use crate::supervisor::ExitOutcome;
+use crate::exec::{parse_environment_file, ExecCommand};
+use crate::state::{keep_across_exec, take_inherited_fd};
 use anyhow::{Result, Context};
-use std::process::{Command, Stdio, Child};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::io::{AsyncReadExt, BufReader};
-use tokio::process::Command as TokioCommand;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio, Child, ExitStatus};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::task::JoinHandle;
+use nix::errno::Errno;
 use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use log::{info, warn, error, debug};
+use log::{info, warn, debug};
+
+/// How often commands and stopping processes are checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const CONSOLE_PATH: &str = "/dev/console";
+const KMSG_PATH: &str = "/dev/kmsg";
+/// Longer lines of captured output are split.
+const LINE_MAX: u64 = 48 * 1024;
+
+/// The step of a service's lifecycle a command runs in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExecPhase {
+    StartPre,
+    Start,
+    StartPost,
+    Stop,
+    StopPost,
+    Reload,
+}
+
+impl std::fmt::Display for ExecPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ExecPhase::StartPre => "ExecStartPre",
+            ExecPhase::Start => "ExecStart",
+            ExecPhase::StartPost => "ExecStartPost",
+            ExecPhase::Stop => "ExecStop",
+            ExecPhase::StopPost => "ExecStopPost",
+            ExecPhase::Reload => "ExecReload",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExecError {
+    #[error("{phase} command {command} failed ({outcome})")]
+    Failed { phase: ExecPhase, command: String, outcome: ExitOutcome },
+    #[error("{phase} timed out after {timeout:?}")]
+    TimedOut { phase: ExecPhase, timeout: Duration },
+    #[error("PID file {} not readable after start", .0.display())]
+    PidFile(PathBuf),
+}
+
+impl ExecError {
+    pub fn phase(&self) -> ExecPhase {
+        match self {
+            ExecError::Failed { phase, .. } | ExecError::TimedOut { phase, .. } => *phase,
+            ExecError::PidFile(_) => ExecPhase::Start,
+        }
+    }
+}
+
+fn deadline(timeout: Option<Duration>) -> Option<Instant> {
+    timeout.map(|timeout| Instant::now() + timeout)
+}
+
+/// A running main process as handed over to the next daemon.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SerializedProcess {
+    /// The process that gets reaped, 0 for a service that is running
+    /// without one (Type=oneshot with RemainAfterExit=)
+    pub pid: u32,
+    /// MAINPID= reported by the service
+    pub main_pid: Option<u32>,
+    pub cgroup: Option<PathBuf>,
+    /// When `pid` started, in clock ticks since boot, so a reused PID is
+    /// not mistaken for the service
+    #[serde(default)]
+    pub start_time: Option<u64>,
+    pub credentials: Option<Credentials>,
+    pub ignore_failure: bool,
+    /// Read ends of the output pipes, kept open across exec
+    pub stdout_fd: Option<RawFd>,
+    pub stderr_fd: Option<RawFd>,
+}
+
+/// Tasks forwarding the captured stdout and stderr of a process.
+type OutputForwarders = (Option<JoinHandle<()>>, Option<JoinHandle<()>>);
+
+/// A running ExecStartPre=, ExecStop=, ExecReload= etc. command.
+pub struct ControlCommand {
+    child: Child,
+    phase: ExecPhase,
+    command: String,
+    ignore_failure: bool,
+    deadline: Option<Instant>,
+    timeout: Option<Duration>,
+}
+
+impl ControlCommand {
+    /// Waits for the command to exit, killing it once its deadline passed.
+    pub fn wait(mut self) -> Result<()> {
+        let status = loop {
+            if let Some(status) = self.child.try_wait()? {
+                break status;
+            }
+            
+            if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return Err(ExecError::TimedOut { phase: self.phase, timeout: self.timeout.unwrap_or_default() }.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        
+        if !status.success() {
+            let outcome = ExitOutcome::from_status(status);
+            if !self.ignore_failure {
+                return Err(ExecError::Failed { phase: self.phase, command: self.command, outcome }.into());
+            }
+            info!("Ignoring failure of {} command {} ({})", self.phase, self.command, outcome);
+        }
+        
+        Ok(())
+    }
+}
 
 pub struct ServiceProcess {
     unit: ServiceUnit,
     child: Option<Child>,
+    /// A main process this daemon did not start itself, reaped by PID
+    adopted: Option<AdoptedProcess>,
     pid: Option<u32>,
     journal_logger: Arc<JournalLogger>,
-    stdout_handle: Option<tokio::task::JoinHandle<()>>,
-    stderr_handle: Option<tokio::task::JoinHandle<()>>,
+    stdout_handle: Option<JoinHandle<()>>,
+    stderr_handle: Option<JoinHandle<()>>,
+    stdout_fd: Option<RawFd>,
+    stderr_fd: Option<RawFd>,
+    notify_socket: Option<NotifySocket>,
+    listen_fds: Vec<(RawFd, String)>,
+    cgroup: Option<ServiceCgroup>,
+    credentials: Option<Credentials>,
+    /// ExecStart= has the `-` prefix
+    ignore_failure: bool,
+    /// Started successfully and running without a main process: a
+    /// Type=oneshot service that finished, or a forking daemon whose PID
+    /// is unknown
+    without_main: bool,
 }
 
 impl ServiceProcess {
@@ -24,131 +175,683 @@ impl ServiceProcess {
         Ok(Self {
             unit: unit.clone(),
             child: None,
+            adopted: None,
             pid: None,
             journal_logger: Arc::new(journal_logger.clone()),
             stdout_handle: None,
             stderr_handle: None,
+            stdout_fd: None,
+            stderr_fd: None,
+            notify_socket: None,
+            listen_fds: Vec::new(),
+            cgroup: None,
+            credentials: None,
+            ignore_failure: false,
+            without_main: false,
+        })
+    }
+    
+    /// Takes over a main process started by a previous daemon, and the
+    /// output pipes it still holds.
+    pub fn adopt(unit: &ServiceUnit, journal_logger: &JournalLogger, state: SerializedProcess) -> Result<Self> {
+        let mut process = Self::new(unit, journal_logger)?;
+        process.cgroup = state.cgroup.map(ServiceCgroup::open);
+        if state.pid == 0 {
+            process.without_main = true;
+        } else {
+            // The state may be older than the process that has the PID now
+            let start_time = process_start_time(state.pid);
+            let same_process = match state.start_time {
+                Some(expected) => start_time == Some(expected),
+                None => start_time.is_some() && (process.cgroup.is_none() || process.in_cgroup(state.pid)),
+            };
+            if !same_process {
+                return Err(anyhow::anyhow!("Process {} is gone", state.pid));
+            }
+            
+            process.adopted = Some(AdoptedProcess::new(state.pid));
+            process.pid = state.main_pid.or(Some(state.pid));
+        }
+        process.credentials = state.credentials;
+        process.ignore_failure = state.ignore_failure;
+        
+        let pid = process.pid;
+        if let Some(fd) = state.stdout_fd {
+            let pipe = tokio::net::unix::pipe::Receiver::from_owned_fd(take_inherited_fd(fd))?;
+            process.stdout_fd = Some(pipe.as_raw_fd());
+            process.stdout_handle = Some(process.forward_stream(pipe, "stdout", pid, unit.standard_output()?));
+        }
+        if let Some(fd) = state.stderr_fd {
+            let pipe = tokio::net::unix::pipe::Receiver::from_owned_fd(take_inherited_fd(fd))?;
+            process.stderr_fd = Some(pipe.as_raw_fd());
+            process.stderr_handle = Some(process.forward_stream(pipe, "stderr", pid, unit.standard_error()?));
+        }
+        
+        if !process.without_main {
+            info!("Adopted process {} of {}", state.pid, unit.name);
+        }
+        Ok(process)
+    }
+    
+    /// The running main process, for handing over to the next daemon.
+    /// With `keep_fds`, the output pipes are duplicated to survive the
+    /// exec of that daemon.
+    pub fn serialize(&self, keep_fds: bool) -> Option<SerializedProcess> {
+        let pid = self.child.as_ref().map(|child| child.id())
+            .or(self.adopted.as_ref().map(|adopted| adopted.pid))
+            .or(self.without_main.then_some(0))?;
+        
+        let keep = |fd: Option<RawFd>, handle: &Option<JoinHandle<()>>| match (fd, handle) {
+            // A finished forwarder has closed its pipe
+            (Some(fd), Some(handle)) if keep_fds && !handle.is_finished() => match keep_across_exec(fd) {
+                Ok(fd) => Some(fd),
+                Err(e) => {
+                    warn!("Output of {} is lost: {:#}", self.unit.name, e);
+                    None
+                }
+            },
+            _ => None,
+        };
+        
+        Some(SerializedProcess {
+            pid,
+            main_pid: self.pid,
+            cgroup: self.cgroup.as_ref().map(|cgroup| cgroup.path().to_path_buf()),
+            start_time: process_start_time(pid),
+            credentials: self.credentials.clone(),
+            ignore_failure: self.ignore_failure,
+            stdout_fd: keep(self.stdout_fd, &self.stdout_handle),
+            stderr_fd: keep(self.stderr_fd, &self.stderr_handle),
         })
     }
     
     pub fn start(&mut self) -> Result<()> {
         info!("Starting process for service: {}", self.unit.name);
         
-        let exec_start = self.unit.service.exec_start.as_ref()
+        let exec_start = self.unit.service.exec_start.clone()
             .ok_or_else(|| anyhow::anyhow!("No ExecStart specified"))?;
+        let exec_start = exec_start.lines();
+        let deadline = deadline(self.unit.start_timeout());
         
-        // Parse command and arguments
-        let (command, args) = self.parse_command(exec_start)?;
+        // Place the service, its hooks and everything they fork in its own
+        // cgroup, before the sandbox can make /sys/fs/cgroup read-only
+        self.setup_cgroup()?;
         
-        // Build command
-        let mut cmd = Command::new(command);
-        cmd.args(args);
+        // Resolved once, all commands of a service share a DynamicUser=
+        match Credentials::resolve(&self.unit) {
+            Ok(credentials) => self.credentials = Some(credentials),
+            Err(e) => {
+                self.cleanup_cgroup();
+                return Err(e);
+            }
+        }
         
-        // Set working directory
-        if let Some(working_dir) = &self.unit.service.working_directory {
-            cmd.current_dir(working_dir);
+        if let Err(e) = self.own_notify_socket().and_then(|()| self.run_start(exec_start, deadline)) {
+            // ExecStopPost= also runs after a failed start
+            if let Some(stop_error) = self.stop_main() {
+                warn!("Failed to stop {} after it failed to start: {:#}", self.unit.name, stop_error);
+            }
+            if let Err(finish_error) = self.finish() {
+                warn!("{:#}", finish_error);
+            }
+            return Err(e);
         }
         
-        // Set user/group if specified
-        if let Some(user) = &self.unit.service.user {
-            // In a real implementation, you'd set the user here
-            debug!("Would run as user: {}", user);
+        Ok(())
+    }
+    
+    fn run_start(&mut self, exec_start: &[String], deadline: Option<Instant>) -> Result<()> {
+        for line in self.unit.service.exec_start_pre.clone().unwrap_or_default() {
+            self.run_command(ExecPhase::StartPre, &line, deadline)?;
         }
         
-        // Set environment variables
-        if let Some(env) = &self.unit.service.environment {
-            for (key, value) in env {
-                cmd.env(key, value);
+        if self.unit.is_oneshot() {
+            // The start is done once every command ran to completion
+            for line in exec_start {
+                self.run_command(ExecPhase::Start, line, deadline)?;
+            }
+            self.without_main = true;
+        } else {
+            let (mut cmd, exec) = self.command(&exec_start[0], true)?;
+            let child = cmd.spawn().context("Failed to start service process")?;
+            
+            self.ignore_failure = exec.ignore_failure;
+            self.pid = Some(child.id());
+            self.child = Some(child);
+            
+            // Start output logging
+            self.start_output_logging()?;
+            
+            info!("Process started with PID: {}", self.pid.unwrap());
+            
+            if self.unit.is_forking() {
+                self.wait_forked(&exec_start[0], deadline)?;
             }
         }
         
-        // Load environment files
-        if let Some(env_files) = &self.unit.service.environment_file {
-            for file in env_files {
-                self.load_environment_file(&mut cmd, file)?;
-            }
+        for line in self.unit.service.exec_start_post.clone().unwrap_or_default() {
+            self.run_command(ExecPhase::StartPost, &line, deadline)?;
         }
         
-        // Set up output handling
-        cmd.stdout(Stdio::piped());
-        cmd.stderr(Stdio::piped());
+        Ok(())
+    }
+    
+    /// Waits for the parent process of a Type=forking service to exit,
+    /// then takes the daemon it left behind as the main process.
+    fn wait_forked(&mut self, exec_start: &str, deadline: Option<Instant>) -> Result<()> {
+        let Some(mut parent) = self.child.take() else {
+            return Ok(());
+        };
         
-        // Apply sandboxing if configured
-        self.apply_sandboxing(&mut cmd)?;
+        let status = loop {
+            if let Some(status) = parent.try_wait().context("Failed to poll service process")? {
+                break status;
+            }
+            
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                let _ = parent.kill();
+                let _ = parent.wait();
+                self.pid = None;
+                let timeout = self.unit.start_timeout().unwrap_or_default();
+                return Err(ExecError::TimedOut { phase: ExecPhase::Start, timeout }.into());
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        self.pid = None;
         
-        // Start the process
-        let mut child = cmd.spawn()
-            .context("Failed to start service process")?;
+        if !status.success() && !self.ignore_failure {
+            let command = exec_start.trim_start_matches(['-', '@', '+', '!', ':']).to_string();
+            let outcome = ExitOutcome::from_status(status);
+            return Err(ExecError::Failed { phase: ExecPhase::Start, command, outcome }.into());
+        }
         
-        self.pid = child.id();
-        self.child = Some(child);
+        let main_pid = match &self.unit.service.pid_file {
+            Some(path) => Some(read_pid_file(Path::new(path), deadline)?),
+            None => self.guess_main_pid(),
+        };
         
-        // Start output logging
-        self.start_output_logging()?;
+        match main_pid {
+            Some(pid) => {
+                info!("Main PID of {} is {}", self.unit.name, pid);
+                self.adopted = Some(AdoptedProcess::new(pid));
+                self.pid = Some(pid);
+            }
+            None => {
+                warn!("Cannot determine the main PID of {}, set PIDFile=", self.unit.name);
+                self.without_main = true;
+            }
+        }
         
-        info!("Process started with PID: {}", self.pid.unwrap());
         Ok(())
     }
     
+    /// The one process left in the service's cgroup whose parent is
+    /// outside it, if there is exactly one.
+    fn guess_main_pid(&self) -> Option<u32> {
+        let pids = self.cgroup.as_ref()?.pids();
+        let mut roots = pids.iter()
+            .filter(|pid| parent_pid(**pid).is_some_and(|parent| !pids.contains(&parent)));
+        
+        match (roots.next(), roots.next()) {
+            (Some(pid), None) => Some(*pid as u32),
+            _ => None,
+        }
+    }
+    
+    /// Stops the service and runs its ExecStopPost= commands. Fails if a
+    /// command failed or the service had to be killed with SIGKILL, the
+    /// service is stopped either way.
     pub fn stop(&mut self) -> Result<()> {
-        if let Some(pid) = self.pid {
-            info!("Stopping process with PID: {}", pid);
+        let mut failure = self.stop_main();
+        
+        if let Err(e) = self.finish() {
+            failure.get_or_insert(e);
+        }
+        
+        failure.map_or(Ok(()), Err)
+    }
+    
+    /// Runs ExecStop=, sends KillSignal= to whatever is left and SIGKILL
+    /// once TimeoutStopSec= passed.
+    fn stop_main(&mut self) -> Option<anyhow::Error> {
+        if self.pid.is_none() && !self.without_main {
+            return None;
+        }
+        
+        let timeout = self.unit.stop_timeout();
+        let mut failure = None;
+        
+        // Try graceful stop first. Without a main process, ExecStop= is
+        // still what undoes the start
+        if let Some(exec_stop) = self.unit.service.exec_stop.clone() {
+            if let Err(e) = self.run_command(ExecPhase::Stop, &exec_stop, deadline(timeout)) {
+                warn!("{:#}", e);
+                failure = Some(e);
+            }
+        }
+        self.without_main = false;
+        
+        let Some(pid) = self.pid else {
+            return failure;
+        };
+        info!("Stopping process with PID: {}", pid);
+        
+        if self.kill_mode() == KillMode::None {
+            // Left running on purpose, only forget about it
+            debug!("Not killing {} with KillMode=none", self.unit.name);
+            self.child = None;
+            self.adopted = None;
+        } else if !self.wait_main(Some(Instant::now())) {
+            let signal = self.unit.kill_signal().unwrap_or(Signal::SIGTERM);
+            if let Err(e) = self.signal_for_stop(pid, signal) {
+                warn!("{:#}", e);
+            }
             
-            // Try graceful stop first
-            if let Some(exec_stop) = &self.unit.service.exec_stop {
-                self.execute_stop_command(exec_stop)?;
-            } else {
-                // Send SIGTERM
-                kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
-                    .context("Failed to send SIGTERM")?;
+            if !self.wait_main(deadline(timeout)) {
+                warn!("{} did not stop within {:?}, sending SIGKILL", self.unit.name, timeout.unwrap_or_default());
+                if let Err(e) = self.signal_for_stop(pid, Signal::SIGKILL) {
+                    warn!("{:#}", e);
+                }
+                self.wait_main(None);
+                failure = Some(ExecError::TimedOut { phase: ExecPhase::Stop, timeout: timeout.unwrap_or_default() }.into());
+            }
+        }
+        
+        // Cancel output logging tasks
+        if let Some(handle) = self.stdout_handle.take() {
+            handle.abort();
+        }
+        if let Some(handle) = self.stderr_handle.take() {
+            handle.abort();
+        }
+        self.stdout_fd = None;
+        self.stderr_fd = None;
+        
+        self.pid = None;
+        failure
+    }
+    
+    /// Runs ExecStopPost= once the main process is gone, however it ended,
+    /// and releases the cgroup and credentials.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut failure = None;
+        
+        for line in self.unit.service.exec_stop_post.clone().unwrap_or_default() {
+            // Each command gets the full timeout, one that hangs does not
+            // keep the others from running
+            if let Err(e) = self.run_command(ExecPhase::StopPost, &line, deadline(self.unit.stop_timeout())) {
+                warn!("{:#}", e);
+                failure.get_or_insert(e);
+            }
+        }
+        
+        self.cleanup_cgroup();
+        self.release_credentials();
+        
+        failure.map_or(Ok(()), Err)
+    }
+    
+    /// Polls the main process until it exits or `deadline` passes, and
+    /// returns whether it exited. Without a deadline it waits for good.
+    fn wait_main(&mut self, deadline: Option<Instant>) -> bool {
+        loop {
+            if self.child.is_none() && self.adopted.is_none() {
+                return true;
             }
             
-            // Wait for process to terminate
-            if let Some(mut child) = self.child.take() {
-                match child.wait() {
-                    Ok(status) => {
-                        info!("Process {} exited with status: {:?}", pid, status);
-                    }
-                    Err(e) => {
-                        warn!("Error waiting for process {}: {}", pid, e);
-                    }
+            match self.try_wait() {
+                Ok(Some(status)) => {
+                    info!("Main process of {} exited with status: {:?}", self.unit.name, status);
+                    return true;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Error waiting for main process of {}: {:#}", self.unit.name, e);
+                    self.child = None;
+                    self.adopted = None;
+                    return true;
                 }
             }
             
-            // Cancel output logging tasks
-            if let Some(handle) = self.stdout_handle.take() {
-                handle.abort();
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return false;
             }
-            if let Some(handle) = self.stderr_handle.take() {
-                handle.abort();
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+    
+    /// Runs an ExecStartPre=, ExecStartPost=, ExecStop= or ExecStopPost=
+    /// command to completion, in the same environment as the service.
+    fn run_command(&mut self, phase: ExecPhase, line: &str, deadline: Option<Instant>) -> Result<()> {
+        self.spawn_command(phase, line, deadline)?.wait()
+    }
+    
+    fn spawn_command(&self, phase: ExecPhase, line: &str, deadline: Option<Instant>) -> Result<ControlCommand> {
+        debug!("Running {} command of {}: {}", phase, self.unit.name, line);
+        
+        let (mut cmd, exec) = self.command(line, phase == ExecPhase::Start)?;
+        let command = line.trim_start_matches(['-', '@', '+', '!', ':']);
+        
+        let mut child = cmd.spawn()
+            .with_context(|| format!("Failed to run {} command {}", phase, command))?;
+        self.forward_output(&mut child)?;
+        
+        let timeout = match phase {
+            ExecPhase::StartPre | ExecPhase::Start | ExecPhase::StartPost | ExecPhase::Reload => self.unit.start_timeout(),
+            ExecPhase::Stop | ExecPhase::StopPost => self.unit.stop_timeout(),
+        };
+        
+        Ok(ControlCommand {
+            child,
+            phase,
+            command: command.to_string(),
+            ignore_failure: exec.ignore_failure,
+            deadline,
+            timeout,
+        })
+    }
+    
+    /// A command in the execution environment of the service. The main
+    /// process also gets the notify socket, watchdog and listen fds, the
+    /// other commands $MAINPID.
+    fn command(&self, line: &str, main: bool) -> Result<(Command, ExecCommand)> {
+        let mut env = self.environment()?;
+        if let (false, Some(pid)) = (main, self.pid) {
+            env.insert("MAINPID".to_string(), pid.to_string());
+        }
+        
+        let exec = ExecCommand::parse(line, &env)
+            .with_context(|| format!("Invalid command line in {}", self.unit.name))?;
+        
+        // Build command
+        let mut cmd = Command::new(&exec.path);
+        cmd.arg0(&exec.argv0);
+        cmd.args(&exec.args);
+        cmd.envs(&env);
+        
+        // Set working directory
+        if let Some(working_dir) = &self.unit.service.working_directory {
+            cmd.current_dir(working_dir);
+        }
+        
+        if main {
+            // Readiness and watchdog protocol
+            if let Some(notify_socket) = &self.notify_socket {
+                cmd.env("NOTIFY_SOCKET", notify_socket.path());
             }
             
-            self.pid = None;
+            if let Some(watchdog) = self.unit.watchdog_interval() {
+                cmd.env("WATCHDOG_USEC", watchdog.as_micros().to_string());
+            }
+            
+            // Sockets handed over by socket activation
+            if !self.listen_fds.is_empty() {
+                self.pass_listen_fds(&mut cmd);
+            }
         }
         
-        Ok(())
+        self.set_output(&mut cmd)?;
+        
+        self.join_cgroup(&mut cmd)?;
+        
+        // LISTEN_PID= names the service itself, exec it last
+        let listen_pid = main && !self.listen_fds.is_empty();
+        
+        // `+` commands run with full privileges
+        if exec.full_privileges {
+            if listen_pid {
+                exec_with_listen_pid(&mut cmd, &exec)?;
+            }
+            return Ok((cmd, exec));
+        }
+        
+        // Apply sandboxing if configured
+        self.apply_sandboxing(&mut cmd)?;
+        
+        // Drop to User=/Group= last, the hooks above need root. `!`
+        // commands keep the manager's identity
+        if let (false, Some(credentials)) = (exec.keep_identity, &self.credentials) {
+            credentials.apply(&mut cmd)?;
+        }
+        
+        // The syscall filter goes in after everything else, it may
+        // forbid the calls the other hooks make
+        if let Some(sandbox) = &self.unit.sandbox {
+            SandboxManager::new().apply_syscall_filter(&mut cmd, sandbox)?;
+        }
+        
+        if listen_pid {
+            exec_with_listen_pid(&mut cmd, &exec)?;
+        }
+        
+        Ok((cmd, exec))
     }
     
-    pub fn reload(&mut self) -> Result<()> {
+    /// Environment= merged with the EnvironmentFile= files, which take
+    /// precedence. A file prefixed with `-` may be missing.
+    fn environment(&self) -> Result<HashMap<String, String>> {
+        let mut env = self.unit.service.environment.clone().unwrap_or_default();
+        
+        for file in self.unit.service.environment_file.iter().flatten() {
+            let (optional, path) = match file.strip_prefix('-') {
+                Some(path) => (true, path),
+                None => (false, file.as_str()),
+            };
+            
+            let content = match std::fs::read_to_string(path) {
+                Ok(content) => content,
+                Err(e) if optional && e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).context(format!("Failed to read environment file: {}", path)),
+            };
+            env.extend(parse_environment_file(&content)
+                .with_context(|| format!("Failed to parse environment file: {}", path))?);
+        }
+        
+        Ok(env)
+    }
+    
+    /// Starts ExecReload=, or sends SIGHUP without one. The command is
+    /// returned to be waited for without holding on to the process.
+    pub fn reload(&mut self) -> Result<Option<ControlCommand>> {
         if let Some(pid) = self.pid {
             info!("Reloading process with PID: {}", pid);
             
             if let Some(exec_reload) = &self.unit.service.exec_reload {
-                self.execute_reload_command(exec_reload)?;
-            } else {
-                // Send SIGHUP for graceful reload
-                kill(Pid::from_raw(pid as i32), Signal::SIGHUP)
-                    .context("Failed to send SIGHUP")?;
+                let deadline = deadline(self.unit.start_timeout());
+                return self.spawn_command(ExecPhase::Reload, exec_reload, deadline).map(Some);
             }
+            
+            // Send SIGHUP for graceful reload
+            kill(Pid::from_raw(pid as i32), Signal::SIGHUP)
+                .context("Failed to send SIGHUP")?;
+        }
+        
+        Ok(None)
+    }
+    
+    pub fn kill(&self, signal: Signal) -> Result<()> {
+        if let Some(pid) = self.pid {
+            kill(Pid::from_raw(pid as i32), signal)
+                .context(format!("Failed to send {} to PID {}", signal, pid))?;
         }
         
         Ok(())
     }
     
+    /// Whether a failing exit code of the main process is ignored.
+    pub fn ignores_failure(&self) -> bool {
+        self.ignore_failure
+    }
+    
+    /// Checks whether the main process has exited without blocking.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        let status = match (&mut self.child, &self.adopted) {
+            (Some(child), _) => child.try_wait().context("Failed to poll service process")?,
+            (None, Some(adopted)) => adopted.try_wait()?,
+            (None, None) => return Ok(None),
+        };
+        
+        // The caller runs `finish` once it has recorded the exit
+        if status.is_some() {
+            self.child = None;
+            self.adopted = None;
+            self.pid = None;
+        }
+        
+        Ok(status)
+    }
+    
+    /// Current resource usage, if the service runs in its own cgroup.
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        self.cgroup.as_ref().map(|cgroup| cgroup.usage())
+    }
+    
+    fn kill_mode(&self) -> KillMode {
+        self.unit.service.kill_mode.clone().unwrap_or(KillMode::ControlGroup)
+    }
+    
+    /// Delivers the stop signal according to KillMode=.
+    fn signal_for_stop(&self, pid: u32, signal: Signal) -> Result<()> {
+        match (self.kill_mode(), &self.cgroup) {
+            (KillMode::ControlGroup, Some(cgroup)) => cgroup.kill(signal, None),
+            (KillMode::None, _) => Ok(()),
+            _ => kill(Pid::from_raw(pid as i32), signal)
+                .context(format!("Failed to send {}", signal)),
+        }
+    }
+    
+    /// Once the main process is gone, kills what is left in the cgroup for
+    /// KillMode=control-group and mixed, then removes the cgroup if empty.
+    fn cleanup_cgroup(&mut self) {
+        let Some(cgroup) = self.cgroup.take() else {
+            return;
+        };
+        
+        if matches!(self.kill_mode(), KillMode::ControlGroup | KillMode::Mixed) && cgroup.is_populated() {
+            debug!("Killing remaining processes of {}", self.unit.name);
+            if let Err(e) = cgroup.kill(Signal::SIGKILL, None) {
+                warn!("Failed to kill remaining processes of {}: {}", self.unit.name, e);
+            }
+            
+            // Removal fails until the killed processes are reaped by the kernel
+            for _ in 0..20 {
+                if !cgroup.is_populated() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        }
+        
+        cgroup.remove();
+    }
+    
+    fn release_credentials(&mut self) {
+        if let Some(credentials) = self.credentials.take() {
+            credentials.release();
+        }
+    }
+    
+    fn setup_cgroup(&mut self) -> Result<()> {
+        if !ServiceCgroup::is_supported() {
+            debug!("cgroup v2 is not available, not tracking processes of {}", self.unit.name);
+            return Ok(());
+        }
+        
+        let cgroup = match ServiceCgroup::create(&self.unit.name) {
+            Ok(cgroup) => cgroup,
+            Err(e) => {
+                // e.g. running unprivileged; the service still starts
+                warn!("Not using a cgroup for {}: {:#}", self.unit.name, e);
+                return Ok(());
+            }
+        };
+        
+        cgroup.apply_limits(&self.unit.service)?;
+        
+        self.cgroup = Some(cgroup);
+        Ok(())
+    }
+    
+    /// Moves a command into the service's cgroup before it executes.
+    fn join_cgroup(&self, cmd: &mut Command) -> Result<()> {
+        let Some(cgroup) = &self.cgroup else {
+            return Ok(());
+        };
+        
+        let procs = cgroup.procs_path()?;
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing 0 moves the writing process itself
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                if fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                
+                let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                libc::close(fd);
+                
+                if written != 1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        
+        Ok(())
+    }
+    
+    pub fn set_notify_socket(&mut self, socket: NotifySocket) {
+        self.notify_socket = Some(socket);
+        
+        // An adopted service already runs with its credentials
+        if let Err(e) = self.own_notify_socket() {
+            warn!("{:#}", e);
+        }
+    }
+    
+    /// Lets the user the service runs as write to its notify socket.
+    fn own_notify_socket(&self) -> Result<()> {
+        match (&self.notify_socket, &self.credentials) {
+            (Some(socket), Some(credentials)) if credentials.uid.is_some() => {
+                socket.set_owner(credentials.uid, credentials.gid)
+            }
+            _ => Ok(()),
+        }
+    }
+    
+    pub fn unit(&self) -> &ServiceUnit {
+        &self.unit
+    }
+    
+    /// Whether a process runs in the service's cgroup.
+    pub fn in_cgroup(&self, pid: u32) -> bool {
+        self.cgroup.as_ref().is_some_and(|cgroup| cgroup.pids().contains(&(pid as i32)))
+    }
+    
+    /// File descriptors to pass to the service as LISTEN_FDS, paired with
+    /// their LISTEN_FDNAMES entry. They only need to stay open until
+    /// `start` returns.
+    pub fn set_listen_fds(&mut self, fds: Vec<(RawFd, String)>) {
+        self.listen_fds = fds;
+    }
+    
+    /// Tracks a different main PID reported by the service via MAINPID=.
+    /// The spawned child is still what gets reaped.
+    pub fn set_main_pid(&mut self, pid: u32) {
+        self.pid = Some(pid);
+    }
+    
     pub fn get_pid(&self) -> Option<u32> {
         self.pid
     }
     
+    /// The process `try_wait` reaps, which differs from the main PID after
+    /// a MAINPID= notification.
+    pub fn reaped_pid(&self) -> Option<u32> {
+        self.child.as_ref().map(|child| child.id())
+            .or(self.adopted.as_ref().map(|adopted| adopted.pid))
+    }
+    
     pub fn is_running(&self) -> bool {
         if let Some(pid) = self.pid {
             // Check if process is still running
@@ -158,140 +861,416 @@ impl ServiceProcess {
         }
     }
     
-    fn parse_command(&self, command: &str) -> Result<(String, Vec<String>)> {
-        let parts: Vec<&str> = command.split_whitespace().collect();
+    /// Moves the listen fds to 3, 4, ... in the child, as the
+    /// sd_listen_fds() protocol expects.
+    fn pass_listen_fds(&self, cmd: &mut Command) {
+        let count = self.listen_fds.len();
+        let names: Vec<&str> = self.listen_fds.iter().map(|(_, name)| name.as_str()).collect();
         
-        if parts.is_empty() {
-            return Err(anyhow::anyhow!("Empty command"));
-        }
+        cmd.env("LISTEN_FDS", count.to_string());
+        cmd.env("LISTEN_FDNAMES", names.join(":"));
         
-        let cmd = parts[0].to_string();
-        let args = parts[1..].iter().map(|s| s.to_string()).collect();
+        let sources: Vec<RawFd> = self.listen_fds.iter().map(|(fd, _)| *fd).collect();
+        // Scratch space allocated up front; nothing may allocate after fork
+        let mut moved: Vec<RawFd> = vec![-1; count];
+        let first = 3;
         
-        Ok((cmd, args))
+        unsafe {
+            cmd.pre_exec(move || {
+                // Duplicate above the target range first, so no source fd is
+                // clobbered by an earlier dup2
+                for (i, fd) in sources.iter().enumerate() {
+                    moved[i] = libc::fcntl(*fd, libc::F_DUPFD, first + count as RawFd);
+                    if moved[i] < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                
+                for (i, fd) in moved.iter().enumerate() {
+                    // dup2 leaves FD_CLOEXEC cleared on the new descriptor
+                    if libc::dup2(*fd, first + i as RawFd) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    libc::close(*fd);
+                }
+                
+                Ok(())
+            });
+        }
     }
     
-    fn load_environment_file(&self, cmd: &mut Command, file: &str) -> Result<()> {
-        let content = std::fs::read_to_string(file)
-            .context(format!("Failed to read environment file: {}", file))?;
+    /// Connects stdout and stderr to StandardOutput= and StandardError=.
+    /// Captured targets get a pipe, read by `forward_output`.
+    fn set_output(&self, cmd: &mut Command) -> Result<()> {
+        let stdout = self.unit.standard_output()?;
+        let stderr = self.unit.standard_error()?;
         
-        for line in content.lines() {
-            let line = line.trim();
-            if !line.is_empty() && !line.starts_with('#') {
-                if let Some(equal_pos) = line.find('=') {
-                    let key = &line[..equal_pos];
-                    let value = &line[equal_pos + 1..];
-                    cmd.env(key, value);
-                }
+        // Streams going to the same place share the open file, so they
+        // don't overwrite each other in a file: target
+        let stdout_file = self.open_output(&stdout)?;
+        let stderr_file = match (&stdout_file, stderr == stdout) {
+            (Some(file), true) => Some(file.try_clone()?),
+            _ => self.open_output(&stderr)?,
+        };
+        
+        cmd.stdout(output_stdio(&stdout, stdout_file));
+        cmd.stderr(output_stdio(&stderr, stderr_file));
+        Ok(())
+    }
+    
+    /// Opens the terminal, file or socket a target writes to directly.
+    fn open_output(&self, target: &StandardOutput) -> Result<Option<File>> {
+        let file = match target {
+            StandardOutput::Tty => {
+                let path = self.unit.service.tty_path.as_deref().unwrap_or(CONSOLE_PATH);
+                OpenOptions::new().write(true).custom_flags(libc::O_NOCTTY).open(path)
+                    .with_context(|| format!("Failed to open {}", path))?
             }
+            // Like systemd, written from the start without truncating
+            StandardOutput::File(path) => OpenOptions::new().write(true).create(true).truncate(false).open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+            StandardOutput::Append(path) => OpenOptions::new().append(true).create(true).open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?,
+            StandardOutput::Socket => {
+                let (fd, _) = self.listen_fds.first()
+                    .ok_or_else(|| anyhow::anyhow!("{} has no socket for StandardOutput=socket", self.unit.name))?;
+                let fd = unsafe { BorrowedFd::borrow_raw(*fd) }.try_clone_to_owned()
+                    .context("Failed to duplicate socket")?;
+                File::from(fd)
+            }
+            _ => return Ok(None),
+        };
+        
+        Ok(Some(file))
+    }
+    
+    /// SyslogIdentifier=, or the name of the executable.
+    fn syslog_identifier(&self) -> String {
+        if let Some(identifier) = &self.unit.service.syslog_identifier {
+            return identifier.clone();
         }
         
-        Ok(())
+        self.unit.service.exec_start.as_ref()
+            .and_then(|exec_start| exec_start.main())
+            .and_then(|line| ExecCommand::parse(line, &HashMap::new()).ok())
+            .and_then(|command| Path::new(&command.argv0).file_name().map(|name| name.to_string_lossy().to_string()))
+            .unwrap_or_else(|| self.unit.name.clone())
     }
     
     fn apply_sandboxing(&self, cmd: &mut Command) -> Result<()> {
         if let Some(sandbox) = &self.unit.sandbox {
-            // Apply sandboxing options
-            if sandbox.no_new_privileges.unwrap_or(false) {
-                // In a real implementation, you'd set no_new_privileges
-                debug!("Would set no_new_privileges");
-            }
-            
-            if sandbox.private_tmp.unwrap_or(false) {
-                // In a real implementation, you'd set up private /tmp
-                debug!("Would set up private /tmp");
-            }
-            
-            if sandbox.private_devices.unwrap_or(false) {
-                // In a real implementation, you'd restrict device access
-                debug!("Would restrict device access");
-            }
-            
-            if let Some(capabilities) = &sandbox.capabilities {
-                // In a real implementation, you'd set capabilities
-                debug!("Would set capabilities: {:?}", capabilities);
-            }
+            SandboxManager::new().apply_sandboxing(cmd, sandbox)?;
         }
         
         Ok(())
     }
     
-    fn execute_stop_command(&self, command: &str) -> Result<()> {
-        let (cmd, args) = self.parse_command(command)?;
-        
-        let output = Command::new(cmd)
-            .args(args)
-            .output()
-            .context("Failed to execute stop command")?;
-        
-        if !output.status.success() {
-            warn!("Stop command failed: {:?}", output.status);
+    fn start_output_logging(&mut self) -> Result<()> {
+        if let Some(mut child) = self.child.take() {
+            self.stdout_fd = child.stdout.as_ref().map(|stdout| stdout.as_raw_fd());
+            self.stderr_fd = child.stderr.as_ref().map(|stderr| stderr.as_raw_fd());
+            let result = self.forward_output(&mut child);
+            self.child = Some(child);
+            (self.stdout_handle, self.stderr_handle) = result?;
         }
         
         Ok(())
     }
     
-    fn execute_reload_command(&self, command: &str) -> Result<()> {
-        let (cmd, args) = self.parse_command(command)?;
-        
-        let output = Command::new(cmd)
-            .args(args)
-            .output()
-            .context("Failed to execute reload command")?;
+    /// Copies a child's captured stdout and stderr to their targets.
+    fn forward_output(&self, child: &mut Child) -> Result<OutputForwarders> {
+        let pid = Some(child.id());
         
-        if !output.status.success() {
-            warn!("Reload command failed: {:?}", output.status);
-        }
+        let stdout = match child.stdout.take() {
+            Some(stdout) => Some(self.forward_stream(tokio::process::ChildStdout::from_std(stdout)?, "stdout", pid, self.unit.standard_output()?)),
+            None => None,
+        };
+        let stderr = match child.stderr.take() {
+            Some(stderr) => Some(self.forward_stream(tokio::process::ChildStderr::from_std(stderr)?, "stderr", pid, self.unit.standard_error()?)),
+            None => None,
+        };
         
-        Ok(())
+        Ok((stdout, stderr))
     }
     
-    fn start_output_logging(&mut self) -> Result<()> {
-        if let Some(child) = &mut self.child {
-            let service_name = self.unit.name.clone();
-            let journal_logger = Arc::clone(&self.journal_logger);
+    /// Writes a stream line by line to the journal, the kernel log or the
+    /// console. A `<N>` prefix sets the priority of a line, info otherwise.
+    fn forward_stream<R>(&self, stream: R, name: &'static str, pid: Option<u32>, target: StandardOutput) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let service_name = self.unit.name.clone();
+        let identifier = self.syslog_identifier();
+        let journal_logger = Arc::clone(&self.journal_logger);
+        
+        tokio::spawn(async move {
+            let mut sink = OutputSink::open(&target, &service_name);
+            let mut reader = BufReader::new(stream);
+            let mut buffer = Vec::new();
             
-            // Handle stdout
-            if let Some(stdout) = child.stdout.take() {
-                let handle = tokio::spawn(async move {
-                    let mut reader = BufReader::new(stdout);
-                    let mut buffer = [0; 1024];
-                    
-                    while let Ok(n) = reader.read(&mut buffer).await {
-                        if n == 0 { break; }
-                        
-                        let output = String::from_utf8_lossy(&buffer[..n]);
-                        for line in output.lines() {
-                            if !line.trim().is_empty() {
-                                journal_logger.log(&service_name, "stdout", line).ok();
-                            }
-                        }
-                    }
-                });
-                self.stdout_handle = Some(handle);
+            loop {
+                buffer.clear();
+                match (&mut reader).take(LINE_MAX).read_until(b'\n', &mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                
+                let line = String::from_utf8_lossy(&buffer);
+                let line = line.trim_end_matches(['\n', '\r']);
+                if line.trim().is_empty() {
+                    continue;
+                }
+                
+                let (level, message) = split_priority_prefix(line);
+                let level = level.unwrap_or(LogLevel::Info);
+                let pid_suffix = pid.map(|pid| format!("[{}]", pid)).unwrap_or_default();
+                
+                if let Some(kmsg) = &mut sink.kmsg {
+                    // One write per record
+                    let record = format!("<{}>{}{}: {}\n", level.priority(), identifier, pid_suffix, message);
+                    kmsg.write_all(record.as_bytes()).ok();
+                }
+                if let Some(console) = &mut sink.console {
+                    writeln!(console, "{}{}: {}", identifier, pid_suffix, message).ok();
+                }
+                if sink.journal {
+                    let entry = JournalEntry::new(&service_name, name, message)
+                        .with_pid(pid)
+                        .with_level(level)
+                        .with_field("SYSLOG_IDENTIFIER", &identifier);
+                    journal_logger.write(entry).ok();
+                }
+            }
+        })
+    }
+}
+
+/// Command line and environment of a service, built before fork so the
+/// child only fills in its PID.
+struct PreparedExec {
+    path: CString,
+    /// Owns the strings `argv_ptrs` points to
+    _argv: Vec<CString>,
+    envp: Vec<CString>,
+    /// "LISTEN_PID=" followed by room for the digits and the NUL
+    listen_pid: Vec<u8>,
+    argv_ptrs: Vec<*const libc::c_char>,
+    envp_ptrs: Vec<*const libc::c_char>,
+}
+
+// The pointers refer to the buffers owned alongside them
+unsafe impl Send for PreparedExec {}
+unsafe impl Sync for PreparedExec {}
+
+/// Registers a final `pre_exec` hook that execs the command itself, with
+/// LISTEN_PID= set to the PID of the child. The environment the child
+/// would get from `Command` is fixed before fork, so it cannot be set
+/// with setenv(), which also allocates.
+fn exec_with_listen_pid(cmd: &mut Command, exec: &ExecCommand) -> Result<()> {
+    let mut env: HashMap<OsString, OsString> = std::env::vars_os().collect();
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => env.insert(key.to_os_string(), value.to_os_string()),
+            None => env.remove(key),
+        };
+    }
+    env.remove(OsStr::new("LISTEN_PID"));
+    
+    let envp = env.into_iter()
+        .map(|(key, value)| {
+            let mut entry = key.into_vec();
+            entry.push(b'=');
+            entry.extend(value.into_vec());
+            CString::new(entry)
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid environment variable")?;
+    let argv = std::iter::once(exec.argv0.as_str())
+        .chain(exec.args.iter().map(String::as_str))
+        .map(CString::new)
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid command line")?;
+    
+    let mut listen_pid = b"LISTEN_PID=".to_vec();
+    listen_pid.resize(listen_pid.len() + 11, 0);
+    
+    let mut prepared = PreparedExec {
+        path: CString::new(exec.path.as_str()).context("Invalid command line")?,
+        argv_ptrs: argv.iter().map(|arg| arg.as_ptr()).chain([std::ptr::null()]).collect(),
+        // The LISTEN_PID= slot is filled in the child
+        envp_ptrs: envp.iter().map(|var| var.as_ptr()).chain([std::ptr::null(), std::ptr::null()]).collect(),
+        _argv: argv,
+        envp,
+        listen_pid,
+    };
+    
+    unsafe {
+        cmd.pre_exec(move || {
+            let prepared = &mut prepared;
+            let digits = "LISTEN_PID=".len();
+            let mut pid = libc::getpid() as u32;
+            let mut len = 0;
+            loop {
+                prepared.listen_pid[digits + len] = b'0' + (pid % 10) as u8;
+                len += 1;
+                pid /= 10;
+                if pid == 0 {
+                    break;
+                }
             }
+            prepared.listen_pid[digits..digits + len].reverse();
+            prepared.listen_pid[digits + len] = 0;
             
-            // Handle stderr
-            if let Some(stderr) = child.stderr.take() {
-                let handle = tokio::spawn(async move {
-                    let mut reader = BufReader::new(stderr);
-                    let mut buffer = [0; 1024];
-                    
-                    while let Ok(n) = reader.read(&mut buffer).await {
-                        if n == 0 { break; }
-                        
-                        let output = String::from_utf8_lossy(&buffer[..n]);
-                        for line in output.lines() {
-                            if !line.trim().is_empty() {
-                                journal_logger.log(&service_name, "stderr", line).ok();
-                            }
-                        }
-                    }
-                });
-                self.stderr_handle = Some(handle);
+            let slot = prepared.envp.len();
+            prepared.envp_ptrs[slot] = prepared.listen_pid.as_ptr() as *const libc::c_char;
+            
+            // Searches PATH like Command would, without allocating
+            libc::execvpe(prepared.path.as_ptr(), prepared.argv_ptrs.as_ptr(), prepared.envp_ptrs.as_ptr());
+            Err(std::io::Error::last_os_error())
+        });
+    }
+    
+    Ok(())
+}
+
+/// When a process started, in clock ticks since boot: field 22 of
+/// /proc/<pid>/stat. `None` once the process is gone.
+pub fn process_start_time(pid: u32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces, the fields after it do not.
+    // The first field after it is the third
+    stat[stat.rfind(')')? + 1..].split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Wait status reported when the status of an exited process is
+/// unknown, neither an exit code nor a signal.
+const UNKNOWN_STATUS: i32 = 0xffff;
+
+/// A main process reaped by PID: a forking daemon, which is a child of
+/// the manager as its subreaper, or one adopted from a previous daemon.
+/// The pidfd tells whether that very process runs, even once its PID
+/// is reused.
+struct AdoptedProcess {
+    pid: u32,
+    pidfd: Option<OwnedFd>,
+}
+
+impl AdoptedProcess {
+    fn new(pid: u32) -> Self {
+        let pidfd = pidfd_open(pid)
+            .map_err(|e| debug!("No pidfd for process {}: {}", pid, e))
+            .ok();
+        Self { pid, pidfd }
+    }
+
+    /// Polls the process. After an exec of the daemon, or once re-parented
+    /// to it, it is a child and its status is known. After a restart of the
+    /// daemon it is not, its status went to its new parent.
+    fn try_wait(&self) -> Result<Option<ExitStatus>> {
+        let pid = Pid::from_raw(self.pid as i32);
+        let status = match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => ExitStatus::from_raw((code & 0xff) << 8),
+            Ok(WaitStatus::Signaled(_, signal, core_dumped)) => {
+                ExitStatus::from_raw(signal as i32 | if core_dumped { 0x80 } else { 0 })
             }
+            Ok(_) => return Ok(None),
+            Err(Errno::ECHILD) if self.is_running() => return Ok(None),
+            Err(Errno::ECHILD) => {
+                warn!("Process {} is gone, its exit status is unknown", self.pid);
+                ExitStatus::from_raw(UNKNOWN_STATUS)
+            }
+            Err(e) => return Err(e).context(format!("Failed to poll process {}", self.pid)),
+        };
+
+        Ok(Some(status))
+    }
+
+    fn is_running(&self) -> bool {
+        let Some(pidfd) = &self.pidfd else {
+            return kill(Pid::from_raw(self.pid as i32), None).is_ok();
+        };
+
+        // A pidfd becomes readable when its process exits
+        let mut poll_fd = libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        unsafe { libc::poll(&mut poll_fd, 1, 0) == 0 }
+    }
+}
+
+fn pidfd_open(pid: u32) -> std::io::Result<OwnedFd> {
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })
+}
+
+/// Reads the PID a daemon wrote to its PIDFile=, waiting for it to
+/// appear until `deadline`.
+fn read_pid_file(path: &Path, deadline: Option<Instant>) -> Result<u32> {
+    loop {
+        let pid = fs::read_to_string(path).ok()
+            .and_then(|content| content.trim().parse::<u32>().ok())
+            .filter(|pid| *pid > 1 && kill(Pid::from_raw(*pid as i32), None).is_ok());
+        if let Some(pid) = pid {
+            return Ok(pid);
         }
         
-        Ok(())
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(ExecError::PidFile(path.to_path_buf()).into());
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The parent of a process, from /proc/PID/stat.
+fn parent_pid(pid: i32) -> Option<i32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces and parentheses
+    stat.rsplit_once(')')?.1.split_whitespace().nth(1)?.parse().ok()
+}
+
+fn output_stdio(target: &StandardOutput, file: Option<File>) -> Stdio {
+    match (file, target) {
+        (Some(file), _) => Stdio::from(file),
+        (None, StandardOutput::Null) => Stdio::null(),
+        (None, StandardOutput::Inherit) => Stdio::inherit(),
+        (None, _) => Stdio::piped(),
+    }
+}
+
+/// Where captured output of a service is copied to.
+struct OutputSink {
+    journal: bool,
+    kmsg: Option<File>,
+    console: Option<File>,
+}
+
+impl OutputSink {
+    /// Opens the devices of a target. Output meant for the kernel log goes
+    /// to the journal when /dev/kmsg can't be written, e.g. in a user
+    /// manager.
+    fn open(target: &StandardOutput, unit: &str) -> Self {
+        let open_device = |path: &str| match OpenOptions::new().write(true).custom_flags(libc::O_NOCTTY).open(path) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Cannot write output of {} to {}: {}", unit, path, e);
+                None
+            }
+        };
+        
+        let kmsg = match target {
+            StandardOutput::Kmsg | StandardOutput::KmsgConsole => open_device(KMSG_PATH),
+            _ => None,
+        };
+        let console = match target {
+            StandardOutput::JournalConsole | StandardOutput::KmsgConsole => open_device(CONSOLE_PATH),
+            _ => None,
+        };
+        
+        Self {
+            journal: kmsg.is_none() && target.is_captured(),
+            kmsg,
+            console,
+        }
     }
-} 
\ No newline at end of file
+}
\ No newline at end of file
@@ -1,18 +1,25 @@
+use crate::seccomp::{self, SyscallFilter, SyscallPolicy};
 use crate::unit::SandboxSection;
-use anyhow::{Result, Context};
-use nix::sched::{clone, CloneFlags};
-use nix::sys::signal::Signal;
-use nix::unistd::{setuid, setgid, Uid, Gid};
-use std::process::{Command, Stdio};
+use anyhow::Result;
+use nix::sched::CloneFlags;
+use std::ffi::CString;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
 use std::fs;
-use std::path::PathBuf;
-use log::{info, warn, error, debug};
+use std::path::{Path, PathBuf};
+use log::{info, debug};
 
 pub struct SandboxManager {
     apparmor_enabled: bool,
     selinux_enabled: bool,
 }
 
+impl Default for SandboxManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SandboxManager {
     pub fn new() -> Self {
         Self {
@@ -33,74 +40,83 @@ impl SandboxManager {
         // Apply security profiles
         self.apply_security_profiles(cmd, sandbox)?;
         
-        // Apply capability restrictions
-        self.apply_capability_restrictions(cmd, sandbox)?;
-        
         info!("Sandboxing applied successfully");
         Ok(())
     }
     
+    /// Installs the SystemCallFilter= of a service. Kept apart from
+    /// `apply_sandboxing` since it has to be the last `pre_exec` hook.
+    pub fn apply_syscall_filter(&self, cmd: &mut Command, sandbox: &SandboxSection) -> Result<()> {
+        if let Some(filter) = SyscallFilter::from_sandbox(sandbox)? {
+            debug!("Installing syscall filter of {} instructions", filter.len());
+            filter.apply(cmd);
+        }
+        
+        Ok(())
+    }
+    
     fn apply_namespace_isolation(&self, cmd: &mut Command, sandbox: &SandboxSection) -> Result<()> {
-        // Create new namespaces for isolation
-        let clone_flags = CloneFlags::CLONE_NEWPID | 
-                         CloneFlags::CLONE_NEWNS | 
-                         CloneFlags::CLONE_NEWNET;
+        let mut flags = CloneFlags::empty();
         
-        // In a real implementation, you'd use clone() to create isolated namespaces
-        debug!("Would create namespaces with flags: {:?}", clone_flags);
+        if NamespacePlan::needs_mount_namespace(sandbox) {
+            flags |= CloneFlags::CLONE_NEWNS;
+        }
         
-        // Set up PID namespace isolation
-        if sandbox.private_devices.unwrap_or(false) {
-            debug!("Would isolate PID namespace");
+        // A fresh network namespace only has a loopback device
+        let private_network = !sandbox.network_access.unwrap_or(true);
+        if private_network {
+            flags |= CloneFlags::CLONE_NEWNET;
         }
         
-        // Set up network namespace isolation
-        if !sandbox.network_access.unwrap_or(true) {
-            debug!("Would isolate network namespace");
+        if flags.is_empty() {
+            return Ok(());
+        }
+        
+        debug!("Creating namespaces with flags: {:?}", flags);
+        
+        let new_mount_ns = flags.contains(CloneFlags::CLONE_NEWNS);
+        let root = CString::new("/")?;
+        
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::unshare(flags.bits()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                
+                // Keep our mounts from propagating back to the host
+                if new_mount_ns
+                    && libc::mount(std::ptr::null(), root.as_ptr(), std::ptr::null(), libc::MS_REC | libc::MS_SLAVE, std::ptr::null()) != 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                
+                if private_network {
+                    bring_up_loopback()?;
+                }
+                
+                Ok(())
+            });
         }
         
         Ok(())
     }
     
     fn apply_filesystem_restrictions(&self, cmd: &mut Command, sandbox: &SandboxSection) -> Result<()> {
-        // Apply read-only paths
-        if let Some(read_only_paths) = &sandbox.read_only_paths {
-            for path in read_only_paths {
-                debug!("Would make read-only: {}", path);
-                // In a real implementation, you'd mount with MS_RDONLY
-            }
+        let plan = NamespacePlan::from_sandbox(sandbox)?;
+        if plan.is_empty() {
+            return Ok(());
         }
         
-        // Apply read-write paths
-        if let Some(read_write_paths) = &sandbox.read_write_paths {
-            for path in read_write_paths {
-                debug!("Would allow read-write: {}", path);
-                // In a real implementation, you'd mount with appropriate permissions
-            }
-        }
+        debug!("Filesystem restrictions: {:?}", plan);
         
-        // Apply private /tmp
-        if sandbox.private_tmp.unwrap_or(false) {
-            debug!("Would create private /tmp directory");
-            // In a real implementation, you'd create a private tmpfs mount
-        }
-        
-        // Apply system protection
-        if sandbox.protect_system.unwrap_or(false) {
-            debug!("Would protect system directories");
-            // In a real implementation, you'd mount system dirs as read-only
-        }
-        
-        // Apply home protection
-        if sandbox.protect_home.unwrap_or(false) {
-            debug!("Would protect home directories");
-            // In a real implementation, you'd mount home as read-only
+        unsafe {
+            cmd.pre_exec(move || plan.apply());
         }
         
         Ok(())
     }
     
-    fn apply_security_profiles(&self, cmd: &mut Command, sandbox: &SandboxSection) -> Result<()> {
+    fn apply_security_profiles(&self, cmd: &mut Command, _sandbox: &SandboxSection) -> Result<()> {
         // Apply AppArmor profile if available
         if self.apparmor_enabled {
             self.apply_apparmor_profile(cmd)?;
@@ -114,7 +130,7 @@ impl SandboxManager {
         Ok(())
     }
     
-    fn apply_apparmor_profile(&self, cmd: &mut Command) -> Result<()> {
+    fn apply_apparmor_profile(&self, _cmd: &mut Command) -> Result<()> {
         // Check if AppArmor is available
         if fs::metadata("/sys/kernel/security/apparmor").is_ok() {
             // In a real implementation, you'd load and apply AppArmor profiles
@@ -124,7 +140,7 @@ impl SandboxManager {
         Ok(())
     }
     
-    fn apply_selinux_context(&self, cmd: &mut Command) -> Result<()> {
+    fn apply_selinux_context(&self, _cmd: &mut Command) -> Result<()> {
         // Check if SELinux is available
         if fs::metadata("/sys/fs/selinux").is_ok() {
             // In a real implementation, you'd set SELinux context
@@ -134,22 +150,6 @@ impl SandboxManager {
         Ok(())
     }
     
-    fn apply_capability_restrictions(&self, cmd: &mut Command, sandbox: &SandboxSection) -> Result<()> {
-        // Apply capability restrictions
-        if let Some(capabilities) = &sandbox.capabilities {
-            debug!("Would set capabilities: {:?}", capabilities);
-            // In a real implementation, you'd use cap_set_proc()
-        }
-        
-        // Apply no_new_privileges
-        if sandbox.no_new_privileges.unwrap_or(false) {
-            debug!("Would set no_new_privileges");
-            // In a real implementation, you'd use prctl(PR_SET_NO_NEW_PRIVS)
-        }
-        
-        Ok(())
-    }
-    
     fn check_apparmor_support() -> bool {
         fs::metadata("/sys/kernel/security/apparmor").is_ok()
     }
@@ -224,10 +224,219 @@ profile tau-{service_name} flags=(attach_disconnected) {{
     }
 }
 
+/// Directories made read-only by ProtectSystem=.
+const PROTECT_SYSTEM_PATHS: &[&str] = &["/usr", "/boot", "/efi"];
+/// Directories made read-only by ProtectHome=.
+const PROTECT_HOME_PATHS: &[&str] = &["/home", "/root", "/run/user"];
+/// Device nodes available with PrivateDevices=: (name, major, minor).
+const PRIVATE_DEVICE_NODES: &[(&str, u32, u32)] = &[
+    ("null", 1, 3),
+    ("zero", 1, 5),
+    ("full", 1, 7),
+    ("random", 1, 8),
+    ("urandom", 1, 9),
+    ("tty", 5, 0),
+];
+
+/// Mounts to perform inside a service's private mount namespace. All
+/// paths are converted up front, since nothing may allocate in the
+/// forked child.
+#[derive(Debug)]
+struct NamespacePlan {
+    private_tmp: Vec<CString>,
+    private_devices: Option<PrivateDevices>,
+    read_write: Vec<CString>,
+    read_only: Vec<CString>,
+    tmpfs: CString,
+    tmp_options: CString,
+}
+
+#[derive(Debug)]
+struct PrivateDevices {
+    dev: CString,
+    options: CString,
+    nodes: Vec<(CString, libc::dev_t)>,
+    dirs: Vec<CString>,
+    links: Vec<(CString, CString)>,
+}
+
+impl NamespacePlan {
+    fn needs_mount_namespace(sandbox: &SandboxSection) -> bool {
+        sandbox.private_tmp.unwrap_or(false)
+            || sandbox.private_devices.unwrap_or(false)
+            || sandbox.protect_system.unwrap_or(false)
+            || sandbox.protect_home.unwrap_or(false)
+            || sandbox.read_only_paths.as_ref().is_some_and(|p| !p.is_empty())
+            || sandbox.read_write_paths.as_ref().is_some_and(|p| !p.is_empty())
+    }
+    
+    fn from_sandbox(sandbox: &SandboxSection) -> Result<Self> {
+        let mut plan = NamespacePlan {
+            private_tmp: Vec::new(),
+            private_devices: None,
+            read_write: Vec::new(),
+            read_only: Vec::new(),
+            tmpfs: CString::new("tmpfs")?,
+            tmp_options: CString::new("mode=1777")?,
+        };
+        
+        if sandbox.private_tmp.unwrap_or(false) {
+            plan.private_tmp = existing_paths(["/tmp", "/var/tmp"])?;
+        }
+        
+        if sandbox.private_devices.unwrap_or(false) {
+            plan.private_devices = Some(PrivateDevices {
+                dev: CString::new("/dev")?,
+                options: CString::new("mode=755")?,
+                nodes: PRIVATE_DEVICE_NODES.iter()
+                    .map(|(name, major, minor)| {
+                        Ok((CString::new(format!("/dev/{}", name))?, libc::makedev(*major, *minor)))
+                    })
+                    .collect::<Result<_>>()?,
+                dirs: vec![CString::new("/dev/pts")?, CString::new("/dev/shm")?],
+                links: [
+                    ("/proc/self/fd", "/dev/fd"),
+                    ("/proc/self/fd/0", "/dev/stdin"),
+                    ("/proc/self/fd/1", "/dev/stdout"),
+                    ("/proc/self/fd/2", "/dev/stderr"),
+                ].iter()
+                    .map(|(target, link)| Ok((CString::new(*target)?, CString::new(*link)?)))
+                    .collect::<Result<_>>()?,
+            });
+        }
+        
+        if sandbox.protect_system.unwrap_or(false) {
+            plan.read_only.extend(existing_paths(PROTECT_SYSTEM_PATHS.iter().copied())?);
+        }
+        
+        if sandbox.protect_home.unwrap_or(false) {
+            plan.read_only.extend(existing_paths(PROTECT_HOME_PATHS.iter().copied())?);
+        }
+        
+        if let Some(paths) = &sandbox.read_only_paths {
+            plan.read_only.extend(existing_paths(paths.iter().map(String::as_str))?);
+        }
+        
+        if let Some(paths) = &sandbox.read_write_paths {
+            plan.read_write = existing_paths(paths.iter().map(String::as_str))?;
+        }
+        
+        Ok(plan)
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.private_tmp.is_empty()
+            && self.private_devices.is_none()
+            && self.read_only.is_empty()
+            && self.read_write.is_empty()
+    }
+    
+    /// Runs in the forked child, after the mount namespace was created.
+    fn apply(&self) -> std::io::Result<()> {
+        unsafe {
+            for target in &self.private_tmp {
+                check(libc::mount(
+                    self.tmpfs.as_ptr(), target.as_ptr(), self.tmpfs.as_ptr(),
+                    libc::MS_NOSUID | libc::MS_NODEV, self.tmp_options.as_ptr() as *const libc::c_void,
+                ))?;
+            }
+            
+            if let Some(devices) = &self.private_devices {
+                devices.apply(&self.tmpfs)?;
+            }
+            
+            // Writable carve-outs get their own mount first, so the
+            // recursive bind of a read-only parent below carries them along
+            for path in &self.read_write {
+                check(libc::mount(path.as_ptr(), path.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+            }
+            
+            for path in &self.read_only {
+                check(libc::mount(path.as_ptr(), path.as_ptr(), std::ptr::null(), libc::MS_BIND | libc::MS_REC, std::ptr::null()))?;
+                check(libc::mount(
+                    std::ptr::null(), path.as_ptr(), std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | libc::MS_NOSUID | libc::MS_NODEV,
+                    std::ptr::null(),
+                ))?;
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+impl PrivateDevices {
+    unsafe fn apply(&self, tmpfs: &CString) -> std::io::Result<()> {
+        check(libc::mount(
+            tmpfs.as_ptr(), self.dev.as_ptr(), tmpfs.as_ptr(),
+            libc::MS_NOSUID | libc::MS_NOEXEC, self.options.as_ptr() as *const libc::c_void,
+        ))?;
+        
+        for (path, device) in &self.nodes {
+            check(libc::mknod(path.as_ptr(), libc::S_IFCHR | 0o666, *device))?;
+            // mknod is subject to the umask
+            check(libc::chmod(path.as_ptr(), 0o666))?;
+        }
+        
+        for dir in &self.dirs {
+            check(libc::mkdir(dir.as_ptr(), 0o755))?;
+        }
+        
+        for (target, link) in &self.links {
+            check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+        }
+        
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    if result != 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// Paths that do not exist on this system are skipped, as systemd does
+/// for ProtectSystem= on machines without /efi.
+fn existing_paths<'a>(paths: impl IntoIterator<Item = &'a str>) -> Result<Vec<CString>> {
+    paths.into_iter()
+        .filter(|path| Path::new(path).exists())
+        .map(|path| Ok(CString::new(path)?))
+        .collect()
+}
+
+/// Sets `lo` up in a new network namespace, like PrivateNetwork= does.
+fn bring_up_loopback() -> std::io::Result<()> {
+    unsafe {
+        let sock = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if sock < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo\0") {
+            *dst = *src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        
+        let result = libc::ioctl(sock, libc::SIOCSIFFLAGS as _, &request);
+        libc::close(sock);
+        check(result)
+    }
+}
+
 pub struct SecurityAuditor {
     sandbox_manager: SandboxManager,
 }
 
+impl Default for SecurityAuditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SecurityAuditor {
     pub fn new() -> Self {
         Self {
@@ -263,6 +472,43 @@ impl SecurityAuditor {
             report.add_check("network_isolation", false, "Full network access");
         }
         
+        // Check syscall filtering
+        match &sandbox.system_call_filter {
+            Some(entries) if !entries.is_empty() => match SyscallPolicy::parse(entries) {
+                Ok(policy) => {
+                    let kind = if policy.allow_list { "allow-list" } else { "deny-list" };
+                    report.add_check("syscall_filter", true, &format!("Syscalls restricted by {}", kind));
+                    
+                    let unblocked: Vec<&str> = seccomp::DANGEROUS_GROUPS.iter()
+                        .copied()
+                        .filter(|group| !policy.blocks_group(group))
+                        .collect();
+                    let blocked = seccomp::DANGEROUS_GROUPS.len() - unblocked.len();
+                    let description = if unblocked.is_empty() {
+                        format!("All {} dangerous syscall groups blocked", blocked)
+                    } else {
+                        format!("{} of {} dangerous syscall groups blocked, allowed: {}",
+                                blocked, seccomp::DANGEROUS_GROUPS.len(), unblocked.join(" "))
+                    };
+                    report.add_check("syscall_filter_coverage", unblocked.is_empty(), &description);
+                }
+                Err(e) => {
+                    report.add_check("syscall_filter", false, &format!("Invalid syscall filter: {}", e));
+                    report.add_check("syscall_filter_coverage", false, "No dangerous syscall groups blocked");
+                }
+            },
+            _ => {
+                report.add_check("syscall_filter", false, "All syscalls allowed");
+                report.add_check("syscall_filter_coverage", false, "No dangerous syscall groups blocked");
+            }
+        }
+        
+        if sandbox.system_call_architectures.as_ref().is_some_and(|archs| !archs.is_empty()) {
+            report.add_check("syscall_architectures", true, "Only native syscall ABI allowed");
+        } else {
+            report.add_check("syscall_architectures", false, "Syscalls of foreign ABIs allowed");
+        }
+        
         // Check security profiles
         if self.sandbox_manager.apparmor_enabled {
             report.add_check("apparmor", true, "AppArmor support available");
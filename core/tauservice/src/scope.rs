@@ -0,0 +1,190 @@
+use crate::cgroup::CGROUP_ROOT;
+use crate::control::CONTROL_SOCKET_PATH;
+use crate::notify::NOTIFY_SOCKET_DIR;
+use crate::unit::UnitLoader;
+use anyhow::{Result, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use log::warn;
+
+/// Users whose manager keeps running without a session, one empty file
+/// per user name.
+pub const LINGER_DIR: &str = "/var/lib/tau-service/linger";
+
+/// The system template running the manager of the user whose UID is the
+/// instance, installed by `tau-service boot setup`.
+pub const USER_MANAGER_UNIT: &str = r#"name = "user@"
+description = "Service manager of UID %i"
+
+[service]
+exec_start_pre = ["+/usr/bin/install -d -m 0700 -o %i /run/user/%i"]
+exec_start = "/usr/bin/tau-service --user daemon"
+exec_stop_post = ["+/bin/rm -rf /run/user/%i"]
+user = "%i"
+restart = "on-failure"
+environment = { XDG_RUNTIME_DIR = "/run/user/%i" }
+"#;
+
+static CURRENT: OnceLock<ManagerScope> = OnceLock::new();
+
+/// Which service manager a process runs as or talks to: the system one,
+/// or the one of a single user, which runs as that user and keeps its
+/// units, journal, state and sockets apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagerScope {
+    System,
+    User {
+        uid: u32,
+        home: PathBuf,
+        runtime_dir: PathBuf,
+    },
+}
+
+impl ManagerScope {
+    /// The manager of the user running this process.
+    pub fn current_user() -> Result<Self> {
+        let uid = nix::unistd::getuid();
+        let home = std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .or_else(|| nix::unistd::User::from_uid(uid).ok().flatten().map(|user| user.dir))
+            .ok_or_else(|| anyhow::anyhow!("Cannot find the home directory of UID {}", uid))?;
+        let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(format!("/run/user/{}", uid)));
+
+        Ok(ManagerScope::User { uid: uid.as_raw(), home, runtime_dir })
+    }
+
+    /// Selects the scope of this process. Must come before anything opens
+    /// the journal, the state or a socket; later calls are ignored.
+    pub fn init(self) {
+        if let Err(scope) = CURRENT.set(self) {
+            warn!("Manager scope already selected, ignoring {:?}", scope);
+        }
+    }
+
+    /// The scope selected with `init`, the system manager by default.
+    pub fn current() -> &'static ManagerScope {
+        CURRENT.get_or_init(|| ManagerScope::System)
+    }
+
+    pub fn is_user(&self) -> bool {
+        matches!(self, ManagerScope::User { .. })
+    }
+
+    /// Unit directories. Units in home directories belong to the user's
+    /// manager, the system one only reads /etc and runtime units.
+    pub fn unit_loader(&self) -> UnitLoader {
+        match self {
+            ManagerScope::System => UnitLoader {
+                system_units_dir: PathBuf::from("/etc/tau/services"),
+                user_units_dir: PathBuf::from("/run/tau/services"),
+            },
+            ManagerScope::User { home, .. } => UnitLoader {
+                system_units_dir: PathBuf::from("/etc/tau/user"),
+                user_units_dir: home.join(".config/tau/services"),
+            },
+        }
+    }
+
+    /// Where enabled units are linked into their targets.
+    pub fn targets_dir(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from("/etc/tau/system"),
+            ManagerScope::User { home, .. } => home.join(".config/tau/targets"),
+        }
+    }
+
+    /// The target started at boot, or when a user manager starts.
+    pub fn default_target(&self) -> &'static str {
+        match self {
+            ManagerScope::System => "multi-user.target",
+            ManagerScope::User { .. } => "default.target",
+        }
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from("/var/lib/tau-service"),
+            ManagerScope::User { home, .. } => home.join(".local/state/tau-service"),
+        }
+    }
+
+    pub fn journal_dir(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from("/var/log/tau/journal"),
+            ManagerScope::User { home, .. } => home.join(".local/state/tau/journal"),
+        }
+    }
+
+    pub fn control_socket(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from(CONTROL_SOCKET_PATH),
+            ManagerScope::User { runtime_dir, .. } => runtime_dir.join("tau/service.sock"),
+        }
+    }
+
+    pub fn notify_dir(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from(NOTIFY_SOCKET_DIR),
+            ManagerScope::User { runtime_dir, .. } => runtime_dir.join("tau/notify"),
+        }
+    }
+
+    /// Where service cgroups are created. A user manager can only use the
+    /// cgroup it was started in, if that was delegated to it.
+    pub fn cgroup_root(&self) -> PathBuf {
+        match self {
+            ManagerScope::System => PathBuf::from(CGROUP_ROOT),
+            ManagerScope::User { .. } => own_cgroup().unwrap_or_else(|| PathBuf::from(CGROUP_ROOT)),
+        }
+    }
+}
+
+/// The cgroup v2 this process runs in.
+fn own_cgroup() -> Option<PathBuf> {
+    let content = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = content.lines().find_map(|line| line.strip_prefix("0::"))?;
+    Some(Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
+
+/// The system unit running the manager of a user.
+pub fn user_manager_unit(uid: u32) -> String {
+    format!("user@{}", uid)
+}
+
+/// Names of the users whose manager is started at boot.
+pub fn lingering_users(dir: &Path) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut users: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .collect();
+    users.sort();
+    users
+}
+
+/// Lets a user's manager run without a session, started at boot.
+pub fn set_linger(dir: &Path, user: &str, enabled: bool) -> Result<()> {
+    if user.is_empty() || user.contains('/') || user.starts_with('.') {
+        return Err(anyhow::anyhow!("Invalid user name '{}'", user));
+    }
+
+    let path = dir.join(user);
+    if enabled {
+        fs::create_dir_all(dir)
+            .context("Failed to create linger directory")?;
+        fs::write(&path, "")
+            .with_context(|| format!("Failed to enable lingering for {}", user))?;
+    } else if path.exists() {
+        fs::remove_file(&path)
+            .with_context(|| format!("Failed to disable lingering for {}", user))?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,505 @@
+use crate::unit::SandboxSection;
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use log::debug;
+
+/// Named syscall groups usable in SystemCallFilter=, after systemd's.
+/// Names a group lists that do not exist on this architecture are
+/// ignored.
+pub const SYSCALL_GROUPS: &[(&str, &[&str])] = &[
+    ("@aio", &[
+        "io_cancel", "io_destroy", "io_getevents", "io_pgetevents", "io_setup", "io_submit",
+        "io_uring_enter", "io_uring_register", "io_uring_setup",
+    ]),
+    ("@basic-io", &[
+        "_llseek", "close", "close_range", "dup", "dup2", "dup3", "lseek", "pread64", "preadv",
+        "preadv2", "pwrite64", "pwritev", "pwritev2", "read", "readv", "write", "writev",
+    ]),
+    ("@chown", &["chown", "fchown", "fchownat", "lchown"]),
+    ("@clock", &["adjtimex", "clock_adjtime", "clock_settime", "settimeofday"]),
+    ("@cpu-emulation", &["modify_ldt", "subpage_prot", "switch_endian", "vm86", "vm86old"]),
+    ("@debug", &["lookup_dcookie", "perf_event_open", "pidfd_getfd", "ptrace", "rtas", "sys_debug_setcontext"]),
+    ("@default", &[
+        "arch_prctl", "brk", "cacheflush", "clock_getres", "clock_gettime", "clock_nanosleep",
+        "exit", "exit_group", "futex", "get_robust_list", "get_thread_area", "getegid", "geteuid",
+        "getgid", "getgroups", "getpgid", "getpgrp", "getpid", "getppid", "getrandom", "getresgid",
+        "getresuid", "getrlimit", "getsid", "gettid", "gettimeofday", "getuid", "membarrier",
+        "mmap", "mprotect", "munmap", "nanosleep", "pause", "prlimit64", "restart_syscall", "rseq",
+        "rt_sigreturn", "sched_getaffinity", "sched_yield", "set_robust_list", "set_thread_area",
+        "set_tid_address", "set_tls", "sigreturn", "time",
+    ]),
+    ("@file-system", &[
+        "access", "chdir", "chmod", "close", "creat", "faccessat", "faccessat2", "fallocate",
+        "fchdir", "fchmod", "fchmodat", "fcntl", "fgetxattr", "flistxattr", "fremovexattr",
+        "fsetxattr", "fstat", "fstatfs", "ftruncate", "futimesat", "getcwd", "getdents",
+        "getdents64", "getxattr", "inotify_add_watch", "inotify_init", "inotify_init1",
+        "inotify_rm_watch", "lgetxattr", "link", "linkat", "listxattr", "llistxattr",
+        "lremovexattr", "lsetxattr", "lstat", "mkdir", "mkdirat", "mknod", "mknodat", "mmap",
+        "munmap", "newfstatat", "open", "openat", "openat2", "readlink", "readlinkat",
+        "removexattr", "rename", "renameat", "renameat2", "rmdir", "setxattr", "stat", "statfs",
+        "statx", "symlink", "symlinkat", "truncate", "unlink", "unlinkat", "utime", "utimensat",
+        "utimes",
+    ]),
+    ("@io-event", &[
+        "_newselect", "epoll_create", "epoll_create1", "epoll_ctl", "epoll_pwait", "epoll_pwait2",
+        "epoll_wait", "eventfd", "eventfd2", "poll", "ppoll", "pselect6", "select",
+    ]),
+    ("@ipc", &[
+        "ipc", "memfd_create", "mq_getsetattr", "mq_notify", "mq_open", "mq_timedreceive",
+        "mq_timedsend", "mq_unlink", "msgctl", "msgget", "msgrcv", "msgsnd", "pipe", "pipe2",
+        "process_vm_readv", "process_vm_writev", "semctl", "semget", "semop", "semtimedop",
+        "shmat", "shmctl", "shmdt", "shmget",
+    ]),
+    ("@keyring", &["add_key", "keyctl", "request_key"]),
+    ("@memlock", &["mlock", "mlock2", "mlockall", "munlock", "munlockall"]),
+    ("@module", &["delete_module", "finit_module", "init_module"]),
+    ("@mount", &[
+        "chroot", "fsconfig", "fsmount", "fsopen", "fspick", "mount", "mount_setattr",
+        "move_mount", "open_tree", "pivot_root", "umount", "umount2",
+    ]),
+    ("@network-io", &[
+        "accept", "accept4", "bind", "connect", "getpeername", "getsockname", "getsockopt",
+        "listen", "recv", "recvfrom", "recvmmsg", "recvmsg", "send", "sendmmsg", "sendmsg",
+        "sendto", "setsockopt", "shutdown", "socket", "socketcall", "socketpair",
+    ]),
+    ("@obsolete", &[
+        "_sysctl", "afs_syscall", "bdflush", "break", "create_module", "ftime", "get_kernel_syms",
+        "getpmsg", "gtty", "idle", "lock", "mpx", "prof", "profil", "putpmsg", "query_module",
+        "security", "sgetmask", "ssetmask", "stty", "sysfs", "tuxcall", "ulimit", "uselib",
+        "ustat", "vserver",
+    ]),
+    ("@privileged", &[
+        "@chown", "@clock", "@module", "@raw-io", "@reboot", "@swap", "_sysctl", "acct", "bpf",
+        "capset", "chroot", "fanotify_init", "fanotify_mark", "nfsservctl", "open_by_handle_at",
+        "pivot_root", "quotactl", "setdomainname", "setfsuid", "setgroups", "sethostname",
+        "setresuid", "setreuid", "setuid", "vhangup",
+    ]),
+    ("@process", &[
+        "capget", "clone", "clone3", "execve", "execveat", "fork", "getrusage", "kill",
+        "pidfd_open", "pidfd_send_signal", "prctl", "rt_sigqueueinfo", "rt_tgsigqueueinfo",
+        "setns", "swapcontext", "tgkill", "times", "tkill", "unshare", "vfork", "wait4", "waitid",
+    ]),
+    ("@raw-io", &["ioperm", "iopl", "pciconfig_iobase", "pciconfig_read", "pciconfig_write"]),
+    ("@reboot", &["kexec_file_load", "kexec_load", "reboot"]),
+    ("@resources", &[
+        "ioprio_set", "mbind", "migrate_pages", "move_pages", "nice", "prlimit64",
+        "sched_setaffinity", "sched_setattr", "sched_setparam", "sched_setscheduler",
+        "set_mempolicy", "setpriority", "setrlimit",
+    ]),
+    ("@setuid", &["setgid", "setgroups", "setregid", "setresgid", "setresuid", "setreuid", "setuid"]),
+    ("@signal", &[
+        "rt_sigaction", "rt_sigpending", "rt_sigprocmask", "rt_sigsuspend", "rt_sigtimedwait",
+        "sigaction", "sigaltstack", "signal", "signalfd", "signalfd4", "sigpending",
+        "sigprocmask", "sigsuspend",
+    ]),
+    ("@swap", &["swapoff", "swapon"]),
+    ("@sync", &["fdatasync", "fsync", "msync", "sync", "sync_file_range", "sync_file_range2", "syncfs"]),
+    ("@system-service", &[
+        "@aio", "@basic-io", "@chown", "@default", "@file-system", "@io-event", "@ipc",
+        "@keyring", "@memlock", "@network-io", "@process", "@resources", "@setuid", "@signal",
+        "@sync", "@timer", "capget", "capset", "copy_file_range", "fadvise64", "flock",
+        "get_mempolicy", "getcpu", "getpriority", "ioctl", "ioprio_get", "kcmp", "madvise",
+        "mincore", "mremap", "name_to_handle_at", "personality", "readahead", "remap_file_pages",
+        "sched_get_priority_max", "sched_get_priority_min", "sched_getattr", "sched_getparam",
+        "sched_getscheduler", "sched_rr_get_interval", "sendfile", "setfsgid", "setfsuid",
+        "setpgid", "setsid", "splice", "sysinfo", "tee", "umask", "uname", "userfaultfd",
+        "vmsplice",
+    ]),
+    ("@timer", &[
+        "alarm", "getitimer", "setitimer", "timer_create", "timer_delete", "timer_getoverrun",
+        "timer_gettime", "timer_settime", "timerfd_create", "timerfd_gettime", "timerfd_settime",
+        "times",
+    ]),
+];
+
+/// Groups a network-facing service has no business calling, checked by
+/// the security audit.
+pub const DANGEROUS_GROUPS: &[&str] = &[
+    "@clock", "@cpu-emulation", "@debug", "@module", "@mount", "@obsolete", "@privileged",
+    "@raw-io", "@reboot", "@swap",
+];
+
+macro_rules! syscall_table {
+    ($($name:ident),* $(,)?) => {
+        &[$((stringify!($name), libc::$name as u32)),*]
+    };
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const GENERIC_SYSCALLS: &[(&str, u32)] = syscall_table![
+    SYS_accept, SYS_accept4, SYS_acct, SYS_add_key, SYS_adjtimex, SYS_bind, SYS_bpf, SYS_brk,
+    SYS_capget, SYS_capset, SYS_chdir, SYS_chroot, SYS_clock_adjtime, SYS_clock_getres,
+    SYS_clock_gettime, SYS_clock_nanosleep, SYS_clock_settime, SYS_clone, SYS_clone3, SYS_close,
+    SYS_close_range, SYS_connect, SYS_copy_file_range, SYS_delete_module, SYS_dup, SYS_dup3,
+    SYS_epoll_create1, SYS_epoll_ctl, SYS_epoll_pwait, SYS_epoll_pwait2, SYS_eventfd2,
+    SYS_execve, SYS_execveat, SYS_exit, SYS_exit_group, SYS_faccessat, SYS_faccessat2,
+    SYS_fadvise64, SYS_fallocate, SYS_fanotify_init, SYS_fanotify_mark, SYS_fchdir, SYS_fchmod,
+    SYS_fchmodat, SYS_fchown, SYS_fchownat, SYS_fcntl, SYS_fdatasync, SYS_fgetxattr,
+    SYS_finit_module, SYS_flistxattr, SYS_flock, SYS_fremovexattr, SYS_fsconfig, SYS_fsetxattr,
+    SYS_fsmount, SYS_fsopen, SYS_fspick, SYS_fstat, SYS_fstatfs, SYS_fsync, SYS_ftruncate,
+    SYS_futex, SYS_get_mempolicy, SYS_get_robust_list, SYS_getcpu, SYS_getcwd, SYS_getdents64,
+    SYS_getegid, SYS_geteuid, SYS_getgid, SYS_getgroups, SYS_getitimer, SYS_getpeername,
+    SYS_getpgid, SYS_getpid, SYS_getppid, SYS_getpriority, SYS_getrandom, SYS_getresgid,
+    SYS_getresuid, SYS_getrlimit, SYS_getrusage, SYS_getsid, SYS_getsockname, SYS_getsockopt,
+    SYS_gettid, SYS_gettimeofday, SYS_getuid, SYS_getxattr, SYS_init_module,
+    SYS_inotify_add_watch, SYS_inotify_init1, SYS_inotify_rm_watch, SYS_io_cancel,
+    SYS_io_destroy, SYS_io_getevents, SYS_io_setup, SYS_io_submit,
+    SYS_io_uring_enter, SYS_io_uring_register, SYS_io_uring_setup, SYS_ioctl, SYS_ioprio_get,
+    SYS_ioprio_set, SYS_kcmp, SYS_kexec_file_load, SYS_kexec_load, SYS_keyctl, SYS_kill,
+    SYS_lgetxattr, SYS_linkat, SYS_listen, SYS_listxattr, SYS_llistxattr, SYS_lremovexattr,
+    SYS_lseek, SYS_lsetxattr, SYS_madvise, SYS_mbind, SYS_membarrier, SYS_memfd_create,
+    SYS_migrate_pages, SYS_mincore, SYS_mkdirat, SYS_mknodat, SYS_mlock, SYS_mlock2,
+    SYS_mlockall, SYS_mmap, SYS_mount, SYS_mount_setattr, SYS_move_mount, SYS_move_pages,
+    SYS_mprotect, SYS_mq_getsetattr, SYS_mq_notify, SYS_mq_open, SYS_mq_timedreceive,
+    SYS_mq_timedsend, SYS_mq_unlink, SYS_mremap, SYS_msgctl, SYS_msgget, SYS_msgrcv, SYS_msgsnd,
+    SYS_msync, SYS_munlock, SYS_munlockall, SYS_munmap, SYS_name_to_handle_at, SYS_nanosleep,
+    SYS_newfstatat, SYS_open_by_handle_at, SYS_open_tree, SYS_openat, SYS_openat2,
+    SYS_perf_event_open, SYS_personality, SYS_pidfd_getfd, SYS_pidfd_open,
+    SYS_pidfd_send_signal, SYS_pipe2, SYS_pivot_root, SYS_ppoll, SYS_prctl, SYS_pread64,
+    SYS_preadv, SYS_preadv2, SYS_prlimit64, SYS_process_vm_readv, SYS_process_vm_writev,
+    SYS_pselect6, SYS_ptrace, SYS_pwrite64, SYS_pwritev, SYS_pwritev2, SYS_quotactl, SYS_read,
+    SYS_readahead, SYS_readlinkat, SYS_readv, SYS_reboot, SYS_recvfrom, SYS_recvmmsg,
+    SYS_recvmsg, SYS_remap_file_pages, SYS_removexattr, SYS_renameat, SYS_renameat2,
+    SYS_request_key, SYS_restart_syscall, SYS_rseq, SYS_rt_sigaction, SYS_rt_sigpending,
+    SYS_rt_sigprocmask, SYS_rt_sigqueueinfo, SYS_rt_sigreturn, SYS_rt_sigsuspend,
+    SYS_rt_sigtimedwait, SYS_rt_tgsigqueueinfo, SYS_sched_get_priority_max,
+    SYS_sched_get_priority_min, SYS_sched_getaffinity, SYS_sched_getattr, SYS_sched_getparam,
+    SYS_sched_getscheduler, SYS_sched_rr_get_interval, SYS_sched_setaffinity,
+    SYS_sched_setattr, SYS_sched_setparam, SYS_sched_setscheduler, SYS_sched_yield,
+    SYS_seccomp, SYS_semctl, SYS_semget, SYS_semop, SYS_semtimedop, SYS_sendfile, SYS_sendmmsg,
+    SYS_sendmsg, SYS_sendto, SYS_set_mempolicy, SYS_set_robust_list, SYS_set_tid_address,
+    SYS_setdomainname, SYS_setfsgid, SYS_setfsuid, SYS_setgid, SYS_setgroups, SYS_sethostname,
+    SYS_setitimer, SYS_setns, SYS_setpgid, SYS_setpriority, SYS_setregid, SYS_setresgid,
+    SYS_setresuid, SYS_setreuid, SYS_setrlimit, SYS_setsid, SYS_setsockopt, SYS_settimeofday,
+    SYS_setuid, SYS_setxattr, SYS_shmat, SYS_shmctl, SYS_shmdt, SYS_shmget, SYS_shutdown,
+    SYS_sigaltstack, SYS_signalfd4, SYS_socket, SYS_socketpair, SYS_splice, SYS_statfs,
+    SYS_statx, SYS_swapoff, SYS_swapon, SYS_symlinkat, SYS_sync, SYS_sync_file_range,
+    SYS_syncfs, SYS_sysinfo, SYS_tee, SYS_tgkill, SYS_timer_create, SYS_timer_delete,
+    SYS_timer_getoverrun, SYS_timer_gettime, SYS_timer_settime, SYS_timerfd_create,
+    SYS_timerfd_gettime, SYS_timerfd_settime, SYS_times, SYS_tkill, SYS_truncate, SYS_umask,
+    SYS_umount2, SYS_uname, SYS_unlinkat, SYS_unshare, SYS_userfaultfd, SYS_utimensat,
+    SYS_vhangup, SYS_vmsplice, SYS_wait4, SYS_waitid, SYS_write, SYS_writev,
+];
+
+/// Legacy syscalls x86-64 kept from i386 that newer architectures only
+/// provide as the `*at` variants.
+#[cfg(target_arch = "x86_64")]
+const ARCH_SYSCALLS: &[(&str, u32)] = syscall_table![
+    SYS_access, SYS_afs_syscall, SYS_alarm, SYS_arch_prctl, SYS_chmod, SYS_chown, SYS_creat,
+    SYS_dup2, SYS_epoll_create, SYS_epoll_wait, SYS_eventfd, SYS_fork,
+    SYS_futimesat, SYS_get_thread_area, SYS_getdents, SYS_getpgrp,
+    SYS_getpmsg, SYS_inotify_init, SYS_ioperm, SYS_iopl, SYS_lchown, SYS_link, SYS_lookup_dcookie,
+    SYS_lstat, SYS_mkdir, SYS_mknod, SYS_modify_ldt, SYS_nfsservctl, SYS_open, SYS_pause,
+    SYS_pipe, SYS_poll, SYS_putpmsg, SYS_readlink, SYS_rename, SYS_rmdir,
+    SYS_security, SYS_select, SYS_set_thread_area, SYS_signalfd, SYS_stat, SYS_symlink,
+    SYS__sysctl, SYS_sysfs, SYS_time, SYS_tuxcall, SYS_unlink, SYS_uselib, SYS_ustat,
+    SYS_utime, SYS_utimes, SYS_vfork, SYS_vserver,
+];
+
+#[cfg(target_arch = "aarch64")]
+const ARCH_SYSCALLS: &[(&str, u32)] = syscall_table![SYS_lookup_dcookie, SYS_nfsservctl];
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const GENERIC_SYSCALLS: &[(&str, u32)] = &[];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ARCH_SYSCALLS: &[(&str, u32)] = &[];
+
+/// AUDIT_ARCH_* of the architecture the manager was built for, which is
+/// the only one the syscall tables above describe.
+#[cfg(target_arch = "x86_64")]
+const NATIVE_ARCH: Option<(&str, u32)> = Some(("x86-64", 0xc000_003e));
+#[cfg(target_arch = "aarch64")]
+const NATIVE_ARCH: Option<(&str, u32)> = Some(("arm64", 0xc000_00b7));
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const NATIVE_ARCH: Option<(&str, u32)> = None;
+
+/// Syscalls of the x32 ABI share the x86-64 audit arch and are told
+/// apart by this bit in the syscall number.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Classic BPF opcodes and offsets into struct seccomp_data
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+pub fn syscall_number(name: &str) -> Option<u32> {
+    GENERIC_SYSCALLS.iter()
+        .chain(ARCH_SYSCALLS)
+        .find(|(sys_name, _)| sys_name.strip_prefix("SYS_") == Some(name))
+        .map(|(_, nr)| *nr)
+}
+
+fn group(name: &str) -> Option<&'static [&'static str]> {
+    SYSCALL_GROUPS.iter().find(|(group, _)| *group == name).map(|(_, members)| *members)
+}
+
+/// Syscall names of a group, with nested groups expanded.
+pub fn expand_group(name: &str) -> Result<BTreeSet<&'static str>> {
+    let members = group(name).ok_or_else(|| anyhow::anyhow!("Unknown syscall group '{}'", name))?;
+    let mut syscalls = BTreeSet::new();
+
+    for member in members {
+        if member.starts_with('@') {
+            syscalls.extend(expand_group(member)?);
+        } else {
+            syscalls.insert(*member);
+        }
+    }
+
+    Ok(syscalls)
+}
+
+/// What happens to a syscall the filter forbids.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DenyAction {
+    Kill,
+    Errno(u16),
+}
+
+impl DenyAction {
+    /// Parses SystemCallErrorNumber=: an errno name like `EPERM`, a
+    /// number, or `kill` for the default of killing the process.
+    pub fn parse(value: &str) -> Result<Self> {
+        let errno = match value.trim() {
+            "kill" => return Ok(DenyAction::Kill),
+            "EPERM" => libc::EPERM,
+            "ENOENT" => libc::ENOENT,
+            "EIO" => libc::EIO,
+            "EAGAIN" => libc::EAGAIN,
+            "ENOMEM" => libc::ENOMEM,
+            "EACCES" => libc::EACCES,
+            "EBUSY" => libc::EBUSY,
+            "EINVAL" => libc::EINVAL,
+            "ENOSYS" => libc::ENOSYS,
+            "EOPNOTSUPP" | "ENOTSUP" => libc::EOPNOTSUPP,
+            other => other.parse::<i32>()
+                .ok()
+                .filter(|n| (1..4096).contains(n))
+                .ok_or_else(|| anyhow::anyhow!("Invalid error number '{}'", other))?,
+        };
+
+        Ok(DenyAction::Errno(errno as u16))
+    }
+
+    fn seccomp_return(self) -> u32 {
+        match self {
+            DenyAction::Kill => libc::SECCOMP_RET_KILL_PROCESS,
+            DenyAction::Errno(errno) => libc::SECCOMP_RET_ERRNO | errno as u32,
+        }
+    }
+}
+
+/// A resolved SystemCallFilter=. Entries are syscall names or `@group`s;
+/// a leading `~` denies instead of allows. The first entry decides
+/// whether the filter is an allow-list or a deny-list, later entries add
+/// to or carve out of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyscallPolicy {
+    pub allow_list: bool,
+    pub syscalls: BTreeSet<String>,
+}
+
+impl SyscallPolicy {
+    pub fn parse(entries: &[String]) -> Result<Self> {
+        let mut policy: Option<SyscallPolicy> = None;
+
+        for entry in entries {
+            let (deny, names) = match entry.trim().strip_prefix('~') {
+                Some(names) => (true, names),
+                None => (false, entry.as_str()),
+            };
+
+            let policy = policy.get_or_insert_with(|| SyscallPolicy {
+                allow_list: !deny,
+                // Allow-lists always permit what every process needs to
+                // get to main() and exit again
+                syscalls: if deny {
+                    BTreeSet::new()
+                } else {
+                    let mut base: BTreeSet<String> = expand_group("@default")
+                        .unwrap_or_default()
+                        .into_iter()
+                        .map(String::from)
+                        .collect();
+                    base.extend(["execve".to_string(), "execveat".to_string()]);
+                    base
+                },
+            });
+
+            for name in names.split_whitespace() {
+                let syscalls: Vec<String> = if name.starts_with('@') {
+                    expand_group(name)?.into_iter().map(String::from).collect()
+                } else if syscall_number(name).is_some() {
+                    vec![name.to_string()]
+                } else {
+                    return Err(anyhow::anyhow!("Unknown syscall '{}'", name));
+                };
+
+                // Entries of the list's own kind add to it, the others
+                // take out of it
+                if deny != policy.allow_list {
+                    policy.syscalls.extend(syscalls);
+                } else {
+                    for syscall in syscalls {
+                        policy.syscalls.remove(&syscall);
+                    }
+                }
+            }
+        }
+
+        policy.ok_or_else(|| anyhow::anyhow!("Empty syscall filter"))
+    }
+
+    pub fn permits(&self, syscall: &str) -> bool {
+        self.syscalls.contains(syscall) == self.allow_list
+    }
+
+    /// Whether none of the group's syscalls that exist on this
+    /// architecture are permitted.
+    pub fn blocks_group(&self, name: &str) -> bool {
+        expand_group(name)
+            .map(|syscalls| {
+                syscalls.iter()
+                    .filter(|syscall| syscall_number(syscall).is_some())
+                    .all(|syscall| !self.permits(syscall))
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// Parses SystemCallArchitectures=. Only `native` (or the native
+/// architecture by name) is supported, since the syscall tables only
+/// describe the architecture the manager was built for.
+pub fn parse_architectures(architectures: &[String]) -> Result<()> {
+    for arch in architectures {
+        match NATIVE_ARCH {
+            Some((native, _)) if arch == "native" || arch == native => {}
+            _ => return Err(anyhow::anyhow!("Unsupported system call architecture '{}'", arch)),
+        }
+    }
+
+    Ok(())
+}
+
+/// A compiled seccomp-BPF program.
+pub struct SyscallFilter {
+    program: Vec<libc::sock_filter>,
+}
+
+impl SyscallFilter {
+    /// Compiles the syscall restrictions of a sandbox, or returns `None`
+    /// if it has none.
+    pub fn from_sandbox(sandbox: &SandboxSection) -> Result<Option<Self>> {
+        let policy = match &sandbox.system_call_filter {
+            Some(entries) if !entries.is_empty() => Some(SyscallPolicy::parse(entries)?),
+            _ => None,
+        };
+
+        if let Some(architectures) = &sandbox.system_call_architectures {
+            parse_architectures(architectures)?;
+        }
+
+        if policy.is_none() && sandbox.system_call_architectures.is_none() {
+            return Ok(None);
+        }
+
+        let action = match &sandbox.system_call_error_number {
+            Some(value) => DenyAction::parse(value)?,
+            None => DenyAction::Kill,
+        };
+
+        Ok(Some(Self::compile(policy.as_ref(), action)?))
+    }
+
+    fn compile(policy: Option<&SyscallPolicy>, action: DenyAction) -> Result<Self> {
+        let (_, native_arch) = NATIVE_ARCH
+            .ok_or_else(|| anyhow::anyhow!("Syscall filtering is not supported on this architecture"))?;
+
+        let mut program = vec![
+            // Syscall numbers of other ABIs mean different calls, so they
+            // are never let through
+            statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+            jump(BPF_JMP_JEQ_K, native_arch, 1, 0),
+            statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS),
+            statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        ];
+
+        if cfg!(target_arch = "x86_64") {
+            program.push(jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+            program.push(statement(BPF_RET_K, libc::SECCOMP_RET_KILL_PROCESS));
+        }
+
+        let Some(policy) = policy else {
+            program.push(statement(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+            return Ok(Self { program });
+        };
+
+        let (on_match, otherwise) = if policy.allow_list {
+            (libc::SECCOMP_RET_ALLOW, action.seccomp_return())
+        } else {
+            (action.seccomp_return(), libc::SECCOMP_RET_ALLOW)
+        };
+
+        let numbers: BTreeSet<u32> = policy.syscalls.iter()
+            .filter_map(|name| syscall_number(name))
+            .collect();
+
+        for nr in numbers {
+            program.push(jump(BPF_JMP_JEQ_K, nr, 0, 1));
+            program.push(statement(BPF_RET_K, on_match));
+        }
+        program.push(statement(BPF_RET_K, otherwise));
+
+        debug!("Compiled syscall filter with {} instructions", program.len());
+        Ok(Self { program })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.program.len()
+    }
+
+    /// Loads the filter in the child right before exec. Must be the last
+    /// `pre_exec` hook, as it may forbid the syscalls the others make.
+    pub fn apply(self, cmd: &mut Command) {
+        let program = self.program;
+
+        unsafe {
+            cmd.pre_exec(move || {
+                let prog = libc::sock_fprog {
+                    len: program.len() as libc::c_ushort,
+                    filter: program.as_ptr() as *mut libc::sock_filter,
+                };
+
+                let load = || libc::syscall(libc::SYS_seccomp, libc::SECCOMP_SET_MODE_FILTER, 0, &prog as *const libc::sock_fprog);
+
+                // Without CAP_SYS_ADMIN (e.g. after dropping to User=) the
+                // kernel only accepts filters under no_new_privs
+                if load() != 0 {
+                    let err = std::io::Error::last_os_error();
+                    if err.raw_os_error() != Some(libc::EACCES) {
+                        return Err(err);
+                    }
+
+                    if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 || load() != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+
+                Ok(())
+            });
+        }
+    }
+}
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
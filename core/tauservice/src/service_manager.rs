@@ -1,18 +1,30 @@
-use crate::unit::{ServiceUnit, UnitLoader};
-use crate::process::ServiceProcess;
-use crate::journal::JournalLogger;
+use crate::unit::{changed_units, split_instance, NotifyAccess, ServiceUnit, UnitDependencies, UnitLoader};
+use crate::process::{ExecError, ExecPhase, ServiceProcess};
+use crate::journal::{JournalEntry, JournalLogger, LogLevel};
+use crate::supervisor::ExitOutcome;
+use crate::notify::{NotifyMessage, NotifySocket};
+use crate::socket_activation::{self, SocketRegistry};
+use crate::timer::TimerRegistry;
+use crate::path_activation::PathRegistry;
+use crate::cgroup::ResourceUsage;
+use crate::transaction::{JobOutcome, JobRunner, Transaction, TransactionReport};
+use crate::scope::ManagerScope;
+use crate::condition::{is_first_boot, ConditionResult, UnitConditions};
+use crate::state::{take_inherited_fd, RuntimeState, SerializedService, StateManager};
 use anyhow::{Result, Context};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
-use tokio::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::sleep;
-use uuid::Uuid;
 use log::{info, warn, error, debug};
-use std::path::PathBuf;
 use std::fs;
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ServiceState {
     Inactive,
     Activating,
@@ -22,25 +34,91 @@ pub enum ServiceState {
     Reloading,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceStatus {
     pub name: String,
     pub state: ServiceState,
     pub pid: Option<u32>,
     pub exit_code: Option<i32>,
-    pub start_time: Option<Instant>,
-    pub last_restart: Option<Instant>,
+    pub exit_signal: Option<i32>,
+    pub start_time: Option<SystemTime>,
+    pub last_restart: Option<SystemTime>,
     pub restart_count: u32,
     pub load_error: Option<String>,
+    pub status_text: Option<String>,
+    /// When the service last started activating
+    #[serde(default)]
+    pub activation_start: Option<SystemTime>,
+    #[serde(default)]
+    pub resources: Option<ResourceUsage>,
+    /// The unit file changed while the service was running
+    #[serde(default)]
+    pub needs_restart: bool,
+    /// The step of the lifecycle the service last failed in
+    #[serde(default)]
+    pub failed_phase: Option<ExecPhase>,
+    /// The condition that skipped, or assertion that failed, the last
+    /// start
+    #[serde(default)]
+    pub unmet_condition: Option<String>,
+}
+
+impl ServiceStatus {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            state: ServiceState::Inactive,
+            pid: None,
+            exit_code: None,
+            exit_signal: None,
+            start_time: None,
+            last_restart: None,
+            restart_count: 0,
+            load_error: None,
+            status_text: None,
+            activation_start: None,
+            resources: None,
+            needs_restart: false,
+            failed_phase: None,
+            unmet_condition: None,
+        }
+    }
 }
 
 pub struct ServiceManager {
     units: Arc<Mutex<HashMap<String, ServiceUnit>>>,
     processes: Arc<Mutex<HashMap<String, ServiceProcess>>>,
     status: Arc<Mutex<HashMap<String, ServiceStatus>>>,
+    start_history: Arc<Mutex<HashMap<String, VecDeque<Instant>>>>,
+    pending_restarts: Arc<Mutex<HashSet<String>>>,
+    watchdogs: Arc<Mutex<HashMap<String, Watchdog>>>,
+    sockets: SocketRegistry,
+    timers: TimerRegistry,
+    paths: PathRegistry,
+    transient: Arc<Mutex<HashSet<String>>>,
+    instance_counter: Arc<AtomicU64>,
+    scope: ManagerScope,
     unit_loader: UnitLoader,
     journal_logger: JournalLogger,
     event_sender: mpsc::UnboundedSender<ServiceEvent>,
+    notifications: broadcast::Sender<ManagerEvent>,
+    started_at: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+struct Watchdog {
+    interval: Duration,
+    last_ping: Instant,
+    fired: bool,
+}
+
+/// State changes published to control socket subscribers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ManagerEvent {
+    StateChanged { name: String, state: ServiceState, pid: Option<u32> },
+    UnitsReloaded { count: usize },
+    /// A transient instance exited and was dropped
+    UnitRemoved { name: String },
 }
 
 #[derive(Debug)]
@@ -52,19 +130,39 @@ pub enum ServiceEvent {
     Enable { name: String },
     Disable { name: String },
     UnitChanged { name: String },
+    /// A notify message with the PID of its sender
+    Notify { name: String, message: NotifyMessage, pid: u32 },
 }
 
 impl ServiceManager {
     pub fn new() -> Result<Self> {
+        Self::for_scope(ManagerScope::current().clone())
+    }
+    
+    /// A manager reading the units of `scope` and writing its journal
+    /// and target links.
+    pub fn for_scope(scope: ManagerScope) -> Result<Self> {
         let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        let (notifications, _) = broadcast::channel(256);
         
         let manager = Self {
             units: Arc::new(Mutex::new(HashMap::new())),
             processes: Arc::new(Mutex::new(HashMap::new())),
             status: Arc::new(Mutex::new(HashMap::new())),
-            unit_loader: UnitLoader::new(),
-            journal_logger: JournalLogger::new()?,
+            start_history: Arc::new(Mutex::new(HashMap::new())),
+            pending_restarts: Arc::new(Mutex::new(HashSet::new())),
+            watchdogs: Arc::new(Mutex::new(HashMap::new())),
+            sockets: SocketRegistry::new(),
+            timers: TimerRegistry::new(),
+            paths: PathRegistry::new(),
+            transient: Arc::new(Mutex::new(HashSet::new())),
+            instance_counter: Arc::new(AtomicU64::new(0)),
+            unit_loader: scope.unit_loader(),
+            journal_logger: JournalLogger::open(&scope.journal_dir())?,
+            scope,
             event_sender,
+            notifications,
+            started_at: SystemTime::now(),
         };
         
         // Start event loop
@@ -76,65 +174,832 @@ impl ServiceManager {
         Ok(manager)
     }
     
+    /// Starts a service along with the units it pulls in, and waits for
+    /// the transaction to finish.
     pub fn start_service(&self, name: &str) -> Result<()> {
+        self.start_units(&[name.to_string()])?.check(name)
+    }
+    
+    /// Starts units and their dependencies as one transaction, running
+    /// independent jobs in parallel.
+    pub fn start_units(&self, names: &[String]) -> Result<TransactionReport> {
+        self.load_instances(names);
+        let transaction = Transaction::start(&self.unit_graph(), names)?;
+        Ok(transaction.execute(self))
+    }
+    
+    /// Stops units together with the units bound to them.
+    pub fn stop_units(&self, names: &[String]) -> Result<TransactionReport> {
+        let transaction = Transaction::stop(&self.unit_graph(), names)?;
+        Ok(transaction.execute(self))
+    }
+    
+    /// Stops every unit that is not inactive, in the reverse order of
+    /// their dependencies, for a daemon that exits without handing over.
+    pub fn stop_all(&self) -> Result<TransactionReport> {
+        let running: Vec<String> = self.status.lock().unwrap()
+            .values()
+            .filter(|status| !matches!(status.state, ServiceState::Inactive | ServiceState::Failed))
+            .map(|status| status.name.clone())
+            .collect();
+        self.stop_units(&running)
+    }
+    
+    /// Loads the template instances a start request refers to, directly
+    /// or through dependencies, that are not loaded yet.
+    fn load_instances(&self, names: &[String]) {
+        let mut visited = HashSet::new();
+        let mut pending = names.to_vec();
+        
+        while let Some(name) = pending.pop() {
+            if !visited.insert(name.clone()) || SocketRegistry::is_socket_name(&name) {
+                continue;
+            }
+            
+            let loaded = self.units.lock().unwrap().get(&name).cloned();
+            let unit = match loaded {
+                Some(unit) => unit,
+                None if split_instance(&name).is_some() => match self.unit_loader.reload_unit(&name) {
+                    Ok(unit) => {
+                        info!("Loaded instance {}", name);
+                        self.units.lock().unwrap().insert(name.clone(), unit.clone());
+                        self.with_status(&name, |_| {});
+                        unit
+                    }
+                    Err(e) => {
+                        // The transaction reports the unit as missing
+                        debug!("Failed to load instance {}: {:#}", name, e);
+                        continue;
+                    }
+                },
+                None => continue,
+            };
+            
+            let deps = unit.dependencies();
+            pending.extend(deps.requires.into_iter().chain(deps.wants).chain(deps.binds_to));
+        }
+    }
+    
+    /// Dependencies of every known unit, for building transactions.
+    pub fn unit_graph(&self) -> HashMap<String, UnitDependencies> {
+        let mut graph: HashMap<String, UnitDependencies> = self.units.lock().unwrap()
+            .iter()
+            .map(|(name, unit)| (name.clone(), unit.dependencies()))
+            .collect();
+        
+        for name in self.sockets.unit_names() {
+            let deps = self.sockets.get_unit(&name)
+                .map(|unit| UnitDependencies::from_section(unit.unit.as_ref()))
+                .unwrap_or_default();
+            graph.insert(name, deps);
+        }
+        
+        for name in self.transient.lock().unwrap().iter() {
+            graph.insert(name.clone(), UnitDependencies::default());
+        }
+        
+        graph
+    }
+    
+    /// Starts a single service, without its dependencies, and waits for
+    /// it to become ready.
+    fn activate_unit(&self, name: &str) -> Result<()> {
         info!("Starting service: {}", name);
         
         let unit = self.get_unit(name)?;
-        let dependencies = self.resolve_dependencies(&unit)?;
         
-        // Start dependencies first
-        for dep in dependencies {
-            if !self.is_service_active(&dep) {
-                self.start_service(&dep)?;
-            }
+        // A skipped start is not an error and does not count against the
+        // start limit
+        if !self.check_conditions(name, &unit)? {
+            return Ok(());
+        }
+        
+        self.check_start_limit(name, &unit)?;
+        
+        // Sockets that activate this service are bound before it starts,
+        // whether it was started by traffic or explicitly
+        for socket in self.sockets.sockets_for_service(name) {
+            self.start_socket(&socket)?;
         }
         
         // Update status
         self.update_service_status(name, ServiceState::Activating, None)?;
+        self.with_status(name, |status| {
+            status.needs_restart = false;
+            status.failed_phase = None;
+        });
         
         // Create and start process
         let mut process = ServiceProcess::new(&unit, &self.journal_logger)?;
-        process.start()?;
+        if unit.uses_notify_socket() {
+            process.set_notify_socket(NotifySocket::bind_in(&self.scope.notify_dir(), name, self.event_sender.clone())?);
+        }
+        process.set_listen_fds(self.sockets.fds_for_service(name));
         
-        let pid = process.get_pid();
-        self.update_service_status(name, ServiceState::Active, pid)?;
+        if let Err(e) = process.start() {
+            self.record_failure(name, &e);
+            self.update_service_status(name, ServiceState::Failed, None)?;
+            return Err(e);
+        }
+        
+        // Type=oneshot ran its commands to completion, and stays active
+        // without a process only with RemainAfterExit=
+        if unit.is_oneshot() {
+            self.with_status(name, |status| {
+                status.exit_code = Some(0);
+                status.exit_signal = None;
+            });
+            
+            if !unit.remain_after_exit() {
+                if let Err(e) = process.finish() {
+                    warn!("{:#}", e);
+                }
+                self.update_service_status(name, ServiceState::Inactive, None)?;
+                info!("Service {} finished", name);
+                return Ok(());
+            }
+        }
         
-        // Store process
+        // Store process, notifications are only accepted from known ones
+        let pid = process.get_pid();
         {
             let mut processes = self.processes.lock().unwrap();
             processes.insert(name.to_string(), process);
         }
         
+        // Type=notify units stay activating until they send READY=1
+        if unit.is_notify() {
+            self.update_service_status(name, ServiceState::Activating, pid)?;
+        } else {
+            self.update_service_status(name, ServiceState::Active, pid)?;
+            self.arm_watchdog(name, &unit);
+        }
+        
+        if let Err(e) = self.wait_until_ready(name) {
+            // A service that never became ready is stopped like any other
+            let process = self.processes.lock().unwrap().remove(name);
+            if let Some(Err(stop_error)) = process.map(|mut process| process.stop()) {
+                warn!("Failed to stop {}: {:#}", name, stop_error);
+            }
+            
+            self.record_failure(name, &e);
+            self.update_service_status(name, ServiceState::Failed, None)?;
+            return Err(e);
+        }
+        
         info!("Service {} started successfully", name);
         Ok(())
     }
     
+    /// Stops a service and the units that require, bind to or are part
+    /// of it.
     pub fn stop_service(&self, name: &str) -> Result<()> {
+        self.stop_units(&[name.to_string()])?.check(name)
+    }
+    
+    fn deactivate_unit(&self, name: &str) -> Result<()> {
         info!("Stopping service: {}", name);
         
+        // An explicit stop wins over a pending automatic restart
+        self.pending_restarts.lock().unwrap().remove(name);
+        self.watchdogs.lock().unwrap().remove(name);
+        
         self.update_service_status(name, ServiceState::Deactivating, None)?;
         
-        // Stop process
+        // Stopping may take until TimeoutStopSec=, don't hold the lock
+        let process = self.processes.lock().unwrap().remove(name);
+        if let Some(Err(e)) = process.map(|mut process| process.stop()) {
+            // Stopped all the same, but not the way it was asked to
+            warn!("Service {} did not stop cleanly: {:#}", name, e);
+            self.record_failure(name, &e);
+            self.update_service_status(name, ServiceState::Failed, None)?;
+            return Ok(());
+        }
+        
+        self.update_service_status(name, ServiceState::Inactive, None)?;
+        
+        info!("Service {} stopped successfully", name);
+        Ok(())
+    }
+    
+    /// Binds a socket unit and starts watching it for traffic. Does nothing
+    /// if it is already listening.
+    pub fn start_socket(&self, name: &str) -> Result<()> {
+        if self.sockets.listen(name)? {
+            let watcher = socket_activation::spawn_watcher(self.clone(), name.to_string())?;
+            self.sockets.set_watcher(name, watcher);
+        }
+        Ok(())
+    }
+    
+    pub fn stop_socket(&self, name: &str) {
+        self.sockets.close(name);
+    }
+    
+    /// Binds every loaded socket unit, done once at daemon startup.
+    pub fn start_all_sockets(&self) {
+        for name in self.sockets.unit_names() {
+            if let Err(e) = self.start_socket(&name) {
+                error!("Failed to start socket {}: {:#}", name, e);
+            }
+        }
+    }
+    
+    pub fn sockets(&self) -> &SocketRegistry {
+        &self.sockets
+    }
+    
+    pub fn timers(&self) -> &TimerRegistry {
+        &self.timers
+    }
+    
+    pub fn paths(&self) -> &PathRegistry {
+        &self.paths
+    }
+    
+    /// Queues an event for the manager's event loop.
+    pub fn send_event(&self, event: ServiceEvent) {
+        let _ = self.event_sender.send(event);
+    }
+    
+    /// Starts a transient `service@N` instance owning a single accepted
+    /// connection, for Accept=yes sockets. The instance is forgotten once
+    /// it exits.
+    pub fn start_socket_instance(&self, service: &str, connection: OwnedFd) -> Result<String> {
+        let mut unit = self.get_unit(service)?;
+        let instance = format!("{}@{}", service, self.instance_counter.fetch_add(1, Ordering::Relaxed));
+        unit.name = instance.clone();
+        
+        let mut process = ServiceProcess::new(&unit, &self.journal_logger)?;
+        process.set_listen_fds(vec![(connection.as_raw_fd(), "connection".to_string())]);
+        process.start()?;
+        
+        // The instance holds its own copy of the connection now
+        drop(connection);
+        
+        self.transient.lock().unwrap().insert(instance.clone());
+        self.update_service_status(&instance, ServiceState::Active, process.get_pid())?;
+        self.processes.lock().unwrap().insert(instance.clone(), process);
+        
+        Ok(instance)
+    }
+    
+    pub fn kill_service(&self, name: &str, signal: Signal) -> Result<()> {
+        info!("Sending {} to service: {}", signal, name);
+        
+        let processes = self.processes.lock().unwrap();
+        if let Some(process) = processes.get(name) {
+            process.kill(signal)?;
+        }
+        
+        Ok(())
+    }
+    
+    /// Processes reaped through `reap_exited`, which must not be waited
+    /// for anywhere else.
+    pub fn reaped_pids(&self) -> HashSet<u32> {
+        let processes = self.processes.lock().unwrap();
+        processes.values().filter_map(|process| process.reaped_pid()).collect()
+    }
+    
+    /// Reaps every service whose main process has exited, recording how it
+    /// ended. Returns the reaped units so the supervisor can apply their
+    /// restart policies.
+    pub fn reap_exited(&self) -> Vec<(String, ExitOutcome)> {
+        let mut exited = Vec::new();
+        let mut finished = HashMap::new();
+        
         {
             let mut processes = self.processes.lock().unwrap();
-            if let Some(process) = processes.get_mut(name) {
-                process.stop()?;
-                processes.remove(name);
+            let statuses: Vec<_> = processes.iter_mut()
+                .filter_map(|(name, process)| match process.try_wait() {
+                    Ok(Some(status)) => Some((name.clone(), status)),
+                    Ok(None) => None,
+                    Err(e) => {
+                        warn!("Failed to check process state of {}: {}", name, e);
+                        None
+                    }
+                })
+                .collect();
+            
+            for (name, status) in statuses {
+                finished.extend(processes.remove_entry(&name));
+                exited.push((name, ExitOutcome::from_status(status)));
             }
         }
         
-        self.update_service_status(name, ServiceState::Inactive, None)?;
+        // A process killed by the watchdog is reported as a watchdog
+        // failure rather than by the signal used to kill it
+        let exited: Vec<(String, ExitOutcome)> = {
+            let mut watchdogs = self.watchdogs.lock().unwrap();
+            exited.into_iter()
+                .map(|(name, outcome)| match watchdogs.remove(&name) {
+                    Some(watchdog) if watchdog.fired => (name, ExitOutcome::Watchdog),
+                    _ => (name, outcome),
+                })
+                .collect()
+        };
+        
+        let mut instances = HashSet::new();
+        let mut ignored = HashSet::new();
+        
+        for (name, outcome) in &exited {
+            // ExecStopPost= runs however the service ended
+            let process = finished.remove(name);
+            let ignore_failure = process.as_ref().is_some_and(|process| process.ignores_failure());
+            let stop_post = process.map_or(Ok(()), |mut process: ServiceProcess| process.finish());
+            
+            if self.transient.lock().unwrap().remove(name) {
+                debug!("Connection instance {} exited ({})", name, outcome);
+                self.status.lock().unwrap().remove(name);
+                instances.insert(name.clone());
+                let _ = self.notifications.send(ManagerEvent::UnitRemoved { name: name.clone() });
+                continue;
+            }
+            
+            self.with_status(name, |status| match outcome {
+                ExitOutcome::Exited(code) => {
+                    status.exit_code = Some(*code);
+                    status.exit_signal = None;
+                }
+                ExitOutcome::Signaled(signal) => {
+                    status.exit_code = None;
+                    status.exit_signal = Some(*signal);
+                }
+                ExitOutcome::Watchdog => {
+                    status.exit_code = None;
+                    status.exit_signal = Some(libc::SIGABRT);
+                    status.load_error = Some("watchdog timeout".to_string());
+                }
+            });
+            
+            // An exit code of an ExecStart= with `-` counts as success
+            let clean = outcome.is_clean() || ignore_failure && matches!(outcome, ExitOutcome::Exited(_));
+            if clean && !outcome.is_clean() {
+                info!("Ignoring failure of {} ({})", name, outcome);
+                ignored.insert(name.clone());
+            }
+            
+            if !clean {
+                self.with_status(name, |status| status.failed_phase = Some(ExecPhase::Start));
+            }
+            
+            let mut state = if clean { ServiceState::Inactive } else { ServiceState::Failed };
+            if let Err(e) = stop_post {
+                self.record_failure(name, &e);
+                state = ServiceState::Failed;
+            }
+            
+            if let Err(e) = self.update_service_status(name, state, None) {
+                error!("Failed to record exit of {}: {}", name, e);
+            }
+        }
+        
+        // Connection instances are never restarted, ignored failures are
+        // restarted as clean exits
+        exited.into_iter()
+            .filter(|(name, _)| !instances.contains(name))
+            .map(|(name, outcome)| match ignored.contains(&name) {
+                true => (name, ExitOutcome::Exited(0)),
+                false => (name, outcome),
+            })
+            .collect()
+    }
+    
+    /// Everything the next daemon needs to take over running services.
+    /// With `keep_fds`, held sockets and output pipes are duplicated to
+    /// survive an exec.
+    pub fn serialize(&self, keep_fds: bool) -> RuntimeState {
+        let transient = self.transient.lock().unwrap().clone();
+        let watchdogs = self.watchdogs.lock().unwrap().clone();
+        
+        let services = self.processes.lock().unwrap()
+            .iter()
+            .filter_map(|(name, process)| Some(SerializedService {
+                name: name.clone(),
+                transient: transient.contains(name),
+                watchdog_usec: watchdogs.get(name).map(|watchdog| watchdog.interval.as_micros() as u64),
+                process: process.serialize(keep_fds)?,
+            }))
+            .collect();
+        
+        RuntimeState {
+            boot_id: self.journal_logger.boot_id().to_string(),
+            daemon_pid: std::process::id(),
+            statuses: self.status.lock().unwrap().values().cloned().collect(),
+            services,
+            sockets: if keep_fds { self.sockets.serialize() } else { Vec::new() },
+            pending_restarts: self.pending_restarts.lock().unwrap().iter().cloned().collect(),
+            instance_counter: self.instance_counter.load(Ordering::Relaxed),
+            first_boot: Some(is_first_boot()),
+        }
+    }
+    
+    /// Takes over from the daemon that wrote `state`: adopts its running
+    /// services and held sockets, and restores the status of every unit.
+    /// Units must be loaded first. Returns the number of adopted services.
+    pub fn restore(&self, state: RuntimeState) -> usize {
+        // Descriptors of another process mean nothing here
+        let owns_fds = state.owns_fds();
+        if !owns_fds && (!state.sockets.is_empty() || state.services.iter().any(|s| s.process.stdout_fd.is_some())) {
+            warn!("Daemon was restarted rather than re-executed, output of adopted services is lost");
+        }
+        
+        for socket in state.sockets.into_iter().filter(|_| owns_fds) {
+            let fds = socket.fds.into_iter().map(take_inherited_fd).collect();
+            if let Err(e) = self.sockets.adopt(&socket.name, fds) {
+                warn!("Closing sockets of {}: {:#}", socket.name, e);
+                continue;
+            }
+            match socket_activation::spawn_watcher(self.clone(), socket.name.clone()) {
+                Ok(watcher) => self.sockets.set_watcher(&socket.name, watcher),
+                Err(e) => error!("Failed to watch socket {}: {:#}", socket.name, e),
+            }
+        }
+        
+        {
+            let units = self.units.lock().unwrap();
+            let mut status = self.status.lock().unwrap();
+            for service in state.statuses {
+                if units.contains_key(&service.name) {
+                    status.insert(service.name.clone(), service);
+                }
+            }
+        }
+        
+        let mut adopted = 0;
+        for mut service in state.services {
+            if !owns_fds {
+                service.process.stdout_fd = None;
+                service.process.stderr_fd = None;
+            }
+            
+            match self.adopt_service(&service) {
+                Ok(()) => adopted += 1,
+                Err(e) => {
+                    warn!("Failed to adopt {}: {:#}", service.name, e);
+                    // Not closed on exec, the next daemon would inherit them
+                    for fd in [service.process.stdout_fd, service.process.stderr_fd].into_iter().flatten() {
+                        drop(take_inherited_fd(fd));
+                    }
+                }
+            }
+        }
+        
+        // Whatever was running and could not be adopted is gone
+        let orphaned: Vec<String> = {
+            let processes = self.processes.lock().unwrap();
+            self.status.lock().unwrap()
+                .values()
+                .filter(|status| status.state != ServiceState::Inactive && status.state != ServiceState::Failed)
+                .filter(|status| !processes.contains_key(&status.name))
+                .map(|status| status.name.clone())
+                .collect()
+        };
+        for name in orphaned {
+            let _ = self.update_service_status(&name, ServiceState::Inactive, None);
+        }
+        
+        self.instance_counter.fetch_max(state.instance_counter, Ordering::Relaxed);
+        
+        // Restarts that were waiting for their delay happen right away
+        for name in state.pending_restarts {
+            self.schedule_restart(&name);
+            let manager = self.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(e) = manager.auto_restart(&name) {
+                    error!("Failed to restart service {}: {}", name, e);
+                }
+            });
+        }
+        
+        adopted
+    }
+    
+    fn adopt_service(&self, service: &SerializedService) -> Result<()> {
+        let unit = if service.transient {
+            // Connection instances are named after the service they run
+            let (base, _) = service.name.rsplit_once('@')
+                .ok_or_else(|| anyhow::anyhow!("Invalid connection instance name"))?;
+            let mut unit = self.get_unit(base)?;
+            unit.name = service.name.clone();
+            unit
+        } else {
+            self.get_unit(&service.name)?
+        };
+        
+        let mut process = ServiceProcess::adopt(&unit, &self.journal_logger, service.process.clone())?;
+        if unit.uses_notify_socket() {
+            process.set_notify_socket(NotifySocket::bind_in(&self.scope.notify_dir(), &service.name, self.event_sender.clone())?);
+        }
+        
+        if service.transient {
+            self.transient.lock().unwrap().insert(service.name.clone());
+            self.with_status(&service.name, |status| status.state = ServiceState::Active);
+        }
+        self.with_status(&service.name, |status| status.pid = process.get_pid());
+        
+        if let Some(usec) = service.watchdog_usec {
+            self.watchdogs.lock().unwrap().insert(service.name.clone(), Watchdog {
+                interval: Duration::from_micros(usec),
+                last_ping: Instant::now(),
+                fired: false,
+            });
+        }
+        
+        self.processes.lock().unwrap().insert(service.name.clone(), process);
+        Ok(())
+    }
+    
+    /// Hands the running services over to a fresh exec of the daemon's
+    /// binary, e.g. after an upgrade. Only returns if that failed.
+    pub fn reexec(&self) -> anyhow::Error {
+        let state = self.serialize(true);
+        
+        let error = match self.exec_daemon(&state) {
+            Ok(never) => match never {},
+            Err(e) => e,
+        };
+        
+        // Still in charge, keep nothing around for a next daemon
+        state.close_fds();
+        if let Err(e) = StateManager::new().and_then(|manager| manager.take_runtime_state()) {
+            warn!("Failed to remove runtime state: {:#}", e);
+        }
+        error
+    }
+    
+    fn exec_daemon(&self, state: &RuntimeState) -> Result<std::convert::Infallible> {
+        StateManager::new()?.save_runtime_state(state)?;
+        
+        // After an upgrade the running binary is deleted, exec the new one
+        let exe = std::env::current_exe().context("Failed to find the daemon binary")?;
+        let exe = exe.to_string_lossy();
+        let exe = std::ffi::CString::new(exe.trim_end_matches(" (deleted)"))?;
+        let args = std::env::args_os()
+            .map(|arg| std::ffi::CString::new(std::os::unix::ffi::OsStringExt::into_vec(arg)))
+            .collect::<Result<Vec<_>, _>>()?;
+        
+        info!("Re-executing {}", exe.to_string_lossy());
+        nix::unistd::execv(&exe, &args).context("Failed to execute the daemon")
+    }
+    
+    /// Flags a running service whose unit changed on disk. The new
+    /// definition takes effect when it is next started.
+    fn handle_unit_changed(&self, name: &str) {
+        if !self.is_service_active(name) {
+            return;
+        }
+        
+        warn!("Unit {} changed on disk, restart it to apply the changes", name);
+        self.with_status(name, |status| status.needs_restart = true);
+    }
+    
+    /// Handles an sd_notify message sent by a service.
+    pub fn handle_notify(&self, name: &str, message: NotifyMessage, sender: u32) {
+        if !self.notify_allowed(name, sender) {
+            warn!("Ignoring notification for {} from PID {}, not allowed by NotifyAccess=", name, sender);
+            return;
+        }
+        debug!("Notification from {} (PID {}): {:?}", name, sender, message);
+        
+        if let Some(pid) = message.main_pid {
+            // Only a process of the service can become its main process,
+            // otherwise stop and kill would hit an unrelated one
+            let accepted = match self.processes.lock().unwrap().get_mut(name) {
+                Some(process) if pid == sender || process.in_cgroup(pid) => {
+                    process.set_main_pid(pid);
+                    true
+                }
+                _ => false,
+            };
+            
+            if accepted {
+                self.with_status(name, |status| status.pid = Some(pid));
+            } else {
+                warn!("Ignoring MAINPID={} for {}, not a process of the service", pid, name);
+            }
+        }
+        
+        if let Some(text) = &message.status {
+            self.with_status(name, |status| status.status_text = Some(text.clone()));
+        }
+        
+        if let Some(usec) = message.watchdog_usec {
+            if let Some(watchdog) = self.watchdogs.lock().unwrap().get_mut(name) {
+                watchdog.interval = Duration::from_micros(usec);
+            }
+        }
+        
+        if message.watchdog {
+            if let Some(watchdog) = self.watchdogs.lock().unwrap().get_mut(name) {
+                watchdog.last_ping = Instant::now();
+            }
+        }
+        
+        if message.watchdog_trigger {
+            warn!("Service {} requested a watchdog restart", name);
+            if let Some(watchdog) = self.watchdogs.lock().unwrap().get_mut(name) {
+                watchdog.last_ping = Instant::now() - watchdog.interval;
+            }
+        }
+        
+        let current = self.get_service_status(name).map(|s| s.state);
+        let pid = self.get_service_status(name).and_then(|s| s.pid);
+        
+        if message.stopping {
+            let _ = self.update_service_status(name, ServiceState::Deactivating, pid);
+        } else if message.reloading {
+            let _ = self.update_service_status(name, ServiceState::Reloading, pid);
+        } else if message.ready {
+            match current {
+                Some(ServiceState::Activating) => {
+                    info!("Service {} is ready", name);
+                    let _ = self.update_service_status(name, ServiceState::Active, pid);
+                    if let Ok(unit) = self.get_unit(name) {
+                        self.arm_watchdog(name, &unit);
+                    }
+                }
+                Some(ServiceState::Reloading) => {
+                    let _ = self.update_service_status(name, ServiceState::Active, pid);
+                }
+                _ => {}
+            }
+        }
+    }
+    
+    /// NotifyAccess=: whether `sender` may send notifications for a
+    /// running service.
+    fn notify_allowed(&self, name: &str, sender: u32) -> bool {
+        let processes = self.processes.lock().unwrap();
+        let Some(process) = processes.get(name) else {
+            return false;
+        };
+        
+        let main = process.get_pid() == Some(sender);
+        match process.unit().notify_access() {
+            NotifyAccess::None => false,
+            NotifyAccess::Main => main,
+            NotifyAccess::All => main || process.in_cgroup(sender),
+        }
+    }
+    
+    /// Blocks until a Type=notify service reports readiness, so units that
+    /// depend on it are not started too early.
+    pub fn wait_until_ready(&self, name: &str) -> Result<()> {
+        let unit = self.get_unit(name)?;
+        self.wait_for_ready(name, &unit, ExecPhase::Start)
+    }
+    
+    fn wait_for_ready(&self, name: &str, unit: &ServiceUnit, phase: ExecPhase) -> Result<()> {
+        if !unit.is_notify() {
+            return Ok(());
+        }
+        
+        let timeout = unit.start_timeout();
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        
+        loop {
+            match self.get_service_status(name).map(|s| s.state) {
+                Some(ServiceState::Active) => return Ok(()),
+                Some(ServiceState::Failed) | Some(ServiceState::Inactive) => {
+                    return Err(anyhow::anyhow!("Service {} failed before becoming ready", name));
+                }
+                _ => {}
+            }
+            
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(ExecError::TimedOut { phase, timeout: timeout.unwrap_or_default() }.into());
+            }
+            
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    
+    fn arm_watchdog(&self, name: &str, unit: &ServiceUnit) {
+        if let Some(interval) = unit.watchdog_interval() {
+            self.watchdogs.lock().unwrap().insert(name.to_string(), Watchdog {
+                interval,
+                last_ping: Instant::now(),
+                fired: false,
+            });
+        }
+    }
+    
+    /// Kills services whose watchdog expired. They are reaped like any
+    /// other exit and reported as `ExitOutcome::Watchdog`.
+    pub fn check_watchdogs(&self) {
+        let expired: Vec<String> = {
+            let mut watchdogs = self.watchdogs.lock().unwrap();
+            watchdogs.iter_mut()
+                .filter(|(_, w)| !w.fired && w.last_ping.elapsed() >= w.interval)
+                .map(|(name, w)| {
+                    w.fired = true;
+                    name.clone()
+                })
+                .collect()
+        };
+        
+        for name in expired {
+            error!("Watchdog timeout for service {}, killing it", name);
+            if let Err(e) = self.kill_service(&name, Signal::SIGABRT) {
+                error!("Failed to kill service {} after watchdog timeout: {}", name, e);
+            }
+        }
+    }
+    
+    pub fn schedule_restart(&self, name: &str) {
+        self.pending_restarts.lock().unwrap().insert(name.to_string());
+    }
+    
+    /// Performs a restart scheduled by the supervisor, unless the service
+    /// was stopped in the meantime.
+    pub fn auto_restart(&self, name: &str) -> Result<()> {
+        if !self.pending_restarts.lock().unwrap().remove(name) {
+            debug!("Automatic restart of {} was cancelled", name);
+            return Ok(());
+        }
+        
+        self.with_status(name, |status| {
+            status.restart_count += 1;
+            status.last_restart = Some(SystemTime::now());
+        });
+        
+        self.start_service(name)
+    }
+    
+    /// Clears the failed state and start rate limit of one or all services.
+    pub fn reset_failed(&self, name: Option<&str>) {
+        {
+            let mut history = self.start_history.lock().unwrap();
+            match name {
+                Some(name) => { history.remove(name); }
+                None => history.clear(),
+            }
+        }
+        
+        let failed: Vec<String> = self.list_services(Some(ServiceState::Failed))
+            .into_iter()
+            .map(|s| s.name)
+            .filter(|n| name.is_none_or(|name| name == n))
+            .collect();
+        
+        for service in failed {
+            self.with_status(&service, |status| status.load_error = None);
+            let _ = self.update_service_status(&service, ServiceState::Inactive, None);
+        }
+    }
+    
+    fn check_start_limit(&self, name: &str, unit: &ServiceUnit) -> Result<()> {
+        let (interval, burst) = unit.start_limit();
+        if burst == 0 || interval.is_zero() {
+            return Ok(());
+        }
+        
+        let now = Instant::now();
+        let limited = {
+            let mut history = self.start_history.lock().unwrap();
+            let attempts = history.entry(name.to_string()).or_default();
+            
+            while attempts.front().is_some_and(|t| now.duration_since(*t) > interval) {
+                attempts.pop_front();
+            }
+            
+            if attempts.len() >= burst as usize {
+                true
+            } else {
+                attempts.push_back(now);
+                false
+            }
+        };
+        
+        if limited {
+            warn!("Start request repeated too quickly for {}, refusing to start", name);
+            self.with_status(name, |status| {
+                status.load_error = Some("start request repeated too quickly".to_string());
+            });
+            self.update_service_status(name, ServiceState::Failed, None)?;
+            return Err(anyhow::anyhow!(
+                "Start request repeated too quickly for {} ({} starts within {:?}); use reset-failed to clear",
+                name, burst, interval
+            ));
+        }
         
-        info!("Service {} stopped successfully", name);
         Ok(())
     }
     
     pub async fn restart_service(&self, name: &str) -> Result<()> {
         info!("Restarting service: {}", name);
         
-        self.stop_service(name)?;
+        // Units that are part of this one are restarted along with it
+        let stopped: Vec<String> = self.stop_units(&[name.to_string()])?
+            .jobs
+            .into_iter()
+            .filter(|job| job.unit == name || job.outcome == JobOutcome::Done)
+            .map(|job| job.unit)
+            .collect();
         sleep(Duration::from_millis(100)).await;
-        self.start_service(name)?;
+        self.start_units(&stopped)?.check(name)?;
         
         info!("Service {} restarted successfully", name);
         Ok(())
@@ -143,25 +1008,44 @@ impl ServiceManager {
     pub fn reload_service(&self, name: &str) -> Result<()> {
         info!("Reloading service: {}", name);
         
+        let status = self.get_service_status(name);
+        if status.as_ref().map(|s| &s.state) != Some(&ServiceState::Active) {
+            return Err(anyhow::anyhow!("Service {} is not active, cannot reload", name));
+        }
+        let pid = status.and_then(|s| s.pid);
+        
         // Reload unit file
         let new_unit = self.unit_loader.reload_unit(name)?;
         {
             let mut units = self.units.lock().unwrap();
-            units.insert(name.to_string(), new_unit);
+            units.insert(name.to_string(), new_unit.clone());
         }
         
-        self.update_service_status(name, ServiceState::Reloading, None)?;
+        self.update_service_status(name, ServiceState::Reloading, pid)?;
         
-        // Send reload signal to process
-        {
+        // Send reload signal to process, ExecReload= is waited for without
+        // the lock so the service can still be notified and reaped
+        let command = {
             let mut processes = self.processes.lock().unwrap();
-            if let Some(process) = processes.get_mut(name) {
-                process.reload()?;
+            match processes.get_mut(name) {
+                Some(process) => process.reload(),
+                None => Ok(None),
             }
+        };
+        let mut result = command.and_then(|command| command.map_or(Ok(()), |command| command.wait()));
+        
+        // Type=notify units report the end of the reload with READY=1
+        if result.is_ok() {
+            result = self.wait_for_ready(name, &new_unit, ExecPhase::Reload);
         }
         
-        self.update_service_status(name, ServiceState::Active, None)?;
+        // A failed reload leaves the service running, unless it exited
+        if self.get_service_status(name).map(|s| s.state) == Some(ServiceState::Reloading) {
+            let pid = self.get_service_status(name).and_then(|s| s.pid);
+            self.update_service_status(name, ServiceState::Active, pid)?;
+        }
         
+        result?;
         info!("Service {} reloaded successfully", name);
         Ok(())
     }
@@ -195,17 +1079,28 @@ impl ServiceManager {
     }
     
     pub fn list_services(&self, filter: Option<ServiceState>) -> Vec<ServiceStatus> {
-        let status = self.status.lock().unwrap();
+        let services: Vec<ServiceStatus> = {
+            let status = self.status.lock().unwrap();
+            status.values()
+                .filter(|s| filter.as_ref().is_none_or(|f| s.state == *f))
+                .cloned()
+                .collect()
+        };
         
-        status.values()
-            .filter(|s| filter.as_ref().map_or(true, |f| s.state == *f))
-            .cloned()
-            .collect()
+        services.into_iter().map(|s| self.with_resource_usage(s)).collect()
     }
     
     pub fn get_service_status(&self, name: &str) -> Option<ServiceStatus> {
-        let status = self.status.lock().unwrap();
-        status.get(name).cloned()
+        let status = self.status.lock().unwrap().get(name).cloned();
+        status.map(|s| self.with_resource_usage(s))
+    }
+    
+    /// Fills in live cgroup accounting for running services.
+    fn with_resource_usage(&self, mut status: ServiceStatus) -> ServiceStatus {
+        status.resources = self.processes.lock().unwrap()
+            .get(&status.name)
+            .and_then(|process| process.resource_usage());
+        status
     }
     
     pub fn is_service_active(&self, name: &str) -> bool {
@@ -216,6 +1111,30 @@ impl ServiceManager {
         }
     }
     
+    /// Names of all loaded services, transient instances included.
+    pub fn unit_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.units.lock().unwrap().keys().cloned().collect();
+        names.extend(self.transient.lock().unwrap().iter().cloned());
+        names
+    }
+    
+    pub fn subscribe(&self) -> broadcast::Receiver<ManagerEvent> {
+        self.notifications.subscribe()
+    }
+    
+    /// When the manager started, the reference for boot timing.
+    pub fn started_at(&self) -> SystemTime {
+        self.started_at
+    }
+    
+    pub fn unit_loader(&self) -> &UnitLoader {
+        &self.unit_loader
+    }
+    
+    pub fn journal(&self) -> &JournalLogger {
+        &self.journal_logger
+    }
+    
     pub fn get_unit(&self, name: &str) -> Result<ServiceUnit> {
         let units = self.units.lock().unwrap();
         units.get(name)
@@ -223,77 +1142,98 @@ impl ServiceManager {
             .ok_or_else(|| anyhow::anyhow!("Service unit not found: {}", name))
     }
     
-    fn resolve_dependencies(&self, unit: &ServiceUnit) -> Result<Vec<String>> {
-        let mut resolved = Vec::new();
-        let mut visited = HashSet::new();
-        let mut visiting = HashSet::new();
-        
-        self.dfs_resolve_dependencies(&unit.name, &mut resolved, &mut visited, &mut visiting)?;
-        
-        Ok(resolved)
-    }
-    
-    fn dfs_resolve_dependencies(
-        &self,
-        service: &str,
-        resolved: &mut Vec<String>,
-        visited: &mut HashSet<String>,
-        visiting: &mut HashSet<String>,
-    ) -> Result<()> {
-        if visited.contains(service) {
-            return Ok(());
-        }
-        
-        if visiting.contains(service) {
-            return Err(anyhow::anyhow!("Circular dependency detected for service: {}", service));
-        }
+    fn update_service_status(&self, name: &str, state: ServiceState, pid: Option<u32>) -> Result<()> {
+        let mut status = self.status.lock().unwrap();
         
-        visiting.insert(service.to_string());
+        let service_status = status.entry(name.to_string())
+            .or_insert_with(|| ServiceStatus::new(name));
         
-        let unit = self.get_unit(service)?;
-        let dependencies = unit.get_dependencies();
+        let previous = std::mem::replace(&mut service_status.state, state.clone());
+        service_status.pid = pid;
         
-        for dep in dependencies {
-            self.dfs_resolve_dependencies(&dep, resolved, visited, visiting)?;
+        match state {
+            ServiceState::Activating => service_status.activation_start = Some(SystemTime::now()),
+            // A reload does not restart the service
+            ServiceState::Active if previous != ServiceState::Reloading => {
+                service_status.start_time = Some(SystemTime::now());
+            }
+            _ => {}
         }
         
-        visiting.remove(service);
-        visited.insert(service.to_string());
-        resolved.push(service.to_string());
+        // Nobody listening is the common case for one-shot CLI invocations
+        let _ = self.notifications.send(ManagerEvent::StateChanged {
+            name: name.to_string(),
+            state,
+            pid,
+        });
         
         Ok(())
     }
     
-    fn update_service_status(&self, name: &str, state: ServiceState, pid: Option<u32>) -> Result<()> {
-        let mut status = self.status.lock().unwrap();
+    /// Evaluates Condition*= and Assert*=. Returns false if the start is
+    /// to be skipped, an error if an assertion failed.
+    fn check_conditions(&self, name: &str, unit: &ServiceUnit) -> Result<bool> {
+        let result = UnitConditions::from_section(unit.unit.as_ref()).check();
+        self.with_status(name, |status| status.unmet_condition = None);
         
-        let service_status = status.entry(name.to_string()).or_insert_with(|| ServiceStatus {
-            name: name.to_string(),
-            state: ServiceState::Inactive,
-            pid: None,
-            exit_code: None,
-            start_time: None,
-            last_restart: None,
-            restart_count: 0,
-            load_error: None,
-        });
-        
-        service_status.state = state;
-        service_status.pid = pid;
-        
-        if state == ServiceState::Active {
-            service_status.start_time = Some(Instant::now());
+        match result {
+            ConditionResult::Met => Ok(true),
+            ConditionResult::Skipped(condition) => {
+                let message = format!("Condition check resulted in {} being skipped: {} was not met", name, condition);
+                info!("{}", message);
+                self.log_condition(name, LogLevel::Notice, &message, &condition.to_string());
+                
+                self.with_status(name, |status| status.unmet_condition = Some(condition.to_string()));
+                self.update_service_status(name, ServiceState::Inactive, None)?;
+                Ok(false)
+            }
+            ConditionResult::AssertionFailed(assertion) => {
+                let message = format!("Assertion failed for {}: {}", name, assertion);
+                error!("{}", message);
+                self.log_condition(name, LogLevel::Error, &message, &assertion.to_string());
+                
+                self.with_status(name, |status| {
+                    status.unmet_condition = Some(assertion.to_string());
+                    status.failed_phase = None;
+                    status.load_error = Some(message.clone());
+                });
+                self.update_service_status(name, ServiceState::Failed, None)?;
+                Err(anyhow::anyhow!(message))
+            }
         }
-        
-        Ok(())
+    }
+    
+    /// Records an unmet condition or assertion in the unit's journal.
+    fn log_condition(&self, name: &str, level: LogLevel, message: &str, condition: &str) {
+        let entry = JournalEntry::new(name, "system", message)
+            .with_level(level)
+            .with_field("CONDITION", condition);
+        if let Err(e) = self.journal_logger.write(entry) {
+            warn!("Failed to write to the journal of {}: {}", name, e);
+        }
+    }
+    
+    /// Records the step of the lifecycle that failed and why.
+    fn record_failure(&self, name: &str, error: &anyhow::Error) {
+        let phase = error.downcast_ref::<ExecError>().map_or(ExecPhase::Start, ExecError::phase);
+        self.with_status(name, |status| {
+            status.failed_phase = Some(phase);
+            status.load_error = Some(format!("{:#}", error));
+        });
+    }
+    
+    fn with_status<F: FnOnce(&mut ServiceStatus)>(&self, name: &str, f: F) {
+        let mut status = self.status.lock().unwrap();
+        f(status.entry(name.to_string()).or_insert_with(|| ServiceStatus::new(name)));
     }
     
     fn create_symlink(&self, service_name: &str, target: &str) -> Result<()> {
-        let target_dir = PathBuf::from(format!("/etc/tau/system/{}", target));
+        let target_dir = self.scope.targets_dir().join(target);
         fs::create_dir_all(&target_dir)?;
         
         let symlink_path = target_dir.join(format!("{}.tau", service_name));
-        let unit_path = PathBuf::from(format!("/etc/tau/services/{}.tau", service_name));
+        let unit_path = self.unit_loader.find_fragment(service_name)
+            .ok_or_else(|| anyhow::anyhow!("Unit file not found for: {}", service_name))?;
         
         if symlink_path.exists() {
             fs::remove_file(&symlink_path)?;
@@ -304,7 +1244,7 @@ impl ServiceManager {
     }
     
     fn remove_symlink(&self, service_name: &str, target: &str) -> Result<()> {
-        let symlink_path = PathBuf::from(format!("/etc/tau/system/{}/{}.tau", target, service_name));
+        let symlink_path = self.scope.targets_dir().join(target).join(format!("{}.tau", service_name));
         
         if symlink_path.exists() {
             fs::remove_file(&symlink_path)?;
@@ -316,25 +1256,40 @@ impl ServiceManager {
     async fn event_loop(&self, mut receiver: mpsc::UnboundedReceiver<ServiceEvent>) {
         while let Some(event) = receiver.recv().await {
             match event {
+                // Jobs run off the loop: a start waits for READY=1, which
+                // only arrives through it
                 ServiceEvent::Start { name } => {
-                    if let Err(e) = self.start_service(&name) {
-                        error!("Failed to start service {}: {}", name, e);
-                    }
+                    let manager = self.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = manager.start_service(&name) {
+                            error!("Failed to start service {}: {}", name, e);
+                        }
+                    });
                 }
                 ServiceEvent::Stop { name } => {
-                    if let Err(e) = self.stop_service(&name) {
-                        error!("Failed to stop service {}: {}", name, e);
-                    }
+                    let manager = self.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = manager.stop_service(&name) {
+                            error!("Failed to stop service {}: {}", name, e);
+                        }
+                    });
                 }
                 ServiceEvent::Restart { name } => {
-                    if let Err(e) = self.restart_service(&name).await {
-                        error!("Failed to restart service {}: {}", name, e);
-                    }
+                    let manager = self.clone();
+                    let runtime = tokio::runtime::Handle::current();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = runtime.block_on(manager.restart_service(&name)) {
+                            error!("Failed to restart service {}: {}", name, e);
+                        }
+                    });
                 }
                 ServiceEvent::Reload { name } => {
-                    if let Err(e) = self.reload_service(&name) {
-                        error!("Failed to reload service {}: {}", name, e);
-                    }
+                    let manager = self.clone();
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = manager.reload_service(&name) {
+                            error!("Failed to reload service {}: {}", name, e);
+                        }
+                    });
                 }
                 ServiceEvent::Enable { name } => {
                     if let Err(e) = self.enable_service(&name) {
@@ -347,53 +1302,111 @@ impl ServiceManager {
                     }
                 }
                 ServiceEvent::UnitChanged { name } => {
-                    if let Err(e) = self.reload_service(&name) {
-                        error!("Failed to reload service {} after unit change: {}", name, e);
-                    }
+                    self.handle_unit_changed(&name);
+                }
+                ServiceEvent::Notify { name, message, pid } => {
+                    self.handle_notify(&name, message, pid);
                 }
             }
         }
     }
     
     pub fn load_units(&self) -> Result<()> {
-        let units = self.unit_loader.load_all_units()?;
+        let mut units = self.unit_loader.load_all_units()?;
+        self.sockets.set_units(self.unit_loader.load_all_sockets()?);
+        self.timers.set_units(self.unit_loader.load_all_timers()?);
+        self.paths.set_units(self.unit_loader.load_all_paths()?);
         
         {
             let mut units_guard = self.units.lock().unwrap();
+            
+            // Instances are not found by scanning, reload the ones in use
+            for name in units_guard.keys().filter(|name| split_instance(name).is_some()) {
+                if units.contains_key(name) {
+                    continue;
+                }
+                match self.unit_loader.reload_unit(name) {
+                    Ok(unit) => {
+                        units.insert(name.clone(), unit);
+                    }
+                    Err(e) => warn!("Dropping instance {}: {:#}", name, e),
+                }
+            }
+            
+            for name in changed_units(&units_guard, &units) {
+                let _ = self.event_sender.send(ServiceEvent::UnitChanged { name });
+            }
+            
             *units_guard = units;
         }
         
-        // Initialize status for all units
+        // Initialize status for new units, keeping the live state of units
+        // the daemon already tracks
         {
             let mut status = self.status.lock().unwrap();
-            for (name, unit) in self.units.lock().unwrap().iter() {
-                status.insert(name.clone(), ServiceStatus {
-                    name: name.clone(),
-                    state: ServiceState::Inactive,
-                    pid: None,
-                    exit_code: None,
-                    start_time: None,
-                    last_restart: None,
-                    restart_count: 0,
-                    load_error: None,
-                });
+            for name in self.units.lock().unwrap().keys() {
+                status.entry(name.clone()).or_insert_with(|| ServiceStatus::new(name));
             }
         }
         
-        info!("Loaded {} service units", units.len());
+        let count = self.units.lock().unwrap().len();
+        let _ = self.notifications.send(ManagerEvent::UnitsReloaded { count });
+        
+        info!("Loaded {} service units", count);
         Ok(())
     }
 }
 
+impl JobRunner for ServiceManager {
+    fn is_active(&self, unit: &str) -> bool {
+        if SocketRegistry::is_socket_name(unit) {
+            return self.sockets.is_listening(unit);
+        }
+        
+        matches!(
+            self.get_service_status(unit).map(|s| s.state),
+            Some(ServiceState::Active) | Some(ServiceState::Activating) | Some(ServiceState::Reloading)
+        )
+    }
+    
+    fn start_unit(&self, unit: &str) -> Result<()> {
+        if SocketRegistry::is_socket_name(unit) {
+            self.start_socket(unit)
+        } else {
+            self.activate_unit(unit)
+        }
+    }
+    
+    fn stop_unit(&self, unit: &str) -> Result<()> {
+        if SocketRegistry::is_socket_name(unit) {
+            self.stop_socket(unit);
+            Ok(())
+        } else {
+            self.deactivate_unit(unit)
+        }
+    }
+}
+
 impl Clone for ServiceManager {
     fn clone(&self) -> Self {
         Self {
             units: Arc::clone(&self.units),
             processes: Arc::clone(&self.processes),
             status: Arc::clone(&self.status),
+            start_history: Arc::clone(&self.start_history),
+            pending_restarts: Arc::clone(&self.pending_restarts),
+            watchdogs: Arc::clone(&self.watchdogs),
+            sockets: self.sockets.clone(),
+            timers: self.timers.clone(),
+            paths: self.paths.clone(),
+            transient: Arc::clone(&self.transient),
+            instance_counter: Arc::clone(&self.instance_counter),
+            scope: self.scope.clone(),
             unit_loader: self.unit_loader.clone(),
             journal_logger: self.journal_logger.clone(),
             event_sender: self.event_sender.clone(),
+            notifications: self.notifications.clone(),
+            started_at: self.started_at,
         }
     }
 } 
\ No newline at end of file
@@ -0,0 +1,488 @@
+use crate::service_manager::{ServiceManager, ServiceState};
+use crate::unit::{ListenAddress, SocketUnit};
+use crate::state::{keep_across_exec, SerializedSocket};
+use anyhow::{Result, Context};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::sync::broadcast::error::RecvError;
+use log::{info, warn, error, debug};
+
+/// A bound `.socket` unit. The manager keeps the listening file
+/// descriptors open for its whole lifetime so the activated service can
+/// exit and be started again without dropping queued connections.
+struct ListeningSocket {
+    unit: SocketUnit,
+    fds: Vec<OwnedFd>,
+    watcher: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl Drop for ListeningSocket {
+    fn drop(&mut self) {
+        if let Some(watcher) = self.watcher.take() {
+            watcher.abort();
+        }
+
+        for address in self.unit.socket.listen_stream.iter()
+            .chain(self.unit.socket.listen_datagram.iter())
+            .flatten()
+        {
+            if let Ok(ListenAddress::Unix(path)) = ListenAddress::parse(address) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SocketSummary {
+    pub name: String,
+    pub listen: Vec<String>,
+    pub service: String,
+    pub accept: bool,
+    pub listening: bool,
+}
+
+/// Socket units known to the manager and the sockets bound for them.
+#[derive(Clone)]
+pub struct SocketRegistry {
+    units: Arc<Mutex<HashMap<String, SocketUnit>>>,
+    listening: Arc<Mutex<HashMap<String, ListeningSocket>>>,
+}
+
+impl SocketRegistry {
+    pub fn new() -> Self {
+        Self {
+            units: Arc::new(Mutex::new(HashMap::new())),
+            listening: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn is_socket_name(name: &str) -> bool {
+        name.ends_with(".socket")
+    }
+
+    pub fn set_units(&self, units: HashMap<String, SocketUnit>) {
+        *self.units.lock().unwrap() = units;
+    }
+
+    pub fn get_unit(&self, name: &str) -> Result<SocketUnit> {
+        self.units.lock().unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Socket unit not found: {}", name))
+    }
+
+    pub fn unit_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.units.lock().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Socket units that activate the given service.
+    pub fn sockets_for_service(&self, service: &str) -> Vec<String> {
+        self.units.lock().unwrap()
+            .values()
+            .filter(|unit| unit.service_name() == service)
+            .map(|unit| unit.name.clone())
+            .collect()
+    }
+
+    pub fn is_listening(&self, name: &str) -> bool {
+        self.listening.lock().unwrap().contains_key(name)
+    }
+
+    /// Binds the sockets of a unit. Returns false if it was already bound.
+    pub fn listen(&self, name: &str) -> Result<bool> {
+        if self.is_listening(name) {
+            return Ok(false);
+        }
+
+        let unit = self.get_unit(name)?;
+        let fds = bind_socket_unit(&unit)
+            .context(format!("Failed to bind sockets for {}", name))?;
+
+        info!("Listening on {} for {}", name, unit.service_name());
+
+        self.listening.lock().unwrap().insert(name.to_string(), ListeningSocket {
+            unit,
+            fds,
+            watcher: None,
+        });
+
+        Ok(true)
+    }
+
+    /// Takes over the sockets of a unit bound by a previous daemon.
+    pub fn adopt(&self, name: &str, fds: Vec<OwnedFd>) -> Result<()> {
+        let unit = self.get_unit(name)?;
+
+        info!("Adopted {} sockets of {}", fds.len(), name);
+
+        self.listening.lock().unwrap().insert(name.to_string(), ListeningSocket {
+            unit,
+            fds,
+            watcher: None,
+        });
+
+        Ok(())
+    }
+
+    /// Duplicates of the bound sockets that survive the exec of the next
+    /// daemon.
+    pub fn serialize(&self) -> Vec<SerializedSocket> {
+        let listening = self.listening.lock().unwrap();
+
+        listening.iter()
+            .filter_map(|(name, socket)| {
+                let fds = socket.fds.iter()
+                    .map(|fd| keep_across_exec(fd.as_raw_fd()))
+                    .collect::<Result<Vec<_>>>();
+                match fds {
+                    Ok(fds) => Some(SerializedSocket { name: name.clone(), fds }),
+                    Err(e) => {
+                        warn!("Not handing over sockets of {}: {:#}", name, e);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    pub fn set_watcher(&self, name: &str, watcher: tokio::task::JoinHandle<()>) {
+        if let Some(socket) = self.listening.lock().unwrap().get_mut(name) {
+            socket.watcher = Some(watcher);
+        }
+    }
+
+    pub fn close(&self, name: &str) {
+        if self.listening.lock().unwrap().remove(name).is_some() {
+            info!("Closed sockets of {}", name);
+        }
+    }
+
+    /// File descriptors to pass to a service via LISTEN_FDS, paired with
+    /// their LISTEN_FDNAMES entry. Accept=yes sockets are never passed
+    /// whole; their instances get a single connection instead.
+    pub fn fds_for_service(&self, service: &str) -> Vec<(RawFd, String)> {
+        let listening = self.listening.lock().unwrap();
+        let mut names: Vec<&String> = listening.keys().collect();
+        names.sort();
+
+        names.into_iter()
+            .filter_map(|name| listening.get(name))
+            .filter(|socket| socket.unit.service_name() == service && !socket.unit.accepts_connections())
+            .flat_map(|socket| {
+                let fd_name = socket.unit.name.trim_end_matches(".socket").to_string();
+                socket.fds.iter().map(move |fd| (fd.as_raw_fd(), fd_name.clone()))
+            })
+            .collect()
+    }
+
+    fn dup_fds(&self, name: &str) -> Result<Vec<OwnedFd>> {
+        let listening = self.listening.lock().unwrap();
+        let socket = listening.get(name)
+            .ok_or_else(|| anyhow::anyhow!("Socket {} is not listening", name))?;
+
+        socket.fds.iter()
+            .map(|fd| fd.try_clone().context("Failed to duplicate socket descriptor"))
+            .collect()
+    }
+
+    pub fn summaries(&self) -> Vec<SocketSummary> {
+        let units = self.units.lock().unwrap();
+        let listening = self.listening.lock().unwrap();
+
+        let mut summaries: Vec<SocketSummary> = units.values()
+            .map(|unit| SocketSummary {
+                name: unit.name.clone(),
+                listen: unit.socket.listen_stream.iter()
+                    .chain(unit.socket.listen_datagram.iter())
+                    .chain(unit.socket.listen_fifo.iter())
+                    .flatten()
+                    .cloned()
+                    .collect(),
+                service: unit.service_name(),
+                accept: unit.accepts_connections(),
+                listening: listening.contains_key(&unit.name),
+            })
+            .collect();
+
+        summaries.sort_by(|a, b| a.name.cmp(&b.name));
+        summaries
+    }
+}
+
+impl Default for SocketRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn bind_socket_unit(unit: &SocketUnit) -> Result<Vec<OwnedFd>> {
+    let section = &unit.socket;
+    let mode = unit.socket_mode();
+    let backlog = section.backlog.unwrap_or(libc::SOMAXCONN);
+    let mut fds = Vec::new();
+
+    for address in section.listen_stream.iter().flatten() {
+        fds.push(bind_stream(&ListenAddress::parse(address)?, backlog, mode)?);
+    }
+
+    for address in section.listen_datagram.iter().flatten() {
+        fds.push(bind_datagram(&ListenAddress::parse(address)?, mode)?);
+    }
+
+    for path in section.listen_fifo.iter().flatten() {
+        fds.push(open_fifo(Path::new(path), mode)?);
+    }
+
+    // Connections are accepted by the manager itself for Accept=yes, and
+    // must never block the event loop
+    if unit.accepts_connections() {
+        for fd in &fds {
+            set_nonblocking(fd.as_raw_fd())?;
+        }
+    }
+
+    Ok(fds)
+}
+
+fn prepare_socket_path(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+fn bind_stream(address: &ListenAddress, backlog: i32, mode: u32) -> Result<OwnedFd> {
+    let fd: OwnedFd = match address {
+        ListenAddress::Unix(path) => {
+            prepare_socket_path(path)?;
+            let listener = std::os::unix::net::UnixListener::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            listener.into()
+        }
+        ListenAddress::Abstract(name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            std::os::unix::net::UnixListener::bind_addr(&addr)?.into()
+        }
+        ListenAddress::Inet(addr) => std::net::TcpListener::bind(addr)?.into(),
+    };
+
+    // std picks its own backlog; listen() again to apply Backlog=
+    if unsafe { libc::listen(fd.as_raw_fd(), backlog) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to set socket backlog");
+    }
+
+    Ok(fd)
+}
+
+fn bind_datagram(address: &ListenAddress, mode: u32) -> Result<OwnedFd> {
+    let fd: OwnedFd = match address {
+        ListenAddress::Unix(path) => {
+            prepare_socket_path(path)?;
+            let socket = std::os::unix::net::UnixDatagram::bind(path)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            socket.into()
+        }
+        ListenAddress::Abstract(name) => {
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            std::os::unix::net::UnixDatagram::bind_addr(&addr)?.into()
+        }
+        ListenAddress::Inet(addr) => std::net::UdpSocket::bind(addr)?.into(),
+    };
+
+    Ok(fd)
+}
+
+fn open_fifo(path: &Path, mode: u32) -> Result<OwnedFd> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    if !path.exists() && unsafe { libc::mkfifo(c_path.as_ptr(), mode as libc::mode_t) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .context(format!("Failed to create FIFO {}", path.display()));
+    }
+
+    // Opening read-write keeps the FIFO from reporting EOF whenever the
+    // last writer goes away
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .context(format!("Failed to open FIFO {}", path.display()));
+    }
+
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_blocking(fd.as_raw_fd())?;
+
+    Ok(fd)
+}
+
+fn set_nonblocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn set_blocking(fd: RawFd) -> Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Non-blocking check for pending connections or data.
+fn has_pending_input(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, 0) > 0 && pollfd.revents & libc::POLLIN != 0 }
+}
+
+fn accept_connection(listener: RawFd) -> std::io::Result<OwnedFd> {
+    let fd = unsafe {
+        libc::accept4(listener, std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_CLOEXEC)
+    };
+
+    if fd < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+/// Watches a bound socket unit and activates its service on first
+/// activity. Runs until the socket is closed.
+pub fn spawn_watcher(manager: ServiceManager, socket_name: String) -> Result<tokio::task::JoinHandle<()>> {
+    let unit = manager.sockets().get_unit(&socket_name)?;
+    let fds = manager.sockets().dup_fds(&socket_name)?
+        .into_iter()
+        .map(|fd| AsyncFd::with_interest(fd, Interest::READABLE))
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    Ok(tokio::spawn(async move {
+        watch_socket(manager, unit, fds).await;
+    }))
+}
+
+async fn watch_socket(manager: ServiceManager, unit: SocketUnit, fds: Vec<AsyncFd<OwnedFd>>) {
+    let service = unit.service_name();
+    let mut events = manager.subscribe();
+
+    loop {
+        let ready = wait_readable(&fds).await;
+
+        if unit.accepts_connections() {
+            accept_pending(&manager, &service, &fds[ready]).await;
+            continue;
+        }
+
+        if !is_running(&manager, &service) {
+            info!("Activating {} for incoming traffic on {}", service, unit.name);
+
+            let starter = manager.clone();
+            let name = service.clone();
+            let result = tokio::task::spawn_blocking(move || starter.start_service(&name)).await;
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => {
+                    error!("Failed to activate {} from {}: {}", service, unit.name, e);
+                    // Avoid spinning on a socket whose service cannot start
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(e) => {
+                    error!("Activation task for {} panicked: {}", service, e);
+                    continue;
+                }
+            }
+        }
+
+        // The service owns the socket now; resume watching once it exits
+        while is_running(&manager, &service) {
+            match events.recv().await {
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            }
+        }
+
+        debug!("{} exited, {} is listening again", service, unit.name);
+    }
+}
+
+fn is_running(manager: &ServiceManager, service: &str) -> bool {
+    matches!(
+        manager.get_service_status(service).map(|s| s.state),
+        Some(ServiceState::Active) | Some(ServiceState::Activating)
+            | Some(ServiceState::Reloading) | Some(ServiceState::Deactivating)
+    )
+}
+
+/// Waits until one of the descriptors has input queued and returns its
+/// index. Readiness is only cleared once the queue is actually empty,
+/// since the service may exit leaving connections behind.
+async fn wait_readable(fds: &[AsyncFd<OwnedFd>]) -> usize {
+    loop {
+        let waiters = fds.iter().map(|fd| Box::pin(fd.readable()));
+        let (result, index, _) = futures::future::select_all(waiters).await;
+
+        match result {
+            Ok(mut guard) => {
+                if has_pending_input(fds[index].get_ref().as_raw_fd()) {
+                    return index;
+                }
+                guard.clear_ready();
+            }
+            Err(e) => {
+                warn!("Failed to poll socket: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+async fn accept_pending(manager: &ServiceManager, service: &str, listener: &AsyncFd<OwnedFd>) {
+    loop {
+        match accept_connection(listener.get_ref().as_raw_fd()) {
+            Ok(connection) => {
+                let starter = manager.clone();
+                let name = service.to_string();
+                let result = tokio::task::spawn_blocking(move || {
+                    starter.start_socket_instance(&name, connection)
+                }).await;
+
+                match result {
+                    Ok(Ok(instance)) => debug!("Started {} for new connection", instance),
+                    Ok(Err(e)) => error!("Failed to start instance of {}: {}", service, e),
+                    Err(e) => error!("Instance task panicked: {}", e),
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                return;
+            }
+        }
+    }
+}
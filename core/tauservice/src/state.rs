@@ -1,11 +1,15 @@
 use crate::service_manager::{ServiceStatus, ServiceState};
+use crate::scope::ManagerScope;
+use crate::process::SerializedProcess;
+use crate::journal::current_boot_id;
 use anyhow::{Result, Context};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
-use log::{info, warn, error};
+use log::info;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct PersistentServiceState {
@@ -21,26 +25,108 @@ pub struct PersistentServiceState {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServiceStateStore {
     pub services: HashMap<String, PersistentServiceState>,
+    /// Last trigger time of each timer unit, in seconds since the epoch
+    #[serde(default)]
+    pub timers: HashMap<String, u64>,
     pub last_save: u64,
     pub version: String,
 }
 
+/// What a daemon hands over to the one replacing it, so running services
+/// are adopted instead of orphaned.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuntimeState {
+    /// Processes of other boots are long gone
+    pub boot_id: String,
+    /// The daemon that wrote the state. Its file descriptors are only
+    /// valid in the same process, after an exec
+    pub daemon_pid: u32,
+    pub statuses: Vec<ServiceStatus>,
+    pub services: Vec<SerializedService>,
+    pub sockets: Vec<SerializedSocket>,
+    /// Services waiting for an automatic restart
+    pub pending_restarts: Vec<String>,
+    pub instance_counter: u64,
+    /// Whether the boot counts as the first, decided by the first daemon
+    #[serde(default)]
+    pub first_boot: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SerializedService {
+    pub name: String,
+    /// A connection instance of an Accept=yes socket
+    pub transient: bool,
+    pub watchdog_usec: Option<u64>,
+    pub process: SerializedProcess,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SerializedSocket {
+    pub name: String,
+    pub fds: Vec<RawFd>,
+}
+
+impl RuntimeState {
+    /// Whether the descriptors in the state belong to this process.
+    pub fn owns_fds(&self) -> bool {
+        self.daemon_pid == std::process::id()
+    }
+    
+    /// Closes the descriptors kept for an exec that did not happen.
+    pub fn close_fds(&self) {
+        if !self.owns_fds() {
+            return;
+        }
+        
+        let sockets = self.sockets.iter().flat_map(|socket| socket.fds.iter().copied());
+        let pipes = self.services.iter().flat_map(|service| [service.process.stdout_fd, service.process.stderr_fd]).flatten();
+        for fd in sockets.chain(pipes) {
+            drop(take_inherited_fd(fd));
+        }
+    }
+}
+
+/// Duplicates a descriptor without FD_CLOEXEC, so it survives the exec
+/// of the next daemon.
+pub fn keep_across_exec(fd: RawFd) -> Result<RawFd> {
+    let kept = unsafe { libc::fcntl(fd, libc::F_DUPFD, 3) };
+    if kept < 0 {
+        return Err(std::io::Error::last_os_error()).context("Failed to duplicate descriptor");
+    }
+    Ok(kept)
+}
+
+/// Takes ownership of a descriptor kept by `keep_across_exec`, closing
+/// it on exec again.
+pub fn take_inherited_fd(fd: RawFd) -> OwnedFd {
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        OwnedFd::from_raw_fd(fd)
+    }
+}
+
+#[derive(Clone)]
 pub struct StateManager {
     state_dir: PathBuf,
     state_file: PathBuf,
+    runtime_file: PathBuf,
 }
 
 impl StateManager {
     pub fn new() -> Result<Self> {
-        let state_dir = PathBuf::from("/var/lib/tau-service");
-        let state_file = state_dir.join("state.json");
-        
+        let state_dir = ManagerScope::current().state_dir();
+        Self::in_dir(&state_dir)
+    }
+    
+    pub fn in_dir(state_dir: &Path) -> Result<Self> {
         // Ensure state directory exists
-        fs::create_dir_all(&state_dir)?;
+        fs::create_dir_all(state_dir)?;
         
         Ok(Self {
-            state_dir,
-            state_file,
+            state_dir: state_dir.to_path_buf(),
+            state_file: state_dir.join("state.json"),
+            runtime_file: state_dir.join("runtime.json"),
         })
     }
     
@@ -70,6 +156,85 @@ impl StateManager {
         Ok(store.services.get(service_name).cloned())
     }
     
+    pub fn save_timer_trigger(&self, timer_name: &str, triggered: SystemTime) -> Result<()> {
+        let mut store = self.load_state_store()?;
+        
+        store.timers.insert(
+            timer_name.to_string(),
+            triggered.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        );
+        store.last_save = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        
+        self.save_state_store(&store)
+    }
+    
+    pub fn load_timer_trigger(&self, timer_name: &str) -> Result<Option<SystemTime>> {
+        let store = self.load_state_store()?;
+        Ok(store.timers.get(timer_name).map(|secs| UNIX_EPOCH + std::time::Duration::from_secs(*secs)))
+    }
+    
+    /// Writes the runtime state for the next daemon, and the persistent
+    /// state of each service along with it.
+    pub fn save_runtime_state(&self, state: &RuntimeState) -> Result<()> {
+        let content = serde_json::to_string(state)
+            .context("Failed to serialize runtime state")?;
+        fs::write(&self.runtime_file, content)
+            .context("Failed to write runtime state")?;
+        
+        let mut store = self.load_state_store()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        for status in &state.statuses {
+            let service = store.services.entry(status.name.clone()).or_insert_with(|| PersistentServiceState {
+                name: status.name.clone(),
+                enabled: false,
+                last_state: status.state.clone(),
+                last_pid: None,
+                last_start_time: None,
+                restart_count: 0,
+                last_updated: now,
+            });
+            service.last_state = status.state.clone();
+            service.last_pid = status.pid;
+            service.last_start_time = status.start_time.map(|t| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+            service.restart_count = status.restart_count;
+            service.last_updated = now;
+        }
+        store.last_save = now;
+        
+        self.save_state_store(&store)
+    }
+    
+    /// Reads and removes the state left by the previous daemon. State
+    /// written before a reboot is dropped.
+    pub fn take_runtime_state(&self) -> Result<Option<RuntimeState>> {
+        let content = match fs::read_to_string(&self.runtime_file) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read runtime state"),
+        };
+        fs::remove_file(&self.runtime_file)
+            .context("Failed to remove runtime state")?;
+        
+        let state: RuntimeState = serde_json::from_str(&content)
+            .context("Failed to parse runtime state")?;
+        
+        if state.boot_id != current_boot_id() {
+            info!("Ignoring runtime state of a previous boot");
+            return Ok(None);
+        }
+        
+        Ok(Some(state))
+    }
+    
+    /// Removes state left by a daemon that exited without handing over,
+    /// so no later daemon adopts processes that were stopped.
+    pub fn remove_runtime_state(&self) -> Result<()> {
+        match fs::remove_file(&self.runtime_file) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context("Failed to remove runtime state"),
+            _ => Ok(()),
+        }
+    }
+    
     pub fn get_enabled_services(&self) -> Result<Vec<String>> {
         let store = self.load_state_store()?;
         
@@ -99,6 +264,7 @@ impl StateManager {
     pub fn clear_all_state(&self) -> Result<()> {
         let store = ServiceStateStore {
             services: HashMap::new(),
+            timers: HashMap::new(),
             last_save: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
             version: "1.0".to_string(),
         };
@@ -134,6 +300,7 @@ impl StateManager {
         } else {
             Ok(ServiceStateStore {
                 services: HashMap::new(),
+                timers: HashMap::new(),
                 last_save: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
                 version: "1.0".to_string(),
             })
@@ -0,0 +1,240 @@
+use crate::service_manager::ServiceManager;
+use crate::unit::RestartPolicy;
+use anyhow::{Result, Context};
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, sleep};
+use log::{info, warn, error, debug};
+
+/// Default delay between a service exiting and its automatic restart,
+/// matching systemd's RestartSec default.
+const DEFAULT_RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// How long a zombie child must stay unreaped before it counts as an
+/// orphan, so control commands still waited for by their caller keep
+/// their exit status.
+const ORPHAN_GRACE: Duration = Duration::from_secs(1);
+
+/// How a service's main process ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExitOutcome {
+    Exited(i32),
+    Signaled(i32),
+    /// Killed by the manager after WatchdogSec passed without a ping.
+    Watchdog,
+}
+
+impl ExitOutcome {
+    pub fn from_status(status: ExitStatus) -> Self {
+        match (status.code(), status.signal()) {
+            (Some(code), _) => ExitOutcome::Exited(code),
+            (None, Some(signal)) => ExitOutcome::Signaled(signal),
+            (None, None) => ExitOutcome::Exited(-1),
+        }
+    }
+
+    /// A clean exit is exit code 0 or one of the signals a service is
+    /// expected to die from when asked to stop.
+    pub fn is_clean(&self) -> bool {
+        match self {
+            ExitOutcome::Exited(code) => *code == 0,
+            ExitOutcome::Signaled(signal) => matches!(
+                *signal,
+                libc::SIGHUP | libc::SIGINT | libc::SIGTERM | libc::SIGPIPE
+            ),
+            ExitOutcome::Watchdog => false,
+        }
+    }
+
+    pub fn is_unclean_signal(&self) -> bool {
+        matches!(self, ExitOutcome::Signaled(_)) && !self.is_clean()
+    }
+}
+
+impl std::fmt::Display for ExitOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExitOutcome::Exited(code) => write!(f, "code={}", code),
+            ExitOutcome::Signaled(signal) => match nix::sys::signal::Signal::try_from(*signal) {
+                Ok(signal) => write!(f, "signal={}", signal.as_str()),
+                Err(_) => write!(f, "signal={}", signal),
+            },
+            ExitOutcome::Watchdog => write!(f, "watchdog"),
+        }
+    }
+}
+
+/// Decides whether a unit should be restarted after its main process
+/// ended, following the semantics of systemd's Restart= table.
+pub fn should_restart(policy: &RestartPolicy, outcome: &ExitOutcome) -> bool {
+    match policy {
+        RestartPolicy::No => false,
+        RestartPolicy::Always => true,
+        RestartPolicy::OnSuccess => outcome.is_clean(),
+        RestartPolicy::OnFailure => !outcome.is_clean(),
+        RestartPolicy::OnAbnormal => {
+            outcome.is_unclean_signal() || *outcome == ExitOutcome::Watchdog
+        }
+        RestartPolicy::OnAbort => outcome.is_unclean_signal(),
+        RestartPolicy::OnWatchdog => *outcome == ExitOutcome::Watchdog,
+    }
+}
+
+/// Reaps exited service processes and applies each unit's restart policy.
+pub struct Supervisor {
+    manager: ServiceManager,
+    /// Zombie children nobody reaped yet, by when they were first seen
+    zombies: Mutex<HashMap<i32, Instant>>,
+}
+
+impl Supervisor {
+    pub fn new(manager: ServiceManager) -> Self {
+        Self { manager, zombies: Mutex::new(HashMap::new()) }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let mut sigchld = signal(SignalKind::child())
+            .context("Failed to install SIGCHLD handler")?;
+
+        // SIGCHLD deliveries coalesce, and children that exited before the
+        // handler was installed never raise one, so poll as a fallback.
+        let mut fallback = interval(Duration::from_secs(5));
+        let mut watchdog_tick = interval(Duration::from_millis(500));
+
+        info!("Service supervisor started");
+
+        loop {
+            tokio::select! {
+                _ = sigchld.recv() => {}
+                _ = fallback.tick() => {}
+                _ = watchdog_tick.tick() => {
+                    self.manager.check_watchdogs();
+                    continue;
+                }
+            }
+
+            self.reap().await;
+        }
+    }
+
+    async fn reap(&self) {
+        // ExecStopPost= commands of exited services may take a while
+        let manager = self.manager.clone();
+        let exited = match tokio::task::spawn_blocking(move || manager.reap_exited()).await {
+            Ok(exited) => exited,
+            Err(e) => {
+                error!("Reaping services panicked: {}", e);
+                return;
+            }
+        };
+
+        for (name, outcome) in exited {
+            self.handle_exit(&name, &outcome);
+        }
+
+        self.reap_orphans();
+    }
+
+    /// Reaps processes re-parented to the manager as the subreaper, such as
+    /// the children of a forking daemon that died.
+    fn reap_orphans(&self) {
+        let tracked = self.manager.reaped_pids();
+        let now = Instant::now();
+        let mut zombies = self.zombies.lock().unwrap();
+
+        let current = zombie_children();
+        zombies.retain(|pid, _| current.contains(pid));
+
+        for pid in current {
+            if tracked.contains(&(pid as u32)) {
+                continue;
+            }
+
+            let first_seen = *zombies.entry(pid).or_insert(now);
+            if now.duration_since(first_seen) < ORPHAN_GRACE {
+                continue;
+            }
+
+            match waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+                Ok(status) => debug!("Reaped orphaned process {}: {:?}", pid, status),
+                Err(e) => debug!("Failed to reap orphaned process {}: {}", pid, e),
+            }
+            zombies.remove(&pid);
+        }
+    }
+
+    fn handle_exit(&self, name: &str, outcome: &ExitOutcome) {
+        if outcome.is_clean() {
+            info!("Service {} exited ({})", name, outcome);
+        } else {
+            warn!("Service {} failed ({})", name, outcome);
+        }
+
+        let unit = match self.manager.get_unit(name) {
+            Ok(unit) => unit,
+            Err(e) => {
+                debug!("Not restarting {}: {}", name, e);
+                return;
+            }
+        };
+
+        let policy = unit.service.restart.clone().unwrap_or(RestartPolicy::No);
+        if !should_restart(&policy, outcome) {
+            return;
+        }
+
+        let delay = unit.service.restart_sec
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RESTART_DELAY);
+
+        info!("Scheduling restart of {} in {:?}", name, delay);
+        self.manager.schedule_restart(name);
+
+        let manager = self.manager.clone();
+        let name = name.to_string();
+        tokio::spawn(async move {
+            sleep(delay).await;
+
+            let restart_name = name.clone();
+            let result = tokio::task::spawn_blocking(move || manager.auto_restart(&restart_name)).await;
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to restart service {}: {}", name, e),
+                Err(e) => error!("Restart task for {} panicked: {}", name, e),
+            }
+        });
+    }
+}
+
+/// Children of the manager that exited and were not waited for yet.
+fn zombie_children() -> Vec<i32> {
+    let own_pid = std::process::id() as i32;
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("Failed to list processes: {}", e);
+            return Vec::new();
+        }
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| {
+            let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+                return false;
+            };
+            // The command name may contain spaces, the fields after it do not
+            let fields: Vec<&str> = match stat.rfind(')') {
+                Some(end) => stat[end + 1..].split_whitespace().collect(),
+                None => return false,
+            };
+            fields.first() == Some(&"Z") && fields.get(1).and_then(|ppid| ppid.parse().ok()) == Some(own_pid)
+        })
+        .collect()
+}
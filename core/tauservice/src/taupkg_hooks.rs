@@ -1,11 +1,10 @@
 use crate::service_manager::ServiceManager;
-use crate::unit::ServiceUnit;
 use crate::sandbox::SandboxManager;
-use anyhow::{Result, Context};
+use anyhow::Result;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use log::{info, warn, error};
+use log::info;
 
 pub struct TauPkgHooks {
     service_manager: ServiceManager,
@@ -33,8 +32,8 @@ impl TauPkgHooks {
         
         if !service_units.is_empty() {
             // Register services with TauService
-            for (service_name, unit_content) in service_units {
-                self.register_service(&service_name, &unit_content)?;
+            for (service_name, unit_content) in &service_units {
+                self.register_service(service_name, unit_content)?;
             }
             
             // Create post-install hook
@@ -84,7 +83,7 @@ impl TauPkgHooks {
             .filter_map(|e| e.ok()) {
             
             let path = entry.path();
-            if path.is_file() && path.extension().map_or(false, |ext| ext == "tau") {
+            if path.is_file() && path.extension().is_some_and(|ext| ext == "tau") {
                 if let Some(service_name) = path.file_stem() {
                     let content = fs::read_to_string(path)?;
                     service_units.insert(service_name.to_string_lossy().to_string(), content);
@@ -247,7 +246,7 @@ wanted_by = ["multi-user.target"]
                 let entry = entry?;
                 let path = entry.path();
                 
-                if path.is_file() && path.extension().map_or(false, |ext| ext == "tau") {
+                if path.is_file() && path.extension().is_some_and(|ext| ext == "tau") {
                     // Check if this service belongs to the package
                     if let Ok(content) = fs::read_to_string(&path) {
                         if content.contains(&format!("package = \"{}\"", package_name)) {
@@ -0,0 +1,315 @@
+use crate::calendar::CalendarSpec;
+use crate::service_manager::ServiceManager;
+use crate::state::StateManager;
+use crate::unit::TimerUnit;
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+use uuid::Uuid;
+use log::{info, warn, error, debug};
+
+/// Runtime state of a loaded timer unit.
+struct TimerEntry {
+    unit: TimerUnit,
+    calendar: Vec<CalendarSpec>,
+    /// When the timer last fired, restored from disk for Persistent= timers
+    last_trigger: Option<SystemTime>,
+    /// When the timer was loaded; calendar events before it are only
+    /// caught up on for Persistent= timers
+    loaded_at: SystemTime,
+    next_elapse: Option<SystemTime>,
+    boot_fired: bool,
+    startup_fired: bool,
+}
+
+impl TimerEntry {
+    fn new(unit: TimerUnit, last_trigger: Option<SystemTime>) -> Result<Self> {
+        let calendar = unit.calendar_specs()?;
+
+        Ok(Self {
+            unit,
+            calendar,
+            last_trigger,
+            loaded_at: SystemTime::now(),
+            next_elapse: None,
+            boot_fired: false,
+            startup_fired: false,
+        })
+    }
+
+    /// The next time any trigger elapses, without the randomized delay.
+    fn compute_next(&self, boot_time: SystemTime, startup_time: SystemTime, unit_active: Option<SystemTime>) -> Option<SystemTime> {
+        let timer = &self.unit.timer;
+        let mut candidates = Vec::new();
+
+        if let (Some(sec), false) = (timer.on_boot_sec, self.boot_fired) {
+            candidates.push(boot_time + Duration::from_secs(sec));
+        }
+
+        if let (Some(sec), false) = (timer.on_startup_sec, self.startup_fired) {
+            candidates.push(startup_time + Duration::from_secs(sec));
+        }
+
+        // Relative to whichever happened last: the service starting or this
+        // timer firing. Never fires before the service was activated once.
+        if let Some(sec) = timer.on_unit_active_sec {
+            let base = match (unit_active, self.last_trigger.filter(|t| *t >= boot_time)) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            };
+            if let Some(base) = base {
+                candidates.push(base + Duration::from_secs(sec));
+            }
+        }
+
+        if !self.calendar.is_empty() {
+            let reference = match self.last_trigger {
+                Some(last) if self.unit.is_persistent() => last,
+                Some(last) => last.max(self.loaded_at),
+                None => self.loaded_at,
+            };
+            let reference: DateTime<Local> = reference.into();
+
+            for spec in &self.calendar {
+                if let Some(next) = spec.next_after(reference) {
+                    candidates.push(next.into());
+                }
+            }
+        }
+
+        candidates.into_iter().min()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimerSummary {
+    pub name: String,
+    pub service: String,
+    pub next_elapse: Option<SystemTime>,
+    pub last_trigger: Option<SystemTime>,
+    pub persistent: bool,
+}
+
+/// Timer units known to the manager and when they fire next.
+#[derive(Clone)]
+pub struct TimerRegistry {
+    timers: Arc<Mutex<HashMap<String, TimerEntry>>>,
+    state: Arc<Mutex<Option<StateManager>>>,
+    startup_time: SystemTime,
+}
+
+impl TimerRegistry {
+    pub fn new() -> Self {
+        Self {
+            timers: Arc::new(Mutex::new(HashMap::new())),
+            state: Arc::new(Mutex::new(None)),
+            startup_time: SystemTime::now(),
+        }
+    }
+
+    /// Enables persisting trigger times, and restores those of the
+    /// timers already loaded.
+    pub fn attach_state(&self, state: StateManager) {
+        {
+            let mut timers = self.timers.lock().unwrap();
+            for (name, entry) in timers.iter_mut() {
+                if entry.last_trigger.is_none() {
+                    entry.last_trigger = state.load_timer_trigger(name).ok().flatten();
+                    entry.next_elapse = None;
+                }
+            }
+        }
+
+        *self.state.lock().unwrap() = Some(state);
+    }
+
+    /// Replaces the loaded timer units, keeping the runtime state of
+    /// timers that are still present.
+    pub fn set_units(&self, units: HashMap<String, TimerUnit>) {
+        let state = self.state.lock().unwrap().clone();
+        let mut timers = self.timers.lock().unwrap();
+        let mut previous = std::mem::take(&mut *timers);
+
+        for (name, unit) in units {
+            let entry = match previous.remove(&name) {
+                Some(mut entry) => {
+                    entry.calendar = unit.calendar_specs().unwrap_or_default();
+                    entry.unit = unit;
+                    entry.next_elapse = None;
+                    Ok(entry)
+                }
+                None => {
+                    let last_trigger = state.as_ref()
+                        .and_then(|s| s.load_timer_trigger(&name).ok().flatten());
+                    TimerEntry::new(unit, last_trigger)
+                }
+            };
+
+            match entry {
+                Ok(entry) => { timers.insert(name, entry); }
+                Err(e) => warn!("Failed to load timer {}: {}", name, e),
+            }
+        }
+    }
+
+    pub fn get_unit(&self, name: &str) -> Result<TimerUnit> {
+        self.timers.lock().unwrap()
+            .get(name)
+            .map(|entry| entry.unit.clone())
+            .ok_or_else(|| anyhow::anyhow!("Timer unit not found: {}", name))
+    }
+
+    pub fn summaries(&self) -> Vec<TimerSummary> {
+        let timers = self.timers.lock().unwrap();
+
+        let mut summaries: Vec<TimerSummary> = timers.iter()
+            .map(|(name, entry)| TimerSummary {
+                name: name.clone(),
+                service: entry.unit.service_name(),
+                next_elapse: entry.next_elapse,
+                last_trigger: entry.last_trigger,
+                persistent: entry.unit.is_persistent(),
+            })
+            .collect();
+
+        // Soonest first, timers that will never fire again last
+        summaries.sort_by(|a, b| match (a.next_elapse, b.next_elapse) {
+            (Some(x), Some(y)) => x.cmp(&y),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => a.name.cmp(&b.name),
+        });
+        summaries
+    }
+
+    /// Schedules timers without a next elapse and returns the services of
+    /// those that are due.
+    fn elapse(&self, manager: &ServiceManager, now: SystemTime) -> Vec<(String, String)> {
+        let boot_time = boot_time(now);
+        let state = self.state.lock().unwrap().clone();
+        let mut due = Vec::new();
+        let mut timers = self.timers.lock().unwrap();
+
+        for (name, entry) in timers.iter_mut() {
+            let service = entry.unit.service_name();
+
+            if entry.next_elapse.is_none() {
+                let unit_active = manager.get_service_status(&service).and_then(|s| s.start_time);
+                entry.next_elapse = entry.compute_next(boot_time, self.startup_time, unit_active)
+                    .map(|next| next + random_delay(entry.unit.randomized_delay()));
+
+                if let Some(next) = entry.next_elapse {
+                    let next: DateTime<Local> = next.into();
+                    debug!("Timer {} next elapses at {}", name, next.format("%Y-%m-%d %H:%M:%S"));
+                }
+            }
+
+            let Some(next) = entry.next_elapse else {
+                continue;
+            };
+
+            if next > now {
+                continue;
+            }
+
+            if let Some(sec) = entry.unit.timer.on_boot_sec {
+                entry.boot_fired |= boot_time + Duration::from_secs(sec) <= now;
+            }
+            if let Some(sec) = entry.unit.timer.on_startup_sec {
+                entry.startup_fired |= self.startup_time + Duration::from_secs(sec) <= now;
+            }
+
+            entry.last_trigger = Some(now);
+            entry.next_elapse = None;
+
+            if let Some(state) = &state {
+                if let Err(e) = state.save_timer_trigger(name, now) {
+                    warn!("Failed to save trigger time of {}: {}", name, e);
+                }
+            }
+
+            due.push((name.clone(), service));
+        }
+
+        due
+    }
+}
+
+impl Default for TimerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wall clock time the system booted, from the monotonic boot clock.
+fn boot_time(now: SystemTime) -> SystemTime {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    if unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) } != 0 {
+        return now;
+    }
+
+    now - Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// Uniformly spreads elapse times over RandomizedDelaySec so many
+/// machines with the same timer do not fire at once.
+fn random_delay(max: Duration) -> Duration {
+    if max.is_zero() {
+        return Duration::ZERO;
+    }
+
+    let millis = max.as_millis();
+    Duration::from_millis((Uuid::new_v4().as_u128() % (millis + 1)) as u64)
+}
+
+/// Fires timer units and starts the services they activate.
+pub struct TimerScheduler {
+    manager: ServiceManager,
+}
+
+impl TimerScheduler {
+    pub fn new(manager: ServiceManager) -> Self {
+        Self { manager }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        match StateManager::new() {
+            Ok(state) => self.manager.timers().attach_state(state),
+            Err(e) => warn!("Timer trigger times will not persist: {}", e),
+        }
+
+        // The default AccuracySec of systemd is a minute; a second is plenty
+        let mut tick = interval(Duration::from_secs(1));
+
+        info!("Timer scheduler started");
+
+        loop {
+            tick.tick().await;
+
+            for (timer, service) in self.manager.timers().elapse(&self.manager, SystemTime::now()) {
+                if self.manager.is_service_active(&service) {
+                    debug!("Timer {} elapsed, {} is already running", timer, service);
+                    continue;
+                }
+
+                info!("Timer {} elapsed, starting {}", timer, service);
+
+                // A oneshot runs to completion in its start, which must not
+                // hold up the other timers
+                let manager = self.manager.clone();
+                tokio::spawn(async move {
+                    let result = tokio::task::spawn_blocking(move || manager.start_service(&service)).await;
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(e)) => error!("Failed to start service for timer {}: {}", timer, e),
+                        Err(e) => error!("Timer task for {} panicked: {}", timer, e),
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,511 @@
+use crate::unit::UnitDependencies;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use log::{info, warn, debug};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum TransactionError {
+    #[error("Unit {unit} required by {required_by} not found")]
+    UnitNotFound { unit: String, required_by: String },
+    #[error("Ordering cycle: {}", .0.join(" -> "))]
+    OrderingCycle(Vec<String>),
+    #[error("Conflicting jobs: {0} conflicts with {1}")]
+    Conflict(String, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobType {
+    Start,
+    Stop,
+}
+
+impl fmt::Display for JobType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobType::Start => write!(f, "start"),
+            JobType::Stop => write!(f, "stop"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JobOutcome {
+    Done,
+    /// Nothing to do, the unit already was in the requested state
+    Skipped,
+    Failed(String),
+    /// Not run, or undone, because a unit it requires failed
+    DependencyFailed(String),
+}
+
+impl fmt::Display for JobOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobOutcome::Done => write!(f, "done"),
+            JobOutcome::Skipped => write!(f, "skipped"),
+            JobOutcome::Failed(e) => write!(f, "failed: {}", e),
+            JobOutcome::DependencyFailed(dep) => write!(f, "dependency {} failed", dep),
+        }
+    }
+}
+
+impl JobOutcome {
+    pub fn is_failure(&self) -> bool {
+        matches!(self, JobOutcome::Failed(_) | JobOutcome::DependencyFailed(_))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobResult {
+    pub unit: String,
+    pub job_type: JobType,
+    pub outcome: JobOutcome,
+    /// Offset from the start of the transaction
+    pub started_at: Duration,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TransactionReport {
+    pub jobs: Vec<JobResult>,
+}
+
+impl TransactionReport {
+    pub fn outcome(&self, unit: &str) -> Option<&JobOutcome> {
+        self.jobs.iter().find(|job| job.unit == unit).map(|job| &job.outcome)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &JobResult> {
+        self.jobs.iter().filter(|job| job.outcome.is_failure())
+    }
+
+    /// Turns the outcome of a unit into an error if its job failed.
+    pub fn check(&self, unit: &str) -> Result<()> {
+        match self.outcome(unit) {
+            Some(JobOutcome::Failed(e)) => Err(anyhow::anyhow!("{}", e)),
+            Some(JobOutcome::DependencyFailed(dep)) => {
+                Err(anyhow::anyhow!("Dependency {} of {} failed", dep, unit))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What the transaction engine needs from the manager to run jobs.
+pub trait JobRunner: Sync {
+    fn is_active(&self, unit: &str) -> bool;
+    /// Starts a single unit and returns once it is up (for Type=notify,
+    /// ready); dependencies are the transaction's business.
+    fn start_unit(&self, unit: &str) -> Result<()>;
+    fn stop_unit(&self, unit: &str) -> Result<()>;
+}
+
+#[derive(Debug, Clone)]
+struct Job {
+    job_type: JobType,
+    /// Jobs that have to finish before this one runs
+    waits_for: BTreeSet<String>,
+    /// Start jobs whose failure fails this one (Requires=, BindsTo=)
+    requires: BTreeSet<String>,
+    /// Requested explicitly rather than pulled in
+    anchor: bool,
+}
+
+/// A set of jobs built from the dependency graph of the units involved,
+/// checked for cycles and conflicts before anything runs.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    jobs: BTreeMap<String, Job>,
+}
+
+impl Transaction {
+    /// Starts `roots` and everything they pull in through Requires=,
+    /// BindsTo= and Wants=. Units listed in Conflicts= (in either
+    /// direction) are stopped first.
+    pub fn start(graph: &HashMap<String, UnitDependencies>, roots: &[String]) -> Result<Self, TransactionError> {
+        let mut jobs: BTreeMap<String, Job> = BTreeMap::new();
+        let mut queue: Vec<(String, Option<String>, bool)> = roots.iter()
+            .map(|root| (root.clone(), None, true))
+            .collect();
+
+        // Pull in dependencies. Requires= and BindsTo= must exist, a
+        // missing Wants= is only worth a warning.
+        while let Some((unit, pulled_by, hard)) = queue.pop() {
+            let Some(deps) = graph.get(&unit) else {
+                match pulled_by {
+                    Some(by) if !hard => {
+                        warn!("Unit {} wanted by {} not found, ignoring", unit, by);
+                        continue;
+                    }
+                    by => {
+                        return Err(TransactionError::UnitNotFound {
+                            required_by: by.unwrap_or_else(|| "request".to_string()),
+                            unit,
+                        });
+                    }
+                }
+            };
+
+            if let Some(job) = jobs.get_mut(&unit) {
+                job.anchor |= pulled_by.is_none();
+                continue;
+            }
+
+            jobs.insert(unit.clone(), Job {
+                job_type: JobType::Start,
+                waits_for: BTreeSet::new(),
+                requires: deps.requires.iter().chain(&deps.binds_to).cloned().collect(),
+                anchor: pulled_by.is_none(),
+            });
+
+            for dep in deps.requires.iter().chain(&deps.binds_to) {
+                queue.push((dep.clone(), Some(unit.clone()), true));
+            }
+            for dep in &deps.wants {
+                queue.push((dep.clone(), Some(unit.clone()), false));
+            }
+        }
+
+        // Stop conflicting units, before the unit conflicting with them
+        // starts
+        let started: Vec<String> = jobs.keys().cloned().collect();
+        for unit in &started {
+            for other in conflicts_of(graph, unit) {
+                if let Some(job) = jobs.get(&other) {
+                    if job.job_type == JobType::Start {
+                        return Err(TransactionError::Conflict(unit.clone(), other));
+                    }
+                }
+
+                jobs.entry(other.clone()).or_insert_with(|| Job {
+                    job_type: JobType::Stop,
+                    waits_for: BTreeSet::new(),
+                    requires: BTreeSet::new(),
+                    anchor: false,
+                });
+                jobs.get_mut(unit).unwrap().waits_for.insert(other);
+            }
+        }
+
+        let mut transaction = Self { jobs };
+        transaction.add_ordering(graph);
+        transaction.break_cycles()?;
+        Ok(transaction)
+    }
+
+    /// Stops `roots` and the units that cannot run without them: those
+    /// with Requires=, BindsTo= or PartOf= on a stopped unit.
+    pub fn stop(graph: &HashMap<String, UnitDependencies>, roots: &[String]) -> Result<Self, TransactionError> {
+        let mut jobs = BTreeMap::new();
+        let mut queue: Vec<(String, bool)> = roots.iter().map(|root| (root.clone(), true)).collect();
+
+        while let Some((unit, anchor)) = queue.pop() {
+            if jobs.contains_key(&unit) {
+                continue;
+            }
+
+            if anchor && !graph.contains_key(&unit) {
+                return Err(TransactionError::UnitNotFound { unit, required_by: "request".to_string() });
+            }
+
+            for (other, deps) in graph {
+                if deps.requires.contains(&unit) || deps.binds_to.contains(&unit) || deps.part_of.contains(&unit) {
+                    queue.push((other.clone(), false));
+                }
+            }
+
+            jobs.insert(unit, Job {
+                job_type: JobType::Stop,
+                waits_for: BTreeSet::new(),
+                requires: BTreeSet::new(),
+                anchor,
+            });
+        }
+
+        let mut transaction = Self { jobs };
+        transaction.add_ordering(graph);
+        transaction.break_cycles()?;
+        Ok(transaction)
+    }
+
+    pub fn units(&self) -> impl Iterator<Item = (&str, JobType)> {
+        self.jobs.iter().map(|(unit, job)| (unit.as_str(), job.job_type))
+    }
+
+    /// Units the job of `unit` waits for, for inspection and tests.
+    pub fn waits_for(&self, unit: &str) -> Vec<String> {
+        self.jobs.get(unit).map(|job| job.waits_for.iter().cloned().collect()).unwrap_or_default()
+    }
+
+    /// Turns After=/Before= between units of the transaction into edges
+    /// between their jobs. Stop jobs run in the reverse order of starts.
+    fn add_ordering(&mut self, graph: &HashMap<String, UnitDependencies>) {
+        let names: Vec<String> = self.jobs.keys().cloned().collect();
+
+        for a in &names {
+            for b in &names {
+                if a == b {
+                    continue;
+                }
+
+                let a_after_b = graph.get(a).is_some_and(|deps| deps.after.contains(b))
+                    || graph.get(b).is_some_and(|deps| deps.before.contains(a));
+
+                // Sockets are listening before the services they activate
+                let socket_first = b.ends_with(".socket")
+                    && self.jobs[a].job_type == JobType::Start
+                    && graph.get(a).is_some_and(|deps| {
+                        deps.requires.contains(b) || deps.wants.contains(b) || deps.binds_to.contains(b)
+                    });
+
+                if !(a_after_b || socket_first) {
+                    continue;
+                }
+
+                match (self.jobs[a].job_type, self.jobs[b].job_type) {
+                    (JobType::Start, JobType::Start) => { self.jobs.get_mut(a).unwrap().waits_for.insert(b.clone()); }
+                    (JobType::Stop, JobType::Stop) => { self.jobs.get_mut(b).unwrap().waits_for.insert(a.clone()); }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Finds ordering cycles. A cycle through a job that nothing needs
+    /// (pulled in by Wants= only) is broken by dropping that job, any
+    /// other cycle fails the transaction.
+    fn break_cycles(&mut self) -> Result<(), TransactionError> {
+        while let Some(cycle) = self.find_cycle() {
+            let droppable = cycle.iter().find(|unit| {
+                let job = &self.jobs[*unit];
+                !job.anchor && !self.jobs.values().any(|other| other.requires.contains(*unit))
+            }).cloned();
+
+            let Some(unit) = droppable else {
+                return Err(TransactionError::OrderingCycle(cycle));
+            };
+
+            warn!("Ordering cycle {} broken by dropping the job of {}", cycle.join(" -> "), unit);
+            self.jobs.remove(&unit);
+            for job in self.jobs.values_mut() {
+                job.waits_for.remove(&unit);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns a cycle as the path around it, starting and ending with
+    /// the same unit.
+    fn find_cycle(&self) -> Option<Vec<String>> {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Mark { Visiting, Done }
+
+        fn visit(transaction: &Transaction, unit: &str, marks: &mut HashMap<String, Mark>, path: &mut Vec<String>) -> Option<Vec<String>> {
+            match marks.get(unit) {
+                Some(Mark::Done) => return None,
+                Some(Mark::Visiting) => {
+                    let start = path.iter().position(|u| u == unit).unwrap();
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(unit.to_string());
+                    return Some(cycle);
+                }
+                None => {}
+            }
+
+            marks.insert(unit.to_string(), Mark::Visiting);
+            path.push(unit.to_string());
+
+            for next in &transaction.jobs[unit].waits_for {
+                if let Some(cycle) = visit(transaction, next, marks, path) {
+                    return Some(cycle);
+                }
+            }
+
+            path.pop();
+            marks.insert(unit.to_string(), Mark::Done);
+            None
+        }
+
+        let mut marks = HashMap::new();
+        for unit in self.jobs.keys() {
+            if let Some(cycle) = visit(self, unit, &mut marks, &mut Vec::new()) {
+                return Some(cycle);
+            }
+        }
+
+        None
+    }
+
+    /// Runs the jobs, each on its own thread as soon as the jobs it is
+    /// ordered after have finished. A failed start fails the jobs that
+    /// require it and stops those of them that already started.
+    pub fn execute<R: JobRunner>(&self, runner: &R) -> TransactionReport {
+        let begin = Instant::now();
+        let runtime = tokio::runtime::Handle::try_current().ok();
+        let mut results: HashMap<String, JobResult> = HashMap::new();
+        let mut running: BTreeSet<String> = BTreeSet::new();
+
+        info!("Executing transaction of {} jobs", self.jobs.len());
+
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel::<(String, JobOutcome, Duration, Duration)>();
+
+            loop {
+                let ready: Vec<String> = self.jobs.iter()
+                    .filter(|(unit, _)| !results.contains_key(*unit) && !running.contains(*unit))
+                    .filter(|(_, job)| job.waits_for.iter().all(|dep| results.contains_key(dep)))
+                    .map(|(unit, _)| unit.clone())
+                    .collect();
+
+                for unit in ready {
+                    let job = &self.jobs[&unit];
+
+                    if let Some(dep) = job.requires.iter().find(|dep| self.failed(&results, dep)) {
+                        debug!("Not starting {}, {} failed", unit, dep);
+                        let outcome = JobOutcome::DependencyFailed(dep.clone());
+                        results.insert(unit.clone(), self.result(&unit, outcome, begin.elapsed(), Duration::ZERO));
+                        continue;
+                    }
+
+                    running.insert(unit.clone());
+                    let sender = sender.clone();
+                    let runtime = runtime.clone();
+                    let job_type = job.job_type;
+                    let anchor = job.anchor;
+
+                    scope.spawn(move || {
+                        // Units may spawn tasks on the manager's runtime
+                        let _guard = runtime.as_ref().map(|handle| handle.enter());
+                        let started_at = begin.elapsed();
+                        let outcome = run_job(runner, &unit, job_type, anchor);
+                        let _ = sender.send((unit, outcome, started_at, begin.elapsed() - started_at));
+                    });
+                }
+
+                if running.is_empty() {
+                    if results.len() < self.jobs.len() {
+                        // Cannot happen with an acyclic graph
+                        warn!("Transaction stalled with {} jobs left", self.jobs.len() - results.len());
+                    }
+                    break;
+                }
+
+                let Ok((unit, outcome, started_at, duration)) = receiver.recv() else {
+                    break;
+                };
+                running.remove(&unit);
+
+                // A required unit may have failed while this one was
+                // starting in parallel to it
+                let outcome = match self.jobs[&unit].requires.iter().find(|dep| self.failed(&results, dep)) {
+                    Some(dep) if outcome == JobOutcome::Done => {
+                        warn!("Stopping {}, required unit {} failed", unit, dep);
+                        if let Err(e) = runner.stop_unit(&unit) {
+                            warn!("Failed to stop {}: {}", unit, e);
+                        }
+                        JobOutcome::DependencyFailed(dep.clone())
+                    }
+                    _ => outcome,
+                };
+
+                let is_failure = outcome.is_failure();
+                results.insert(unit.clone(), self.result(&unit, outcome, started_at, duration));
+
+                if is_failure {
+                    self.propagate_failure(runner, &unit, &mut results, begin);
+                }
+            }
+        });
+
+        let mut jobs: Vec<JobResult> = results.into_values().collect();
+        jobs.sort_by(|a, b| a.started_at.cmp(&b.started_at).then_with(|| a.unit.cmp(&b.unit)));
+        TransactionReport { jobs }
+    }
+
+    fn result(&self, unit: &str, outcome: JobOutcome, started_at: Duration, duration: Duration) -> JobResult {
+        JobResult {
+            unit: unit.to_string(),
+            job_type: self.jobs[unit].job_type,
+            outcome,
+            started_at,
+            duration,
+        }
+    }
+
+    fn failed(&self, results: &HashMap<String, JobResult>, unit: &str) -> bool {
+        results.get(unit).is_some_and(|result| result.outcome.is_failure())
+    }
+
+    /// Units that require a failed unit without being ordered after it
+    /// may already be up; they are stopped again.
+    fn propagate_failure<R: JobRunner>(&self, runner: &R, failed: &str, results: &mut HashMap<String, JobResult>, begin: Instant) {
+        let mut queue = vec![failed.to_string()];
+
+        while let Some(failed) = queue.pop() {
+            for (unit, job) in &self.jobs {
+                if !job.requires.contains(&failed) {
+                    continue;
+                }
+
+                let Some(result) = results.get_mut(unit) else {
+                    // Still pending, it sees the failure when it is ready
+                    continue;
+                };
+
+                if result.outcome != JobOutcome::Done {
+                    continue;
+                }
+
+                warn!("Stopping {}, required unit {} failed", unit, failed);
+                if let Err(e) = runner.stop_unit(unit) {
+                    warn!("Failed to stop {}: {}", unit, e);
+                }
+
+                result.outcome = JobOutcome::DependencyFailed(failed.clone());
+                result.duration = begin.elapsed() - result.started_at;
+                queue.push(unit.clone());
+            }
+        }
+    }
+}
+
+/// Explicitly requested stops always run, they also cancel pending
+/// automatic restarts of units that are not currently up.
+fn run_job<R: JobRunner>(runner: &R, unit: &str, job_type: JobType, anchor: bool) -> JobOutcome {
+    let result = match job_type {
+        JobType::Start if runner.is_active(unit) => return JobOutcome::Skipped,
+        JobType::Stop if !anchor && !runner.is_active(unit) => return JobOutcome::Skipped,
+        JobType::Start => runner.start_unit(unit),
+        JobType::Stop => runner.stop_unit(unit),
+    };
+
+    match result {
+        Ok(()) => JobOutcome::Done,
+        Err(e) => {
+            warn!("Job {} {} failed: {:#}", job_type, unit, e);
+            JobOutcome::Failed(format!("{:#}", e))
+        }
+    }
+}
+
+/// Units a unit conflicts with, declared on either side.
+fn conflicts_of(graph: &HashMap<String, UnitDependencies>, unit: &str) -> BTreeSet<String> {
+    let mut conflicts: BTreeSet<String> = graph.get(unit)
+        .map(|deps| deps.conflicts.iter().cloned().collect())
+        .unwrap_or_default();
+
+    for (other, deps) in graph {
+        if deps.conflicts.iter().any(|c| c == unit) {
+            conflicts.insert(other.clone());
+        }
+    }
+
+    conflicts.remove(unit);
+    conflicts
+}
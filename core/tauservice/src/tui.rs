@@ -1,37 +1,88 @@
-use crate::service_manager::{ServiceManager, ServiceState, ServiceStatus};
+use crate::control::{ControlClient, ControlRequest, ServiceSummary};
+use crate::journal::JournalQuery;
+use crate::service_manager::{ManagerEvent, ServiceState};
 use crate::state::StateManager;
 use crate::sandbox::SecurityAuditor;
+use crate::unit::UnitLoader;
 use anyhow::Result;
-use std::io::{self, Write};
-use std::time::Duration;
-use tokio::time::sleep;
-use log::{info, warn, error};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use tokio::sync::mpsc;
+use log::{info, error};
 
 pub struct TauServiceTUI {
-    service_manager: ServiceManager,
+    client: ControlClient,
+    /// State changes streamed by the daemon, which redraw the services
+    events: mpsc::UnboundedReceiver<ManagerEvent>,
+    /// Lines typed by the user, read on a thread of their own so waiting
+    /// for them does not hold up the events
+    input: mpsc::UnboundedReceiver<String>,
+    services: BTreeMap<String, ServiceSummary>,
     state_manager: StateManager,
     security_auditor: SecurityAuditor,
+    unit_loader: UnitLoader,
 }
 
 impl TauServiceTUI {
-    pub fn new() -> Result<Self> {
-        Ok(Self {
-            service_manager: ServiceManager::new()?,
+    pub async fn new() -> Result<Self> {
+        // Subscribed before the services are listed, so no change is missed
+        let mut subscription = ControlClient::connect_default().await?;
+        subscription.subscribe().await?;
+        let (event_sender, events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(Some(event)) = subscription.next_event().await {
+                if event_sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+        
+        let (input_sender, input) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                if input_sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        
+        let mut tui = Self {
+            client: ControlClient::connect_default().await?,
+            events,
+            input,
+            services: BTreeMap::new(),
             state_manager: StateManager::new()?,
             security_auditor: SecurityAuditor::new(),
-        })
+            unit_loader: UnitLoader::new(),
+        };
+        tui.refresh_services().await?;
+        Ok(tui)
     }
     
     pub async fn run(&mut self) -> Result<()> {
-        self.service_manager.load_units()?;
-        
         loop {
             self.clear_screen()?;
             self.display_header()?;
             self.display_services()?;
             self.display_menu()?;
             
-            let choice = self.get_user_choice()?;
+            let choice = tokio::select! {
+                line = self.input.recv() => match line {
+                    Some(line) => line.trim().to_string(),
+                    None => break,
+                },
+                event = self.events.recv() => {
+                    let Some(event) = event else {
+                        return Err(anyhow::anyhow!("Daemon closed the event stream"));
+                    };
+                    self.apply_event(event).await?;
+                    // Redraw once for a burst of changes
+                    while let Ok(event) = self.events.try_recv() {
+                        self.apply_event(event).await?;
+                    }
+                    continue;
+                }
+            };
             
             match choice.as_str() {
                 "1" => self.start_service_prompt().await?,
@@ -46,7 +97,7 @@ impl TauServiceTUI {
                 "0" => break,
                 _ => {
                     println!("Invalid choice. Press Enter to continue...");
-                    self.wait_for_enter()?;
+                    self.wait_for_enter().await?;
                 }
             }
         }
@@ -70,14 +121,39 @@ impl TauServiceTUI {
         Ok(())
     }
     
+    async fn refresh_services(&mut self) -> Result<()> {
+        let services = self.client.services(ControlRequest::Status { service: None }).await?;
+        self.services = services.into_iter()
+            .map(|summary| (summary.status.name.clone(), summary))
+            .collect();
+        Ok(())
+    }
+    
+    async fn apply_event(&mut self, event: ManagerEvent) -> Result<()> {
+        match event {
+            ManagerEvent::StateChanged { name, state, pid } => match self.services.get_mut(&name) {
+                Some(summary) => {
+                    summary.status.state = state;
+                    summary.status.pid = pid;
+                }
+                // A unit loaded since, such as a new instance
+                None => self.refresh_services().await?,
+            },
+            ManagerEvent::UnitsReloaded { .. } => self.refresh_services().await?,
+            ManagerEvent::UnitRemoved { name } => {
+                self.services.remove(&name);
+            }
+        }
+        Ok(())
+    }
+    
     fn display_services(&self) -> Result<()> {
-        let services = self.service_manager.list_services(None);
-        
         println!("📋 Service Status:");
         println!("{:<20} {:<12} {:<10} {:<8}", "SERVICE", "STATE", "PID", "ENABLED");
         println!("{:-<50}", "");
         
-        for status in services {
+        for summary in self.services.values() {
+            let status = &summary.status;
             let state_icon = match status.state {
                 ServiceState::Active => "🟢",
                 ServiceState::Inactive => "⚪",
@@ -97,11 +173,7 @@ impl TauServiceTUI {
             };
             
             let pid_str = status.pid.map_or("".to_string(), |pid| pid.to_string());
-            let enabled = if let Some(unit) = self.service_manager.get_unit(&status.name).ok() {
-                if unit.is_enabled() { "yes" } else { "no" }
-            } else {
-                "no"
-            };
+            let enabled = if summary.enabled { "yes" } else { "no" };
             
             println!("{:<20} {:<12} {:<10} {:<8}", 
                 status.name, 
@@ -132,23 +204,21 @@ impl TauServiceTUI {
         Ok(())
     }
     
-    fn get_user_choice(&self) -> Result<String> {
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice)?;
-        Ok(choice.trim().to_string())
+    async fn read_line(&mut self) -> Result<String> {
+        self.input.recv().await
+            .ok_or_else(|| anyhow::anyhow!("Standard input closed"))
     }
     
     async fn start_service_prompt(&mut self) -> Result<()> {
         print!("Enter service name to start: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             info!("Starting service: {}", service_name);
-            if let Err(e) = self.service_manager.start_service(service_name) {
+            if let Err(e) = self.client.call(ControlRequest::Start { service: service_name.to_string(), wait: false }).await {
                 error!("Failed to start service {}: {}", service_name, e);
                 println!("❌ Failed to start service: {}", e);
             } else {
@@ -156,7 +226,7 @@ impl TauServiceTUI {
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -164,13 +234,12 @@ impl TauServiceTUI {
         print!("Enter service name to stop: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             info!("Stopping service: {}", service_name);
-            if let Err(e) = self.service_manager.stop_service(service_name) {
+            if let Err(e) = self.client.call(ControlRequest::Stop { service: service_name.to_string(), force: false }).await {
                 error!("Failed to stop service {}: {}", service_name, e);
                 println!("❌ Failed to stop service: {}", e);
             } else {
@@ -178,7 +247,7 @@ impl TauServiceTUI {
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -186,13 +255,12 @@ impl TauServiceTUI {
         print!("Enter service name to restart: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             info!("Restarting service: {}", service_name);
-            if let Err(e) = self.service_manager.restart_service(service_name).await {
+            if let Err(e) = self.client.call(ControlRequest::Restart { service: service_name.to_string() }).await {
                 error!("Failed to restart service {}: {}", service_name, e);
                 println!("❌ Failed to restart service: {}", e);
             } else {
@@ -200,7 +268,7 @@ impl TauServiceTUI {
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -208,13 +276,12 @@ impl TauServiceTUI {
         print!("Enter service name to enable: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             info!("Enabling service: {}", service_name);
-            if let Err(e) = self.service_manager.enable_service(service_name) {
+            if let Err(e) = self.client.call(ControlRequest::Enable { service: service_name.to_string() }).await {
                 error!("Failed to enable service {}: {}", service_name, e);
                 println!("❌ Failed to enable service: {}", e);
             } else {
@@ -222,7 +289,7 @@ impl TauServiceTUI {
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -230,13 +297,12 @@ impl TauServiceTUI {
         print!("Enter service name to disable: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             info!("Disabling service: {}", service_name);
-            if let Err(e) = self.service_manager.disable_service(service_name) {
+            if let Err(e) = self.client.call(ControlRequest::Disable { service: service_name.to_string() }).await {
                 error!("Failed to disable service {}: {}", service_name, e);
                 println!("❌ Failed to disable service: {}", e);
             } else {
@@ -244,7 +310,7 @@ impl TauServiceTUI {
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -252,19 +318,34 @@ impl TauServiceTUI {
         print!("Enter service name for logs: ");
         io::stdout().flush()?;
         
-        let mut service_name = String::new();
-        io::stdin().read_line(&mut service_name)?;
+        let service_name = self.read_line().await?;
         let service_name = service_name.trim();
         
         if !service_name.is_empty() {
             println!("📋 Recent logs for service: {}", service_name);
             println!("{:-<60}", "");
             
-            // This would integrate with the journal system
-            println!("(Log display would be implemented here)");
+            let query = JournalQuery {
+                unit: Some(service_name.to_string()),
+                lines: Some(20),
+                ..Default::default()
+            };
+            
+            match self.client.logs(query).await {
+                Ok(entries) => {
+                    for entry in entries {
+                        println!("{} [{}] {}: {}",
+                            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+                            entry.stream.to_uppercase(),
+                            entry.level,
+                            entry.message);
+                    }
+                }
+                Err(e) => println!("❌ Failed to fetch logs: {}", e),
+            }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -272,20 +353,19 @@ impl TauServiceTUI {
         println!("🔒 Security Audit");
         println!("{:-<40}", "");
         
-        let services = self.service_manager.list_services(None);
+        let mut units: Vec<_> = self.unit_loader.load_all_units()?.into_values().collect();
+        units.sort_by(|a, b| a.name.cmp(&b.name));
         
-        for status in services {
-            if let Ok(unit) = self.service_manager.get_unit(&status.name) {
-                if let Some(sandbox) = &unit.sandbox {
-                    let report = self.security_auditor.audit_service_security(&status.name, sandbox);
-                    println!("Service: {}", status.name);
-                    println!("Security Score: {:.1}%", report.overall_score);
-                    println!();
-                }
+        for unit in units {
+            if let Some(sandbox) = &unit.sandbox {
+                let report = self.security_auditor.audit_service_security(&unit.name, sandbox);
+                println!("Service: {}", unit.name);
+                println!("Security Score: {:.1}%", report.overall_score);
+                println!();
             }
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
@@ -300,35 +380,33 @@ impl TauServiceTUI {
             println!("Last Save: {}", summary.last_save);
         }
         
-        let services = self.service_manager.list_services(None);
-        let active_count = services.iter().filter(|s| s.state == ServiceState::Active).count();
-        let failed_count = services.iter().filter(|s| s.state == ServiceState::Failed).count();
+        let active_count = self.services.values().filter(|s| s.status.state == ServiceState::Active).count();
+        let failed_count = self.services.values().filter(|s| s.status.state == ServiceState::Failed).count();
         
         println!("Active Services: {}", active_count);
         println!("Failed Services: {}", failed_count);
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
     async fn reload_units(&mut self) -> Result<()> {
         info!("Reloading service units");
-        if let Err(e) = self.service_manager.load_units() {
+        if let Err(e) = self.client.call(ControlRequest::DaemonReload).await {
             error!("Failed to reload units: {}", e);
             println!("❌ Failed to reload units: {}", e);
         } else {
             println!("✅ Service units reloaded successfully");
         }
         
-        self.wait_for_enter()?;
+        self.wait_for_enter().await?;
         Ok(())
     }
     
-    fn wait_for_enter(&self) -> Result<()> {
+    async fn wait_for_enter(&mut self) -> Result<()> {
         print!("Press Enter to continue...");
         io::stdout().flush()?;
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+        self.read_line().await?;
         Ok(())
     }
 } 
\ No newline at end of file
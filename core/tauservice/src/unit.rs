@@ -1,9 +1,17 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use thiserror::Error;
+use crate::calendar::CalendarSpec;
+use crate::cgroup;
+use crate::credentials;
+use crate::seccomp;
+use crate::path_activation::PathCondition;
+use crate::scope::ManagerScope;
 use anyhow::{Result, Context};
+use nix::sys::signal::Signal;
 
 #[derive(Error, Debug)]
 pub enum UnitError {
@@ -29,9 +37,9 @@ pub struct ServiceUnit {
     pub sandbox: Option<SandboxSection>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct ServiceSection {
-    pub exec_start: Option<String>,
+    pub exec_start: Option<ExecStart>,
     pub exec_start_pre: Option<Vec<String>>,
     pub exec_start_post: Option<Vec<String>>,
     pub exec_stop: Option<String>,
@@ -41,6 +49,8 @@ pub struct ServiceSection {
     pub restart_sec: Option<u64>,
     pub user: Option<String>,
     pub group: Option<String>,
+    pub supplementary_groups: Option<Vec<String>>,
+    pub dynamic_user: Option<bool>,
     pub working_directory: Option<String>,
     pub environment: Option<HashMap<String, String>>,
     pub environment_file: Option<Vec<String>>,
@@ -50,13 +60,58 @@ pub struct ServiceSection {
     pub timeout_stop_sec: Option<u64>,
     pub kill_mode: Option<KillMode>,
     pub kill_signal: Option<String>,
+    #[serde(rename = "type")]
     pub type_: Option<ServiceType>,
     pub remain_after_exit: Option<bool>,
-    pub standard_output: Option<StandardOutput>,
-    pub standard_error: Option<StandardOutput>,
+    /// PIDFile=, where a Type=forking daemon writes its PID
+    pub pid_file: Option<String>,
+    pub watchdog_sec: Option<u64>,
+    pub notify_access: Option<NotifyAccess>,
+    pub memory_max: Option<String>,
+    pub memory_high: Option<String>,
+    pub cpu_quota: Option<String>,
+    pub cpu_weight: Option<u64>,
+    pub tasks_max: Option<u64>,
+    pub io_weight: Option<u64>,
+    pub standard_output: Option<String>,
+    pub standard_error: Option<String>,
+    pub syslog_identifier: Option<String>,
+    pub tty_path: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// Dependencies of a unit as the transaction engine sees them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct UnitDependencies {
+    pub requires: Vec<String>,
+    pub wants: Vec<String>,
+    pub binds_to: Vec<String>,
+    pub part_of: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub after: Vec<String>,
+    pub before: Vec<String>,
+}
+
+impl UnitDependencies {
+    pub fn from_section(section: Option<&UnitSection>) -> Self {
+        let Some(section) = section else {
+            return Self::default();
+        };
+        
+        let list = |field: &Option<Vec<String>>| field.clone().unwrap_or_default();
+        
+        Self {
+            requires: list(&section.requires),
+            wants: list(&section.wants),
+            binds_to: list(&section.binds_to),
+            part_of: list(&section.part_of),
+            conflicts: list(&section.conflicts),
+            after: list(&section.after),
+            before: list(&section.before),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct InstallSection {
     pub wanted_by: Option<Vec<String>>,
     pub required_by: Option<Vec<String>>,
@@ -64,7 +119,7 @@ pub struct InstallSection {
     pub alias: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct UnitSection {
     pub description: Option<String>,
     pub documentation: Option<Vec<String>>,
@@ -75,9 +130,31 @@ pub struct UnitSection {
     pub conflicts: Option<Vec<String>>,
     pub part_of: Option<Vec<String>>,
     pub binds_to: Option<Vec<String>>,
+    pub start_limit_interval_sec: Option<u64>,
+    pub start_limit_burst: Option<u32>,
+    /// Condition*= skip the start when not met, Assert*= fail it. Values
+    /// may be prefixed with `|` (triggering) and `!` (negated).
+    pub condition_path_exists: Option<Vec<String>>,
+    pub condition_path_is_directory: Option<Vec<String>>,
+    pub condition_file_not_empty: Option<Vec<String>>,
+    pub condition_kernel_command_line: Option<Vec<String>>,
+    pub condition_virtualization: Option<Vec<String>>,
+    pub condition_first_boot: Option<bool>,
+    pub condition_ac_power: Option<bool>,
+    pub condition_environment: Option<Vec<String>>,
+    pub condition_host: Option<Vec<String>>,
+    pub assert_path_exists: Option<Vec<String>>,
+    pub assert_path_is_directory: Option<Vec<String>>,
+    pub assert_file_not_empty: Option<Vec<String>>,
+    pub assert_kernel_command_line: Option<Vec<String>>,
+    pub assert_virtualization: Option<Vec<String>>,
+    pub assert_first_boot: Option<bool>,
+    pub assert_ac_power: Option<bool>,
+    pub assert_environment: Option<Vec<String>>,
+    pub assert_host: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct SandboxSection {
     pub read_write_paths: Option<Vec<String>>,
     pub read_only_paths: Option<Vec<String>>,
@@ -88,6 +165,123 @@ pub struct SandboxSection {
     pub protect_home: Option<bool>,
     pub network_access: Option<bool>,
     pub capabilities: Option<Vec<String>>,
+    pub capability_bounding_set: Option<Vec<String>>,
+    pub ambient_capabilities: Option<Vec<String>>,
+    pub system_call_filter: Option<Vec<String>>,
+    pub system_call_architectures: Option<Vec<String>>,
+    pub system_call_error_number: Option<String>,
+}
+
+/// A `.socket` unit: sockets the manager binds ahead of time and hands to
+/// the associated service on first activity.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SocketUnit {
+    #[serde(default)]
+    pub name: String,
+    pub description: Option<String>,
+    pub socket: SocketSection,
+    pub install: Option<InstallSection>,
+    pub unit: Option<UnitSection>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SocketSection {
+    pub listen_stream: Option<Vec<String>>,
+    pub listen_datagram: Option<Vec<String>>,
+    pub listen_fifo: Option<Vec<String>>,
+    pub accept: Option<bool>,
+    pub service: Option<String>,
+    pub backlog: Option<i32>,
+    pub socket_mode: Option<String>,
+}
+
+/// A `.timer` unit: starts its service on a calendar schedule or after
+/// a monotonic delay.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TimerUnit {
+    #[serde(default)]
+    pub name: String,
+    pub description: Option<String>,
+    pub timer: TimerSection,
+    pub install: Option<InstallSection>,
+    pub unit: Option<UnitSection>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct TimerSection {
+    pub on_calendar: Option<Vec<String>>,
+    pub on_boot_sec: Option<u64>,
+    pub on_startup_sec: Option<u64>,
+    pub on_unit_active_sec: Option<u64>,
+    pub persistent: Option<bool>,
+    pub randomized_delay_sec: Option<u64>,
+    pub unit: Option<String>,
+}
+
+/// A `.path` unit: starts its service when a file appears, changes, or
+/// a directory fills up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PathUnit {
+    #[serde(default)]
+    pub name: String,
+    pub description: Option<String>,
+    pub path: PathSection,
+    pub install: Option<InstallSection>,
+    pub unit: Option<UnitSection>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct PathSection {
+    pub path_exists: Option<Vec<String>>,
+    pub path_exists_glob: Option<Vec<String>>,
+    pub path_changed: Option<Vec<String>>,
+    pub path_modified: Option<Vec<String>>,
+    pub directory_not_empty: Option<Vec<String>>,
+    pub unit: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddress {
+    Unix(PathBuf),
+    /// Linux abstract namespace socket, written as `@name`
+    Abstract(String),
+    Inet(std::net::SocketAddr),
+}
+
+/// ExecStart=: a single command, or for Type=oneshot a list of commands
+/// run one after the other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ExecStart {
+    Single(String),
+    Sequence(Vec<String>),
+}
+
+impl ExecStart {
+    pub fn lines(&self) -> &[String] {
+        match self {
+            ExecStart::Single(line) => std::slice::from_ref(line),
+            ExecStart::Sequence(lines) => lines,
+        }
+    }
+    
+    /// The command that becomes the main process, for types other than
+    /// oneshot there is exactly one.
+    pub fn main(&self) -> Option<&str> {
+        self.lines().first().map(String::as_str)
+    }
+}
+
+impl From<&str> for ExecStart {
+    fn from(line: &str) -> Self {
+        ExecStart::Single(line.to_string())
+    }
+}
+
+impl From<String> for ExecStart {
+    fn from(line: String) -> Self {
+        ExecStart::Single(line)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -120,6 +314,17 @@ pub enum KillMode {
     None,
 }
 
+/// Which processes of a service may send notify messages.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum NotifyAccess {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "main")]
+    Main,
+    #[serde(rename = "all")]
+    All,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub enum ServiceType {
     #[serde(rename = "simple")]
@@ -134,24 +339,20 @@ pub enum ServiceType {
     Dbus,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StandardOutput {
-    #[serde(rename = "inherit")]
     Inherit,
-    #[serde(rename = "null")]
     Null,
-    #[serde(rename = "tty")]
     Tty,
-    #[serde(rename = "journal")]
     Journal,
-    #[serde(rename = "kmsg")]
     Kmsg,
-    #[serde(rename = "journal+console")]
     JournalConsole,
-    #[serde(rename = "kmsg+console")]
     KmsgConsole,
-    #[serde(rename = "socket")]
     Socket,
+    /// `file:PATH`, written from the start without truncating
+    File(PathBuf),
+    /// `append:PATH`
+    Append(PathBuf),
 }
 
 impl ServiceUnit {
@@ -179,8 +380,16 @@ impl ServiceUnit {
     
     pub fn validate(&self) -> Result<()> {
         // Check required fields
-        if self.service.exec_start.is_none() {
-            return Err(UnitError::MissingField("ExecStart".into()).into());
+        match &self.service.exec_start {
+            None => return Err(UnitError::MissingField("ExecStart".into()).into()),
+            Some(exec_start) if exec_start.lines().is_empty() => {
+                return Err(UnitError::MissingField("ExecStart".into()).into());
+            }
+            Some(exec_start) if exec_start.lines().len() > 1 && !self.is_oneshot() => {
+                return Err(UnitError::InvalidValue("ExecStart".into(),
+                    "more than one command is only allowed for Type=oneshot".into()).into());
+            }
+            _ => {}
         }
         
         // Validate restart policy
@@ -202,9 +411,69 @@ impl ServiceUnit {
             }
         }
         
+        // Validate capability names
+        if let Some(sandbox) = &self.sandbox {
+            for (field, names) in [
+                ("Capabilities", &sandbox.capabilities),
+                ("CapabilityBoundingSet", &sandbox.capability_bounding_set),
+                ("AmbientCapabilities", &sandbox.ambient_capabilities),
+            ] {
+                for name in names.iter().flatten() {
+                    credentials::parse_capability(name)
+                        .map_err(|_| UnitError::InvalidValue(field.into(), name.clone()))?;
+                }
+            }
+            
+            // Validate the syscall filter
+            if let Some(entries) = &sandbox.system_call_filter {
+                seccomp::SyscallPolicy::parse(entries)
+                    .map_err(|e| UnitError::InvalidValue("SystemCallFilter".into(), e.to_string()))?;
+            }
+            
+            if let Some(architectures) = &sandbox.system_call_architectures {
+                seccomp::parse_architectures(architectures)
+                    .map_err(|_| UnitError::InvalidValue("SystemCallArchitectures".into(), architectures.join(" ")))?;
+            }
+            
+            if let Some(errno) = &sandbox.system_call_error_number {
+                seccomp::DenyAction::parse(errno)
+                    .map_err(|_| UnitError::InvalidValue("SystemCallErrorNumber".into(), errno.clone()))?;
+            }
+        }
+        
+        self.standard_output()?;
+        self.standard_error()?;
+        
+        // Validate resource limits
+        for (field, value) in [("MemoryMax", &self.service.memory_max), ("MemoryHigh", &self.service.memory_high)] {
+            if let Some(value) = value {
+                cgroup::format_bytes_limit(value)
+                    .map_err(|_| UnitError::InvalidValue(field.into(), value.clone()))?;
+            }
+        }
+        
+        if let Some(quota) = &self.service.cpu_quota {
+            cgroup::parse_cpu_quota(quota)
+                .map_err(|_| UnitError::InvalidValue("CPUQuota".into(), quota.clone()))?;
+        }
+        
+        self.kill_signal()?;
+        
+        for (field, value) in [("CPUWeight", self.service.cpu_weight), ("IOWeight", self.service.io_weight)] {
+            if let Some(weight) = value {
+                if !(1..=10000).contains(&weight) {
+                    return Err(UnitError::InvalidValue(field.into(), weight.to_string()).into());
+                }
+            }
+        }
+        
         Ok(())
     }
     
+    pub fn dependencies(&self) -> UnitDependencies {
+        UnitDependencies::from_section(self.unit.as_ref())
+    }
+    
     pub fn get_dependencies(&self) -> Vec<String> {
         let mut deps = Vec::new();
         
@@ -223,6 +492,109 @@ impl ServiceUnit {
         deps
     }
     
+    /// Returns the start rate limit as (interval, burst). A burst of zero
+    /// disables rate limiting.
+    pub fn start_limit(&self) -> (Duration, u32) {
+        let interval = self.unit.as_ref()
+            .and_then(|u| u.start_limit_interval_sec)
+            .unwrap_or(10);
+        let burst = self.unit.as_ref()
+            .and_then(|u| u.start_limit_burst)
+            .unwrap_or(5);
+        
+        (Duration::from_secs(interval), burst)
+    }
+    
+    pub fn is_notify(&self) -> bool {
+        self.service.type_ == Some(ServiceType::Notify)
+    }
+    
+    pub fn is_forking(&self) -> bool {
+        self.service.type_ == Some(ServiceType::Forking)
+    }
+    
+    pub fn is_oneshot(&self) -> bool {
+        self.service.type_ == Some(ServiceType::OneShot)
+    }
+    
+    /// RemainAfterExit=, whether a Type=oneshot service stays active once
+    /// its commands finished.
+    pub fn remain_after_exit(&self) -> bool {
+        self.service.remain_after_exit.unwrap_or(false)
+    }
+    
+    pub fn watchdog_interval(&self) -> Option<Duration> {
+        self.service.watchdog_sec
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+    
+    /// TimeoutStartSec=, 90 seconds by default and none for Type=oneshot,
+    /// whose start lasts as long as its commands run. Zero disables the
+    /// timeout.
+    pub fn start_timeout(&self) -> Option<Duration> {
+        let default = if self.is_oneshot() { 0 } else { 90 };
+        let secs = self.service.timeout_start_sec.unwrap_or(default);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+    
+    /// TimeoutStopSec=, 90 seconds by default. Zero disables the timeout.
+    pub fn stop_timeout(&self) -> Option<Duration> {
+        let secs = self.service.timeout_stop_sec.unwrap_or(90);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+    
+    /// StandardOutput=, the journal by default.
+    pub fn standard_output(&self) -> Result<StandardOutput> {
+        match &self.service.standard_output {
+            Some(value) => StandardOutput::parse(value)
+                .map_err(|_| UnitError::InvalidValue("StandardOutput".into(), value.clone()).into()),
+            None => Ok(StandardOutput::Journal),
+        }
+    }
+    
+    /// StandardError=. `inherit`, the default, sends it wherever stdout
+    /// goes.
+    pub fn standard_error(&self) -> Result<StandardOutput> {
+        let target = match &self.service.standard_error {
+            Some(value) => StandardOutput::parse(value)
+                .map_err(|_| UnitError::InvalidValue("StandardError".into(), value.clone()))?,
+            None => StandardOutput::Inherit,
+        };
+        
+        match target {
+            StandardOutput::Inherit => self.standard_output(),
+            target => Ok(target),
+        }
+    }
+    
+    /// KillSignal=, SIGTERM by default. Takes `SIGTERM`, `TERM` or a
+    /// signal number.
+    pub fn kill_signal(&self) -> Result<Signal> {
+        let Some(name) = &self.service.kill_signal else {
+            return Ok(Signal::SIGTERM);
+        };
+        
+        let name = name.trim().to_ascii_uppercase();
+        let signal = match name.parse::<i32>() {
+            Ok(number) => Signal::try_from(number).ok(),
+            Err(_) if name.starts_with("SIG") => name.parse().ok(),
+            Err(_) => format!("SIG{}", name).parse().ok(),
+        };
+        
+        signal.ok_or_else(|| UnitError::InvalidValue("KillSignal".into(), name).into())
+    }
+    
+    /// Whether the service gets a NOTIFY_SOCKET to talk to the manager.
+    pub fn uses_notify_socket(&self) -> bool {
+        self.is_notify() || self.watchdog_interval().is_some()
+    }
+    
+    /// NotifyAccess=, only the main process by default.
+    pub fn notify_access(&self) -> NotifyAccess {
+        self.service.notify_access.clone().unwrap_or(NotifyAccess::Main)
+    }
+    
     pub fn get_targets(&self) -> Vec<String> {
         let mut targets = Vec::new();
         
@@ -245,21 +617,650 @@ impl ServiceUnit {
     }
 }
 
+impl SocketUnit {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context("Failed to read unit file")?;
+        
+        Self::from_str(&content, path)
+    }
+    
+    pub fn from_str(content: &str, path: &Path) -> Result<Self> {
+        let mut unit: SocketUnit = toml::from_str(content)
+            .context("Failed to parse socket unit file")?;
+        
+        if let Some(file_name) = path.file_stem() {
+            unit.name = format!("{}.socket", file_name.to_string_lossy());
+        }
+        
+        unit.validate()?;
+        
+        Ok(unit)
+    }
+    
+    pub fn validate(&self) -> Result<()> {
+        let socket = &self.socket;
+        
+        let listeners = socket.listen_stream.as_ref().map_or(0, |l| l.len())
+            + socket.listen_datagram.as_ref().map_or(0, |l| l.len())
+            + socket.listen_fifo.as_ref().map_or(0, |l| l.len());
+        if listeners == 0 {
+            return Err(UnitError::MissingField("ListenStream, ListenDatagram or ListenFIFO".into()).into());
+        }
+        
+        if self.accepts_connections() && (socket.listen_datagram.is_some() || socket.listen_fifo.is_some()) {
+            return Err(UnitError::InvalidValue(
+                "Accept".into(),
+                "accept = true only works with ListenStream sockets".into(),
+            ).into());
+        }
+        
+        for address in socket.listen_stream.iter().chain(socket.listen_datagram.iter()).flatten() {
+            ListenAddress::parse(address)?;
+        }
+        
+        if let Some(mode) = &socket.socket_mode {
+            u32::from_str_radix(mode, 8)
+                .map_err(|_| UnitError::InvalidValue("SocketMode".into(), mode.clone()))?;
+        }
+        
+        Ok(())
+    }
+    
+    /// The service activated by this socket, `foo.socket` activating
+    /// `foo` unless Service= says otherwise.
+    pub fn service_name(&self) -> String {
+        self.socket.service.clone().unwrap_or_else(|| {
+            self.name.trim_end_matches(".socket").to_string()
+        })
+    }
+    
+    pub fn accepts_connections(&self) -> bool {
+        self.socket.accept.unwrap_or(false)
+    }
+    
+    pub fn socket_mode(&self) -> u32 {
+        self.socket.socket_mode.as_deref()
+            .and_then(|mode| u32::from_str_radix(mode, 8).ok())
+            .unwrap_or(0o666)
+    }
+}
+
+impl TimerUnit {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context("Failed to read unit file")?;
+        
+        Self::from_str(&content, path)
+    }
+    
+    pub fn from_str(content: &str, path: &Path) -> Result<Self> {
+        let mut unit: TimerUnit = toml::from_str(content)
+            .context("Failed to parse timer unit file")?;
+        
+        if let Some(file_name) = path.file_stem() {
+            unit.name = format!("{}.timer", file_name.to_string_lossy());
+        }
+        
+        unit.validate()?;
+        
+        Ok(unit)
+    }
+    
+    pub fn validate(&self) -> Result<()> {
+        let timer = &self.timer;
+        
+        if timer.on_calendar.as_ref().is_none_or(|c| c.is_empty())
+            && timer.on_boot_sec.is_none()
+            && timer.on_startup_sec.is_none()
+            && timer.on_unit_active_sec.is_none()
+        {
+            return Err(UnitError::MissingField("OnCalendar, OnBootSec, OnStartupSec or OnUnitActiveSec".into()).into());
+        }
+        
+        self.calendar_specs()?;
+        
+        Ok(())
+    }
+    
+    pub fn calendar_specs(&self) -> Result<Vec<CalendarSpec>> {
+        self.timer.on_calendar.iter()
+            .flatten()
+            .map(|expression| {
+                CalendarSpec::parse(expression)
+                    .map_err(|e| UnitError::InvalidValue("OnCalendar".into(), e.to_string()).into())
+            })
+            .collect()
+    }
+    
+    /// The service started by this timer, `foo.timer` starting `foo`
+    /// unless Unit= says otherwise.
+    pub fn service_name(&self) -> String {
+        self.timer.unit.clone().unwrap_or_else(|| {
+            self.name.trim_end_matches(".timer").to_string()
+        })
+    }
+    
+    pub fn is_persistent(&self) -> bool {
+        self.timer.persistent.unwrap_or(false)
+    }
+    
+    pub fn randomized_delay(&self) -> Duration {
+        Duration::from_secs(self.timer.randomized_delay_sec.unwrap_or(0))
+    }
+}
+
+/// Parses the value of one of the path conditions of a path unit.
+type PathConditionParser = fn(String) -> Result<PathCondition>;
+
+impl PathUnit {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .context("Failed to read unit file")?;
+        
+        Self::from_str(&content, path)
+    }
+    
+    pub fn from_str(content: &str, path: &Path) -> Result<Self> {
+        let mut unit: PathUnit = toml::from_str(content)
+            .context("Failed to parse path unit file")?;
+        
+        if let Some(file_name) = path.file_stem() {
+            unit.name = format!("{}.path", file_name.to_string_lossy());
+        }
+        
+        unit.validate()?;
+        
+        Ok(unit)
+    }
+    
+    pub fn validate(&self) -> Result<()> {
+        if self.conditions()?.is_empty() {
+            return Err(UnitError::MissingField(
+                "PathExists, PathExistsGlob, PathChanged, PathModified or DirectoryNotEmpty".into()
+            ).into());
+        }
+        
+        Ok(())
+    }
+    
+    pub fn conditions(&self) -> Result<Vec<PathCondition>> {
+        let path = &self.path;
+        let fields: [(&str, &Option<Vec<String>>, PathConditionParser); 5] = [
+            ("PathExists", &path.path_exists, |p| PathCondition::absolute(p, PathCondition::Exists)),
+            ("PathExistsGlob", &path.path_exists_glob, PathCondition::glob),
+            ("PathChanged", &path.path_changed, |p| PathCondition::absolute(p, PathCondition::Changed)),
+            ("PathModified", &path.path_modified, |p| PathCondition::absolute(p, PathCondition::Modified)),
+            ("DirectoryNotEmpty", &path.directory_not_empty, |p| PathCondition::absolute(p, PathCondition::DirectoryNotEmpty)),
+        ];
+        
+        let mut conditions = Vec::new();
+        for (field, values, parse) in fields {
+            for value in values.iter().flatten() {
+                let condition = parse(value.clone())
+                    .map_err(|e| UnitError::InvalidValue(field.into(), e.to_string()))?;
+                conditions.push(condition);
+            }
+        }
+        
+        Ok(conditions)
+    }
+    
+    /// The service started by this path unit, `foo.path` starting `foo`
+    /// unless Unit= says otherwise.
+    pub fn service_name(&self) -> String {
+        self.path.unit.clone().unwrap_or_else(|| {
+            self.name.trim_end_matches(".path").to_string()
+        })
+    }
+}
+
+impl ListenAddress {
+    /// Parses ListenStream/ListenDatagram values: an absolute path, an
+    /// `@abstract` name, a bare port, or `host:port` / `[v6]:port`.
+    pub fn parse(value: &str) -> Result<Self> {
+        let value = value.trim();
+        
+        if value.starts_with('/') {
+            return Ok(ListenAddress::Unix(PathBuf::from(value)));
+        }
+        
+        if let Some(name) = value.strip_prefix('@') {
+            return Ok(ListenAddress::Abstract(name.to_string()));
+        }
+        
+        if let Ok(port) = value.parse::<u16>() {
+            return Ok(ListenAddress::Inet(std::net::SocketAddr::from(([0, 0, 0, 0], port))));
+        }
+        
+        value.parse::<std::net::SocketAddr>()
+            .map(ListenAddress::Inet)
+            .map_err(|_| UnitError::InvalidValue("Listen".into(), value.to_string()).into())
+    }
+}
+
+impl StandardOutput {
+    /// Parses a StandardOutput=/StandardError= value. `file:` and
+    /// `append:` take an absolute path.
+    pub fn parse(value: &str) -> Result<Self> {
+        let target = match value.trim() {
+            "inherit" => StandardOutput::Inherit,
+            "null" => StandardOutput::Null,
+            "tty" => StandardOutput::Tty,
+            "journal" => StandardOutput::Journal,
+            "kmsg" => StandardOutput::Kmsg,
+            "journal+console" => StandardOutput::JournalConsole,
+            "kmsg+console" => StandardOutput::KmsgConsole,
+            "socket" => StandardOutput::Socket,
+            other => match other.split_once(':') {
+                Some(("file", path)) if path.starts_with('/') => StandardOutput::File(PathBuf::from(path)),
+                Some(("append", path)) if path.starts_with('/') => StandardOutput::Append(PathBuf::from(path)),
+                _ => return Err(anyhow::anyhow!("Unknown output target '{}'", other)),
+            },
+        };
+        
+        Ok(target)
+    }
+    
+    /// Whether output goes to a pipe read by the manager.
+    pub fn is_captured(&self) -> bool {
+        matches!(self, StandardOutput::Journal | StandardOutput::Kmsg
+            | StandardOutput::JournalConsole | StandardOutput::KmsgConsole)
+    }
+}
+
+impl std::fmt::Display for StandardOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StandardOutput::Inherit => write!(f, "inherit"),
+            StandardOutput::Null => write!(f, "null"),
+            StandardOutput::Tty => write!(f, "tty"),
+            StandardOutput::Journal => write!(f, "journal"),
+            StandardOutput::Kmsg => write!(f, "kmsg"),
+            StandardOutput::JournalConsole => write!(f, "journal+console"),
+            StandardOutput::KmsgConsole => write!(f, "kmsg+console"),
+            StandardOutput::Socket => write!(f, "socket"),
+            StandardOutput::File(path) => write!(f, "file:{}", path.display()),
+            StandardOutput::Append(path) => write!(f, "append:{}", path.display()),
+        }
+    }
+}
+
+impl std::fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddress::Unix(path) => write!(f, "{}", path.display()),
+            ListenAddress::Abstract(name) => write!(f, "@{}", name),
+            ListenAddress::Inet(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// Unit file kinds the loader can discover by extension.
+trait UnitFile: Sized {
+    const EXTENSION: &'static str;
+    /// Sections in which `%` specifiers are expanded
+    const SPECIFIER_SECTIONS: &'static [&'static str];
+    
+    fn from_str(content: &str, path: &Path) -> Result<Self>;
+    fn unit_name(&self) -> &str;
+}
+
+impl UnitFile for ServiceUnit {
+    const EXTENSION: &'static str = "tau";
+    const SPECIFIER_SECTIONS: &'static [&'static str] = &["service", "unit"];
+    
+    fn from_str(content: &str, path: &Path) -> Result<Self> {
+        ServiceUnit::from_str(content, path)
+    }
+    
+    fn unit_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl UnitFile for TimerUnit {
+    const EXTENSION: &'static str = "timer";
+    const SPECIFIER_SECTIONS: &'static [&'static str] = &["timer", "unit"];
+    
+    fn from_str(content: &str, path: &Path) -> Result<Self> {
+        TimerUnit::from_str(content, path)
+    }
+    
+    fn unit_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl UnitFile for PathUnit {
+    const EXTENSION: &'static str = "path";
+    const SPECIFIER_SECTIONS: &'static [&'static str] = &["path", "unit"];
+    
+    fn from_str(content: &str, path: &Path) -> Result<Self> {
+        PathUnit::from_str(content, path)
+    }
+    
+    fn unit_name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl UnitFile for SocketUnit {
+    const EXTENSION: &'static str = "socket";
+    const SPECIFIER_SECTIONS: &'static [&'static str] = &["socket", "unit"];
+    
+    fn from_str(content: &str, path: &Path) -> Result<Self> {
+        SocketUnit::from_str(content, path)
+    }
+    
+    fn unit_name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Names of units whose definition differs between two loads, including
+/// removed units.
+pub fn changed_units(old: &HashMap<String, ServiceUnit>, new: &HashMap<String, ServiceUnit>) -> Vec<String> {
+    let definition = |unit: &ServiceUnit| serde_json::to_value(unit).ok();
+    
+    let mut changed: Vec<String> = old.iter()
+        .filter(|(name, unit)| new.get(*name).map(definition) != Some(definition(unit)))
+        .map(|(name, _)| name.clone())
+        .collect();
+    changed.sort();
+    changed
+}
+
+/// Splits an instance name like `getty@tty1` into the template prefix
+/// and the instance. Templates (`getty@`) and plain units have none.
+pub fn split_instance(name: &str) -> Option<(&str, &str)> {
+    let (prefix, instance) = name.split_once('@')?;
+    if instance.is_empty() {
+        None
+    } else {
+        Some((prefix, instance))
+    }
+}
+
+/// Template units are named `prefix@` and only used to create instances.
+pub fn is_template(name: &str) -> bool {
+    name.ends_with('@')
+}
+
+/// Values of the `%` specifiers in a unit file. `%h` and `%u` refer to
+/// the user running the service manager, not to `User=`.
+pub struct Specifiers {
+    name: String,
+    prefix: String,
+    instance: String,
+    home: String,
+    user: String,
+}
+
+impl Specifiers {
+    pub fn for_unit(name: &str) -> Self {
+        let (prefix, instance) = split_instance(name).unwrap_or((name, ""));
+        let user = nix::unistd::User::from_uid(nix::unistd::getuid()).ok().flatten();
+        
+        Self {
+            name: name.to_string(),
+            prefix: prefix.to_string(),
+            instance: instance.to_string(),
+            home: user.as_ref()
+                .map(|user| user.dir.display().to_string())
+                .or_else(|| std::env::var("HOME").ok())
+                .unwrap_or_else(|| "/".to_string()),
+            user: user.map(|user| user.name).unwrap_or_else(|| "root".to_string()),
+        }
+    }
+    
+    /// Expands `%i` (instance), `%n` (unit name), `%p` (template
+    /// prefix), `%h` (home directory), `%u` (user name) and `%%`.
+    pub fn expand(&self, value: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(value.len());
+        let mut chars = value.chars();
+        
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            
+            match chars.next() {
+                Some('i') => expanded.push_str(&self.instance),
+                Some('n') => expanded.push_str(&self.name),
+                Some('p') => expanded.push_str(&self.prefix),
+                Some('h') => expanded.push_str(&self.home),
+                Some('u') => expanded.push_str(&self.user),
+                Some('%') => expanded.push('%'),
+                other => {
+                    let specifier = other.map(|c| format!("%{}", c)).unwrap_or_else(|| "%".to_string());
+                    return Err(UnitError::InvalidValue("specifier".into(), format!("{} in '{}'", specifier, value)).into());
+                }
+            }
+        }
+        
+        Ok(expanded)
+    }
+    
+    fn expand_value(&self, value: &mut toml::Value) -> Result<()> {
+        match value {
+            toml::Value::String(s) => *s = self.expand(s)?,
+            toml::Value::Array(values) => {
+                for value in values {
+                    self.expand_value(value)?;
+                }
+            }
+            toml::Value::Table(table) => {
+                for (_, value) in table.iter_mut() {
+                    self.expand_value(value)?;
+                }
+            }
+            _ => {}
+        }
+        
+        Ok(())
+    }
+}
+
+/// A unit file merged with its drop-ins, remembering which file each
+/// setting came from.
+pub struct UnitSource {
+    pub name: String,
+    pub fragment: PathBuf,
+    pub drop_ins: Vec<PathBuf>,
+    pub merged: toml::Table,
+    /// Dotted key path to the file that set it
+    provenance: BTreeMap<String, PathBuf>,
+}
+
+impl UnitSource {
+    fn load(name: &str, fragment: &Path, drop_ins: Vec<PathBuf>, sections: &[&str]) -> Result<Self> {
+        let content = fs::read_to_string(fragment)
+            .context("Failed to read unit file")?;
+        let mut merged: toml::Table = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse unit file {}", fragment.display()))?;
+        
+        let mut provenance = BTreeMap::new();
+        record_sources(&merged, "", fragment, &mut provenance);
+        
+        for drop_in in &drop_ins {
+            let content = fs::read_to_string(drop_in)
+                .with_context(|| format!("Failed to read drop-in {}", drop_in.display()))?;
+            let overlay: toml::Table = toml::from_str(&content)
+                .with_context(|| format!("Failed to parse drop-in {}", drop_in.display()))?;
+            
+            record_sources(&overlay, "", drop_in, &mut provenance);
+            merge_tables(&mut merged, overlay);
+        }
+        
+        // Instances are named after themselves, not their template
+        merged.insert("name".to_string(), toml::Value::String(name.to_string()));
+        
+        let specifiers = Specifiers::for_unit(name);
+        for section in sections {
+            if let Some(value) = merged.get_mut(*section) {
+                specifiers.expand_value(value)
+                    .with_context(|| format!("Failed to expand specifiers in [{}]", section))?;
+            }
+        }
+        
+        Ok(Self {
+            name: name.to_string(),
+            fragment: fragment.to_path_buf(),
+            drop_ins,
+            merged,
+            provenance,
+        })
+    }
+    
+    fn parse<T: UnitFile>(&self) -> Result<T> {
+        let content = toml::to_string(&self.merged)?;
+        let path = self.fragment.with_file_name(unit_file_name(&self.name));
+        T::from_str(&content, &path)
+    }
+    
+    /// Files that set `key` (like `service.environment`) or anything
+    /// below it, in the order they were applied.
+    pub fn sources_of(&self, key: &str) -> Vec<&Path> {
+        let nested = format!("{}.", key);
+        let mut sources: Vec<&Path> = Vec::new();
+        
+        for (path, source) in &self.provenance {
+            if (path == key || path.starts_with(&nested)) && !sources.contains(&source.as_path()) {
+                sources.push(source);
+            }
+        }
+        
+        let order = |source: &Path| {
+            self.drop_ins.iter().position(|drop_in| drop_in == source).map_or(0, |i| i + 1)
+        };
+        sources.sort_by_key(|source| order(source));
+        sources
+    }
+    
+    /// The merged unit as TOML, each setting annotated with its source.
+    pub fn render(&self) -> String {
+        let mut out = format!("# {}\n", self.fragment.display());
+        for drop_in in &self.drop_ins {
+            out.push_str(&format!("# {}\n", drop_in.display()));
+        }
+        
+        let annotate = |out: &mut String, key: &str, path: &str, value: &toml::Value| {
+            let sources: Vec<String> = self.sources_of(path).iter()
+                .map(|source| source.file_name().unwrap_or_default().to_string_lossy().to_string())
+                .collect();
+            out.push_str(&format!("{} = {}", key, value));
+            if !sources.is_empty() {
+                out.push_str(&format!("  # {}", sources.join(", ")));
+            }
+            out.push('\n');
+        };
+        
+        out.push('\n');
+        for (key, value) in self.merged.iter().filter(|(_, value)| !value.is_table()) {
+            annotate(&mut out, key, key, value);
+        }
+        
+        for (section, table) in self.merged.iter().filter_map(|(key, value)| value.as_table().map(|table| (key, table))) {
+            out.push_str(&format!("\n[{}]\n", section));
+            for (key, value) in table {
+                annotate(&mut out, key, &format!("{}.{}", section, key), value);
+            }
+        }
+        
+        out
+    }
+}
+
+/// Tables are merged key by key, anything else is replaced, so an empty
+/// list in a drop-in clears the setting.
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => merge_tables(base, overlay),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn record_sources(table: &toml::Table, prefix: &str, source: &Path, provenance: &mut BTreeMap<String, PathBuf>) {
+    for (key, value) in table {
+        let path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        
+        match value {
+            toml::Value::Table(table) => record_sources(table, &path, source, provenance),
+            _ => {
+                // A replaced table no longer has values from earlier files
+                let nested = format!("{}.", path);
+                provenance.retain(|existing, _| !existing.starts_with(&nested));
+                provenance.insert(path, source.to_path_buf());
+            }
+        }
+    }
+}
+
+/// The file name of a unit: services are `name.tau`, other kinds carry
+/// their extension in the name already.
+fn unit_file_name(name: &str) -> String {
+    if name.ends_with(".socket") || name.ends_with(".timer") || name.ends_with(".path") {
+        name.to_string()
+    } else {
+        format!("{}.tau", name)
+    }
+}
+
+/// The file name of the template an instance is created from.
+fn template_file_name(name: &str) -> Option<String> {
+    let (prefix, instance) = split_instance(name)?;
+    let file_name = unit_file_name(name);
+    let suffix = &file_name[prefix.len() + 1 + instance.len()..];
+    Some(format!("{}@{}", prefix, suffix))
+}
+
+#[derive(Clone)]
 pub struct UnitLoader {
     pub system_units_dir: PathBuf,
     pub user_units_dir: PathBuf,
 }
 
+impl Default for UnitLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UnitLoader {
+    /// The unit directories of the manager this process runs as or
+    /// talks to.
     pub fn new() -> Self {
-        Self {
-            system_units_dir: PathBuf::from("/etc/tau/services"),
-            user_units_dir: PathBuf::from(format!("{}/.config/tau/services", 
-                std::env::var("HOME").unwrap_or_else(|_| "/home/user".to_string()))),
-        }
+        ManagerScope::current().unit_loader()
+    }
+    
+    /// Unit directories in increasing precedence.
+    pub fn unit_dirs(&self) -> Vec<PathBuf> {
+        vec![self.system_units_dir.clone(), self.user_units_dir.clone()]
     }
     
     pub fn load_all_units(&self) -> Result<HashMap<String, ServiceUnit>> {
+        self.load_all()
+    }
+    
+    pub fn load_all_sockets(&self) -> Result<HashMap<String, SocketUnit>> {
+        self.load_all()
+    }
+    
+    pub fn load_all_timers(&self) -> Result<HashMap<String, TimerUnit>> {
+        self.load_all()
+    }
+    
+    pub fn load_all_paths(&self) -> Result<HashMap<String, PathUnit>> {
+        self.load_all()
+    }
+    
+    fn load_all<T: UnitFile>(&self) -> Result<HashMap<String, T>> {
         let mut units = HashMap::new();
         
         // Load system units
@@ -275,20 +1276,33 @@ impl UnitLoader {
         Ok(units)
     }
     
-    fn load_units_from_dir(&self, dir: &Path, units: &mut HashMap<String, ServiceUnit>) -> Result<()> {
+    fn load_units_from_dir<T: UnitFile>(&self, dir: &Path, units: &mut HashMap<String, T>) -> Result<()> {
         for entry in walkdir::WalkDir::new(dir)
             .follow_links(true)
             .into_iter()
             .filter_map(|e| e.ok()) {
             
             let path = entry.path();
-            if path.is_file() && path.extension().map_or(false, |ext| ext == "tau") {
-                match ServiceUnit::from_file(path) {
+            if path.is_file() && path.extension().is_some_and(|ext| ext == T::EXTENSION) {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+                
+                // Templates are only loaded when an instance is requested
+                if is_template(&stem) {
+                    continue;
+                }
+                
+                let name = if T::EXTENSION == ServiceUnit::EXTENSION {
+                    stem
+                } else {
+                    format!("{}.{}", stem, T::EXTENSION)
+                };
+                
+                match UnitSource::load(&name, path, self.drop_ins(&name), T::SPECIFIER_SECTIONS).and_then(|source| source.parse::<T>()) {
                     Ok(unit) => {
-                        units.insert(unit.name.clone(), unit);
+                        units.insert(unit.unit_name().to_string(), unit);
                     }
                     Err(e) => {
-                        log::warn!("Failed to load unit file {}: {}", path.display(), e);
+                        log::warn!("Failed to load unit file {}: {:#}", path.display(), e);
                     }
                 }
             }
@@ -297,17 +1311,75 @@ impl UnitLoader {
         Ok(())
     }
     
-    pub fn reload_unit(&self, unit_name: &str) -> Result<ServiceUnit> {
-        // Try system units first, then user units
-        let system_path = self.system_units_dir.join(format!("{}.tau", unit_name));
-        let user_path = self.user_units_dir.join(format!("{}.tau", unit_name));
-        
-        if system_path.exists() {
-            ServiceUnit::from_file(&system_path)
-        } else if user_path.exists() {
-            ServiceUnit::from_file(&user_path)
+    /// The file defining a unit. Instances without a file of their own
+    /// use their template. User units take precedence over system ones.
+    pub fn find_fragment(&self, name: &str) -> Option<PathBuf> {
+        let candidates = std::iter::once(unit_file_name(name)).chain(template_file_name(name));
+        
+        for file_name in candidates {
+            for dir in [&self.user_units_dir, &self.system_units_dir] {
+                let path = dir.join(&file_name);
+                if path.is_file() {
+                    return Some(path);
+                }
+            }
+        }
+        
+        None
+    }
+    
+    /// Drop-ins of a unit, `<unit file>.d/*.toml`, in the order they are
+    /// applied: sorted by file name, with a user drop-in replacing a system
+    /// one of the same name and an instance's replacing its template's.
+    pub fn drop_ins(&self, name: &str) -> Vec<PathBuf> {
+        let dir_names = template_file_name(name).into_iter()
+            .chain(std::iter::once(unit_file_name(name)))
+            .map(|file_name| format!("{}.d", file_name));
+        
+        let mut drop_ins = BTreeMap::new();
+        for dir_name in dir_names {
+            for units_dir in [&self.system_units_dir, &self.user_units_dir] {
+                let Ok(entries) = fs::read_dir(units_dir.join(&dir_name)) else {
+                    continue;
+                };
+                
+                for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+                    if path.is_file() && path.extension().is_some_and(|ext| ext == "toml") {
+                        drop_ins.insert(path.file_name().unwrap_or_default().to_os_string(), path);
+                    }
+                }
+            }
+        }
+        
+        drop_ins.into_values().collect()
+    }
+    
+    /// Loads a unit merged with its drop-ins, for inspection.
+    pub fn source(&self, name: &str) -> Result<UnitSource> {
+        let fragment = self.find_fragment(name)
+            .ok_or_else(|| anyhow::anyhow!("Unit file not found for: {}", name))?;
+        let sections = if name.ends_with(".socket") {
+            SocketUnit::SPECIFIER_SECTIONS
+        } else if name.ends_with(".timer") {
+            TimerUnit::SPECIFIER_SECTIONS
+        } else if name.ends_with(".path") {
+            PathUnit::SPECIFIER_SECTIONS
         } else {
-            Err(anyhow::anyhow!("Unit file not found for service: {}", unit_name))
+            ServiceUnit::SPECIFIER_SECTIONS
+        };
+        
+        UnitSource::load(name, &fragment, self.drop_ins(name), sections)
+    }
+    
+    /// Loads a service unit by name, instantiating its template for
+    /// names like `foo@bar`.
+    pub fn reload_unit(&self, unit_name: &str) -> Result<ServiceUnit> {
+        if is_template(unit_name) {
+            return Err(anyhow::anyhow!("Template unit {} cannot be started without an instance", unit_name));
         }
+        
+        self.source(unit_name)
+            .with_context(|| format!("Failed to load service {}", unit_name))?
+            .parse()
     }
-} 
\ No newline at end of file
+}
\ No newline at end of file
@@ -0,0 +1,815 @@
+use crate::condition::{ConditionKind, UnitConditions, Virtualization};
+use crate::credentials;
+use crate::exec::ExecCommand;
+use crate::seccomp::{self, SyscallPolicy};
+use crate::unit::{
+    InstallSection, PathSection, PathUnit, SandboxSection, ServiceSection, ServiceUnit,
+    SocketSection, SocketUnit, TimerSection, TimerUnit, UnitError, UnitLoader, UnitSection,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Searched for commands without a slash when verifying.
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Capabilities that amount to full control of the machine.
+const DANGEROUS_CAPABILITIES: &[&str] = &[
+    "CAP_SYS_ADMIN", "CAP_SYS_MODULE", "CAP_SYS_RAWIO", "CAP_SYS_PTRACE", "CAP_SYS_BOOT", "CAP_BPF",
+];
+
+/// Environment variable names that suggest a secret stored in the unit.
+const SECRET_NAMES: &[&str] = &["PASSWORD", "PASSWD", "SECRET", "TOKEN", "PRIVATE_KEY"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found in a unit file, at a 1-based line and column.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diagnostic {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}: {}: {}", self.path.display(), self.line, self.column, self.severity, self.message)
+    }
+}
+
+/// Checks unit files without loading them into a manager.
+pub struct Verifier {
+    loaders: Vec<UnitLoader>,
+    root: Option<PathBuf>,
+    /// Units defined by the files being verified
+    units: HashSet<String>,
+}
+
+impl Verifier {
+    pub fn new(loader: UnitLoader) -> Self {
+        Self {
+            loaders: vec![loader],
+            root: None,
+            units: HashSet::new(),
+        }
+    }
+
+    /// Also looks for executables and units below `root`, like the
+    /// staging directory of a package build.
+    pub fn with_root(mut self, root: PathBuf) -> Self {
+        let rerooted = |dir: &Path| root.join(dir.strip_prefix("/").unwrap_or(dir));
+        let loader = UnitLoader {
+            system_units_dir: rerooted(&self.loaders[0].system_units_dir),
+            user_units_dir: rerooted(&self.loaders[0].user_units_dir),
+        };
+        self.loaders.insert(0, loader);
+        self.root = Some(root);
+        self
+    }
+
+    /// Verifies files together, so they may depend on each other.
+    pub fn verify_files(&mut self, files: &[PathBuf]) -> Vec<Diagnostic> {
+        self.units.extend(files.iter().filter_map(|path| unit_name(path)));
+
+        let mut diagnostics = Vec::new();
+        for path in files {
+            match fs::read_to_string(path) {
+                Ok(content) => diagnostics.extend(self.verify(path, &content)),
+                Err(e) => diagnostics.push(Diagnostic {
+                    path: path.clone(),
+                    line: 1,
+                    column: 1,
+                    severity: Severity::Error,
+                    message: format!("Failed to read unit file: {}", e),
+                }),
+            }
+        }
+
+        diagnostics
+    }
+
+    pub fn verify(&self, path: &Path, content: &str) -> Vec<Diagnostic> {
+        let mut report = Report {
+            path: path.to_path_buf(),
+            content,
+            locations: KeyLocations::scan(content),
+            diagnostics: Vec::new(),
+        };
+
+        let Some(kind) = UnitKind::from_path(path) else {
+            report.at(1, 1, Severity::Error, "Unknown unit type, expected a .tau, .socket, .timer or .path file".into());
+            return report.diagnostics;
+        };
+
+        let table = match content.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => {
+                report.toml_error(&e, 0);
+                return report.diagnostics;
+            }
+        };
+
+        check_keys(&mut report, kind, &table);
+
+        if let Some(unit) = parse_unit(&mut report, kind, path, &table) {
+            self.check_dependencies(&mut report, &unit);
+
+            if let Some(section) = unit.unit_section() {
+                check_conditions(&mut report, section);
+            }
+
+            if let Unit::Service(service) = &unit {
+                self.check_executables(&mut report, &service.service);
+                check_settings(&mut report, service);
+
+                if let Some(sandbox) = &service.sandbox {
+                    check_sandbox(&mut report, sandbox);
+                }
+            }
+
+            if let Unit::Socket(socket) = &unit {
+                let mode = socket.socket.socket_mode.as_deref().and_then(|mode| u32::from_str_radix(mode, 8).ok());
+                if mode.is_some_and(|mode| mode & 0o002 != 0) {
+                    report.key("socket", "socket_mode", Severity::Warning,
+                        "SocketMode makes the socket writable by every user".into());
+                }
+            }
+        }
+
+        report.diagnostics.sort_by_key(|d| (d.line, d.column, d.severity));
+        report.diagnostics
+    }
+
+    fn unit_exists(&self, name: &str) -> bool {
+        self.units.contains(name) || self.loaders.iter().any(|loader| loader.find_fragment(name).is_some())
+    }
+
+    fn check_dependencies(&self, report: &mut Report, unit: &Unit) {
+        if let Some(section) = unit.unit_section() {
+            let lists = [
+                ("requires", &section.requires, Severity::Error),
+                ("binds_to", &section.binds_to, Severity::Error),
+                ("part_of", &section.part_of, Severity::Error),
+                ("wants", &section.wants, Severity::Warning),
+            ];
+
+            for (key, names, severity) in lists {
+                for name in names.iter().flatten() {
+                    if !self.unit_exists(name) {
+                        report.value("unit", key, name, severity, format!("Dependency {} does not exist", name));
+                    }
+                }
+            }
+        }
+
+        if let Some((section, key, service)) = unit.triggered_service() {
+            if !self.unit_exists(&service) {
+                report.value(section, key, &service, Severity::Error,
+                    format!("Triggered unit {} does not exist", service));
+            }
+        }
+    }
+
+    fn check_executables(&self, report: &mut Report, service: &ServiceSection) {
+        let commands = [
+            ("exec_start", service.exec_start.iter().flat_map(|exec_start| exec_start.lines()).collect::<Vec<_>>()),
+            ("exec_start_pre", service.exec_start_pre.iter().flatten().collect()),
+            ("exec_start_post", service.exec_start_post.iter().flatten().collect()),
+            ("exec_stop", service.exec_stop.iter().collect()),
+            ("exec_stop_post", service.exec_stop_post.iter().flatten().collect()),
+            ("exec_reload", service.exec_reload.iter().collect()),
+        ];
+
+        for (key, lines) in commands {
+            for line in lines {
+                let command = match ExecCommand::parse(line, &HashMap::new()) {
+                    Ok(command) => command,
+                    Err(e) => {
+                        report.key("service", key, Severity::Error, e.to_string());
+                        continue;
+                    }
+                };
+
+                // Expanded at runtime, nothing to check
+                let raw = line.trim_start().trim_start_matches(['-', '@', '+', '!', ':']);
+                if raw.split_whitespace().next().is_none_or(|word| word.contains(['%', '$'])) {
+                    continue;
+                }
+                let program = command.path.as_str();
+
+                if !program.contains('/') {
+                    if self.find_in_path(program).is_none() {
+                        report.value("service", key, program, Severity::Error,
+                            format!("Command {} not found in PATH", program));
+                    }
+                } else if !program.starts_with('/') {
+                    report.value("service", key, program, Severity::Error,
+                        format!("Executable path {} is not absolute", program));
+                } else {
+                    match self.candidates(Path::new(program)).into_iter().find(|path| path.exists()) {
+                        Some(path) if is_executable(&path) => {}
+                        Some(_) => report.value("service", key, program, Severity::Error,
+                            format!("{} is not executable", program)),
+                        None => report.value("service", key, program, Severity::Error,
+                            format!("Executable {} does not exist", program)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Where an absolute path may be found: below the root, then on the host.
+    fn candidates(&self, path: &Path) -> Vec<PathBuf> {
+        let mut candidates = Vec::new();
+        if let Some(root) = &self.root {
+            candidates.push(root.join(path.strip_prefix("/").unwrap_or(path)));
+        }
+        candidates.push(path.to_path_buf());
+        candidates
+    }
+
+    fn find_in_path(&self, program: &str) -> Option<PathBuf> {
+        let search = std::env::var("PATH").unwrap_or_else(|_| DEFAULT_PATH.to_string());
+
+        search.split(':')
+            .filter(|dir| dir.starts_with('/'))
+            .flat_map(|dir| self.candidates(&Path::new(dir).join(program)))
+            .find(|path| is_executable(path))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnitKind {
+    Service,
+    Socket,
+    Timer,
+    Path,
+}
+
+impl UnitKind {
+    fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "tau" => Some(UnitKind::Service),
+            "socket" => Some(UnitKind::Socket),
+            "timer" => Some(UnitKind::Timer),
+            "path" => Some(UnitKind::Path),
+            _ => None,
+        }
+    }
+
+    /// The sections of this kind of unit and the keys each accepts.
+    fn sections(&self) -> Vec<(&'static str, Vec<String>)> {
+        let mut sections = vec![
+            ("install", known_keys(InstallSection::default())),
+            ("unit", known_keys(UnitSection::default())),
+        ];
+
+        match self {
+            UnitKind::Service => {
+                sections.push(("service", known_keys(ServiceSection::default())));
+                sections.push(("sandbox", known_keys(SandboxSection::default())));
+            }
+            UnitKind::Socket => sections.push(("socket", known_keys(SocketSection::default()))),
+            UnitKind::Timer => sections.push(("timer", known_keys(TimerSection::default()))),
+            UnitKind::Path => sections.push(("path", known_keys(PathSection::default()))),
+        }
+
+        sections
+    }
+}
+
+/// The name a unit file defines, as the loader names it.
+fn unit_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_string_lossy();
+    match UnitKind::from_path(path)? {
+        UnitKind::Service => Some(stem.to_string()),
+        _ => Some(format!("{}.{}", stem, path.extension()?.to_string_lossy())),
+    }
+}
+
+fn known_keys<T: Serialize>(section: T) -> Vec<String> {
+    match serde_json::to_value(section) {
+        Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+enum Unit {
+    Service(Box<ServiceUnit>),
+    Socket(SocketUnit),
+    Timer(TimerUnit),
+    Path(PathUnit),
+}
+
+impl Unit {
+    fn unit_section(&self) -> Option<&UnitSection> {
+        match self {
+            Unit::Service(unit) => unit.unit.as_ref(),
+            Unit::Socket(unit) => unit.unit.as_ref(),
+            Unit::Timer(unit) => unit.unit.as_ref(),
+            Unit::Path(unit) => unit.unit.as_ref(),
+        }
+    }
+
+    /// The section and key naming the triggered service, and the service.
+    fn triggered_service(&self) -> Option<(&'static str, &'static str, String)> {
+        match self {
+            Unit::Service(_) => None,
+            // Accepted connections each get an instance of a template
+            Unit::Socket(unit) if unit.accepts_connections() => Some(("socket", "service", format!("{}@", unit.service_name()))),
+            Unit::Socket(unit) => Some(("socket", "service", unit.service_name())),
+            Unit::Timer(unit) => Some(("timer", "unit", unit.service_name())),
+            Unit::Path(unit) => Some(("path", "unit", unit.service_name())),
+        }
+    }
+}
+
+/// Deserializes and validates the unit, reporting type errors with the
+/// span toml gives and validation errors at the offending key.
+fn parse_unit(report: &mut Report, kind: UnitKind, path: &Path, table: &toml::Table) -> Option<Unit> {
+    // Service files get their name from the loader, add it the same way
+    let prefix = match unit_name(path) {
+        Some(name) if !table.contains_key("name") => format!("name = {}\n", toml::Value::String(name)),
+        _ => String::new(),
+    };
+    let content = format!("{}{}", prefix, report.content);
+
+    let typed = match kind {
+        UnitKind::Service => toml::from_str::<ServiceUnit>(&content).map(|_| ()),
+        UnitKind::Socket => toml::from_str::<SocketUnit>(&content).map(|_| ()),
+        UnitKind::Timer => toml::from_str::<TimerUnit>(&content).map(|_| ()),
+        UnitKind::Path => toml::from_str::<PathUnit>(&content).map(|_| ()),
+    };
+    if let Err(e) = typed {
+        report.toml_error(&e, prefix.len());
+        return None;
+    }
+
+    let unit = match kind {
+        UnitKind::Service => ServiceUnit::from_str(&content, path).map(|unit| Unit::Service(Box::new(unit))),
+        UnitKind::Socket => SocketUnit::from_str(&content, path).map(Unit::Socket),
+        UnitKind::Timer => TimerUnit::from_str(&content, path).map(Unit::Timer),
+        UnitKind::Path => PathUnit::from_str(&content, path).map(Unit::Path),
+    };
+
+    match unit {
+        Ok(unit) => Some(unit),
+        Err(e) => {
+            let field = match e.downcast_ref::<UnitError>() {
+                Some(UnitError::InvalidValue(field, _)) | Some(UnitError::MissingField(field)) => Some(snake_case(field)),
+                _ => None,
+            };
+            let (line, column) = field
+                .and_then(|field| report.locations.find_key(&field))
+                .unwrap_or((1, 1));
+            report.at(line, column, Severity::Error, format!("{:#}", e));
+            None
+        }
+    }
+}
+
+fn check_keys(report: &mut Report, kind: UnitKind, table: &toml::Table) {
+    let sections = kind.sections();
+
+    for (key, value) in table {
+        if key == "name" || key == "description" {
+            continue;
+        }
+
+        let Some((_, known)) = sections.iter().find(|(section, _)| section == key) else {
+            let names: Vec<&str> = sections.iter().map(|(section, _)| *section).collect();
+            let (line, column) = report.locations.table(key).unwrap_or((1, 1));
+            report.at(line, column, Severity::Error, unknown_message(&format!("section [{}]", key), key, &names));
+            continue;
+        };
+
+        let Some(fields) = value.as_table() else {
+            continue;
+        };
+
+        for field in fields.keys() {
+            if !known.contains(field) {
+                let names: Vec<&str> = known.iter().map(String::as_str).collect();
+                report.key(key, field, Severity::Error, unknown_message(&format!("key '{}' in [{}]", field, key), field, &names));
+            }
+        }
+    }
+}
+
+fn unknown_message(what: &str, name: &str, candidates: &[&str]) -> String {
+    let closest = candidates.iter()
+        .map(|candidate| (edit_distance(name, candidate), *candidate))
+        .filter(|(distance, _)| *distance <= 2)
+        .min();
+
+    match closest {
+        Some((_, candidate)) => format!("Unknown {}, did you mean '{}'?", what, candidate),
+        None => format!("Unknown {}", what),
+    }
+}
+
+fn check_settings(report: &mut Report, unit: &ServiceUnit) {
+    let service = &unit.service;
+
+    if service.restart.is_some() && service.restart != Some(crate::unit::RestartPolicy::No) && service.restart_sec == Some(0) {
+        report.key("service", "restart_sec", Severity::Warning,
+            "RestartSec=0 restarts a failing service in a tight loop".into());
+    }
+
+    for name in service.environment.iter().flat_map(|env| env.keys()) {
+        let upper = name.to_ascii_uppercase();
+        if SECRET_NAMES.iter().any(|secret| upper.contains(secret)) {
+            let (line, column) = report.locations.get("service.environment", name)
+                .or_else(|| report.locations.get("service", "environment"))
+                .unwrap_or((1, 1));
+            report.at(line, column, Severity::Warning,
+                format!("Environment variable {} looks like a secret, unit files are world-readable", name));
+        }
+    }
+
+    if unit.sandbox.as_ref().and_then(|s| s.no_new_privileges) == Some(false) && service.user.is_none() {
+        report.key("sandbox", "no_new_privileges", Severity::Warning,
+            "NoNewPrivileges=false for a service running as root".into());
+    }
+
+    if let Some(path) = &service.pid_file {
+        if !Path::new(path).is_absolute() {
+            report.value("service", "pid_file", path, Severity::Error,
+                format!("PIDFile path {} is not absolute", path));
+        }
+        if !unit.is_forking() {
+            report.key("service", "pid_file", Severity::Warning,
+                "PIDFile only has an effect with Type=forking".into());
+        }
+    }
+
+    if service.remain_after_exit == Some(true) && !unit.is_oneshot() {
+        report.key("service", "remain_after_exit", Severity::Warning,
+            "RemainAfterExit only has an effect with Type=oneshot".into());
+    }
+}
+
+fn check_conditions(report: &mut Report, section: &UnitSection) {
+    let conditions = UnitConditions::from_section(Some(section));
+
+    for check in conditions.conditions.iter().chain(&conditions.assertions) {
+        let prefix = if check.assert { "assert" } else { "condition" };
+        let key = match check.kind {
+            ConditionKind::PathExists => "path_exists",
+            ConditionKind::PathIsDirectory => "path_is_directory",
+            ConditionKind::FileNotEmpty => "file_not_empty",
+            ConditionKind::KernelCommandLine => "kernel_command_line",
+            ConditionKind::Virtualization => "virtualization",
+            ConditionKind::Environment => "environment",
+            ConditionKind::Host => "host",
+            // Booleans, checked by the parser
+            ConditionKind::FirstBoot | ConditionKind::ACPower => continue,
+        };
+        let key = format!("{}_{}", prefix, key);
+
+        if check.parameter.is_empty() {
+            report.key("unit", &key, Severity::Error, format!("{} has no value", check));
+            continue;
+        }
+
+        match check.kind {
+            ConditionKind::PathExists | ConditionKind::PathIsDirectory | ConditionKind::FileNotEmpty
+                if !Path::new(&check.parameter).is_absolute() => {
+                report.value("unit", &key, &check.parameter, Severity::Error,
+                    format!("{} needs an absolute path", check));
+            }
+            ConditionKind::Virtualization if !Virtualization::is_known(&check.parameter) => {
+                report.value("unit", &key, &check.parameter, Severity::Warning,
+                    format!("Unknown virtualization technology {}, the check never passes", check.parameter));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_sandbox(report: &mut Report, sandbox: &SandboxSection) {
+    let read_write = sandbox.read_write_paths.clone().unwrap_or_default();
+    let read_only = sandbox.read_only_paths.clone().unwrap_or_default();
+
+    for path in &read_write {
+        if read_only.contains(path) {
+            report.value("sandbox", "read_write_paths", path, Severity::Error,
+                format!("{} is in both ReadWritePaths and ReadOnlyPaths", path));
+        }
+        if path.trim_end_matches('/').is_empty() {
+            report.value("sandbox", "read_write_paths", path, Severity::Warning,
+                "ReadWritePaths=/ makes the whole file system writable".into());
+        }
+    }
+
+    // PrivateTmp and PrivateDevices replace these directories, so paths
+    // below them no longer exist in the service's namespace
+    let replaced = [
+        (sandbox.private_devices, "PrivateDevices", &["/dev"][..]),
+        (sandbox.private_tmp, "PrivateTmp", &["/tmp", "/var/tmp"][..]),
+    ];
+    for (enabled, option, dirs) in replaced {
+        if enabled != Some(true) {
+            continue;
+        }
+
+        for (key, path) in read_write.iter().map(|p| ("read_write_paths", p))
+            .chain(read_only.iter().map(|p| ("read_only_paths", p))) {
+            if dirs.iter().any(|dir| Path::new(path).starts_with(dir)) {
+                report.value("sandbox", key, path, Severity::Error,
+                    format!("{} hides {}, it cannot be bound into the sandbox", option, path));
+            }
+        }
+    }
+
+    let parse = |names: &Option<Vec<String>>| -> Vec<(String, Option<u32>)> {
+        names.iter().flatten()
+            .map(|name| (name.clone(), credentials::parse_capability(name).ok()))
+            .collect()
+    };
+    let bounding = sandbox.capability_bounding_set.as_ref().map(|_| parse(&sandbox.capability_bounding_set));
+
+    let granted = [
+        ("capabilities", "Capabilities", &sandbox.capabilities),
+        ("ambient_capabilities", "AmbientCapabilities", &sandbox.ambient_capabilities),
+    ];
+    for (key, option, names) in granted {
+        for (name, cap) in parse(names) {
+            if let (Some(bounding), Some(cap)) = (&bounding, cap) {
+                if !bounding.iter().any(|(_, b)| *b == Some(cap)) {
+                    report.value("sandbox", key, &name, Severity::Error,
+                        format!("{} in {} is not in CapabilityBoundingSet and will be dropped", name, option));
+                }
+            }
+
+            let dangerous = cap.is_some_and(|cap| {
+                DANGEROUS_CAPABILITIES.iter().any(|name| credentials::parse_capability(name).ok() == Some(cap))
+            });
+            if dangerous {
+                report.value("sandbox", key, &name, Severity::Warning,
+                    format!("{} grants nearly unrestricted control of the system", name));
+            }
+        }
+    }
+
+    if let Some(entries) = sandbox.system_call_filter.as_ref().filter(|entries| !entries.is_empty()) {
+        if let Ok(policy) = SyscallPolicy::parse(entries) {
+            let allowed: Vec<&str> = seccomp::DANGEROUS_GROUPS.iter()
+                .copied()
+                .filter(|group| !policy.blocks_group(group))
+                .collect();
+            if !allowed.is_empty() {
+                report.key("sandbox", "system_call_filter", Severity::Warning,
+                    format!("SystemCallFilter allows dangerous syscall groups: {}", allowed.join(" ")));
+            }
+        }
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+}
+
+/// `CPUQuota` → `cpu_quota`, the key a validation error refers to.
+fn snake_case(field: &str) -> String {
+    let chars: Vec<char> = field.chars().collect();
+    let mut snake = String::new();
+
+    for (i, c) in chars.iter().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            let after_lower = chars[i - 1].is_lowercase();
+            let ends_acronym = chars[i - 1].is_uppercase() && chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if after_lower || ends_acronym {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+
+    snake
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// Diagnostics of one file.
+struct Report<'a> {
+    path: PathBuf,
+    content: &'a str,
+    locations: KeyLocations,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Report<'_> {
+    fn at(&mut self, line: usize, column: usize, severity: Severity, message: String) {
+        self.diagnostics.push(Diagnostic {
+            path: self.path.clone(),
+            line,
+            column,
+            severity,
+            message,
+        });
+    }
+
+    fn key(&mut self, table: &str, key: &str, severity: Severity, message: String) {
+        let (line, column) = self.locations.get(table, key)
+            .or_else(|| self.locations.table(table))
+            .unwrap_or((1, 1));
+        self.at(line, column, severity, message);
+    }
+
+    /// At `value` within the value of `key`, falling back to the key.
+    fn value(&mut self, table: &str, key: &str, value: &str, severity: Severity, message: String) {
+        match self.locations.find_value(self.content, table, key, value) {
+            Some((line, column)) => self.at(line, column, severity, message),
+            None => self.key(table, key, severity, message),
+        }
+    }
+
+    /// A toml error in the content with `prefix` bytes prepended.
+    fn toml_error(&mut self, error: &toml::de::Error, prefix: usize) {
+        let (line, column) = error.span()
+            .map(|span| line_column(self.content, span.start.saturating_sub(prefix)))
+            .unwrap_or((1, 1));
+        self.at(line, column, Severity::Error, error.message().trim().to_string());
+    }
+}
+
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |l| l.chars().count()) + 1;
+    (line, column)
+}
+
+/// Where tables and keys are defined in a unit file. A small scanner
+/// rather than a full parser: it only runs on files toml already parsed.
+#[derive(Debug, Default)]
+struct KeyLocations {
+    tables: HashMap<String, (usize, usize)>,
+    /// (table, key) → (line, column, last line of the value)
+    keys: HashMap<(String, String), (usize, usize, usize)>,
+}
+
+impl KeyLocations {
+    fn scan(content: &str) -> Self {
+        let mut locations = KeyLocations::default();
+        let mut table = String::new();
+        let mut current: Option<(String, String)> = None;
+        let mut depth = 0i32;
+        let mut multiline: Option<&str> = None;
+
+        for (index, line) in content.lines().enumerate() {
+            let number = index + 1;
+            let trimmed = line.trim_start();
+            let indent = line.len() - trimmed.len();
+
+            if let Some(delimiter) = multiline {
+                if line.matches(delimiter).count() % 2 == 1 {
+                    multiline = None;
+                }
+            } else if depth > 0 {
+                depth += bracket_depth(line);
+            } else if trimmed.starts_with('[') {
+                let name = trimmed.trim_start_matches('[');
+                let name = name[..name.find(']').unwrap_or(name.len())].trim();
+                table = unquote_path(name);
+                locations.tables.entry(table.clone()).or_insert((number, indent + 1));
+                current = None;
+                continue;
+            } else if let Some(equals) = find_equals(trimmed) {
+                let mut path = unquote_path(trimmed[..equals].trim());
+                if !table.is_empty() {
+                    path = format!("{}.{}", table, path);
+                }
+                let (parent, key) = match path.rsplit_once('.') {
+                    Some((parent, key)) => (parent.to_string(), key.to_string()),
+                    None => (String::new(), path),
+                };
+
+                let value = &trimmed[equals + 1..];
+                depth = bracket_depth(value);
+                for delimiter in ["\"\"\"", "'''"] {
+                    if value.matches(delimiter).count() % 2 == 1 {
+                        multiline = Some(delimiter);
+                    }
+                }
+
+                locations.keys.entry((parent.clone(), key.clone())).or_insert((number, indent + 1, number));
+                current = Some((parent, key));
+            } else {
+                continue;
+            }
+
+            if let Some(entry) = current.as_ref().and_then(|current| locations.keys.get_mut(current)) {
+                entry.2 = number;
+            }
+        }
+
+        locations
+    }
+
+    fn table(&self, table: &str) -> Option<(usize, usize)> {
+        self.tables.get(table).copied()
+    }
+
+    fn get(&self, table: &str, key: &str) -> Option<(usize, usize)> {
+        self.keys.get(&(table.to_string(), key.to_string())).map(|(line, column, _)| (*line, *column))
+    }
+
+    /// A key in any table, for errors that do not say which.
+    fn find_key(&self, key: &str) -> Option<(usize, usize)> {
+        self.keys.iter()
+            .filter(|((_, k), _)| k == key)
+            .map(|(_, (line, column, _))| (*line, *column))
+            .min()
+    }
+
+    /// The quoted `value` within the lines of `key`.
+    fn find_value(&self, content: &str, table: &str, key: &str, value: &str) -> Option<(usize, usize)> {
+        let (first, _, last) = *self.keys.get(&(table.to_string(), key.to_string()))?;
+
+        content.lines()
+            .enumerate()
+            .skip(first - 1)
+            .take(last - first + 1)
+            .find_map(|(index, line)| {
+                ["\"", "'"].iter().find_map(|quote| {
+                    line.find(&format!("{}{}", quote, value))
+                        .map(|column| (index + 1, line[..column].chars().count() + 1))
+                })
+            })
+    }
+}
+
+/// The `=` of a key/value line, outside quoted keys.
+fn find_equals(line: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '=') => return Some(i),
+            (None, '#') => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// How many brackets a line opens, outside strings and comments.
+fn bracket_depth(line: &str) -> i32 {
+    let mut depth = 0;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '[' | '{') => depth += 1,
+            (None, ']' | '}') => depth -= 1,
+            (None, '#') => break,
+            _ => {}
+        }
+    }
+    depth
+}
+
+/// `service."exec_start"` → `service.exec_start`.
+fn unquote_path(path: &str) -> String {
+    path.split('.')
+        .map(|part| part.trim().trim_matches(|c| c == '"' || c == '\''))
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -0,0 +1,89 @@
+use crate::service_manager::ServiceManager;
+use anyhow::{Result, Context};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::path::Path;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use log::{info, warn, error, debug};
+
+/// How long unit directories must be quiet before units are reloaded, so
+/// an editor saving or a package installing several files causes a
+/// single reload.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Reloads units when files in the unit directories change.
+pub struct UnitWatcher {
+    manager: ServiceManager,
+    debounce: Duration,
+}
+
+impl UnitWatcher {
+    pub fn new(manager: ServiceManager) -> Self {
+        Self {
+            manager,
+            debounce: DEFAULT_DEBOUNCE,
+        }
+    }
+
+    pub async fn run(&self) -> Result<()> {
+        let (sender, mut changes) = mpsc::unbounded_channel();
+
+        let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            match result {
+                Ok(event) if is_unit_change(&event) => {
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Unit directory watch failed: {}", e),
+            }
+        }).context("Failed to create unit directory watcher")?;
+
+        for dir in self.manager.unit_loader().unit_dirs() {
+            if !dir.is_dir() {
+                debug!("Not watching missing unit directory {}", dir.display());
+                continue;
+            }
+
+            watcher.watch(&dir, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", dir.display()))?;
+            info!("Watching {} for unit changes", dir.display());
+        }
+
+        while let Some(first) = changes.recv().await {
+            let mut changed = vec![first];
+
+            // Wait for the directories to settle
+            while let Ok(Some(path)) = tokio::time::timeout(self.debounce, changes.recv()).await {
+                if !changed.contains(&path) {
+                    changed.push(path);
+                }
+            }
+
+            debug!("Unit files changed: {:?}", changed);
+            info!("Unit files changed on disk, reloading units");
+
+            let manager = self.manager.clone();
+            match tokio::task::spawn_blocking(move || manager.load_units()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to reload units: {}", e),
+                Err(e) => error!("Unit reload panicked: {}", e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Unit files, drop-ins and drop-in directories. Reads and editor swap
+/// files do not trigger a reload.
+fn is_unit_change(event: &Event) -> bool {
+    !matches!(event.kind, EventKind::Access(_)) && event.paths.iter().any(|path| is_unit_path(path))
+}
+
+fn is_unit_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| {
+        matches!(ext.to_str(), Some("tau" | "socket" | "timer" | "path" | "toml" | "d"))
+    })
+}
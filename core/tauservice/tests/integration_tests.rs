@@ -1,18 +1,55 @@
 use std::fs;
+use std::os::fd::AsRawFd;
 use std::path::PathBuf;
 use tempfile::TempDir;
+use zbus::zvariant::Value;
 use tau_service::{
-    service_manager::ServiceManager,
+    service_manager::{ManagerEvent, ServiceEvent, ServiceManager, ServiceState, ServiceStatus},
     unit::ServiceUnit,
-    state::StateManager,
+    state::{keep_across_exec, RuntimeState, SerializedService, StateManager},
     sandbox::SandboxManager,
     boot::BootManager,
+    control::{ControlClient, ControlRequest, ControlResponse, ControlServer},
+    supervisor::{should_restart, ExitOutcome},
+    notify::{NotifyMessage, NotifySocket},
+    unit::{NotifyAccess, RestartPolicy},
+    unit::{ListenAddress, SocketUnit, TimerUnit},
+    calendar::CalendarSpec,
+    cgroup::{parse_bytes, parse_cpu_quota, ServiceCgroup},
+    credentials::{allocate_dynamic_uid, parse_capability, release_dynamic_uid, Credentials},
+    sandbox::SecurityAuditor,
+    seccomp::{DenyAction, SyscallPolicy},
+    transaction::{JobOutcome, JobRunner, JobType, Transaction, TransactionError},
+    unit::{changed_units, UnitDependencies, UnitLoader},
+    journal::{current_boot_id, parse_time, split_priority_prefix, JournalEntry, JournalLogger, JournalQuery, LogLevel},
+    unit::{PathUnit, StandardOutput},
+    analyze::{format_span, BootTimes, UnitTiming},
+    verify::{Severity, Verifier},
+    process::{process_start_time, ExecError, ExecPhase, SerializedProcess, ServiceProcess},
+    exec::{parse_environment_file, ExecCommand},
+    condition::{ConditionResult, HostFacts, UnitConditions, Virtualization},
+    coredump::{core_pattern, parse_backtrace, unit_from_cgroup, CoredumpInfo, CoredumpStore, Crash},
+    dbus_api::{changed_properties, escape_path_label, job_path, job_result, may_manage_units, unit_path, unit_properties},
+    scope::{lingering_users, set_linger, user_manager_unit, ManagerScope, USER_MANAGER_UNIT},
 };
+use notify::event::{AccessKind, AccessMode, CreateKind, DataChange, ModifyKind};
+use notify::{Event, EventKind};
+
+/// A user manager scope living in the temporary directory, so tests
+/// neither read nor write the units, state and journal of the host.
+fn temp_scope(temp_dir: &TempDir) -> ManagerScope {
+    ManagerScope::User {
+        uid: nix::unistd::getuid().as_raw(),
+        home: temp_dir.path().to_path_buf(),
+        runtime_dir: temp_dir.path().join("run"),
+    }
+}
 
 #[tokio::test]
 async fn test_service_lifecycle() {
     let temp_dir = TempDir::new().unwrap();
-    let services_dir = temp_dir.path().join("services");
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
     fs::create_dir_all(&services_dir).unwrap();
     
     // Create a test service unit
@@ -36,7 +73,7 @@ wanted_by = ["multi-user.target"]
     fs::write(&service_file, service_content).unwrap();
     
     // Create service manager
-    let manager = ServiceManager::new().unwrap();
+    let manager = ServiceManager::for_scope(scope).unwrap();
     manager.load_units().unwrap();
     
     // Test service discovery
@@ -82,7 +119,7 @@ wanted_by = ["multi-user.target"]
     let unit = ServiceUnit::from_file(&service_file).unwrap();
     
     assert_eq!(unit.name, "nginx");
-    assert_eq!(unit.service.exec_start, Some("/usr/sbin/nginx".to_string()));
+    assert_eq!(unit.service.exec_start, Some("/usr/sbin/nginx".into()));
     assert_eq!(unit.service.user, Some("www-data".to_string()));
     assert!(unit.sandbox.is_some());
     assert!(unit.install.is_some());
@@ -94,7 +131,8 @@ fn test_state_persistence() {
     let state_dir = temp_dir.path().join("state");
     fs::create_dir_all(&state_dir).unwrap();
     
-    let state_manager = StateManager::new().unwrap();
+    let state_manager = StateManager::in_dir(&state_dir).unwrap();
+    state_manager.clear_all_state().unwrap();
     
     // Test state backup and restore
     state_manager.backup_state().unwrap();
@@ -124,7 +162,7 @@ fn test_boot_manager() {
     let system_dir = temp_dir.path().join("system");
     fs::create_dir_all(&system_dir).unwrap();
     
-    let boot_manager = BootManager::new();
+    let boot_manager = BootManager::for_scope(temp_scope(&temp_dir));
     
     // Test boot integration setup
     boot_manager.setup_boot_integration().unwrap();
@@ -137,7 +175,8 @@ fn test_boot_manager() {
 #[tokio::test]
 async fn test_service_dependencies() {
     let temp_dir = TempDir::new().unwrap();
-    let services_dir = temp_dir.path().join("services");
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
     fs::create_dir_all(&services_dir).unwrap();
     
     // Create dependent services
@@ -168,7 +207,7 @@ type = "simple"
     fs::write(services_dir.join("service-a.tau"), service_a).unwrap();
     fs::write(services_dir.join("service-b.tau"), service_b).unwrap();
     
-    let manager = ServiceManager::new().unwrap();
+    let manager = ServiceManager::for_scope(scope).unwrap();
     manager.load_units().unwrap();
     
     // Test dependency resolution
@@ -177,13 +216,14 @@ type = "simple"
     assert!(dependencies.contains(&"service-b".to_string()));
 }
 
-#[test]
-fn test_circular_dependency_detection() {
+#[tokio::test]
+async fn test_circular_dependency_detection() {
     let temp_dir = TempDir::new().unwrap();
-    let services_dir = temp_dir.path().join("services");
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
     fs::create_dir_all(&services_dir).unwrap();
     
-    // Create circular dependency
+    // Requires alone may form a cycle, ordering both ways may not
     let service_a = r#"
 name = "service-a"
 description = "Service A"
@@ -195,6 +235,7 @@ type = "simple"
 
 [unit]
 requires = ["service-b"]
+after = ["service-b"]
 "#;
     
     let service_b = r#"
@@ -208,17 +249,18 @@ type = "simple"
 
 [unit]
 requires = ["service-a"]
+after = ["service-a"]
 "#;
     
     fs::write(services_dir.join("service-a.tau"), service_a).unwrap();
     fs::write(services_dir.join("service-b.tau"), service_b).unwrap();
     
-    let manager = ServiceManager::new().unwrap();
+    let manager = ServiceManager::for_scope(scope).unwrap();
     manager.load_units().unwrap();
     
     // This should detect circular dependency
     let result = manager.start_service("service-a");
-    assert!(result.is_err());
+    assert!(result.unwrap_err().to_string().contains("Ordering cycle"));
 }
 
 #[test]
@@ -264,9 +306,82 @@ async fn test_journal_logging() {
     let journal_dir = temp_dir.path().join("journal");
     fs::create_dir_all(&journal_dir).unwrap();
     
-    // This would test the journal logging system
-    // Implementation would depend on the actual journal module
-    assert!(journal_dir.exists());
+    let journal = JournalLogger::open(&journal_dir).unwrap()
+        .with_limits(4096, 64 * 1024)
+        .with_boot_id("previous");
+    let mut follower = journal.subscribe();
+    
+    for i in 0..60 {
+        let entry = JournalEntry::new("web", "stdout", &format!("request {}", i))
+            .with_pid(Some(100))
+            .with_field("REQUEST_ID", &i.to_string());
+        journal.write(entry).unwrap();
+    }
+    journal.write(JournalEntry::new("db", "stderr", "fatal: disk full").with_level(LogLevel::Error)).unwrap();
+    
+    // The live stream sees entries as they are written
+    let first = follower.try_recv().unwrap();
+    assert_eq!(first.seqnum, 1);
+    assert_eq!(first.message, "request 0");
+    
+    // Entries written by a later boot after reopening the journal
+    drop(journal);
+    let journal = JournalLogger::open(&journal_dir).unwrap()
+        .with_limits(4096, 64 * 1024)
+        .with_boot_id("current");
+    journal.log("web", "stdout", "started again").unwrap();
+    
+    let files = fs::read_dir(&journal_dir).unwrap().count();
+    assert!(files > 2, "journal was not rotated");
+    assert_eq!(journal.list_services(), vec!["db".to_string(), "web".to_string()]);
+    
+    let web = journal.get_logs("web", None);
+    assert_eq!(web.len(), 61);
+    assert!(web.windows(2).all(|pair| pair[0].seqnum < pair[1].seqnum));
+    assert_eq!(web[5].pid, Some(100));
+    
+    let tail = journal.get_logs("web", Some(3));
+    assert_eq!(tail.iter().map(|e| e.message.as_str()).collect::<Vec<_>>(),
+        vec!["request 58", "request 59", "started again"]);
+    
+    let errors = journal.query(&JournalQuery { priority: Some(LogLevel::parse("err").unwrap().priority()), ..Default::default() }).unwrap();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].service, "db");
+    
+    let grep = journal.query(&JournalQuery { grep: Some("request 1[0-2]$".to_string()), ..Default::default() }).unwrap();
+    assert_eq!(grep.len(), 3);
+    
+    let mut fields = std::collections::BTreeMap::new();
+    fields.insert("REQUEST_ID".to_string(), "42".to_string());
+    let by_field = journal.query(&JournalQuery { fields, ..Default::default() }).unwrap();
+    assert_eq!(by_field.len(), 1);
+    assert_eq!(by_field[0].message, "request 42");
+    
+    // Boots are addressed by offset from the current one
+    assert_eq!(journal.resolve_boot("0").unwrap(), "current");
+    assert_eq!(journal.resolve_boot("-1").unwrap(), "previous");
+    assert!(journal.resolve_boot("-2").is_err());
+    let previous = journal.query(&JournalQuery { boot: Some("-1".to_string()), ..Default::default() }).unwrap();
+    assert_eq!(previous.len(), 61);
+    let current = journal.query(&JournalQuery { boot: Some("0".to_string()), ..Default::default() }).unwrap();
+    assert_eq!(current.len(), 1);
+    
+    let future = journal.query(&JournalQuery { since: Some(parse_time("tomorrow").unwrap()), ..Default::default() }).unwrap();
+    assert!(future.is_empty());
+    let recent = journal.query(&JournalQuery { since: Some(parse_time("-5m").unwrap()), ..Default::default() }).unwrap();
+    assert_eq!(recent.len(), 62);
+    assert!(parse_time("in a while").is_err());
+    
+    // Vacuuming removes the oldest archived files only
+    let before = journal.disk_usage();
+    assert!(journal.vacuum(0) > 0);
+    assert!(journal.disk_usage() < before);
+    let remaining = journal.get_logs("web", None);
+    assert_eq!(remaining.last().unwrap().message, "started again");
+    assert!(remaining.len() < 61);
+    
+    journal.clear_logs(Some("web")).unwrap();
+    assert!(journal.get_logs("web", None).is_empty());
 }
 
 #[test]
@@ -339,7 +454,8 @@ required_by = ["network.target"]
 #[tokio::test]
 async fn test_service_reload() {
     let temp_dir = TempDir::new().unwrap();
-    let services_dir = temp_dir.path().join("services");
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
     fs::create_dir_all(&services_dir).unwrap();
     
     let service = r#"
@@ -355,11 +471,1809 @@ type = "simple"
     
     fs::write(services_dir.join("reload-service.tau"), service).unwrap();
     
-    let manager = ServiceManager::new().unwrap();
+    let manager = ServiceManager::for_scope(scope).unwrap();
     manager.load_units().unwrap();
     
     // Test reload functionality
     let unit = manager.get_unit("reload-service").unwrap();
     assert!(unit.service.exec_reload.is_some());
     assert_eq!(unit.service.exec_reload.unwrap(), "/usr/bin/reload-service --reload");
-} 
\ No newline at end of file
+    
+    // Only an active service can be reloaded
+    assert!(manager.reload_service("reload-service").is_err());
+    
+    let marker = temp_dir.path().join("reloaded");
+    fs::write(services_dir.join("reloader.tau"), format!(r#"
+name = "reloader"
+
+[service]
+exec_start = "/bin/sleep 30"
+exec_reload = "/bin/touch {}"
+timeout_start_sec = 1
+"#, marker.display())).unwrap();
+    manager.load_units().unwrap();
+    manager.start_service("reloader").unwrap();
+    let pid = manager.get_service_status("reloader").unwrap().pid;
+    assert!(pid.is_some());
+    
+    manager.reload_service("reloader").unwrap();
+    assert!(marker.exists());
+    let status = manager.get_service_status("reloader").unwrap();
+    assert_eq!(status.state, ServiceState::Active);
+    assert_eq!(status.pid, pid);
+    
+    manager.stop_service("reloader").unwrap();
+    
+    // ExecReload= is bounded by TimeoutStartSec=, the service keeps running
+    fs::write(services_dir.join("slow-reloader.tau"), r#"
+name = "slow-reloader"
+
+[service]
+exec_start = "/bin/sleep 30"
+exec_reload = "/bin/sleep 10"
+timeout_start_sec = 1
+"#).unwrap();
+    manager.load_units().unwrap();
+    manager.start_service("slow-reloader").unwrap();
+    let pid = manager.get_service_status("slow-reloader").unwrap().pid;
+    
+    let error = manager.reload_service("slow-reloader").unwrap_err();
+    assert_eq!(error.downcast_ref::<ExecError>().map(ExecError::phase), Some(ExecPhase::Reload));
+    let status = manager.get_service_status("slow-reloader").unwrap();
+    assert_eq!(status.state, ServiceState::Active);
+    assert_eq!(status.pid, pid);
+    
+    manager.stop_service("slow-reloader").unwrap();
+}
+
+#[test]
+fn test_control_protocol_wire_format() {
+    let request = ControlRequest::Start { service: "nginx".to_string(), wait: true };
+    let encoded = serde_json::to_string(&request).unwrap();
+    assert!(encoded.contains("\"method\":\"start\""));
+    
+    let decoded: ControlRequest = serde_json::from_str(&encoded).unwrap();
+    match decoded {
+        ControlRequest::Start { service, wait } => {
+            assert_eq!(service, "nginx");
+            assert!(wait);
+        }
+        other => panic!("Unexpected request: {:?}", other),
+    }
+    
+    let response: ControlResponse = serde_json::from_str(r#"{"result":"error","data":"Service unit not found: foo"}"#).unwrap();
+    assert!(matches!(response, ControlResponse::Error(message) if message.contains("foo")));
+}
+
+#[test]
+fn test_restart_policy_decisions() {
+    let clean = ExitOutcome::Exited(0);
+    let failed = ExitOutcome::Exited(1);
+    let terminated = ExitOutcome::Signaled(libc::SIGTERM);
+    let crashed = ExitOutcome::Signaled(libc::SIGSEGV);
+    
+    assert!(!should_restart(&RestartPolicy::No, &crashed));
+    assert!(should_restart(&RestartPolicy::Always, &clean));
+    
+    assert!(should_restart(&RestartPolicy::OnSuccess, &clean));
+    assert!(should_restart(&RestartPolicy::OnSuccess, &terminated));
+    assert!(!should_restart(&RestartPolicy::OnSuccess, &failed));
+    
+    assert!(should_restart(&RestartPolicy::OnFailure, &failed));
+    assert!(should_restart(&RestartPolicy::OnFailure, &crashed));
+    assert!(!should_restart(&RestartPolicy::OnFailure, &terminated));
+    
+    assert!(should_restart(&RestartPolicy::OnAbnormal, &crashed));
+    assert!(!should_restart(&RestartPolicy::OnAbnormal, &failed));
+    assert!(should_restart(&RestartPolicy::OnAbort, &crashed));
+    assert!(!should_restart(&RestartPolicy::OnAbort, &clean));
+}
+
+#[test]
+fn test_notify_message_parsing() {
+    let message = NotifyMessage::parse("READY=1\nSTATUS=Listening on port 80\nMAINPID=4242\nX_CUSTOM=1");
+    assert!(message.ready);
+    assert!(!message.watchdog);
+    assert_eq!(message.status, Some("Listening on port 80".to_string()));
+    assert_eq!(message.main_pid, Some(4242));
+    
+    let ping = NotifyMessage::parse("WATCHDOG=1");
+    assert!(ping.watchdog);
+    assert!(!ping.ready);
+    
+    let trigger = NotifyMessage::parse("WATCHDOG=trigger");
+    assert!(trigger.watchdog_trigger);
+    assert!(!trigger.watchdog);
+    
+    assert!(should_restart(&RestartPolicy::OnWatchdog, &ExitOutcome::Watchdog));
+    assert!(should_restart(&RestartPolicy::OnAbnormal, &ExitOutcome::Watchdog));
+    assert!(!should_restart(&RestartPolicy::OnAbort, &ExitOutcome::Watchdog));
+}
+
+#[tokio::test]
+async fn test_notify_socket_credentials() {
+    use std::os::unix::fs::PermissionsExt;
+    
+    let temp_dir = TempDir::new().unwrap();
+    let (sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    let socket = NotifySocket::bind_in(temp_dir.path(), "notifier", sender).unwrap();
+    
+    // Only the unit's user may write to it
+    let mode = fs::metadata(socket.path()).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    
+    let client = std::os::unix::net::UnixDatagram::unbound().unwrap();
+    client.send_to(b"READY=1\nMAINPID=1", socket.path()).unwrap();
+    
+    let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+    match event {
+        ServiceEvent::Notify { name, message, pid } => {
+            assert_eq!(name, "notifier");
+            assert!(message.ready);
+            assert_eq!(message.main_pid, Some(1));
+            assert_eq!(pid, std::process::id());
+        }
+        other => panic!("Unexpected event: {:?}", other),
+    }
+    
+    let parse = |options: &str| ServiceUnit::from_str(&format!("name = \"notifier\"\n[service]\nexec_start = \"/bin/true\"\n{}", options),
+        &PathBuf::from("/etc/tau/services/notifier.tau")).unwrap();
+    assert_eq!(parse("").notify_access(), NotifyAccess::Main);
+    assert_eq!(parse("notify_access = \"all\"").notify_access(), NotifyAccess::All);
+    assert_eq!(parse("notify_access = \"none\"").notify_access(), NotifyAccess::None);
+}
+
+#[test]
+fn test_socket_unit_parsing() {
+    assert_eq!(ListenAddress::parse("/run/echo.sock").unwrap(), ListenAddress::Unix(PathBuf::from("/run/echo.sock")));
+    assert_eq!(ListenAddress::parse("@echo").unwrap(), ListenAddress::Abstract("echo".to_string()));
+    assert_eq!(ListenAddress::parse("8080").unwrap(), ListenAddress::Inet("0.0.0.0:8080".parse().unwrap()));
+    assert_eq!(ListenAddress::parse("127.0.0.1:53").unwrap(), ListenAddress::Inet("127.0.0.1:53".parse().unwrap()));
+    assert!(ListenAddress::parse("not-an-address").is_err());
+    
+    let path = PathBuf::from("/etc/tau/services/echo.socket");
+    let unit = SocketUnit::from_str(r#"
+        description = "Echo socket"
+        
+        [socket]
+        listen_stream = ["/run/echo.sock"]
+    "#, &path).unwrap();
+    
+    assert_eq!(unit.name, "echo.socket");
+    assert_eq!(unit.service_name(), "echo");
+    assert!(!unit.accepts_connections());
+    
+    let no_listener = SocketUnit::from_str("[socket]\naccept = true\n", &path);
+    assert!(no_listener.is_err());
+    
+    let datagram_accept = SocketUnit::from_str(r#"
+        [socket]
+        listen_datagram = ["/run/echo.dgram"]
+        accept = true
+    "#, &path);
+    assert!(datagram_accept.is_err());
+}
+
+#[test]
+fn test_calendar_expressions() {
+    use chrono::{Local, TimeZone};
+    
+    let now = Local.with_ymd_and_hms(2026, 10, 18, 10, 17, 30).unwrap();
+    let next = |expression: &str| CalendarSpec::parse(expression).unwrap().next_after(now);
+    
+    assert_eq!(next("daily"), Some(Local.with_ymd_and_hms(2026, 10, 19, 0, 0, 0).unwrap()));
+    assert_eq!(next("*:0/15"), Some(Local.with_ymd_and_hms(2026, 10, 18, 10, 30, 0).unwrap()));
+    assert_eq!(next("Mon..Fri *-*-* 04:00"), Some(Local.with_ymd_and_hms(2026, 10, 19, 4, 0, 0).unwrap()));
+    assert_eq!(next("*-02-29 00:00"), Some(Local.with_ymd_and_hms(2028, 2, 29, 0, 0, 0).unwrap()));
+    assert_eq!(next("2027-02-29"), None);
+    
+    assert!(CalendarSpec::parse("25:00").is_err());
+    assert!(CalendarSpec::parse("*-13-01").is_err());
+    assert!(CalendarSpec::parse("bogus").is_err());
+    
+    let path = PathBuf::from("/etc/tau/services/logrotate.timer");
+    let timer = TimerUnit::from_str(r#"
+        [timer]
+        on_calendar = ["daily"]
+        persistent = true
+        randomized_delay_sec = 600
+    "#, &path).unwrap();
+    
+    assert_eq!(timer.name, "logrotate.timer");
+    assert_eq!(timer.service_name(), "logrotate");
+    assert!(timer.is_persistent());
+    
+    assert!(TimerUnit::from_str("[timer]\npersistent = true\n", &path).is_err());
+    assert!(TimerUnit::from_str("[timer]\non_calendar = [\"someday\"]\n", &path).is_err());
+}
+
+#[test]
+fn test_cgroup_resource_limits() {
+    assert_eq!(parse_bytes("512M").unwrap(), 512 * 1024 * 1024);
+    assert_eq!(parse_bytes("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+    assert_eq!(parse_bytes("4096").unwrap(), 4096);
+    assert!(parse_bytes("lots").is_err());
+    
+    assert_eq!(parse_cpu_quota("50%").unwrap(), 50_000);
+    assert_eq!(parse_cpu_quota("200%").unwrap(), 200_000);
+    assert!(parse_cpu_quota("50").is_err());
+    
+    // A plain directory stands in for the cgroup2 mount
+    let temp_dir = TempDir::new().unwrap();
+    let path = temp_dir.path().join("limited.tau");
+    let unit = ServiceUnit::from_str(r#"
+        name = "limited"
+        
+        [service]
+        exec_start = "/bin/sleep 60"
+        memory_max = "256M"
+        cpu_quota = "150%"
+        tasks_max = 32
+    "#, &path).unwrap();
+    
+    let cgroup = ServiceCgroup::create_in(temp_dir.path(), &unit.name).unwrap();
+    assert_eq!(cgroup.path(), temp_dir.path().join("tau.slice/limited.service"));
+    
+    cgroup.apply_limits(&unit.service).unwrap();
+    assert_eq!(fs::read_to_string(cgroup.path().join("memory.max")).unwrap(), "268435456");
+    assert_eq!(fs::read_to_string(cgroup.path().join("memory.high")).unwrap(), "max");
+    assert_eq!(fs::read_to_string(cgroup.path().join("cpu.max")).unwrap(), "150000 100000");
+    assert_eq!(fs::read_to_string(cgroup.path().join("pids.max")).unwrap(), "32");
+    
+    fs::write(cgroup.path().join("memory.current"), "1048576\n").unwrap();
+    assert_eq!(cgroup.usage().memory_current, Some(1048576));
+    
+    // Processes in cgroups the service created below its own count too
+    fs::write(cgroup.path().join("cgroup.procs"), "100\n").unwrap();
+    fs::create_dir_all(cgroup.path().join("worker/inner")).unwrap();
+    fs::write(cgroup.path().join("worker/cgroup.procs"), "").unwrap();
+    fs::write(cgroup.path().join("worker/inner/cgroup.procs"), "200\n201\n").unwrap();
+    let mut pids = cgroup.pids();
+    pids.sort();
+    assert_eq!(pids, [100, 200, 201]);
+    
+    let invalid = ServiceUnit::from_str("name = \"limited\"\n[service]\nexec_start = \"/bin/true\"\ncpu_weight = 0\n", &path);
+    assert!(invalid.is_err());
+}
+
+#[test]
+fn test_capabilities_and_dynamic_users() {
+    assert_eq!(parse_capability("CAP_NET_BIND_SERVICE").unwrap(), 10);
+    assert_eq!(parse_capability("net_bind_service").unwrap(), 10);
+    assert_eq!(parse_capability("CAP_SYS_ADMIN").unwrap(), 21);
+    assert!(parse_capability("CAP_EVERYTHING").is_err());
+    
+    let temp_dir = TempDir::new().unwrap();
+    let first = allocate_dynamic_uid(temp_dir.path(), "web").unwrap();
+    assert!((61184..=65519).contains(&first));
+    assert_eq!(allocate_dynamic_uid(temp_dir.path(), "web").unwrap(), first);
+    
+    let other = allocate_dynamic_uid(temp_dir.path(), "db").unwrap();
+    assert_ne!(other, first);
+    
+    release_dynamic_uid(temp_dir.path(), first, "web");
+    assert!(!temp_dir.path().join(first.to_string()).exists());
+    
+    // Ambient capabilities outside the bounding set can never be raised
+    let path = PathBuf::from("/etc/tau/services/web.tau");
+    let unit = ServiceUnit::from_str(r#"
+        name = "web"
+        
+        [service]
+        exec_start = "/bin/true"
+        
+        [sandbox]
+        capability_bounding_set = ["CAP_NET_BIND_SERVICE"]
+        ambient_capabilities = ["CAP_NET_RAW"]
+    "#, &path).unwrap();
+    assert!(Credentials::resolve_in(&unit, temp_dir.path()).is_err());
+    
+    // no_new_privileges holds without a filter or a change of identity
+    let unit = ServiceUnit::from_str(r#"
+        name = "web"
+        
+        [service]
+        exec_start = "/bin/true"
+        
+        [sandbox]
+        no_new_privileges = true
+    "#, &path).unwrap();
+    let credentials = Credentials::resolve_in(&unit, temp_dir.path()).unwrap();
+    assert!(credentials.no_new_privileges);
+    
+    let mut cmd = std::process::Command::new("/bin/grep");
+    cmd.args(["NoNewPrivs", "/proc/self/status"]);
+    credentials.apply(&mut cmd).unwrap();
+    let output = cmd.output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "NoNewPrivs:\t1");
+}
+
+/// Needs root, e.g. `cargo test` inside a container; skipped otherwise.
+#[test]
+fn test_service_credentials_as_root() {
+    if !nix::unistd::geteuid().is_root() {
+        eprintln!("skipping test_service_credentials_as_root: not running as root");
+        return;
+    }
+    
+    let temp_dir = TempDir::new().unwrap();
+    let path = PathBuf::from("/etc/tau/services/web.tau");
+    let unit = ServiceUnit::from_str(r#"
+        name = "web"
+        
+        [service]
+        exec_start = "/bin/true"
+        user = "nobody"
+        
+        [sandbox]
+        capability_bounding_set = ["CAP_NET_BIND_SERVICE"]
+        ambient_capabilities = ["CAP_NET_BIND_SERVICE"]
+    "#, &path).unwrap();
+    
+    let credentials = Credentials::resolve_in(&unit, temp_dir.path()).unwrap();
+    let nobody = nix::unistd::User::from_name("nobody").unwrap().unwrap();
+    
+    let mut cmd = std::process::Command::new("/bin/sh");
+    cmd.args(["-c", "id -u; id -g; grep -E '^Cap(Bnd|Amb)' /proc/self/status"]);
+    credentials.apply(&mut cmd).unwrap();
+    
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    
+    assert_eq!(lines[0], nobody.uid.to_string());
+    assert_eq!(lines[1], nobody.gid.to_string());
+    assert!(stdout.contains("CapBnd:\t0000000000000400"));
+    assert!(stdout.contains("CapAmb:\t0000000000000400"));
+}
+
+/// Needs root, e.g. `cargo test` inside a container; skipped otherwise.
+#[test]
+fn test_namespace_sandbox_as_root() {
+    if !nix::unistd::geteuid().is_root() {
+        eprintln!("skipping test_namespace_sandbox_as_root: not running as root");
+        return;
+    }
+    
+    // Outside /tmp, which the sandbox replaces
+    let temp_dir = TempDir::new_in(env!("CARGO_MANIFEST_DIR")).unwrap();
+    let read_only = temp_dir.path().join("ro");
+    let read_write = read_only.join("rw");
+    fs::create_dir_all(&read_write).unwrap();
+    
+    let marker = tempfile::NamedTempFile::new_in("/tmp").unwrap();
+    
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "sandboxed"
+        
+        [service]
+        exec_start = "/bin/true"
+        
+        [sandbox]
+        private_tmp = true
+        private_devices = true
+        protect_system = true
+        network_access = false
+        read_only_paths = ["{ro}"]
+        read_write_paths = ["{rw}"]
+    "#, ro = read_only.display(), rw = read_write.display()), &PathBuf::from("sandboxed.tau")).unwrap();
+    
+    let script = format!(
+        "[ ! -e {marker} ] || exit 10; \
+         touch /tmp/scratch || exit 11; \
+         touch /usr/.tau-sandbox-test 2>/dev/null && exit 12; \
+         touch {ro}/file 2>/dev/null && exit 13; \
+         touch {rw}/file || exit 14; \
+         [ -c /dev/null ] && echo ok > /dev/null || exit 15; \
+         [ \"$(tail -n +3 /proc/net/dev | wc -l)\" -eq 1 ] || exit 16",
+        marker = marker.path().display(),
+        ro = read_only.display(),
+        rw = read_write.display(),
+    );
+    
+    let mut cmd = std::process::Command::new("/bin/sh");
+    cmd.args(["-c", &script]);
+    SandboxManager::new().apply_sandboxing(&mut cmd, unit.sandbox.as_ref().unwrap()).unwrap();
+    
+    let status = cmd.status().unwrap();
+    assert_eq!(status.code(), Some(0));
+    assert!(read_write.join("file").exists());
+    assert!(!read_only.join("file").exists());
+}
+
+#[test]
+fn test_syscall_filter() {
+    let policy = SyscallPolicy::parse(&["@system-service".to_string(), "~@privileged @resources".to_string()]).unwrap();
+    assert!(policy.allow_list);
+    assert!(policy.permits("read"));
+    assert!(policy.permits("execve"));
+    assert!(!policy.permits("setuid"));
+    assert!(!policy.permits("setpriority"));
+    assert!(!policy.permits("mount"));
+    assert!(policy.blocks_group("@mount"));
+    assert!(!policy.blocks_group("@network-io"));
+    
+    let deny = SyscallPolicy::parse(&["~@mount".to_string(), "chroot".to_string()]).unwrap();
+    assert!(!deny.allow_list);
+    assert!(!deny.permits("mount"));
+    assert!(deny.permits("chroot"));
+    assert!(deny.permits("read"));
+    
+    assert!(SyscallPolicy::parse(&["@no-such-group".to_string()]).is_err());
+    assert!(SyscallPolicy::parse(&["no_such_syscall".to_string()]).is_err());
+    assert_eq!(DenyAction::parse("EPERM").unwrap(), DenyAction::Errno(1));
+    assert_eq!(DenyAction::parse("kill").unwrap(), DenyAction::Kill);
+    assert!(DenyAction::parse("EWHATEVER").is_err());
+    
+    let unit = ServiceUnit::from_str(r#"
+        name = "filtered"
+        
+        [service]
+        exec_start = "/bin/uname"
+        
+        [sandbox]
+        system_call_filter = ["~uname"]
+        system_call_architectures = ["native"]
+        system_call_error_number = "EPERM"
+    "#, &PathBuf::from("filtered.tau")).unwrap();
+    
+    let sandbox = unit.sandbox.as_ref().unwrap();
+    let report = SecurityAuditor::new().audit_service_security("filtered", sandbox);
+    let coverage = report.checks.iter().find(|c| c.name == "syscall_filter_coverage").unwrap();
+    assert!(!coverage.passed);
+    assert!(report.checks.iter().any(|c| c.name == "syscall_architectures" && c.passed));
+    
+    // The filter applies to the exec'd program, not only the manager
+    let mut cmd = std::process::Command::new("/bin/uname");
+    cmd.stdout(std::process::Stdio::null()).stderr(std::process::Stdio::null());
+    SandboxManager::new().apply_syscall_filter(&mut cmd, sandbox).unwrap();
+    assert!(!cmd.status().unwrap().success());
+    
+    let mut cmd = std::process::Command::new("/bin/true");
+    SandboxManager::new().apply_syscall_filter(&mut cmd, sandbox).unwrap();
+    assert!(cmd.status().unwrap().success());
+    
+    // Only the native syscall tables are known
+    let invalid = ServiceUnit::from_str(r#"
+        name = "invalid"
+        
+        [service]
+        exec_start = "/bin/true"
+        
+        [sandbox]
+        system_call_architectures = ["mips"]
+    "#, &PathBuf::from("invalid.tau"));
+    assert!(invalid.is_err());
+}
+
+/// Records when units start and fails those named "broken".
+struct FakeRunner {
+    active: std::sync::Mutex<std::collections::HashSet<String>>,
+    spans: std::sync::Mutex<std::collections::HashMap<String, (std::time::Instant, std::time::Instant)>>,
+}
+
+impl JobRunner for FakeRunner {
+    fn is_active(&self, unit: &str) -> bool {
+        self.active.lock().unwrap().contains(unit)
+    }
+    
+    fn start_unit(&self, unit: &str) -> anyhow::Result<()> {
+        let begin = std::time::Instant::now();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        self.spans.lock().unwrap().insert(unit.to_string(), (begin, std::time::Instant::now()));
+        
+        if unit == "broken" {
+            return Err(anyhow::anyhow!("exit code 1"));
+        }
+        
+        self.active.lock().unwrap().insert(unit.to_string());
+        Ok(())
+    }
+    
+    fn stop_unit(&self, unit: &str) -> anyhow::Result<()> {
+        self.active.lock().unwrap().remove(unit);
+        Ok(())
+    }
+}
+
+fn deps(requires: &[&str], wants: &[&str], after: &[&str]) -> UnitDependencies {
+    let list = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+    UnitDependencies {
+        requires: list(requires),
+        wants: list(wants),
+        after: list(after),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_transaction_engine() {
+    let mut graph = std::collections::HashMap::new();
+    graph.insert("db".to_string(), deps(&[], &[], &[]));
+    graph.insert("cache".to_string(), deps(&[], &[], &[]));
+    graph.insert("web".to_string(), deps(&["db"], &["cache", "missing"], &["db"]));
+    graph.insert("broken".to_string(), deps(&[], &[], &[]));
+    graph.insert("needs-broken".to_string(), deps(&["broken"], &[], &["broken"]));
+    graph.insert("wants-broken".to_string(), deps(&[], &["broken"], &["broken"]));
+    
+    let roots: Vec<String> = ["web", "needs-broken", "wants-broken"].iter().map(|s| s.to_string()).collect();
+    let transaction = Transaction::start(&graph, &roots).unwrap();
+    assert_eq!(transaction.waits_for("web"), vec!["db".to_string()]);
+    assert!(transaction.waits_for("cache").is_empty());
+    
+    let runner = FakeRunner {
+        active: Default::default(),
+        spans: Default::default(),
+    };
+    let report = transaction.execute(&runner);
+    let spans = runner.spans.lock().unwrap();
+    
+    // Independent units run in parallel, ordered ones one after another
+    let (db, cache, web) = (spans["db"], spans["cache"], spans["web"]);
+    assert!(db.0 < cache.1 && cache.0 < db.1);
+    assert!(web.0 >= db.1);
+    
+    // Failures only travel along Requires=
+    assert_eq!(report.outcome("web"), Some(&JobOutcome::Done));
+    assert!(matches!(report.outcome("broken"), Some(JobOutcome::Failed(_))));
+    assert_eq!(report.outcome("needs-broken"), Some(&JobOutcome::DependencyFailed("broken".to_string())));
+    assert_eq!(report.outcome("wants-broken"), Some(&JobOutcome::Done));
+    assert!(!spans.contains_key("needs-broken"));
+    assert!(report.check("needs-broken").is_err());
+    drop(spans);
+    
+    // Already running units are left alone
+    let report = Transaction::start(&graph, &["web".to_string()]).unwrap().execute(&runner);
+    assert_eq!(report.outcome("db"), Some(&JobOutcome::Skipped));
+    
+    // Stopping a unit stops what requires it
+    let stop = Transaction::stop(&graph, &["db".to_string()]).unwrap();
+    let units: Vec<(&str, JobType)> = stop.units().collect();
+    assert_eq!(units, vec![("db", JobType::Stop), ("web", JobType::Stop)]);
+    assert_eq!(stop.waits_for("db"), vec!["web".to_string()]);
+    
+    // Conflicting units are stopped before the unit starts
+    let mut conflicting = deps(&[], &[], &[]);
+    conflicting.conflicts = vec!["cache".to_string()];
+    graph.insert("cache-replacement".to_string(), conflicting);
+    let transaction = Transaction::start(&graph, &["cache-replacement".to_string()]).unwrap();
+    assert!(transaction.units().any(|unit| unit == ("cache", JobType::Stop)));
+    assert_eq!(transaction.waits_for("cache-replacement"), vec!["cache".to_string()]);
+    assert_eq!(
+        Transaction::start(&graph, &["cache-replacement".to_string(), "cache".to_string()]).unwrap_err(),
+        TransactionError::Conflict("cache".to_string(), "cache-replacement".to_string())
+    );
+    
+    // Ordering cycles are reported, unless a wanted unit can be dropped
+    graph.insert("a".to_string(), deps(&["b"], &[], &["b"]));
+    graph.insert("b".to_string(), deps(&["a"], &[], &["a"]));
+    let err = Transaction::start(&graph, &["a".to_string()]).unwrap_err();
+    assert_eq!(err, TransactionError::OrderingCycle(vec!["a".into(), "b".into(), "a".into()]));
+    assert_eq!(err.to_string(), "Ordering cycle: a -> b -> a");
+    
+    graph.insert("c".to_string(), deps(&[], &["d"], &["d"]));
+    graph.insert("d".to_string(), deps(&[], &[], &["c"]));
+    let transaction = Transaction::start(&graph, &["c".to_string()]).unwrap();
+    assert_eq!(transaction.units().collect::<Vec<_>>(), vec![("c", JobType::Start)]);
+    
+    assert!(matches!(
+        Transaction::start(&graph, &["nonexistent".to_string()]),
+        Err(TransactionError::UnitNotFound { .. })
+    ));
+}
+
+#[test]
+fn test_drop_ins_and_templates() {
+    let temp_dir = TempDir::new().unwrap();
+    let system_dir = temp_dir.path().join("system");
+    let user_dir = temp_dir.path().join("user");
+    fs::create_dir_all(system_dir.join("web.tau.d")).unwrap();
+    fs::create_dir_all(system_dir.join("getty@.tau.d")).unwrap();
+    fs::create_dir_all(user_dir.join("web.tau.d")).unwrap();
+    fs::create_dir_all(user_dir.join("getty@tty2.tau.d")).unwrap();
+    
+    fs::write(system_dir.join("web.tau"), r#"
+name = "web"
+
+[service]
+exec_start = "/usr/bin/web --port 80"
+memory_max = "1G"
+environment = { MODE = "prod", LANG = "C" }
+"#).unwrap();
+    fs::write(system_dir.join("web.tau.d/10-port.toml"), r#"
+[service]
+exec_start = "/usr/bin/web --port 8080"
+environment = { MODE = "debug" }
+"#).unwrap();
+    fs::write(system_dir.join("web.tau.d/20-limits.toml"), "[service]\nmemory_max = \"2G\"\n").unwrap();
+    // Same name as a system drop-in, replaces it
+    fs::write(user_dir.join("web.tau.d/20-limits.toml"), "[service]\nmemory_max = \"512M\"\n").unwrap();
+    
+    fs::write(system_dir.join("getty@.tau"), r#"
+name = "getty@"
+
+[service]
+exec_start = "/sbin/agetty %i --home %h"
+working_directory = "/var/lib/%p/%i"
+environment = { UNIT = "%n", PERCENT = "100%%" }
+
+[unit]
+after = ["console@%i"]
+"#).unwrap();
+    fs::write(system_dir.join("getty@.tau.d/50-restart.toml"), "[service]\nrestart = \"always\"\n").unwrap();
+    fs::write(user_dir.join("getty@tty2.tau.d/50-restart.toml"), "[service]\nrestart = \"no\"\n").unwrap();
+    
+    let loader = UnitLoader { system_units_dir: system_dir.clone(), user_units_dir: user_dir.clone() };
+    
+    // Drop-ins are applied in file name order, tables merged by key
+    let units = loader.load_all_units().unwrap();
+    let web = &units["web"];
+    assert_eq!(web.service.exec_start, Some("/usr/bin/web --port 8080".into()));
+    assert_eq!(web.service.memory_max.as_deref(), Some("512M"));
+    let env = web.service.environment.as_ref().unwrap();
+    assert_eq!(env["MODE"], "debug");
+    assert_eq!(env["LANG"], "C");
+    
+    // Templates are not loaded by themselves, only as instances
+    assert!(!units.contains_key("getty@"));
+    assert!(loader.reload_unit("getty@").is_err());
+    
+    let home = nix::unistd::User::from_uid(nix::unistd::getuid()).unwrap().unwrap().dir;
+    let tty1 = loader.reload_unit("getty@tty1").unwrap();
+    assert_eq!(tty1.name, "getty@tty1");
+    assert_eq!(tty1.service.exec_start, Some(format!("/sbin/agetty tty1 --home {}", home.display()).into()));
+    assert_eq!(tty1.service.working_directory.as_deref(), Some("/var/lib/getty/tty1"));
+    assert_eq!(tty1.service.environment.as_ref().unwrap()["UNIT"], "getty@tty1");
+    assert_eq!(tty1.service.environment.as_ref().unwrap()["PERCENT"], "100%");
+    assert_eq!(tty1.dependencies().after, vec!["console@tty1".to_string()]);
+    assert!(matches!(tty1.service.restart, Some(RestartPolicy::Always)));
+    
+    // Instance drop-ins override template drop-ins of the same name
+    let tty2 = loader.reload_unit("getty@tty2").unwrap();
+    assert!(matches!(tty2.service.restart, Some(RestartPolicy::No)));
+    
+    // Provenance of every merged setting
+    let source = loader.source("web").unwrap();
+    assert_eq!(source.drop_ins.len(), 2);
+    assert_eq!(source.sources_of("service.memory_max"), vec![user_dir.join("web.tau.d/20-limits.toml").as_path()]);
+    assert_eq!(source.sources_of("service.environment"), vec![
+        system_dir.join("web.tau").as_path(),
+        system_dir.join("web.tau.d/10-port.toml").as_path(),
+    ]);
+    let rendered = source.render();
+    assert!(rendered.contains("memory_max = \"512M\"  # 20-limits.toml"));
+    assert!(rendered.contains("exec_start = \"/usr/bin/web --port 8080\"  # 10-port.toml"));
+    
+    // Unknown specifiers are rejected
+    fs::write(system_dir.join("bad@.tau"), "name = \"bad@\"\n[service]\nexec_start = \"/bin/echo %z\"\n").unwrap();
+    assert!(loader.reload_unit("bad@x").is_err());
+}
+
+#[test]
+fn test_changed_units() {
+    let temp_dir = TempDir::new().unwrap();
+    let services_dir = temp_dir.path().join("services");
+    fs::create_dir_all(services_dir.join("api.tau.d")).unwrap();
+    
+    let write_unit = |name: &str, exec: &str| {
+        let content = format!("name = \"{}\"\n\n[service]\nexec_start = \"{}\"\n", name, exec);
+        fs::write(services_dir.join(format!("{}.tau", name)), content).unwrap();
+    };
+    write_unit("api", "/usr/bin/api");
+    write_unit("worker", "/usr/bin/worker");
+    write_unit("cron", "/usr/bin/cron");
+    
+    let loader = UnitLoader { system_units_dir: services_dir.clone(), user_units_dir: temp_dir.path().join("none") };
+    let before = loader.load_all_units().unwrap();
+    assert!(changed_units(&before, &loader.load_all_units().unwrap()).is_empty());
+    
+    // A new drop-in changes the unit, as do edits and removals
+    fs::write(services_dir.join("api.tau.d/override.toml"), "[service]\nmemory_max = \"1G\"\n").unwrap();
+    write_unit("worker", "/usr/bin/worker --threads 4");
+    fs::remove_file(services_dir.join("cron.tau")).unwrap();
+    write_unit("new", "/usr/bin/new");
+    
+    let after = loader.load_all_units().unwrap();
+    assert_eq!(changed_units(&before, &after), vec!["api".to_string(), "cron".to_string(), "worker".to_string()]);
+}
+
+#[test]
+fn test_path_units() {
+    let temp_dir = TempDir::new().unwrap();
+    let spool = temp_dir.path().join("spool");
+    let units_dir = temp_dir.path().join("units");
+    fs::create_dir_all(&units_dir).unwrap();
+    
+    let unit_file = units_dir.join("jobs.path");
+    fs::write(&unit_file, format!(r#"
+description = "Process queued jobs"
+
+[path]
+directory_not_empty = ["{spool}"]
+path_exists_glob = ["{spool}/*.ready"]
+path_changed = ["{spool}/control"]
+path_modified = ["{spool}/log"]
+unit = "job-runner"
+"#, spool = spool.display())).unwrap();
+    
+    let unit = PathUnit::from_file(&unit_file).unwrap();
+    assert_eq!(unit.name, "jobs.path");
+    assert_eq!(unit.service_name(), "job-runner");
+    
+    let conditions = unit.conditions().unwrap();
+    assert_eq!(conditions.len(), 4);
+    let glob = &conditions[0];
+    let changed = &conditions[1];
+    let modified = &conditions[2];
+    let not_empty = &conditions[3];
+    assert_eq!(glob.path(), spool);
+    
+    // State conditions hold once the file system gets there
+    assert!(!not_empty.is_met() && !glob.is_met());
+    fs::create_dir_all(&spool).unwrap();
+    assert!(!not_empty.is_met());
+    fs::write(spool.join("job-1.ready"), "").unwrap();
+    assert!(not_empty.is_met() && glob.is_met());
+    
+    let created = Event::new(EventKind::Create(CreateKind::File)).add_path(spool.join("job-1.ready"));
+    assert!(not_empty.is_triggered_by(&created));
+    assert!(glob.is_triggered_by(&created));
+    assert!(!changed.is_triggered_by(&created));
+    
+    // Writes only count for PathModified, closing after a write for both
+    let write = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)));
+    let close = Event::new(EventKind::Access(AccessKind::Close(AccessMode::Write)));
+    assert!(!changed.is_triggered_by(&write.clone().add_path(spool.join("control"))));
+    assert!(changed.is_triggered_by(&close.clone().add_path(spool.join("control"))));
+    assert!(modified.is_triggered_by(&write.clone().add_path(spool.join("log"))));
+    assert!(!modified.is_triggered_by(&write.add_path(spool.join("other"))));
+    
+    // Conditions need absolute paths
+    let relative = "[path]\npath_exists = [\"spool/ready\"]\n";
+    assert!(PathUnit::from_str(relative, &units_dir.join("bad.path")).is_err());
+    assert!(PathUnit::from_str("[path]\nunit = \"x\"\n", &units_dir.join("empty.path")).is_err());
+}
+
+#[test]
+fn test_boot_analysis() {
+    let start = std::time::SystemTime::now();
+    let at = |ms: u64| Some(start + std::time::Duration::from_millis(ms));
+    let timing = |name: &str, activating: Option<std::time::SystemTime>, active: Option<std::time::SystemTime>, dependencies: UnitDependencies| {
+        UnitTiming { name: name.to_string(), activating, active, dependencies }
+    };
+    
+    // network and storage start in parallel, db waits for both, web for db
+    let times = BootTimes {
+        manager_start: start,
+        units: vec![
+            timing("network", at(0), at(400), UnitDependencies::default()),
+            timing("storage", at(0), at(150), UnitDependencies { before: vec!["db".into()], ..Default::default() }),
+            timing("db", at(400), at(1200), UnitDependencies { requires: vec!["storage".into()], after: vec!["network".into()], ..Default::default() }),
+            timing("web", at(1200), at(1500), UnitDependencies { wants: vec!["db".into()], after: vec!["db".into()], ..Default::default() }),
+            timing("idle", None, None, UnitDependencies { conflicts: vec!["web".into()], ..Default::default() }),
+        ],
+    };
+    
+    let blame: Vec<(String, u128)> = times.blame().into_iter()
+        .map(|(unit, took)| (unit.name.clone(), took.as_millis()))
+        .collect();
+    assert_eq!(blame, vec![
+        ("db".to_string(), 800),
+        ("network".to_string(), 400),
+        ("web".to_string(), 300),
+        ("storage".to_string(), 150),
+    ]);
+    
+    // storage was ready earlier than network, so db waited for network
+    let chain = times.critical_chain("web").unwrap();
+    let names: Vec<&str> = chain.iter().map(|link| link.name.as_str()).collect();
+    assert_eq!(names, vec!["web", "db", "network"]);
+    assert_eq!(chain[1].active_at, Some(std::time::Duration::from_millis(1200)));
+    assert_eq!(chain[1].took, Some(std::time::Duration::from_millis(800)));
+    assert!(times.critical_chain("missing").is_err());
+    
+    let svg = times.plot();
+    assert!(svg.starts_with("<?xml"));
+    assert!(svg.contains(">db (800ms)</text>"));
+    assert!(!svg.contains("idle"));
+    
+    let dot = times.dot(&[]);
+    assert!(dot.contains("\"db\"->\"storage\" [color=\"black\"];"));
+    assert!(dot.contains("\"web\"->\"db\" [color=\"grey66\"];"));
+    assert!(dot.contains("\"db\"->\"storage\" [color=\"green\"];"));
+    assert!(dot.contains("\"idle\"->\"web\" [color=\"red\"];"));
+    let filtered = times.dot(&["network".to_string()]);
+    assert_eq!(filtered.lines().count(), 3);
+    
+    assert_eq!(format_span(std::time::Duration::from_millis(1204)), "1.204s");
+    assert_eq!(format_span(std::time::Duration::from_millis(123_500)), "2min 3.500s");
+}
+
+#[test]
+fn test_verify_unit_files() {
+    let temp_dir = TempDir::new().unwrap();
+    let loader = UnitLoader {
+        system_units_dir: temp_dir.path().join("system"),
+        user_units_dir: temp_dir.path().join("user"),
+    };
+    
+    let good = temp_dir.path().join("web.tau");
+    fs::write(&good, r#"
+[service]
+exec_start = "/bin/sh -c true"
+
+[unit]
+requires = ["db"]
+"#).unwrap();
+    
+    let db = temp_dir.path().join("db.tau");
+    fs::write(&db, r#"
+[service]
+exec_start = "/no/such/daemon --foreground"
+restrat = "always"
+
+[unit]
+wants = ["cache"]
+
+[sandbox]
+read_write_paths = ["/var/lib/db"]
+read_only_paths = [
+    "/etc",
+    "/var/lib/db",
+]
+capability_bounding_set = ["CAP_NET_BIND_SERVICE"]
+ambient_capabilities = ["CAP_SYS_ADMIN"]
+"#).unwrap();
+    
+    let mut verifier = Verifier::new(loader);
+    let diagnostics = verifier.verify_files(&[good.clone(), db.clone()]);
+    let messages: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+    
+    // Units verified together may depend on each other
+    assert!(diagnostics.iter().all(|d| d.path == db), "{:?}", messages);
+    
+    let find = |text: &str| diagnostics.iter()
+        .find(|d| d.message.contains(text))
+        .unwrap_or_else(|| panic!("no diagnostic containing {:?} in {:?}", text, messages));
+    
+    let missing = find("/no/such/daemon does not exist");
+    assert_eq!((missing.line, missing.column, missing.severity), (3, 14, Severity::Error));
+    
+    let unknown = find("Unknown key 'restrat' in [service], did you mean 'restart'?");
+    assert_eq!((unknown.line, unknown.column), (4, 1));
+    
+    let wants = find("Dependency cache does not exist");
+    assert_eq!((wants.line, wants.column, wants.severity), (7, 10, Severity::Warning));
+    
+    let conflict = find("/var/lib/db is in both ReadWritePaths and ReadOnlyPaths");
+    assert_eq!((conflict.line, conflict.severity), (10, Severity::Error));
+    
+    let dropped = find("CAP_SYS_ADMIN in AmbientCapabilities is not in CapabilityBoundingSet");
+    assert_eq!((dropped.line, dropped.column), (16, 25));
+    assert_eq!(find("CAP_SYS_ADMIN grants").severity, Severity::Warning);
+    
+    // Type errors point at the offending value
+    let bad_type = temp_dir.path().join("bad.tau");
+    let diagnostics = verifier.verify(&bad_type, "[service]\nexec_start = \"/bin/true\"\nnice = \"high\"\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!((diagnostics[0].line, diagnostics[0].column), (3, 8));
+    
+    // Validation errors point at the key they are about
+    let diagnostics = verifier.verify(&bad_type, "[service]\nexec_start = \"/bin/true\"\ncpu_quota = \"lots\"\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert!(diagnostics[0].to_string().starts_with(&format!("{}:3:1: error:", bad_type.display())), "{}", diagnostics[0]);
+}
+
+#[tokio::test]
+async fn test_service_lifecycle_hooks_and_timeouts() {
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let dir = temp_dir.path().display();
+    let path = PathBuf::from("/etc/tau/services/hooks.tau");
+    
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "hooks"
+        
+        [service]
+        exec_start_pre = ["/bin/touch {dir}/pre", "-/bin/false"]
+        exec_start = "/bin/sleep 30"
+        exec_start_post = ["/bin/touch {dir}/post"]
+        exec_stop_post = ["/bin/touch {dir}/stop-post"]
+    "#), &path).unwrap();
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    assert!(temp_dir.path().join("pre").exists());
+    assert!(temp_dir.path().join("post").exists());
+    assert!(process.is_running());
+    
+    process.stop().unwrap();
+    assert!(!process.is_running());
+    assert!(temp_dir.path().join("stop-post").exists());
+    
+    // A failing hook fails the start, ExecStopPost= still runs
+    fs::remove_file(temp_dir.path().join("stop-post")).unwrap();
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "hooks"
+        
+        [service]
+        exec_start_pre = ["/bin/false"]
+        exec_start = "/bin/sleep 30"
+        exec_stop_post = ["/bin/touch {dir}/stop-post"]
+    "#), &path).unwrap();
+    
+    let error = ServiceProcess::new(&unit, &journal).unwrap().start().unwrap_err();
+    let error = error.downcast_ref::<ExecError>().unwrap();
+    assert_eq!(error.phase(), ExecPhase::StartPre);
+    assert_eq!(error.to_string(), "ExecStartPre command /bin/false failed (code=1)");
+    assert!(temp_dir.path().join("stop-post").exists());
+    
+    // Hooks that run too long are killed
+    let unit = ServiceUnit::from_str(r#"
+        name = "hooks"
+        
+        [service]
+        exec_start_pre = ["/bin/sleep 30"]
+        exec_start = "/bin/sleep 30"
+        timeout_start_sec = 1
+    "#, &path).unwrap();
+    
+    let started = std::time::Instant::now();
+    let error = ServiceProcess::new(&unit, &journal).unwrap().start().unwrap_err();
+    assert!(matches!(error.downcast_ref::<ExecError>(), Some(ExecError::TimedOut { phase: ExecPhase::StartPre, .. })));
+    assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    
+    // A service that ignores KillSignal= is killed once TimeoutStopSec= passes
+    let unit = ServiceUnit::from_str(r#"
+        name = "hooks"
+        
+        [service]
+        exec_start = "/bin/sleep 30"
+        kill_signal = "CONT"
+        kill_mode = "process"
+        timeout_stop_sec = 1
+    "#, &path).unwrap();
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    let error = process.stop().unwrap_err();
+    assert!(matches!(error.downcast_ref::<ExecError>(), Some(ExecError::TimedOut { phase: ExecPhase::Stop, .. })));
+    assert!(!process.is_running());
+    
+    for (signal, valid) in [("SIGHUP", true), ("term", true), ("9", true), ("SIGNOPE", false)] {
+        let content = format!("name = \"x\"\n[service]\nexec_start = \"/bin/true\"\nkill_signal = \"{}\"\n", signal);
+        assert_eq!(ServiceUnit::from_str(&content, &path).is_ok(), valid, "{}", signal);
+    }
+}
+
+#[tokio::test]
+async fn test_exec_command_line_parsing() {
+    let env: std::collections::HashMap<String, String> = [
+        ("ARGS", "-v  --debug"),
+        ("NAME", "two words"),
+    ].into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    
+    let command = ExecCommand::parse(r#"/bin/echo 'single $NAME' "double $NAME" a\ b $ARGS ${NAME} x${NAME}y $$ $UNSET"#, &env).unwrap();
+    assert_eq!(command.path, "/bin/echo");
+    assert_eq!(command.argv0, "/bin/echo");
+    assert_eq!(command.args, vec![
+        "single $NAME", "double two words", "a b", "-v", "--debug", "two words", "xtwo wordsy", "$",
+    ]);
+    assert!(!command.ignore_failure);
+    
+    // Prefixes
+    let command = ExecCommand::parse("-@/bin/sleep sleeper 10", &env).unwrap();
+    assert!(command.ignore_failure);
+    assert_eq!(command.path, "/bin/sleep");
+    assert_eq!(command.argv0, "sleeper");
+    assert_eq!(command.args, vec!["10"]);
+    
+    let command = ExecCommand::parse("+:/bin/echo $NAME", &env).unwrap();
+    assert!(command.full_privileges && command.no_expand);
+    assert_eq!(command.args, vec!["$NAME"]);
+    assert!(ExecCommand::parse("!/bin/true", &env).unwrap().keep_identity);
+    
+    for invalid in ["", "--/bin/true", "+!/bin/true", "/bin/echo 'open", "/bin/echo ${NAME", "@/bin/true"] {
+        assert!(ExecCommand::parse(invalid, &env).is_err(), "{}", invalid);
+    }
+    
+    let variables = parse_environment_file(r#"
+# comment
+; also a comment
+export PLAIN = value  
+QUOTED="line one
+line two"
+SINGLE='no \n escape'
+CONTINUED=one \
+two
+TRAILING=value # comment
+not an assignment
+EMPTY=
+"#).unwrap();
+    assert_eq!(variables, vec![
+        ("PLAIN".to_string(), "value".to_string()),
+        ("QUOTED".to_string(), "line one\nline two".to_string()),
+        ("SINGLE".to_string(), "no \\n escape".to_string()),
+        ("CONTINUED".to_string(), "one two".to_string()),
+        ("TRAILING".to_string(), "value".to_string()),
+        ("EMPTY".to_string(), String::new()),
+    ]);
+    assert!(parse_environment_file("KEY=\"unterminated").is_err());
+    
+    // Quoted arguments and variables from an environment file reach the command
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let dir = temp_dir.path().display();
+    fs::write(temp_dir.path().join("env"), "GREETING=\"hello  world\"\n").unwrap();
+    
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "quoting"
+        
+        [service]
+        exec_start_pre = ['''/bin/sh -c 'printf %s "$1" > {dir}/argument' sh "${{GREETING}}"''', "-/bin/false"]
+        exec_start = "/bin/sleep 30"
+        environment_file = ["{dir}/env", "-{dir}/missing"]
+        environment = {{ GREETING = "overridden" }}
+    "#), &PathBuf::from("/etc/tau/services/quoting.tau")).unwrap();
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    process.stop().unwrap();
+    assert_eq!(fs::read_to_string(temp_dir.path().join("argument")).unwrap(), "hello  world");
+}
+
+#[test]
+fn test_user_manager_scope() {
+    let user = ManagerScope::User {
+        uid: 1000,
+        home: PathBuf::from("/home/alice"),
+        runtime_dir: PathBuf::from("/run/user/1000"),
+    };
+    
+    // The system manager no longer loads units from home directories
+    assert_eq!(ManagerScope::current(), &ManagerScope::System);
+    assert!(!ManagerScope::System.unit_loader().unit_dirs().iter().any(|dir| dir.starts_with("/home")));
+    
+    assert_eq!(user.unit_loader().unit_dirs(), vec![
+        PathBuf::from("/etc/tau/user"),
+        PathBuf::from("/home/alice/.config/tau/services"),
+    ]);
+    assert_eq!(user.control_socket(), PathBuf::from("/run/user/1000/tau/service.sock"));
+    assert_eq!(user.notify_dir(), PathBuf::from("/run/user/1000/tau/notify"));
+    assert_eq!(user.journal_dir(), PathBuf::from("/home/alice/.local/state/tau/journal"));
+    assert_eq!(user.state_dir(), PathBuf::from("/home/alice/.local/state/tau-service"));
+    assert_eq!(user.targets_dir(), PathBuf::from("/home/alice/.config/tau/targets"));
+    assert_eq!(user.default_target(), "default.target");
+    assert_eq!(ManagerScope::System.default_target(), "multi-user.target");
+    assert_ne!(user.control_socket(), ManagerScope::System.control_socket());
+    
+    // Each user's manager is an instance of the user@ template
+    let temp_dir = TempDir::new().unwrap();
+    let services_dir = temp_dir.path().join("services");
+    fs::create_dir_all(&services_dir).unwrap();
+    fs::write(services_dir.join("user@.tau"), USER_MANAGER_UNIT).unwrap();
+    
+    let loader = UnitLoader { system_units_dir: services_dir, user_units_dir: temp_dir.path().join("none") };
+    let unit = loader.reload_unit(&user_manager_unit(1000)).unwrap();
+    assert_eq!(unit.name, "user@1000");
+    assert_eq!(unit.service.user.as_deref(), Some("1000"));
+    assert_eq!(unit.service.exec_start, Some("/usr/bin/tau-service --user daemon".into()));
+    assert_eq!(unit.service.environment.as_ref().unwrap()["XDG_RUNTIME_DIR"], "/run/user/1000");
+    
+    // Lingering
+    let linger_dir = temp_dir.path().join("linger");
+    assert!(lingering_users(&linger_dir).is_empty());
+    set_linger(&linger_dir, "bob", true).unwrap();
+    set_linger(&linger_dir, "alice", true).unwrap();
+    assert_eq!(lingering_users(&linger_dir), vec!["alice", "bob"]);
+    set_linger(&linger_dir, "bob", false).unwrap();
+    set_linger(&linger_dir, "bob", false).unwrap();
+    assert_eq!(lingering_users(&linger_dir), vec!["alice"]);
+    assert!(set_linger(&linger_dir, "../etc", true).is_err());
+}
+
+#[tokio::test]
+async fn test_standard_output_targets() {
+    assert_eq!(split_priority_prefix("<3>disk full"), (Some(LogLevel::Error), "disk full"));
+    assert_eq!(split_priority_prefix("<7>"), (Some(LogLevel::Debug), ""));
+    assert_eq!(split_priority_prefix("<9>not a priority"), (None, "<9>not a priority"));
+    assert_eq!(split_priority_prefix("error: no prefix"), (None, "error: no prefix"));
+    
+    assert_eq!(StandardOutput::parse("journal+console").unwrap(), StandardOutput::JournalConsole);
+    assert_eq!(StandardOutput::parse("append:/var/log/app.log").unwrap(), StandardOutput::Append(PathBuf::from("/var/log/app.log")));
+    assert_eq!(StandardOutput::parse("file:/tmp/out").unwrap().to_string(), "file:/tmp/out");
+    for invalid in ["syslog", "file:relative", "append:"] {
+        assert!(StandardOutput::parse(invalid).is_err(), "{}", invalid);
+    }
+    
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let dir = temp_dir.path().display();
+    let path = PathBuf::from("/etc/tau/services/output.tau");
+    
+    // Captured output is split into lines, each with its own priority
+    let unit = ServiceUnit::from_str(r#"
+        name = "output"
+        
+        [service]
+        exec_start_pre = ['''/bin/sh -c 'printf "<3>disk "; sleep 0.1; printf "full\nno prefix\n"; echo "<4>low space" >&2' ''']
+        exec_start = "/bin/sleep 30"
+        syslog_identifier = "storage"
+    "#, &path).unwrap();
+    assert_eq!(unit.standard_output().unwrap(), StandardOutput::Journal);
+    assert_eq!(unit.standard_error().unwrap(), StandardOutput::Journal);
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    process.stop().unwrap();
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    
+    let entries = journal.get_logs("output", None);
+    let lines: Vec<_> = entries.iter().map(|e| (e.message.as_str(), e.level.clone(), e.stream.as_str())).collect();
+    assert!(lines.contains(&("disk full", LogLevel::Error, "stdout")), "{:?}", lines);
+    assert!(lines.contains(&("no prefix", LogLevel::Info, "stdout")), "{:?}", lines);
+    assert!(lines.contains(&("low space", LogLevel::Warning, "stderr")), "{:?}", lines);
+    assert!(entries.iter().all(|e| e.field("SYSLOG_IDENTIFIER").as_deref() == Some("storage")));
+    
+    // StandardError= follows a file StandardOutput= into the same file
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "files"
+        
+        [service]
+        exec_start_pre = ["/bin/sh -c 'echo out; echo err >&2'"]
+        exec_start = "/bin/sleep 30"
+        standard_output = "file:{dir}/out.log"
+    "#), &path).unwrap();
+    assert_eq!(unit.standard_error().unwrap(), StandardOutput::File(temp_dir.path().join("out.log")));
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    process.stop().unwrap();
+    assert_eq!(fs::read_to_string(temp_dir.path().join("out.log")).unwrap(), "out\nerr\n");
+    
+    fs::write(temp_dir.path().join("append.log"), "existing\n").unwrap();
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "files"
+        
+        [service]
+        exec_start_pre = ["/bin/sh -c 'echo out; echo err >&2'"]
+        exec_start = "/bin/sleep 30"
+        standard_output = "append:{dir}/append.log"
+        standard_error = "null"
+    "#), &path).unwrap();
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    process.stop().unwrap();
+    assert_eq!(fs::read_to_string(temp_dir.path().join("append.log")).unwrap(), "existing\nout\n");
+    assert!(journal.get_logs("files", None).is_empty());
+    
+    let invalid = "name = \"x\"\n[service]\nexec_start = \"/bin/true\"\nstandard_error = \"syslog\"\n";
+    assert!(ServiceUnit::from_str(invalid, &path).is_err());
+}
+
+#[tokio::test]
+async fn test_runtime_state_handover() {
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let state_manager = StateManager::in_dir(&temp_dir.path().join("state")).unwrap();
+    let path = PathBuf::from("/etc/tau/services/adopted.tau");
+    
+    let unit = ServiceUnit::from_str(r#"
+        name = "adopted"
+        
+        [service]
+        exec_start = "/bin/sleep 30"
+    "#, &path).unwrap();
+    
+    // A main process started by a previous daemon, still writing to the
+    // pipe that daemon handed over. The adopted process reaps it
+    #[allow(clippy::zombie_processes)]
+    let mut child = std::process::Command::new("/bin/sh")
+        .args(["-c", "sleep 0.2; echo '<4>still here'; read line; exit 3"])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.take().unwrap();
+    let stdout_fd = keep_across_exec(child.stdout.as_ref().unwrap().as_raw_fd()).unwrap();
+    drop(child.stdout.take());
+    
+    let state = SerializedProcess {
+        pid: child.id(),
+        main_pid: None,
+        cgroup: None,
+        start_time: None,
+        credentials: None,
+        ignore_failure: true,
+        stdout_fd: Some(stdout_fd),
+        stderr_fd: None,
+    };
+    let mut process = ServiceProcess::adopt(&unit, &journal, state).unwrap();
+    assert_eq!(process.get_pid(), Some(child.id()));
+    assert!(process.ignores_failure());
+    assert!(process.try_wait().unwrap().is_none());
+    
+    let serialized = process.serialize(false).unwrap();
+    assert_eq!(serialized.pid, child.id());
+    assert_eq!(serialized.stdout_fd, None);
+    assert_eq!(serialized.start_time, process_start_time(child.id()));
+    assert!(serialized.start_time.is_some());
+    
+    // A process that started later under the same PID is not the service
+    let mut reused = serialized.clone();
+    reused.start_time = serialized.start_time.map(|time| time + 1);
+    assert!(ServiceProcess::adopt(&unit, &journal, reused).is_err());
+    
+    tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+    let entries = journal.get_logs("adopted", None);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].message, "still here");
+    assert_eq!(entries[0].level, LogLevel::Warning);
+    
+    // The exit status is reaped by PID
+    drop(stdin);
+    let mut status = None;
+    for _ in 0..100 {
+        status = process.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(status.and_then(|status| status.code()), Some(3));
+    assert!(process.serialize(false).is_none());
+    
+    // Processes that are not children are only watched
+    let state = SerializedProcess {
+        pid: 1,
+        main_pid: None,
+        cgroup: None,
+        start_time: None,
+        credentials: None,
+        ignore_failure: false,
+        stdout_fd: None,
+        stderr_fd: None,
+    };
+    let mut process = ServiceProcess::adopt(&unit, &journal, state).unwrap();
+    assert!(process.try_wait().unwrap().is_none());
+    
+    // Once one exits its status is unknown, which is not a clean exit
+    let output = std::process::Command::new("/bin/sh")
+        .args(["-c", "sleep 0.3 >/dev/null & echo $!"])
+        .output()
+        .unwrap();
+    let orphan: u32 = String::from_utf8_lossy(&output.stdout).trim().parse().unwrap();
+    let mut orphaned = ServiceProcess::adopt(&unit, &journal, SerializedProcess {
+        pid: orphan,
+        main_pid: None,
+        cgroup: None,
+        start_time: None,
+        credentials: None,
+        ignore_failure: false,
+        stdout_fd: None,
+        stderr_fd: None,
+    }).unwrap();
+    assert!(orphaned.try_wait().unwrap().is_none());
+    
+    let mut status = None;
+    for _ in 0..100 {
+        status = orphaned.try_wait().unwrap();
+        if status.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(!ExitOutcome::from_status(status.unwrap()).is_clean());
+    
+    // The state file is read once, and only in the boot it was written in
+    let mut status = ServiceStatus::new("adopted");
+    status.restart_count = 4;
+    let mut state = RuntimeState {
+        boot_id: current_boot_id(),
+        daemon_pid: std::process::id(),
+        statuses: vec![status],
+        services: vec![SerializedService {
+            name: "adopted".to_string(),
+            transient: false,
+            watchdog_usec: Some(5_000_000),
+            process: process.serialize(false).unwrap(),
+        }],
+        sockets: Vec::new(),
+        pending_restarts: vec!["other".to_string()],
+        instance_counter: 7,
+        first_boot: Some(true),
+    };
+    assert!(state.owns_fds());
+    
+    state_manager.save_runtime_state(&state).unwrap();
+    assert_eq!(state_manager.get_service_stats("adopted").unwrap().unwrap().restart_count, 4);
+    
+    let restored = state_manager.take_runtime_state().unwrap().unwrap();
+    assert_eq!(restored.statuses[0].restart_count, 4);
+    assert_eq!(restored.services[0].process.pid, 1);
+    assert_eq!(restored.services[0].watchdog_usec, Some(5_000_000));
+    assert_eq!(restored.pending_restarts, vec!["other".to_string()]);
+    assert_eq!(restored.first_boot, Some(true));
+    assert!(state_manager.take_runtime_state().unwrap().is_none());
+    
+    state.boot_id = "0123456789abcdef".to_string();
+    state_manager.save_runtime_state(&state).unwrap();
+    assert!(state_manager.take_runtime_state().unwrap().is_none());
+}
+
+#[test]
+fn test_dbus_unit_objects() {
+    assert_eq!(escape_path_label("nginx"), "nginx");
+    assert_eq!(escape_path_label("getty@tty1"), "getty_40tty1");
+    assert_eq!(escape_path_label("1password"), "_31password");
+    assert_eq!(escape_path_label(""), "_");
+    assert_eq!(unit_path("sshd-keygen").as_str(), "/org/tauos/ServiceManager1/unit/sshd_2dkeygen");
+    assert_eq!(job_path(7).as_str(), "/org/tauos/ServiceManager1/job/7");
+    
+    // Only root or the manager's own user may change units
+    assert!(may_manage_units(0));
+    assert!(may_manage_units(nix::unistd::getuid().as_raw()));
+    if nix::unistd::getuid().is_root() {
+        assert!(!may_manage_units(65534));
+    }
+    
+    let mut status = ServiceStatus::new("web");
+    let before = unit_properties(&status);
+    assert!(before.contains(&("ActiveState", Value::from("inactive"))));
+    assert!(before.contains(&("MainPID", Value::from(0u32))));
+    
+    status.state = ServiceState::Active;
+    status.pid = Some(4242);
+    let after = unit_properties(&status);
+    let changed = changed_properties(&before, &after);
+    assert_eq!(changed.len(), 2);
+    assert_eq!(changed["ActiveState"], &Value::from("active"));
+    assert_eq!(changed["MainPID"], &Value::from(4242u32));
+    assert!(changed_properties(&after, &after).is_empty());
+    
+    assert_eq!(job_result(&JobOutcome::Done), "done");
+    assert_eq!(job_result(&JobOutcome::Skipped), "skipped");
+    assert_eq!(job_result(&JobOutcome::Failed("exit 1".into())), "failed");
+    assert_eq!(job_result(&JobOutcome::DependencyFailed("db".into())), "dependency");
+}
+
+#[test]
+fn test_unit_conditions() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().display();
+    fs::write(temp_dir.path().join("empty"), "").unwrap();
+    fs::write(temp_dir.path().join("config"), "x").unwrap();
+    
+    let host = HostFacts {
+        kernel_command_line: "root=/dev/sda1 quiet console=ttyS0".to_string(),
+        virtualization: Virtualization::Vm("kvm".to_string()),
+        first_boot: false,
+        on_ac_power: true,
+        hostname: "build-03".to_string(),
+        machine_id: "5f2c0a8e3b1d4c6f9e7a0b2c4d6e8f01".to_string(),
+        environment: [("MODE".to_string(), "ci".to_string())].into_iter().collect(),
+    };
+    
+    let parse = |unit_section: &str| {
+        let unit = ServiceUnit::from_str(&format!("name = \"guarded\"\n[service]\nexec_start = \"/bin/true\"\n[unit]\n{}", unit_section),
+            &PathBuf::from("/etc/tau/services/guarded.tau")).unwrap();
+        UnitConditions::from_section(unit.unit.as_ref())
+    };
+    
+    let met = parse(&format!(r#"
+        condition_path_exists = ["{dir}/config", "!{dir}/missing"]
+        condition_path_is_directory = ["{dir}"]
+        condition_file_not_empty = ["{dir}/config"]
+        condition_kernel_command_line = ["quiet", "console=ttyS0", "!single"]
+        condition_virtualization = ["vm", "kvm", "yes"]
+        condition_first_boot = false
+        condition_ac_power = true
+        condition_environment = ["MODE", "MODE=ci"]
+        condition_host = ["build-*"]
+        assert_host = ["5f2c0a8e3b1d4c6f9e7a0b2c4d6e8f01"]
+    "#));
+    assert_eq!(met.conditions.len(), 15);
+    assert_eq!(met.assertions.len(), 1);
+    assert_eq!(met.evaluate(&host), ConditionResult::Met);
+    
+    // Skipped by a condition, the assertion is not even looked at
+    let skipped = parse(&format!(r#"
+        condition_file_not_empty = ["{dir}/empty"]
+        assert_path_exists = ["{dir}/missing"]
+    "#));
+    match skipped.evaluate(&host) {
+        ConditionResult::Skipped(condition) => {
+            assert_eq!(condition.to_string(), format!("ConditionFileNotEmpty={dir}/empty"));
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    
+    let failed = parse(r#"
+        condition_kernel_command_line = ["root"]
+        assert_virtualization = ["!vm"]
+    "#);
+    match failed.evaluate(&host) {
+        ConditionResult::AssertionFailed(assertion) => {
+            assert!(assertion.assert && assertion.negate);
+            assert_eq!(assertion.to_string(), "AssertVirtualization=!vm");
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    
+    // Triggering conditions: one of them is enough
+    let triggered = parse(r#"
+        condition_host = ["|other-host", "|build-03"]
+        condition_environment = ["|MODE=release"]
+    "#);
+    assert_eq!(triggered.evaluate(&host), ConditionResult::Met);
+    let untriggered = parse(r#"
+        condition_host = ["|other-host"]
+        condition_environment = ["|MODE=release"]
+    "#);
+    assert!(matches!(untriggered.evaluate(&host), ConditionResult::Skipped(_)));
+    
+    let container = Virtualization::Container("docker".to_string());
+    assert!(container.matches("container") && container.matches("docker") && !container.matches("vm"));
+    assert!(Virtualization::None.matches("no") && !Virtualization::None.matches("yes"));
+    assert!(!Virtualization::is_known("vmwar"));
+    
+    // verify flags relative paths and unknown technologies
+    let verifier = Verifier::new(UnitLoader::new());
+    let diagnostics = verifier.verify(&PathBuf::from("/etc/tau/services/guarded.tau"), r#"name = "guarded"
+
+[service]
+exec_start = "/bin/true"
+
+[unit]
+condition_path_exists = ["!etc/flag"]
+condition_virtualization = ["vmwar"]
+"#);
+    assert!(diagnostics.iter().any(|d| d.severity == Severity::Error && d.line == 7 && d.message.contains("absolute path")));
+    assert!(diagnostics.iter().any(|d| d.severity == Severity::Warning && d.line == 8 && d.message.contains("vmwar")));
+}
+
+#[tokio::test]
+async fn test_forking_and_oneshot_services() {
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let dir = temp_dir.path().display();
+    let path = PathBuf::from("/etc/tau/services/setup.tau");
+    
+    // Oneshot: the commands run in order, the start is over when they are
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "setup"
+        
+        [service]
+        type = "oneshot"
+        remain_after_exit = true
+        exec_start = ["/bin/touch {dir}/first", "/bin/sh -c 'test -e {dir}/first && touch {dir}/second'"]
+        exec_stop = "/bin/touch {dir}/undone"
+    "#), &path).unwrap();
+    assert!(unit.is_oneshot() && unit.remain_after_exit());
+    assert_eq!(unit.start_timeout(), None);
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    assert!(temp_dir.path().join("second").exists());
+    assert_eq!(process.get_pid(), None);
+    assert!(process.try_wait().unwrap().is_none());
+    
+    // Handed over across a re-exec without a process
+    let state = process.serialize(false).unwrap();
+    assert_eq!(state.pid, 0);
+    let mut process = ServiceProcess::adopt(&unit, &journal, state).unwrap();
+    
+    // ExecStop= undoes the start even though nothing is running
+    process.stop().unwrap();
+    assert!(temp_dir.path().join("undone").exists());
+    
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "setup"
+        
+        [service]
+        type = "oneshot"
+        exec_start = ["/bin/true", "/bin/false", "/bin/touch {dir}/never"]
+    "#), &path).unwrap();
+    let error = ServiceProcess::new(&unit, &journal).unwrap().start().unwrap_err();
+    assert_eq!(error.downcast_ref::<ExecError>().unwrap().phase(), ExecPhase::Start);
+    assert!(!temp_dir.path().join("never").exists());
+    
+    // More than one command is only allowed for oneshot
+    assert!(ServiceUnit::from_str(r#"
+        name = "setup"
+        
+        [service]
+        exec_start = ["/bin/true", "/bin/true"]
+    "#, &path).is_err());
+    
+    // Forking: the daemon named in PIDFile= becomes the main process
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "daemon"
+        
+        [service]
+        type = "forking"
+        pid_file = "{dir}/daemon.pid"
+        exec_start = "/bin/sh -c '/bin/sleep 30 & echo $! > {dir}/daemon.pid'"
+    "#), &PathBuf::from("/etc/tau/services/daemon.tau")).unwrap();
+    assert!(unit.is_forking());
+    
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.start().unwrap();
+    let pid: u32 = fs::read_to_string(temp_dir.path().join("daemon.pid")).unwrap().trim().parse().unwrap();
+    assert_eq!(process.get_pid(), Some(pid));
+    assert!(process.try_wait().unwrap().is_none());
+    assert_eq!(process.serialize(false).unwrap().pid, pid);
+    
+    process.stop().unwrap();
+    assert!(std::fs::read_to_string(format!("/proc/{}/stat", pid)).map_or(true, |stat| stat.contains(") Z ")));
+    
+    // A daemon that never writes its PID file fails the start
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "daemon"
+        
+        [service]
+        type = "forking"
+        pid_file = "{dir}/missing.pid"
+        timeout_start_sec = 1
+        exec_start = "/bin/true"
+    "#), &PathBuf::from("/etc/tau/services/daemon.tau")).unwrap();
+    let error = ServiceProcess::new(&unit, &journal).unwrap().start().unwrap_err();
+    assert!(error.to_string().contains("missing.pid"), "{}", error);
+}
+
+#[test]
+fn test_coredump_store() {
+    assert_eq!(unit_from_cgroup("0::/tau.slice/nginx.service\n"), Some("nginx".to_string()));
+    assert_eq!(unit_from_cgroup("0::/user.slice/user-1000.slice/tau.slice/editor.service\n"), Some("editor".to_string()));
+    assert_eq!(unit_from_cgroup("0::/init.scope\n"), None);
+    assert_eq!(core_pattern(std::path::Path::new("/usr/bin/tau-service")),
+        "|/usr/bin/tau-service coredump handle %P %u %g %s %t %c %h %e");
+    
+    let gdb_output = "[New LWP 4242]\nCore was generated by `/usr/bin/worker'.\n\nThread 1 (LWP 4242):\n#0  0x0000 in crash () at worker.c:12\n#1  0x0001 in main () at worker.c:30\n";
+    assert_eq!(parse_backtrace(gdb_output).unwrap(),
+        "Thread 1 (LWP 4242):\n#0  0x0000 in crash () at worker.c:12\n#1  0x0001 in main () at worker.c:30");
+    assert_eq!(parse_backtrace("No symbol table is loaded.\n"), None);
+    
+    let temp_dir = TempDir::new().unwrap();
+    let store = CoredumpStore::open(temp_dir.path().join("coredump"))
+        .with_limits(1024, 1 << 20, std::time::Duration::from_secs(3600));
+    let now = chrono::Utc::now().timestamp();
+    let crash = |pid: u32, comm: &str, timestamp: i64| Crash {
+        pid,
+        uid: 0,
+        gid: 0,
+        signal: 11,
+        timestamp,
+        core_limit: u64::MAX,
+        hostname: "build-03".to_string(),
+        comm: comm.to_string(),
+    };
+    
+    let mut info = CoredumpInfo::new(&crash(4242, "worker", now - 10), "bootid");
+    info.unit = Some("worker".to_string());
+    info.exe = Some("/nonexistent/worker".to_string());
+    let core = vec![0x7fu8; 4000];
+    let stored = store.store(info, &core[..]).unwrap();
+    assert_eq!(stored.id, format!("core.worker.0.bootid.4242.{}", now - 10));
+    assert_eq!(stored.size, 1024);
+    assert!(stored.truncated);
+    assert!(stored.core_file.as_ref().unwrap().exists());
+    assert!(!store.dir().join(format!("{}.core", stored.id)).exists());
+    
+    let extracted = temp_dir.path().join("core");
+    store.extract(&stored, &extracted).unwrap();
+    assert_eq!(fs::read(&extracted).unwrap(), vec![0x7fu8; 1024]);
+    
+    // No core, only the crash is recorded
+    let crashed = store.store(CoredumpInfo::new(&crash(77, "bad name/x", now), "bootid"), std::io::empty()).unwrap();
+    assert_eq!(crashed.id, format!("core.bad_name_x.0.bootid.77.{}", now));
+    assert_eq!(crashed.core_file, None);
+    assert!(store.extract(&crashed, &extracted).is_err());
+    
+    let dumps = store.list().unwrap();
+    assert_eq!(dumps.len(), 2);
+    assert_eq!(dumps[0], stored);
+    assert_eq!(store.find(None).unwrap().pid, 77);
+    assert_eq!(store.find(Some("worker")).unwrap().pid, 4242);
+    assert_eq!(store.find(Some("4242")).unwrap().id, stored.id);
+    assert_eq!(store.get(&stored.id).unwrap(), stored);
+    assert!(store.find(Some("nginx")).is_err());
+    assert!(store.get("../escape").is_err());
+    
+    let entry = stored.journal_entry();
+    assert_eq!(entry.service, "worker");
+    assert_eq!(entry.level, LogLevel::Critical);
+    assert_eq!(entry.pid, Some(4242));
+    assert_eq!(entry.message, "Process 4242 (worker) of user 0 dumped core (SIGSEGV)");
+    assert_eq!(entry.fields["COREDUMP_ID"], stored.id);
+    assert_eq!(entry.fields["COREDUMP_FILENAME"], stored.core_file.as_ref().unwrap().to_string_lossy());
+    assert_eq!(crashed.journal_entry().service, "coredump");
+    
+    // Too old to keep
+    store.store(CoredumpInfo::new(&crash(1, "old", now - 7200), "bootid"), &core[..100]).unwrap();
+    assert_eq!(store.list().unwrap().len(), 2);
+    
+    // Over the size limit, the oldest go first
+    let small = CoredumpStore::open(store.dir()).with_limits(1024, 1, std::time::Duration::from_secs(3600));
+    assert_eq!(small.vacuum(), 2);
+    assert!(small.list().unwrap().is_empty());
+    assert!(!stored.core_file.unwrap().exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_event_loop_starts_notify_services() {
+    let temp_dir = TempDir::new().unwrap();
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
+    fs::create_dir_all(&services_dir).unwrap();
+    fs::write(services_dir.join("waiter.tau"), r#"
+name = "waiter"
+
+[service]
+type = "notify"
+exec_start = "/bin/sleep 30"
+timeout_start_sec = 20
+"#).unwrap();
+    
+    let manager = ServiceManager::for_scope(scope).unwrap();
+    manager.load_units().unwrap();
+    
+    let wait_for = |state: ServiceState| {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        loop {
+            let status = manager.get_service_status("waiter");
+            if status.as_ref().is_some_and(|status| status.state == state && status.pid.is_some()) {
+                return status.unwrap();
+            }
+            assert!(std::time::Instant::now() < deadline, "waiter never became {:?}: {:?}", state, status);
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+    };
+    
+    // Path, socket and timer activation start services through the
+    // event loop, which also delivers their READY=1
+    manager.send_event(ServiceEvent::Start { name: "waiter".to_string() });
+    let activating = wait_for(ServiceState::Activating);
+    manager.send_event(ServiceEvent::Notify {
+        name: "waiter".to_string(),
+        message: NotifyMessage::parse("READY=1"),
+        pid: activating.pid.unwrap(),
+    });
+    wait_for(ServiceState::Active);
+    
+    manager.stop_service("waiter").unwrap();
+}
+
+#[test]
+fn test_daemon_stops_units_on_sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let runtime_dir = temp_dir.path().join("run");
+    fs::create_dir_all(&runtime_dir).unwrap();
+    let units_dir = temp_dir.path().join(".config/tau/services");
+    fs::create_dir_all(&units_dir).unwrap();
+    
+    let dir = temp_dir.path().display();
+    fs::write(units_dir.join("worker.tau"), format!(r#"
+        name = "worker"
+        
+        [service]
+        exec_start = "/bin/sleep 30"
+        exec_stop = "/bin/touch {dir}/stopped"
+    "#)).unwrap();
+    
+    let tau_service = |args: &[&str]| {
+        let mut cmd = std::process::Command::new(env!("CARGO_BIN_EXE_tau-service"));
+        cmd.arg("--user")
+            .args(args)
+            .env("HOME", temp_dir.path())
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .env_remove("TAU_SERVICE_SOCKET")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null());
+        cmd
+    };
+    
+    let mut daemon = tau_service(&["daemon"]).spawn().unwrap();
+    
+    let socket = runtime_dir.join("tau/service.sock");
+    for _ in 0..250 {
+        if socket.exists() {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(20));
+    }
+    assert!(socket.exists());
+    assert!(tau_service(&["start", "worker"]).status().unwrap().success());
+    
+    // A stop of the daemon stops its services, nothing is left to adopt
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(daemon.id() as i32), nix::sys::signal::Signal::SIGTERM).unwrap();
+    assert!(daemon.wait().unwrap().success());
+    
+    assert!(temp_dir.path().join("stopped").exists());
+    let state_manager = StateManager::in_dir(&temp_dir.path().join(".local/state/tau-service")).unwrap();
+    assert!(state_manager.take_runtime_state().unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_listen_fds_environment() {
+    let temp_dir = TempDir::new().unwrap();
+    let journal = JournalLogger::open(&temp_dir.path().join("journal")).unwrap();
+    let dir = temp_dir.path().display();
+    let path = PathBuf::from("/etc/tau/services/activated.tau");
+    
+    let unit = ServiceUnit::from_str(&format!(r#"
+        name = "activated"
+        
+        [service]
+        type = "oneshot"
+        exec_start = "/bin/sh -c 'echo $LISTEN_PID $$ $LISTEN_FDS $LISTEN_FDNAMES $KEPT > {dir}/env'"
+        environment = {{ KEPT = "yes" }}
+    "#), &path).unwrap();
+    
+    let listener = std::os::unix::net::UnixListener::bind(temp_dir.path().join("listen.sock")).unwrap();
+    let mut process = ServiceProcess::new(&unit, &journal).unwrap();
+    process.set_listen_fds(vec![(listener.as_raw_fd(), "web".to_string())]);
+    process.start().unwrap();
+    
+    // LISTEN_PID= is the PID of the service itself
+    let env = fs::read_to_string(temp_dir.path().join("env")).unwrap();
+    let fields: Vec<&str> = env.split_whitespace().collect();
+    assert_eq!(fields.len(), 5);
+    assert_eq!(fields[0], fields[1]);
+    assert_eq!(&fields[2..], ["1", "web", "yes"]);
+}
+
+#[tokio::test]
+async fn test_control_event_subscription() {
+    let temp_dir = TempDir::new().unwrap();
+    let scope = temp_scope(&temp_dir);
+    let services_dir = scope.unit_loader().user_units_dir;
+    fs::create_dir_all(&services_dir).unwrap();
+    fs::write(services_dir.join("sleeper.tau"), r#"
+name = "sleeper"
+
+[service]
+exec_start = "/bin/sleep 30"
+"#).unwrap();
+    
+    let manager = ServiceManager::for_scope(scope.clone()).unwrap();
+    manager.load_units().unwrap();
+    let socket_path = scope.control_socket();
+    let server = ControlServer::new(manager.clone(), &socket_path);
+    tokio::spawn(async move { server.run().await });
+    
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let mut events = loop {
+        match ControlClient::connect(&socket_path).await {
+            Ok(client) => break client,
+            Err(e) => assert!(std::time::Instant::now() < deadline, "control socket never came up: {:#}", e),
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    };
+    events.subscribe().await.unwrap();
+    
+    // Changes made through another connection reach the subscriber
+    let mut client = ControlClient::connect(&socket_path).await.unwrap();
+    client.call(ControlRequest::Start { service: "sleeper".to_string(), wait: false }).await.unwrap();
+    
+    let mut states = Vec::new();
+    while !states.contains(&ServiceState::Active) {
+        let event = tokio::time::timeout(std::time::Duration::from_secs(5), events.next_event())
+            .await
+            .expect("no event for the start of sleeper")
+            .unwrap()
+            .unwrap();
+        if let ManagerEvent::StateChanged { name, state, pid } = event {
+            assert_eq!(name, "sleeper");
+            if state == ServiceState::Active {
+                assert!(pid.is_some());
+            }
+            states.push(state);
+        }
+    }
+    assert_eq!(states.first(), Some(&ServiceState::Activating));
+    
+    manager.stop_service("sleeper").unwrap();
+}